@@ -1,7 +1,10 @@
-use packed_struct::derive::PackedStruct;
+use ecu_diagnostics::{kwp2000::KwpSessionType, DiagError};
+use packed_struct::{derive::PackedStruct, PackedStructSlice};
 use serde_big_array::BigArray;
 use serde_derive::{Serialize, Deserialize};
 
+use super::{memory::MemoryRegion, Nag52Diag};
+
 #[derive(PackedStruct, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
 #[repr(C)]
 #[packed_struct(endian = "lsb")]
@@ -173,7 +176,7 @@ pub struct CalibrationDatabase {
 }
 
 // On the TCU itself (At address 0x34900)
-#[derive(PackedStruct, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(PackedStruct, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
 #[repr(C)]
 #[packed_struct(endian = "lsb")]
 pub struct EgsStoredCalibration {
@@ -192,4 +195,594 @@ pub struct EgsStoredCalibration {
     pub shift_algo_cal_name: [u8;16],
     #[packed_field(element_size_bytes="672")]
     pub shift_algo_cal: EgsShiftMapConfiguration
-}
\ No newline at end of file
+}
+
+/// Magic of a signed [EgsStoredCalibration]
+pub const EGS_CAL_MAGIC: u32 = 0xDEADBEEF;
+/// Identifier returning the size of [EgsStoredCalibration] the firmware was built with
+const RLI_CALIBRATION_SIZE: u8 = 0xFB;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CalibrationKind {
+    Hydraulic,
+    Mechanical,
+    TorqueConverter,
+    ShiftAlgo,
+}
+
+impl CalibrationKind {
+    pub const ALL: [Self; 4] = [Self::Hydraulic, Self::Mechanical, Self::TorqueConverter, Self::ShiftAlgo];
+}
+
+impl std::fmt::Display for CalibrationKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Hydraulic => "Hydraulic",
+            Self::Mechanical => "Mechanical",
+            Self::TorqueConverter => "TCC properties",
+            Self::ShiftAlgo => "Shift algo pack",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CalibrationDbError {
+    Decompress(String),
+    Decode(String),
+}
+
+impl std::fmt::Display for CalibrationDbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Decompress(e) => write!(f, "LZ4 decompress failed. {e}"),
+            Self::Decode(e) => write!(f, "Database decode failed. {e}"),
+        }
+    }
+}
+
+impl CalibrationDatabase {
+    /// Decodes a database file (`egs_db.bin`), which is bincode encoded and LZ4 compressed
+    pub fn decode(bytes: &[u8]) -> Result<Self, CalibrationDbError> {
+        let raw = lz4_compression::decompress::decompress(bytes).map_err(|e| {
+            CalibrationDbError::Decompress(
+                match e {
+                    lz4_compression::decompress::Error::UnexpectedEnd => "Unexpected End",
+                    lz4_compression::decompress::Error::InvalidDeduplicationOffset => "Invalid deduplication offset",
+                }
+                .into(),
+            )
+        })?;
+        bincode::serde::decode_from_slice::<Self, _>(&raw, bincode::config::legacy())
+            .map(|(db, _)| db)
+            .map_err(|e| CalibrationDbError::Decode(e.to_string()))
+    }
+
+    /// Name the TCU stores next to the data of a record, when it is applied for an EGS part number
+    pub fn stored_name(pn: &str, record_name: &str) -> String {
+        let name = if record_name == "NO NAME" { "NN" } else { record_name };
+        format!("{pn}.{name}")
+    }
+
+    /// Name and valid EGS part numbers of every record of a kind
+    pub fn records(&self, kind: CalibrationKind) -> Vec<(&str, &[String])> {
+        fn list<T: Ord>(records: &[CalibrationRecord<T>]) -> Vec<(&str, &[String])> {
+            records.iter().map(|r| (r.name.as_str(), r.valid_egs_pns.as_slice())).collect()
+        }
+        match kind {
+            CalibrationKind::Hydraulic => list(&self.hydralic_calibrations),
+            CalibrationKind::Mechanical => list(&self.mechanical_calibrations),
+            CalibrationKind::TorqueConverter => list(&self.torqueconverter_calibrations),
+            CalibrationKind::ShiftAlgo => list(&self.shift_algo_map_calibration),
+        }
+    }
+
+    /// Fields of a record (By its index within [Self::records])
+    pub fn record_fields(&self, kind: CalibrationKind, idx: usize) -> Option<Vec<CalibrationField>> {
+        match kind {
+            CalibrationKind::Hydraulic => self.hydralic_calibrations.get(idx).map(|r| calibration_fields(&r.data)),
+            CalibrationKind::Mechanical => self.mechanical_calibrations.get(idx).map(|r| calibration_fields(&r.data)),
+            CalibrationKind::TorqueConverter => {
+                self.torqueconverter_calibrations.get(idx).map(|r| calibration_fields(&r.data))
+            }
+            CalibrationKind::ShiftAlgo => self.shift_algo_map_calibration.get(idx).map(|r| calibration_fields(&r.data)),
+        }
+    }
+
+    /// EGS part numbers and chassis configurations that use a record
+    pub fn record_users(&self, kind: CalibrationKind, name: &str, valid_egs_pns: &[String]) -> Vec<(&str, &ChassisConfig)> {
+        self.egs_list
+            .iter()
+            .filter(|egs| valid_egs_pns.contains(&egs.pn))
+            .flat_map(|egs| egs.chassis.iter().map(move |c| (egs.pn.as_str(), c)))
+            .filter(|(_, c)| c.calibration(kind) == name)
+            .collect()
+    }
+}
+
+impl ChassisConfig {
+    /// Name of the record a chassis uses for a kind of calibration
+    pub fn calibration(&self, kind: CalibrationKind) -> &str {
+        match kind {
+            CalibrationKind::Hydraulic => &self.hydr_cfg,
+            CalibrationKind::Mechanical => &self.mech_cfg,
+            CalibrationKind::TorqueConverter => &self.tcc_cfg,
+            CalibrationKind::ShiftAlgo => &self.shift_algo_cfg,
+        }
+    }
+}
+
+/// Field of a calibration, with arrays flattened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CalibrationField {
+    pub name: String,
+    /// Single value for scalar fields
+    pub values: Vec<i64>,
+    pub is_array: bool,
+}
+
+/// Lists the fields of a calibration, in the order they are declared
+pub fn calibration_fields<T: serde::Serialize>(cal: &T) -> Vec<CalibrationField> {
+    // YAML mappings keep the field order, unlike JSON objects
+    let Ok(serde_yaml::Value::Mapping(map)) = serde_yaml::to_value(cal) else {
+        return Vec::new();
+    };
+    map.into_iter()
+        .filter_map(|(k, v)| {
+            let name = k.as_str()?.to_string();
+            match v {
+                serde_yaml::Value::Sequence(seq) => {
+                    Some(CalibrationField { name, values: seq.iter().filter_map(|x| x.as_i64()).collect(), is_array: true })
+                }
+                v => Some(CalibrationField { name, values: vec![v.as_i64()?], is_array: false }),
+            }
+        })
+        .collect()
+}
+
+/// Same field of two calibrations
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CalibrationFieldDiff {
+    pub name: String,
+    pub a: Vec<i64>,
+    pub b: Vec<i64>,
+    pub is_array: bool,
+    /// Rows and columns to show the values as a table
+    pub shape: (usize, usize),
+}
+
+impl CalibrationFieldDiff {
+    /// Number of values that differ
+    pub fn changed(&self) -> usize {
+        self.a.iter().zip(self.b.iter()).filter(|(a, b)| a != b).count() + self.a.len().abs_diff(self.b.len())
+    }
+
+    /// Differences (b - a) of each value
+    pub fn deltas(&self) -> Vec<i64> {
+        self.a.iter().zip(self.b.iter()).map(|(a, b)| b - a).collect()
+    }
+}
+
+/// Table shape of an array field. Map data (`X_z`) uses the lengths of the axis fields (`X_x` and `X_y`),
+/// other arrays are shown as a single row if short, or as close to a square as possible
+fn field_shape(field: &CalibrationField, all: &[CalibrationField]) -> (usize, usize) {
+    let len = field.values.len();
+    if let Some(prefix) = field.name.strip_suffix("_z") {
+        let axis_len = |axis: &str| all.iter().find(|f| f.name == format!("{prefix}_{axis}")).map(|f| f.values.len());
+        match (axis_len("x"), axis_len("y")) {
+            (Some(x), Some(y)) if x * y == len => return (y, x),
+            (Some(x), None) if x == len => return (1, x),
+            _ => {}
+        }
+    }
+    if len <= 16 {
+        return (1, len);
+    }
+    let cols = (1..=len).find(|c| c * c >= len && len.is_multiple_of(*c)).unwrap_or(len);
+    (len / cols, cols)
+}
+
+/// Compares two calibrations of the same kind (From [calibration_fields]) field by field
+pub fn diff_calibrations(a: &[CalibrationField], b: &[CalibrationField]) -> Vec<CalibrationFieldDiff> {
+    a.iter()
+        .map(|fa| {
+            let vb = b.iter().find(|fb| fb.name == fa.name).map(|fb| fb.values.clone()).unwrap_or_default();
+            CalibrationFieldDiff {
+                name: fa.name.clone(),
+                a: fa.values.clone(),
+                b: vb,
+                is_array: fa.is_array,
+                shape: field_shape(fa, a),
+            }
+        })
+        .collect()
+}
+
+/// Database record with the same data as a stored calibration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CalibrationMatch {
+    pub name: String,
+    pub valid_egs_pns: Vec<String>,
+}
+
+fn find_matches<T: PartialEq + Eq + PartialOrd + Ord>(records: &[CalibrationRecord<T>], data: &T) -> Vec<CalibrationMatch> {
+    // All calibration fields are integers, so equal fields means equal bytes
+    records
+        .iter()
+        .filter(|r| r.data == *data)
+        .map(|r| CalibrationMatch { name: r.name.clone(), valid_egs_pns: r.valid_egs_pns.clone() })
+        .collect()
+}
+
+/// One of the four calibrations within [EgsStoredCalibration]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredCalibrationSet<T> {
+    pub kind: CalibrationKind,
+    /// Name stored next to the data (Without trailing NULLs). None if it is not valid UTF-8
+    pub name: Option<String>,
+    pub data: T,
+    /// Database records with the same data. Empty if the data is custom, corrupted or was modified
+    pub matches: Vec<CalibrationMatch>,
+}
+
+impl<T> StoredCalibrationSet<T> {
+    fn new(kind: CalibrationKind, name: &[u8; 16], data: T, matches: Vec<CalibrationMatch>) -> Self {
+        let len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+        Self { kind, name: String::from_utf8(name[..len].to_vec()).ok(), data, matches }
+    }
+
+    /// Splits a name of a database calibration into the EGS part number and record name.
+    /// Returns None for custom names
+    pub fn database_name(&self) -> Option<(&str, &str)> {
+        self.name.as_deref()?.split_once('.').filter(|(pn, _)| pn.starts_with('A'))
+    }
+
+    /// Returns true if the data is identical to the database record the calibration is named after
+    pub fn matches_name(&self) -> bool {
+        let Some(name) = &self.name else {
+            return false;
+        };
+        self.matches.iter().any(|m| {
+            m.valid_egs_pns.iter().any(|pn| CalibrationDatabase::stored_name(pn, &m.name) == *name)
+        })
+    }
+}
+
+/// Problem with the header of [EgsStoredCalibration]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationFault {
+    InvalidMagic(u32),
+    InvalidLength { stored: u16, expected: u16 },
+    CrcMismatch { stored: u16, calculated: u16 },
+}
+
+impl std::fmt::Display for CalibrationFault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidMagic(m) => write!(f, "Invalid magic 0x{m:08X} (Expected 0x{EGS_CAL_MAGIC:08X})"),
+            Self::InvalidLength { stored, expected } => write!(f, "Invalid length {stored} (Expected {expected})"),
+            Self::CrcMismatch { stored, calculated } => {
+                write!(f, "CRC mismatch. Stored 0x{stored:04X}, calculated 0x{calculated:04X}")
+            }
+        }
+    }
+}
+
+impl EgsStoredCalibration {
+    /// Checksum of the packed calibration, excluding the header
+    pub fn calc_crc(&self) -> u16 {
+        let bytes = self.pack_to_vec().unwrap_or_default();
+        bytes.iter().skip(8).enumerate().fold(0u16, |crc, (idx, b)| crc.wrapping_add(*b as u16).wrapping_add(idx as u16))
+    }
+
+    /// Sets the magic, length and CRC, which the TCU checks before using the calibration
+    pub fn sign(&mut self) {
+        self.crc = self.calc_crc();
+        self.len = Self::packed_bytes_size(None).unwrap_or_default() as u16;
+        self.magic = EGS_CAL_MAGIC;
+    }
+
+    /// Checks the magic, length and CRC
+    pub fn faults(&self) -> Vec<CalibrationFault> {
+        let mut ret = Vec::new();
+        if self.magic != EGS_CAL_MAGIC {
+            ret.push(CalibrationFault::InvalidMagic(self.magic));
+        }
+        let expected = Self::packed_bytes_size(None).unwrap_or_default() as u16;
+        if self.len != expected {
+            ret.push(CalibrationFault::InvalidLength { stored: self.len, expected });
+        }
+        let calculated = self.calc_crc();
+        if self.crc != calculated {
+            ret.push(CalibrationFault::CrcMismatch { stored: self.crc, calculated });
+        }
+        ret
+    }
+
+    /// Sets the stored name of a calibration. Names longer than 16 bytes are refused, rather than truncated
+    pub fn set_name(&mut self, kind: CalibrationKind, name: &str) -> Result<(), CalibrationError> {
+        let dest = match kind {
+            CalibrationKind::Hydraulic => &mut self.hydr_cal_name,
+            CalibrationKind::Mechanical => &mut self.mech_cal_name,
+            CalibrationKind::TorqueConverter => &mut self.tcc_cal_name,
+            CalibrationKind::ShiftAlgo => &mut self.shift_algo_cal_name,
+        };
+        if name.len() > dest.len() {
+            return Err(CalibrationError::NameTooLong { name: name.to_string(), max: dest.len() });
+        }
+        dest.fill(0);
+        dest[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(())
+    }
+}
+
+/// Calibration stored on the TCU, checked and matched against a [CalibrationDatabase]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredCalibration {
+    pub raw: EgsStoredCalibration,
+    /// Header problems. If not empty, the TCU will not use the calibration
+    pub faults: Vec<CalibrationFault>,
+    pub hydraulic: StoredCalibrationSet<EgsHydraulicConfiguration>,
+    pub mechanical: StoredCalibrationSet<EgsMechanicalConfiguration>,
+    pub torque_converter: StoredCalibrationSet<EgsTorqueConverterConfiguration>,
+    pub shift_algo: StoredCalibrationSet<EgsShiftMapConfiguration>,
+}
+
+impl StoredCalibration {
+    pub fn new(raw: EgsStoredCalibration, db: &CalibrationDatabase) -> Self {
+        Self {
+            faults: raw.faults(),
+            hydraulic: StoredCalibrationSet::new(
+                CalibrationKind::Hydraulic,
+                &raw.hydr_cal_name,
+                raw.hydr_cal,
+                find_matches(&db.hydralic_calibrations, &raw.hydr_cal),
+            ),
+            mechanical: StoredCalibrationSet::new(
+                CalibrationKind::Mechanical,
+                &raw.mech_cal_name,
+                raw.mech_cal,
+                find_matches(&db.mechanical_calibrations, &raw.mech_cal),
+            ),
+            torque_converter: StoredCalibrationSet::new(
+                CalibrationKind::TorqueConverter,
+                &raw.tcc_cal_name,
+                raw.tcc_cal,
+                find_matches(&db.torqueconverter_calibrations, &raw.tcc_cal),
+            ),
+            shift_algo: StoredCalibrationSet::new(
+                CalibrationKind::ShiftAlgo,
+                &raw.shift_algo_cal_name,
+                raw.shift_algo_cal,
+                find_matches(&db.shift_algo_map_calibration, &raw.shift_algo_cal),
+            ),
+            raw,
+        }
+    }
+
+    pub fn parse(bytes: &[u8], db: &CalibrationDatabase) -> Result<Self, CalibrationError> {
+        let expected = EgsStoredCalibration::packed_bytes_size(None).unwrap_or_default();
+        let raw = EgsStoredCalibration::unpack_from_slice(bytes).map_err(|_| CalibrationError::SizeMismatch {
+            tcu: bytes.len() as u16,
+            app: expected as u16,
+        })?;
+        Ok(Self::new(raw, db))
+    }
+
+    /// Returns true if the header is valid, and every calibration is identical to the database record it is named after
+    pub fn is_stock(&self) -> bool {
+        self.faults.is_empty()
+            && self.hydraulic.matches_name()
+            && self.mechanical.matches_name()
+            && self.torque_converter.matches_name()
+            && self.shift_algo.matches_name()
+    }
+}
+
+#[derive(Debug)]
+pub enum CalibrationError {
+    /// Firmware does not support calibrations
+    Unsupported,
+    /// Firmware and app were built with different calibration layouts
+    SizeMismatch { tcu: u16, app: u16 },
+    /// Calibration name does not fit in the header
+    NameTooLong { name: String, max: usize },
+    Diag(DiagError),
+}
+
+impl std::fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unsupported => write!(f, "TCU does not support calibration. Please update firmware"),
+            Self::SizeMismatch { tcu, app } => write!(
+                f,
+                "Mismatch calibration size ({tcu} bytes on the TCU, {app} bytes expected)! Either your Firmware or Configuration app is out of date"
+            ),
+            Self::NameTooLong { name, max } => {
+                write!(f, "Calibration name '{name}' is {} bytes long, but at most {max} bytes can be stored", name.len())
+            }
+            Self::Diag(e) => write!(f, "Error downloading calibration. {e}"),
+        }
+    }
+}
+
+impl From<DiagError> for CalibrationError {
+    fn from(e: DiagError) -> Self {
+        Self::Diag(e)
+    }
+}
+
+impl Nag52Diag {
+    /// Reads the calibration block, without checking its contents
+    pub fn read_calibration_bytes(&self) -> Result<Vec<u8>, CalibrationError> {
+        let len = EgsStoredCalibration::packed_bytes_size(None).unwrap_or_default() as u32;
+        let size = self
+            .with_kwp(|kwp| {
+                kwp.kwp_set_session(KwpSessionType::ExtendedDiagnostics.into())?;
+                kwp.kwp_read_custom_local_identifier(RLI_CALIBRATION_SIZE)
+            })
+            .map_err(|e| match e {
+                DiagError::ECUError { .. } => CalibrationError::Unsupported,
+                e => CalibrationError::Diag(e),
+            })?;
+        let size = match size.try_into() {
+            Ok(b) => u16::from_le_bytes(b),
+            Err(_) => return Err(CalibrationError::Diag(DiagError::InvalidResponseLength)),
+        };
+        if size as u32 != len {
+            return Err(CalibrationError::SizeMismatch { tcu: size, app: len as u16 });
+        }
+        Ok(self.read_memory_block(MemoryRegion::EgsCalibration, 0, len)?)
+    }
+
+    /// Reads the calibration block, checks its header, and finds the database records
+    /// that each of the four calibrations is identical to
+    pub fn read_calibration(&self, db: &CalibrationDatabase) -> Result<StoredCalibration, CalibrationError> {
+        StoredCalibration::parse(&self.read_calibration_bytes()?, db)
+    }
+}
+
+#[cfg(test)]
+pub mod test_calibration {
+    use packed_struct::PackedStructSlice;
+
+    use crate::{diag::memory::MemoryRegion, hw::sim::open_test_sim};
+
+    use super::*;
+
+    const EGS_DB_BYTES: &[u8] = include_bytes!("../../../egs_db.bin");
+
+    #[test]
+    pub fn test_decode_db() {
+        let db = CalibrationDatabase::decode(EGS_DB_BYTES).unwrap();
+        assert!(!db.egs_list.is_empty());
+        assert!(!db.hydralic_calibrations.is_empty());
+        assert!(!db.shift_algo_map_calibration.is_empty());
+        assert!(CalibrationDatabase::decode(&EGS_DB_BYTES[..100]).is_err());
+    }
+
+    fn record<T: Ord + Copy>(records: &[CalibrationRecord<T>], pn: &str, name: &str) -> T {
+        records.iter().find(|r| r.name == name && r.valid_egs_pns.iter().any(|p| p == pn)).unwrap().data
+    }
+
+    #[test]
+    pub fn test_diff() {
+        let db = CalibrationDatabase::decode(EGS_DB_BYTES).unwrap();
+        let fields = db.record_fields(CalibrationKind::Hydraulic, 0).unwrap();
+        assert_eq!(fields[0], CalibrationField { name: "multiplier_1".into(), values: vec![db.hydralic_calibrations[0].data.multiplier_1 as i64], is_array: false });
+        assert!(db.record_fields(CalibrationKind::Hydraulic, 10000).is_none());
+
+        let mut b = db.hydralic_calibrations[0].data;
+        b.pcs_map_z[8] += 10;
+        b.spring_overlap_pressure[0] = -5;
+        let diff = diff_calibrations(&fields, &calibration_fields(&b));
+        assert_eq!(diff.len(), fields.len());
+        let changed: Vec<&str> = diff.iter().filter(|d| d.changed() != 0).map(|d| d.name.as_str()).collect();
+        assert_eq!(changed, vec!["spring_overlap_pressure", "pcs_map_z"]);
+        let pcs = diff.iter().find(|d| d.name == "pcs_map_z").unwrap();
+        assert_eq!(pcs.shape, (4, 7));
+        assert_eq!(pcs.deltas()[8], 10);
+        assert_eq!(diff.iter().find(|d| d.name == "spring_overlap_pressure").unwrap().b[0], -5);
+
+        let mech = db.record_fields(CalibrationKind::Mechanical, 0).unwrap();
+        let diff = diff_calibrations(&mech, &mech);
+        assert!(diff.iter().all(|d| d.changed() == 0));
+        assert_eq!(diff.iter().find(|d| d.name == "friction_map").unwrap().shape, (6, 8));
+        assert_eq!(diff.iter().find(|d| d.name == "ratio_table").unwrap().shape, (1, 8));
+        let shift = db.record_fields(CalibrationKind::ShiftAlgo, 0).unwrap();
+        let diff = diff_calibrations(&shift, &shift);
+        assert_eq!(diff.iter().find(|d| d.name == "momentum_2_1_z").unwrap().shape, (10, 6));
+
+        // Every chassis using a record is listed
+        let egs = db.egs_list.iter().find(|e| !e.chassis.is_empty()).unwrap();
+        let name = &egs.chassis[0].hydr_cfg;
+        let (_, pns) = db.records(CalibrationKind::Hydraulic).into_iter().find(|(n, p)| n == name && p.contains(&egs.pn)).unwrap();
+        let users = db.record_users(CalibrationKind::Hydraulic, name, pns);
+        assert!(users.contains(&(egs.pn.as_str(), &egs.chassis[0])));
+        assert!(users.iter().all(|(_, c)| c.hydr_cfg == *name));
+    }
+
+    #[test]
+    pub fn test_read_calibration() {
+        let db = CalibrationDatabase::decode(EGS_DB_BYTES).unwrap();
+        let (sim, nag) = open_test_sim("test_read_calibration");
+
+        // Blank, but signed calibration
+        let cal = nag.read_calibration(&db).unwrap();
+        assert!(cal.faults.is_empty());
+        assert_eq!(cal.hydraulic.name.as_deref(), Some(""));
+        assert!(!cal.is_stock());
+
+        // Apply database records for the first EGS
+        let egs = db.egs_list.iter().find(|e| !e.chassis.is_empty()).unwrap();
+        let chassis = &egs.chassis[0];
+        let mut raw = cal.raw.clone();
+        raw.hydr_cal = record(&db.hydralic_calibrations, &egs.pn, &chassis.hydr_cfg);
+        raw.mech_cal = record(&db.mechanical_calibrations, &egs.pn, &chassis.mech_cfg);
+        raw.tcc_cal = record(&db.torqueconverter_calibrations, &egs.pn, &chassis.tcc_cfg);
+        raw.shift_algo_cal = record(&db.shift_algo_map_calibration, &egs.pn, &chassis.shift_algo_cfg);
+        raw.set_name(CalibrationKind::Hydraulic, &CalibrationDatabase::stored_name(&egs.pn, &chassis.hydr_cfg)).unwrap();
+        raw.set_name(CalibrationKind::Mechanical, &CalibrationDatabase::stored_name(&egs.pn, &chassis.mech_cfg)).unwrap();
+        raw.set_name(CalibrationKind::TorqueConverter, &CalibrationDatabase::stored_name(&egs.pn, &chassis.tcc_cfg)).unwrap();
+        raw.set_name(CalibrationKind::ShiftAlgo, &CalibrationDatabase::stored_name(&egs.pn, &chassis.shift_algo_cfg)).unwrap();
+        raw.sign();
+        let mut bytes = raw.pack_to_vec().unwrap();
+        let write = |bytes: &[u8]| {
+            let mut state = sim.state();
+            for (idx, b) in bytes.iter().enumerate() {
+                state.memory.insert(MemoryRegion::EgsCalibration.start_addr() + idx as u32, *b);
+            }
+        };
+        write(&bytes);
+        let cal = nag.read_calibration(&db).unwrap();
+        assert_eq!(cal.raw, raw);
+        assert!(cal.is_stock(), "{cal:?}");
+        assert!(cal.hydraulic.matches.iter().any(|m| m.name == chassis.hydr_cfg));
+        assert_eq!(cal.hydraulic.database_name(), Some((egs.pn.as_str(), chassis.hydr_cfg.as_str())));
+
+        // Modified without signing
+        bytes[8 + 16] ^= 0x01;
+        write(&bytes);
+        let cal = nag.read_calibration(&db).unwrap();
+        assert!(matches!(cal.faults[..], [CalibrationFault::CrcMismatch { .. }]));
+        assert!(cal.torque_converter.matches.is_empty());
+        assert!(!cal.torque_converter.matches_name());
+        assert!(cal.hydraulic.matches_name());
+        assert!(!cal.is_stock());
+
+        let mut raw = cal.raw.clone();
+        raw.sign();
+        assert!(raw.faults().is_empty());
+        raw.magic = 0;
+        assert_eq!(raw.faults(), vec![CalibrationFault::InvalidMagic(0)]);
+    }
+
+    #[test]
+    pub fn test_read_calibration_short_response() {
+        let db = CalibrationDatabase::decode(EGS_DB_BYTES).unwrap();
+        let (sim, nag) = open_test_sim("test_read_calibration_short_response");
+        // SID only, then one byte short. Neither may be retried forever
+        for resp in [vec![0x63], vec![0x63, 0x00]] {
+            sim.state().clear_request_hooks();
+            sim.state().add_request_hook(move |req| (req[0] == 0x23).then(|| resp.clone()));
+            assert!(matches!(
+                nag.read_calibration(&db),
+                Err(CalibrationError::Diag(DiagError::InvalidResponseLength))
+            ));
+        }
+    }
+
+    #[test]
+    pub fn test_set_name() {
+        let mut raw = EgsStoredCalibration::unpack_from_slice(&vec![0; EgsStoredCalibration::packed_bytes_size(None).unwrap()]).unwrap();
+        raw.set_name(CalibrationKind::Hydraulic, "A0255450532.STDP").unwrap();
+        assert_eq!(&raw.hydr_cal_name, b"A0255450532.STDP");
+        raw.set_name(CalibrationKind::Hydraulic, "short").unwrap();
+        assert_eq!(&raw.hydr_cal_name, b"short\0\0\0\0\0\0\0\0\0\0\0");
+        // Too long names are not cut short
+        assert!(matches!(
+            raw.set_name(CalibrationKind::Hydraulic, "A0255450532.STDP2"),
+            Err(CalibrationError::NameTooLong { max: 16, .. })
+        ));
+        assert_eq!(&raw.hydr_cal_name, b"short\0\0\0\0\0\0\0\0\0\0\0");
+    }
+}
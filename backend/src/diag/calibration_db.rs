@@ -0,0 +1,418 @@
+//! Calibration database sources
+//!
+//! `egs_db.bin` is built from a directory of YAML (Or JSON) files, so calibrations can be reviewed as text:
+//! ```text
+//! egs/<EGS PN>.yml                  EgsData (Chassis configurations of an EGS part number)
+//! hydraulic/<name>_<PN>.yml         CalibrationRecord<EgsHydraulicConfiguration>
+//! mechanical/<name>_<PN>.yml        CalibrationRecord<EgsMechanicalConfiguration>
+//! torque_converter/<name>_<PN>.yml  CalibrationRecord<EgsTorqueConverterConfiguration>
+//! shift_algo/<name>_<PN>.yml        CalibrationRecord<EgsShiftMapConfiguration>
+//! ```
+//! Record names are not unique, so record files are named after the first EGS part number they are valid for.
+//! Names that are still not unique get a number appended (EG: `egs/A0034464110_2.yml`).
+//! Files are loaded in name order, and the database is sorted, so the same sources always build the same database.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::calibration::{CalibrationDatabase, CalibrationKind, CalibrationRecord, EgsData};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceFormat {
+    Yaml,
+    Json,
+}
+
+impl SourceFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            SourceFormat::Yaml => "yml",
+            SourceFormat::Json => "json",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CalibrationSourceError {
+    Io { path: PathBuf, error: std::io::Error },
+    Parse { path: PathBuf, error: String },
+    /// Target directory already has files in it, which would be mixed with the new sources
+    NotEmpty { path: PathBuf },
+}
+
+impl std::fmt::Display for CalibrationSourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io { path, error } => write!(f, "{}: {error}", path.display()),
+            Self::Parse { path, error } => write!(f, "Could not parse {}: {error}", path.display()),
+            Self::NotEmpty { path } => write!(f, "{} is not empty", path.display()),
+        }
+    }
+}
+
+/// Problem found by [CalibrationDatabase::validate]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CalibrationDbIssue {
+    /// EGS part number is listed more than once (Its chassis configurations are all used)
+    DuplicateEgs { pn: String },
+    /// Chassis configuration uses a record that does not exist, or is not valid for the EGS
+    MissingRecord { pn: String, chassis: String, kind: CalibrationKind, name: String },
+    /// Name stored on the TCU (`PN.NAME`) does not fit in 16 bytes
+    NameTooLong { pn: String, kind: CalibrationKind, name: String },
+    /// Record is valid for an EGS part number that is not in the database
+    UnknownEgs { pn: String, kind: CalibrationKind, name: String },
+    /// Record is not used by any chassis configuration
+    UnusedRecord { kind: CalibrationKind, name: String },
+}
+
+impl CalibrationDbIssue {
+    /// Errors would break the EGS compatibility page. Other issues are warnings
+    pub fn is_error(&self) -> bool {
+        matches!(self, Self::MissingRecord { .. } | Self::NameTooLong { .. })
+    }
+}
+
+impl std::fmt::Display for CalibrationDbIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DuplicateEgs { pn } => write!(f, "EGS {pn} is listed more than once"),
+            Self::MissingRecord { pn, chassis, kind, name } => {
+                write!(f, "EGS {pn} ({chassis}) uses {kind} calibration '{name}', which does not exist for {pn}")
+            }
+            Self::NameTooLong { pn, kind, name } => {
+                write!(f, "{kind} calibration '{name}' of {pn} has a name longer than 16 bytes, once stored on the TCU")
+            }
+            Self::UnknownEgs { pn, kind, name } => {
+                write!(f, "{kind} calibration '{name}' is valid for EGS {pn}, which is not in the database")
+            }
+            Self::UnusedRecord { kind, name } => write!(f, "{kind} calibration '{name}' is not used by any chassis"),
+        }
+    }
+}
+
+fn kind_dir(kind: CalibrationKind) -> &'static str {
+    match kind {
+        CalibrationKind::Hydraulic => "hydraulic",
+        CalibrationKind::Mechanical => "mechanical",
+        CalibrationKind::TorqueConverter => "torque_converter",
+        CalibrationKind::ShiftAlgo => "shift_algo",
+    }
+}
+
+/// Replaces characters that are not allowed in file names, and adds a number if the name is already used
+fn file_name(used: &mut HashSet<String>, name: &str) -> String {
+    let base: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect();
+    let mut ret = base.clone();
+    let mut idx = 1;
+    while !used.insert(ret.clone()) {
+        idx += 1;
+        ret = format!("{base}_{idx}");
+    }
+    ret
+}
+
+/// Subdirectories of a source directory
+fn source_dirs() -> [&'static str; 5] {
+    [
+        "egs",
+        kind_dir(CalibrationKind::Hydraulic),
+        kind_dir(CalibrationKind::Mechanical),
+        kind_dir(CalibrationKind::TorqueConverter),
+        kind_dir(CalibrationKind::ShiftAlgo),
+    ]
+}
+
+fn is_source_file(path: &Path) -> bool {
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_lowercase();
+    path.is_file() && matches!(ext.as_str(), "yml" | "yaml" | "json")
+}
+
+/// Deletes the source files of a source directory. Other files, and the directory itself, are left alone
+fn remove_sources(dir: &Path) -> Result<(), CalibrationSourceError> {
+    for sub in source_dirs() {
+        let sub = dir.join(sub);
+        if !sub.is_dir() {
+            continue;
+        }
+        let io_err = |path: &Path| {
+            let path = path.to_path_buf();
+            move |error| CalibrationSourceError::Io { path, error }
+        };
+        for entry in std::fs::read_dir(&sub).map_err(io_err(&sub))? {
+            let path = entry.map_err(io_err(&sub))?.path();
+            if is_source_file(&path) {
+                std::fs::remove_file(&path).map_err(io_err(&path))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_dir<T: DeserializeOwned>(dir: &Path) -> Result<Vec<T>, CalibrationSourceError> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let io_err = |path: &Path| {
+        let path = path.to_path_buf();
+        move |error| CalibrationSourceError::Io { path, error }
+    };
+    let mut paths = std::fs::read_dir(dir)
+        .map_err(io_err(dir))?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(io_err(dir))?;
+    paths.sort();
+    let mut ret = Vec::new();
+    for path in paths {
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_lowercase();
+        let contents = match ext.as_str() {
+            "yml" | "yaml" | "json" => std::fs::read_to_string(&path).map_err(io_err(&path))?,
+            _ => continue,
+        };
+        // JSON is valid YAML, but the JSON parser gives better errors
+        let parsed = match ext.as_str() {
+            "json" => serde_json::from_str(&contents).map_err(|e| e.to_string()),
+            _ => serde_yaml::from_str(&contents).map_err(|e| e.to_string()),
+        };
+        ret.push(parsed.map_err(|error| CalibrationSourceError::Parse { path: path.clone(), error })?);
+    }
+    Ok(ret)
+}
+
+fn write_file<T: Serialize>(path: PathBuf, value: &T, format: SourceFormat) -> Result<(), CalibrationSourceError> {
+    let contents = match format {
+        SourceFormat::Yaml => serde_yaml::to_string(value).map_err(|e| e.to_string()),
+        SourceFormat::Json => serde_json::to_string_pretty(value).map_err(|e| e.to_string()),
+    }
+    .map_err(|error| CalibrationSourceError::Parse { path: path.clone(), error })?;
+    std::fs::write(&path, contents).map_err(|error| CalibrationSourceError::Io { path, error })
+}
+
+fn write_records<T: Serialize + Ord>(
+    dir: &Path,
+    kind: CalibrationKind,
+    records: &[CalibrationRecord<T>],
+    format: SourceFormat,
+) -> Result<(), CalibrationSourceError> {
+    let dir = dir.join(kind_dir(kind));
+    std::fs::create_dir_all(&dir).map_err(|error| CalibrationSourceError::Io { path: dir.clone(), error })?;
+    let mut used = HashSet::new();
+    for record in records {
+        let name = match record.valid_egs_pns.first() {
+            Some(pn) => file_name(&mut used, &format!("{}_{pn}", record.name)),
+            None => file_name(&mut used, &record.name),
+        };
+        write_file(dir.join(format!("{name}.{}", format.extension())), record, format)?;
+    }
+    Ok(())
+}
+
+fn check_records<T: Ord>(
+    kind: CalibrationKind,
+    records: &[CalibrationRecord<T>],
+    egs_list: &[EgsData],
+    issues: &mut Vec<CalibrationDbIssue>,
+) {
+    let pns: HashSet<&str> = egs_list.iter().map(|e| e.pn.as_str()).collect();
+    for egs in egs_list {
+        for chassis in &egs.chassis {
+            let name = chassis.calibration(kind);
+            if !records.iter().any(|r| r.name == name && r.valid_egs_pns.contains(&egs.pn)) {
+                issues.push(CalibrationDbIssue::MissingRecord {
+                    pn: egs.pn.clone(),
+                    chassis: chassis.chassis.clone(),
+                    kind,
+                    name: name.to_string(),
+                });
+            }
+        }
+    }
+    for record in records {
+        for pn in &record.valid_egs_pns {
+            if CalibrationDatabase::stored_name(pn, &record.name).len() > 16 {
+                issues.push(CalibrationDbIssue::NameTooLong { pn: pn.clone(), kind, name: record.name.clone() });
+            }
+            if !pns.contains(pn.as_str()) {
+                issues.push(CalibrationDbIssue::UnknownEgs { pn: pn.clone(), kind, name: record.name.clone() });
+            }
+        }
+        let is_used = egs_list.iter().any(|egs| {
+            record.valid_egs_pns.contains(&egs.pn) && egs.chassis.iter().any(|c| c.calibration(kind) == record.name)
+        });
+        if !is_used {
+            issues.push(CalibrationDbIssue::UnusedRecord { kind, name: record.name.clone() });
+        }
+    }
+}
+
+impl CalibrationDatabase {
+    /// Encodes the database as `egs_db.bin` (Bincode encoded, LZ4 compressed)
+    pub fn encode(&self) -> Vec<u8> {
+        let raw = bincode::serde::encode_to_vec(self, bincode::config::legacy()).unwrap_or_default();
+        lz4_compression::compress::compress(&raw)
+    }
+
+    /// Sorts EGS entries and records, so the encoded database does not depend on the order sources were loaded in
+    pub fn sort(&mut self) {
+        self.egs_list.sort();
+        self.hydralic_calibrations.sort();
+        self.mechanical_calibrations.sort();
+        self.torqueconverter_calibrations.sort();
+        self.shift_algo_map_calibration.sort();
+    }
+
+    /// Checks that every chassis configuration refers to existing records
+    pub fn validate(&self) -> Vec<CalibrationDbIssue> {
+        let mut issues = Vec::new();
+        let mut pns = HashSet::new();
+        for egs in &self.egs_list {
+            if !pns.insert(&egs.pn) {
+                issues.push(CalibrationDbIssue::DuplicateEgs { pn: egs.pn.clone() });
+            }
+        }
+        let egs = &self.egs_list;
+        check_records(CalibrationKind::Hydraulic, &self.hydralic_calibrations, egs, &mut issues);
+        check_records(CalibrationKind::Mechanical, &self.mechanical_calibrations, egs, &mut issues);
+        check_records(CalibrationKind::TorqueConverter, &self.torqueconverter_calibrations, egs, &mut issues);
+        check_records(CalibrationKind::ShiftAlgo, &self.shift_algo_map_calibration, egs, &mut issues);
+        issues
+    }
+
+    /// Loads a database from a source directory (See the module docs for its layout)
+    pub fn load_sources<P: AsRef<Path>>(dir: P) -> Result<Self, CalibrationSourceError> {
+        let dir = dir.as_ref();
+        let mut db = Self {
+            egs_list: read_dir(&dir.join("egs"))?,
+            hydralic_calibrations: read_dir(&dir.join(kind_dir(CalibrationKind::Hydraulic)))?,
+            mechanical_calibrations: read_dir(&dir.join(kind_dir(CalibrationKind::Mechanical)))?,
+            torqueconverter_calibrations: read_dir(&dir.join(kind_dir(CalibrationKind::TorqueConverter)))?,
+            shift_algo_map_calibration: read_dir(&dir.join(kind_dir(CalibrationKind::ShiftAlgo)))?,
+        };
+        db.sort();
+        Ok(db)
+    }
+
+    /// Writes the database as a source directory (See the module docs for its layout).
+    /// Stale files would be built into the database as well, so a directory which is not empty
+    /// is refused, unless `overwrite` is set, in which case the YAML and JSON files in its
+    /// subdirectories are deleted first. Nothing else in the directory is touched
+    pub fn write_sources<P: AsRef<Path>>(&self, dir: P, format: SourceFormat, overwrite: bool) -> Result<(), CalibrationSourceError> {
+        let dir = dir.as_ref();
+        let io_err = |error| CalibrationSourceError::Io { path: dir.to_path_buf(), error };
+        if dir.exists() && std::fs::read_dir(dir).map_err(io_err)?.next().is_some() {
+            if !overwrite {
+                return Err(CalibrationSourceError::NotEmpty { path: dir.to_path_buf() });
+            }
+            remove_sources(dir)?;
+        }
+        let egs_dir = dir.join("egs");
+        std::fs::create_dir_all(&egs_dir).map_err(|error| CalibrationSourceError::Io { path: egs_dir.clone(), error })?;
+        let mut used = HashSet::new();
+        for egs in &self.egs_list {
+            write_file(egs_dir.join(format!("{}.{}", file_name(&mut used, &egs.pn), format.extension())), egs, format)?;
+        }
+        write_records(dir, CalibrationKind::Hydraulic, &self.hydralic_calibrations, format)?;
+        write_records(dir, CalibrationKind::Mechanical, &self.mechanical_calibrations, format)?;
+        write_records(dir, CalibrationKind::TorqueConverter, &self.torqueconverter_calibrations, format)?;
+        write_records(dir, CalibrationKind::ShiftAlgo, &self.shift_algo_map_calibration, format)
+    }
+}
+
+#[cfg(test)]
+pub mod test_calibration_db {
+    use super::*;
+
+    const EGS_DB_BYTES: &[u8] = include_bytes!("../../../egs_db.bin");
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("un52_{name}_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    pub fn test_roundtrip() {
+        let mut db = CalibrationDatabase::decode(EGS_DB_BYTES).unwrap();
+        db.sort();
+        assert_eq!(CalibrationDatabase::decode(&db.encode()).unwrap(), db);
+        assert!(db.validate().iter().all(|i| !i.is_error()), "{:?}", db.validate());
+
+        for format in [SourceFormat::Yaml, SourceFormat::Json] {
+            let dir = temp_dir(&format!("caldb_{format:?}"));
+            db.write_sources(&dir, format, false).unwrap();
+            let loaded = CalibrationDatabase::load_sources(&dir).unwrap();
+            assert_eq!(loaded, db);
+            assert_eq!(loaded.encode(), db.encode());
+
+            // Stale records must not end up in the rebuilt database
+            let stale = dir.join("hydraulic").join(format!("STALE.{}", format.extension()));
+            std::fs::copy(std::fs::read_dir(dir.join("hydraulic")).unwrap().next().unwrap().unwrap().path(), &stale).unwrap();
+            assert!(matches!(
+                db.write_sources(&dir, format, false),
+                Err(CalibrationSourceError::NotEmpty { .. })
+            ));
+            // Only the sources are replaced
+            let unrelated = [dir.join("README.md"), dir.join("hydraulic").join("notes.txt")];
+            for path in &unrelated {
+                std::fs::write(path, "keep").unwrap();
+            }
+            db.write_sources(&dir, format, true).unwrap();
+            assert!(!stale.exists());
+            for path in &unrelated {
+                assert_eq!(std::fs::read_to_string(path).unwrap(), "keep");
+            }
+            assert_eq!(CalibrationDatabase::load_sources(&dir).unwrap(), db);
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+
+    #[test]
+    pub fn test_validate() {
+        let mut db = CalibrationDatabase::decode(EGS_DB_BYTES).unwrap();
+        let egs = db.egs_list.iter_mut().find(|e| !e.chassis.is_empty()).unwrap();
+        let pn = egs.pn.clone();
+        egs.chassis[0].hydr_cfg = "MISSING".into();
+        let chassis = egs.chassis[0].chassis.clone();
+        let dup = EgsData { pn: pn.clone(), chassis: Vec::new() };
+        db.egs_list.push(dup);
+        db.mechanical_calibrations[0].valid_egs_pns.push("A123456789012345".into());
+
+        let issues = db.validate();
+        assert!(issues.contains(&CalibrationDbIssue::DuplicateEgs { pn: pn.clone() }));
+        assert!(issues.contains(&CalibrationDbIssue::MissingRecord {
+            pn,
+            chassis,
+            kind: CalibrationKind::Hydraulic,
+            name: "MISSING".into()
+        }));
+        let name = db.mechanical_calibrations[0].name.clone();
+        assert!(issues.contains(&CalibrationDbIssue::NameTooLong {
+            pn: "A123456789012345".into(),
+            kind: CalibrationKind::Mechanical,
+            name: name.clone()
+        }));
+        assert!(issues.contains(&CalibrationDbIssue::UnknownEgs {
+            pn: "A123456789012345".into(),
+            kind: CalibrationKind::Mechanical,
+            name
+        }));
+    }
+
+    #[test]
+    pub fn test_load_errors() {
+        let dir = temp_dir("caldb_invalid");
+        std::fs::create_dir_all(dir.join("egs")).unwrap();
+        std::fs::write(dir.join("egs/README.md"), "Ignored").unwrap();
+        assert_eq!(CalibrationDatabase::load_sources(&dir).unwrap(), CalibrationDatabase::default());
+        std::fs::write(dir.join("egs/A000.yml"), "pn: A000\nchassis: 5").unwrap();
+        assert!(matches!(
+            CalibrationDatabase::load_sources(&dir),
+            Err(CalibrationSourceError::Parse { path, .. }) if path.ends_with("A000.yml")
+        ));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
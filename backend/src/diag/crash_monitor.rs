@@ -0,0 +1,233 @@
+//! Crash detection
+//!
+//! The TCU reboots when it crashes, but over USB this only shows up as a short connection loss,
+//! so crashes on the road are easily missed. [RebootDetector] watches the TCU's log lines, which carry
+//! the milliseconds since boot, and detects a reboot when:
+//! * A timestamp goes backwards
+//! * After the link was lost, the first log line is a boot message, or has an uptime shorter than the link was down for
+//!
+//! A reboot is then checked for a coredump (See [Nag52Diag::has_coredump]), which can be saved
+//! as a [CrashReport], together with the log lines around the reboot.
+
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use chrono::{DateTime, Local};
+
+use crate::hw::{coredump::CoreDump, usb::EspLogMessage};
+
+use super::{supervisor::ConnectionEvent, Nag52Diag};
+
+/// Log tags that are only printed whilst the TCU boots
+pub const BOOT_LOG_TAGS: &[&str] = &["boot", "cpu_start"];
+/// Log lines kept before a reboot
+pub const DEFAULT_HISTORY_LEN: usize = 500;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RebootReason {
+    /// Log timestamp went backwards
+    TimestampReset { last: u128, now: u128 },
+    /// TCU was booting when the link came back
+    BootAfterLinkLoss { uptime: u128 },
+}
+
+impl std::fmt::Display for RebootReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TimestampReset { last, now } => write!(f, "Log timestamp went from {last}ms to {now}ms"),
+            Self::BootAfterLinkLoss { uptime } => write!(f, "TCU was booting ({uptime}ms since boot) when it reconnected"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RebootEvent {
+    pub reason: RebootReason,
+    pub time: DateTime<Local>,
+    /// Log lines before the reboot, ending with the first line after it
+    pub logs: Vec<EspLogMessage>,
+    /// Number of the first line after the reboot
+    line: u64,
+}
+
+#[derive(Debug)]
+pub struct RebootDetector {
+    last_timestamp: Option<u128>,
+    /// When the link was lost, until the next log line
+    link_lost: Option<Instant>,
+    /// Recent log lines, with their line number
+    history: VecDeque<(u64, EspLogMessage)>,
+    history_len: usize,
+    line: u64,
+}
+
+impl Default for RebootDetector {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY_LEN)
+    }
+}
+
+impl RebootDetector {
+    pub fn new(history_len: usize) -> Self {
+        Self { last_timestamp: None, link_lost: None, history: VecDeque::new(), history_len, line: 0 }
+    }
+
+    pub fn on_connection_event(&mut self, evt: &ConnectionEvent) {
+        if let ConnectionEvent::Lost { .. } = evt {
+            self.link_lost.get_or_insert_with(Instant::now);
+        }
+    }
+
+    /// Processes a log line. Returns the reboot it shows, if any
+    pub fn on_log(&mut self, msg: EspLogMessage) -> Option<RebootEvent> {
+        let reason = match (self.link_lost.take(), self.last_timestamp) {
+            (Some(lost), _) if BOOT_LOG_TAGS.contains(&msg.tag.as_str()) || msg.timestamp <= lost.elapsed().as_millis() => {
+                Some(RebootReason::BootAfterLinkLoss { uptime: msg.timestamp })
+            }
+            (_, Some(last)) if msg.timestamp < last => Some(RebootReason::TimestampReset { last, now: msg.timestamp }),
+            _ => None,
+        };
+        self.last_timestamp = Some(msg.timestamp);
+        self.line += 1;
+        if self.history.len() >= self.history_len {
+            self.history.pop_front();
+        }
+        self.history.push_back((self.line, msg));
+        reason.map(|reason| RebootEvent {
+            reason,
+            time: Local::now(),
+            logs: self.history.iter().map(|(_, m)| m.clone()).collect(),
+            line: self.line,
+        })
+    }
+
+    /// Log lines received after the first line of a reboot, that are still in the history
+    pub fn logs_after(&self, evt: &RebootEvent) -> Vec<EspLogMessage> {
+        self.history.iter().filter(|(line, _)| *line > evt.line).map(|(_, m)| m.clone()).collect()
+    }
+}
+
+/// Coredump of a crash, with the log lines around it
+#[derive(Debug, Clone)]
+pub struct CrashReport {
+    pub reason: RebootReason,
+    pub time: DateTime<Local>,
+    pub logs: Vec<EspLogMessage>,
+    pub coredump: Vec<u8>,
+}
+
+impl CrashReport {
+    /// Saves the report to a new folder within `dir`, named after the time of the crash:
+    /// * coredump.bin - Raw coredump, which can be opened in the coredump viewer
+    /// * summary.txt - Reboot reason and the decoded coredump (Crashed task, exception and backtraces)
+    /// * log.txt - Log lines around the reboot
+    ///
+    /// Returns the folder's path
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> std::io::Result<PathBuf> {
+        let path = dir.as_ref().join(format!("crash_{}", self.time.format("%Y%m%d_%H%M%S")));
+        std::fs::create_dir_all(&path)?;
+        std::fs::write(path.join("coredump.bin"), &self.coredump)?;
+        let decoded = match CoreDump::parse(&self.coredump) {
+            Ok(dump) => dump.summary(),
+            Err(e) => format!("Could not decode the coredump: {e}\n"),
+        };
+        let summary = format!("Crash detected at {}\n{}\n\n{decoded}", self.time.format("%Y-%m-%d %H:%M:%S"), self.reason);
+        std::fs::write(path.join("summary.txt"), summary)?;
+        let logs: String = self.logs.iter().map(|m| format!("{m}\n")).collect();
+        std::fs::write(path.join("log.txt"), logs)?;
+        Ok(path)
+    }
+}
+
+impl Nag52Diag {
+    /// Reads the coredump of a reboot, and creates a report with the log lines before and after the reboot
+    pub fn create_crash_report(
+        &self,
+        evt: &RebootEvent,
+        logs_after: Vec<EspLogMessage>,
+        on_block: impl FnMut(u32, u32),
+    ) -> ecu_diagnostics::DiagServerResult<CrashReport> {
+        let coredump = self.read_coredump(on_block)?;
+        let mut logs = evt.logs.clone();
+        logs.extend(logs_after);
+        Ok(CrashReport { reason: evt.reason.clone(), time: evt.time, logs, coredump })
+    }
+}
+
+#[cfg(test)]
+pub mod test_crash_monitor {
+    use std::time::Duration;
+
+    use crate::{
+        diag::supervisor::ConnectionEvent,
+        hw::{
+            sim::open_test_sim,
+            usb::{EspLogLevel, EspLogMessage},
+        },
+    };
+
+    use super::{RebootDetector, RebootReason};
+
+    fn log(timestamp: u128, tag: &str) -> EspLogMessage {
+        EspLogMessage { lvl: EspLogLevel::Info, timestamp, tag: tag.into(), msg: "Msg".into() }
+    }
+
+    #[test]
+    pub fn test_detect_reboot() {
+        let mut det = RebootDetector::new(3);
+        assert!(det.on_log(log(100, "MAIN")).is_none());
+        assert!(det.on_log(log(200, "MAIN")).is_none());
+        assert!(det.on_log(log(200, "MAIN")).is_none());
+        let evt = det.on_log(log(10, "cpu_start")).unwrap();
+        assert_eq!(evt.reason, RebootReason::TimestampReset { last: 200, now: 10 });
+        assert_eq!(evt.logs.iter().map(|l| l.timestamp).collect::<Vec<_>>(), vec![200, 200, 10]);
+        assert!(det.on_log(log(20, "MAIN")).is_none());
+        assert_eq!(det.logs_after(&evt), vec![log(20, "MAIN")]);
+
+        // Link is lost for longer than the TCU needs to reach a later timestamp
+        det.on_connection_event(&ConnectionEvent::Lost { reason: "Unplugged".into() });
+        let evt = det.on_log(log(5000, "boot")).unwrap();
+        assert_eq!(evt.reason, RebootReason::BootAfterLinkLoss { uptime: 5000 });
+        // TCU was up the whole time
+        det.on_connection_event(&ConnectionEvent::Lost { reason: "Unplugged".into() });
+        assert!(det.on_log(log(60000, "MAIN")).is_none());
+        // Uptime is shorter than the link was down for
+        det.on_connection_event(&ConnectionEvent::Lost { reason: "Unplugged".into() });
+        std::thread::sleep(Duration::from_millis(50));
+        let evt = det.on_log(log(30, "MAIN")).unwrap();
+        assert_eq!(evt.reason, RebootReason::BootAfterLinkLoss { uptime: 30 });
+    }
+
+    #[test]
+    pub fn test_sim_crash_report() {
+        let (sim, nag) = open_test_sim("test_sim_crash_report");
+        assert!(!nag.has_coredump().unwrap());
+        let mut det = RebootDetector::default();
+        std::thread::sleep(Duration::from_millis(20));
+        sim.state().push_log(EspLogLevel::Info, "MAIN", "Running");
+        sim.state().crash();
+        let mut events = vec![];
+        while let Some(msg) = nag.read_log_msg() {
+            events.extend(det.on_log(msg));
+        }
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0].reason, RebootReason::TimestampReset { .. }));
+        assert_eq!(events[0].logs.last().unwrap().tag, "cpu_start");
+        assert!(nag.has_coredump().unwrap());
+
+        let report = nag.create_crash_report(&events[0], det.logs_after(&events[0]), |_, _| {}).unwrap();
+        assert_eq!(report.logs.len(), 6);
+        let dir = std::env::temp_dir().join(format!("un52_crash_{}", std::process::id()));
+        let path = report.save(&dir).unwrap();
+        let summary = std::fs::read_to_string(path.join("summary.txt")).unwrap();
+        assert!(summary.contains("Crashed task: main"), "{summary}");
+        assert!(summary.contains("Exception: StoreProhibited (29)"), "{summary}");
+        let logs = std::fs::read_to_string(path.join("log.txt")).unwrap();
+        assert!(logs.contains("EE - (MAIN) Simulated crash"), "{logs}");
+        assert_eq!(std::fs::read(path.join("coredump.bin")).unwrap(), report.coredump);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
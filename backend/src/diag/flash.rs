@@ -1,10 +1,21 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::Path,
+    time::Duration,
+};
+
 use ecu_diagnostics::{
     kwp2000::{self, KwpSessionTypeByte},
     DiagError, DiagServerResult,
 };
 use packed_struct::{prelude::PackedStruct, PackedStructSlice};
+use sha2::{Digest, Sha256};
 
-use crate::hw::firmware::FirmwareHeader;
+use crate::hw::{
+    firmware::FirmwareHeader,
+    partition_table::{parse_partition_table, EspPartition, PARTITION_TABLE_ADDR, PARTITION_TABLE_MAX_LEN},
+};
 
 use super::Nag52Diag;
 
@@ -18,6 +29,94 @@ pub struct PartitionInfo {
 
 pub const OTA_FORMAT: u8 = 0xF0;
 
+/// Delay before retrying a failed chunk, multiplied by the attempt number.
+/// Gives the connection supervisor time to reconnect
+const DOWNLOAD_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DownloadOptions {
+    /// Bytes read per download request. A failed chunk is read again from its start
+    pub chunk_size: u32,
+    /// Attempts per chunk, before the download fails
+    pub max_retries: u32,
+    /// Once downloaded, read the partition again and compare its SHA-256 with that of the file
+    /// (Including any resumed part). Doubles the download time
+    pub verify: bool,
+    /// Continue from the end of an existing file, rather than overwriting it
+    pub resume: bool,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: 0x8000,
+            max_retries: 5,
+            verify: true,
+            resume: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DownloadProgress {
+    /// Address currently being read
+    pub address: u32,
+    pub done: u32,
+    pub total: u32,
+    /// Total number of failed chunk reads so far
+    pub retries: u32,
+    /// Reading the partition again, to compare it with the file
+    pub verifying: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DownloadResult {
+    /// SHA-256 of the whole partition, as written to the file. If verified, the partition read again has the same hash
+    pub sha256: [u8; 32],
+    /// Bytes that were already in the file when resuming
+    pub resumed_from: u32,
+    pub retries: u32,
+}
+
+#[derive(Debug)]
+pub enum DownloadError {
+    Io(std::io::Error),
+    /// Chunk at the address could not be read after all retries
+    Read { address: u32, error: DiagError },
+    /// File does not match the partition when it is read again
+    VerifyFailed { file_sha256: [u8; 32], partition_sha256: [u8; 32] },
+    /// The file to resume is larger than the partition
+    FileTooLarge { file_len: u64, partition_len: u32 },
+}
+
+impl std::fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DownloadError::Io(e) => write!(f, "IO error: {e}"),
+            DownloadError::Read { address, error } => write!(f, "Failed to read address 0x{address:08X}. {error}"),
+            DownloadError::VerifyFailed { file_sha256, partition_sha256 } => {
+                let hex = |h: &[u8; 32]| h.iter().map(|b| format!("{b:02x}")).collect::<String>();
+                write!(
+                    f,
+                    "Verification failed, the file (SHA-256 {}) does not match the partition read again (SHA-256 {}). Start the backup again without resuming",
+                    hex(file_sha256),
+                    hex(partition_sha256)
+                )
+            }
+            DownloadError::FileTooLarge { file_len, partition_len } => write!(
+                f,
+                "Cannot resume, the file is {file_len} bytes long, but the partition is only {partition_len} bytes"
+            ),
+        }
+    }
+}
+
+impl From<std::io::Error> for DownloadError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
 impl Nag52Diag {
     pub fn get_total_flash_size(&self) -> PartitionInfo {
         PartitionInfo {
@@ -41,6 +140,11 @@ impl Nag52Diag {
         })
     }
 
+    /// Returns true if the TCU has stored a coredump, which it does when it crashes
+    pub fn has_coredump(&self) -> DiagServerResult<bool> {
+        self.get_coredump_flash_info().map(|p| p.size != 0)
+    }
+
     pub fn get_running_partition_flash_info(&self) -> DiagServerResult<PartitionInfo> {
         self.with_kwp(|server| {
             server.kwp_read_custom_local_identifier(0x2A).map(|res| {
@@ -65,6 +169,22 @@ impl Nag52Diag {
         })
     }
 
+    /// Reads the ESP-IDF partition table from flash
+    pub fn read_partition_table(&self) -> DiagServerResult<Vec<EspPartition>> {
+        self.read_flash_region(PARTITION_TABLE_ADDR, PARTITION_TABLE_MAX_LEN, |_| {})
+            .map(|raw| parse_partition_table(&raw))
+    }
+
+    /// Location of the NVS partition, which holds the TCU's EEPROM.
+    /// The TCU has no identifier for this, so it is found via the partition table
+    pub fn get_nvs_partition_info(&self) -> DiagServerResult<PartitionInfo> {
+        self.read_partition_table()?
+            .iter()
+            .find(|p| p.is_nvs())
+            .map(|p| p.info)
+            .ok_or(DiagError::NotSupported)
+    }
+
     pub fn get_running_fw_info(&self) -> DiagServerResult<FirmwareHeader> {
         self.with_kwp(|server| {
             server.kwp_read_custom_local_identifier(0x28).map(|res| {
@@ -162,6 +282,161 @@ impl Nag52Diag {
         })
     }
 
+    /// Ends a download started with [Nag52Diag::begin_download]
+    pub fn end_download(&self) -> DiagServerResult<()> {
+        self.with_kwp(|server| server.send_byte_array_with_response(&[0x37]).map(|_| ()))
+    }
+
+    /// Reads a region of flash in one download request. `on_block` is called with the bytes read so far
+    pub fn read_flash_region(&self, address: u32, size: u32, mut on_block: impl FnMut(u32)) -> DiagServerResult<Vec<u8>> {
+        self.begin_download(&PartitionInfo { address, size })?;
+        let mut ret = Vec::with_capacity(size as usize);
+        let mut counter = 0u8;
+        while ret.len() < size as usize {
+            counter = counter.wrapping_add(1);
+            let data = match self.read_data(counter) {
+                Ok(data) if !data.is_empty() => data,
+                res => {
+                    // Close the transfer, so the read can be retried
+                    let _ = self.end_download();
+                    return Err(res.err().unwrap_or(DiagError::InvalidResponseLength));
+                }
+            };
+            ret.extend_from_slice(&data);
+            on_block(ret.len() as u32);
+        }
+        ret.truncate(size as usize);
+        self.end_download()?;
+        Ok(ret)
+    }
+
+    /// Reads the coredump. Only the length given in its header is read, rather than the whole partition.
+    /// If no coredump is stored, the erased header is returned. `on_block` is called with the bytes read and the total
+    pub fn read_coredump(&self, mut on_block: impl FnMut(u32, u32)) -> DiagServerResult<Vec<u8>> {
+        let part = self.get_coredump_flash_info()?;
+        let header = self.read_flash_region(part.address, 4, |_| {})?;
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap());
+        if len == 0xFFFF_FFFF || len <= 4 {
+            return Ok(header);
+        }
+        let len = len.min(part.size);
+        self.read_flash_region(part.address, len, |done| on_block(done, len))
+    }
+
+    /// Reads the last 32 bytes of the coredump, which end with its checksum. This tells
+    /// dumps apart without reading them in full. Returns `None` if no coredump is stored
+    pub fn read_coredump_fingerprint(&self) -> DiagServerResult<Option<Vec<u8>>> {
+        let part = self.get_coredump_flash_info()?;
+        let header = self.read_flash_region(part.address, 4, |_| {})?;
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap());
+        if len == 0xFFFF_FFFF || len <= 4 {
+            return Ok(None);
+        }
+        let len = len.min(part.size);
+        let start = len.saturating_sub(32);
+        self.read_flash_region(part.address + start, len - start, |_| {}).map(Some)
+    }
+
+    /// Reads one chunk of a download, retrying it until it is read. Failed attempts are counted in `retries`
+    fn download_chunk(
+        &self,
+        address: u32,
+        size: u32,
+        opts: &DownloadOptions,
+        retries: &mut u32,
+        mut on_block: impl FnMut(u32, u32),
+    ) -> Result<Vec<u8>, DownloadError> {
+        let mut attempt = 0;
+        loop {
+            match self.read_flash_region(address, size, |done| on_block(done, *retries)) {
+                Ok(data) => return Ok(data),
+                Err(error) => {
+                    attempt += 1;
+                    *retries += 1;
+                    if attempt > opts.max_retries {
+                        return Err(DownloadError::Read { address, error });
+                    }
+                }
+            }
+            std::thread::sleep(DOWNLOAD_RETRY_DELAY * attempt);
+        }
+    }
+
+    /// Downloads a partition to a file, chunk by chunk. Every chunk is flushed to the file once it is read,
+    /// so a failed download can be resumed (With [DownloadOptions::resume]) once the TCU is reconnected.
+    pub fn download_partition<P: AsRef<Path>>(
+        &self,
+        partition: &PartitionInfo,
+        path: P,
+        opts: &DownloadOptions,
+        mut progress: impl FnMut(DownloadProgress),
+    ) -> Result<DownloadResult, DownloadError> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path.as_ref())?;
+        let file_len = file.metadata()?.len();
+        if !opts.resume {
+            file.set_len(0)?;
+        } else if file_len > partition.size as u64 {
+            return Err(DownloadError::FileTooLarge { file_len, partition_len: partition.size });
+        }
+        let resumed_from = if opts.resume { file_len as u32 } else { 0 };
+        let mut done = resumed_from;
+        let mut retries = 0;
+        while done < partition.size {
+            let address = partition.address + done;
+            let size = opts.chunk_size.min(partition.size - done);
+            let data = self.download_chunk(address, size, opts, &mut retries, |read, retries| {
+                progress(DownloadProgress {
+                    address: address + read,
+                    done: done + read,
+                    total: partition.size,
+                    retries,
+                    verifying: false,
+                })
+            })?;
+            file.write_all(&data)?;
+            file.sync_data()?;
+            done += size;
+        }
+        drop(file);
+
+        // Hash what was written, including any resumed part
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 0x10000];
+        let mut file = File::open(path.as_ref())?;
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        let sha256: [u8; 32] = hasher.finalize().into();
+        if opts.verify {
+            let mut hasher = Sha256::new();
+            let mut done = 0;
+            while done < partition.size {
+                let address = partition.address + done;
+                let size = opts.chunk_size.min(partition.size - done);
+                let data = self.download_chunk(address, size, opts, &mut retries, |read, retries| {
+                    progress(DownloadProgress {
+                        address: address + read,
+                        done: done + read,
+                        total: partition.size,
+                        retries,
+                        verifying: true,
+                    })
+                })?;
+                hasher.update(&data);
+                done += size;
+            }
+            let partition_sha256: [u8; 32] = hasher.finalize().into();
+            if partition_sha256 != sha256 {
+                return Err(DownloadError::VerifyFailed { file_sha256: sha256, partition_sha256 });
+            }
+        }
+        Ok(DownloadResult { sha256, resumed_from, retries })
+    }
+
     pub fn read_mem_by_addr_ext(&self, addr: u32, size: u8) -> DiagServerResult<Vec<u8>> {
         self.with_kwp(|server| {
             let mut req = vec![0x24];
@@ -171,3 +446,159 @@ impl Nag52Diag {
         })
     }
 }
+
+#[cfg(test)]
+pub mod test_flash {
+    use sha2::{Digest, Sha256};
+
+    use crate::{
+        diag::Nag52Diag,
+        hw::{
+            coredump::{CoreDump, CoreDumpError, XtensaRegisters},
+            sim::{open_test_sim, Nag52Sim},
+        },
+    };
+
+    use super::{DownloadError, DownloadOptions};
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    const NRC_CONDITIONS_NOT_CORRECT: u8 = 0x22;
+
+    fn open(name: &str) -> (Nag52Sim, Nag52Diag, std::path::PathBuf) {
+        let (sim, nag) = open_test_sim(name);
+        // Random looking coredump, so misplaced chunks are noticed
+        let part = sim.state().coredump_partition;
+        for (idx, b) in sim.state().flash[part.address as usize..(part.address + part.size) as usize].iter_mut().enumerate() {
+            *b = (idx as u32).wrapping_mul(2654435761).to_le_bytes()[3];
+        }
+        // Header length covers the whole partition, so the TCU reports a coredump
+        sim.state().flash[part.address as usize..part.address as usize + 4].copy_from_slice(&part.size.to_le_bytes());
+        let path = std::env::temp_dir().join(format!("un52_{name}_{}.bin", std::process::id()));
+        (sim, nag, path)
+    }
+
+    fn expected(sim: &Nag52Sim) -> Vec<u8> {
+        let part = sim.state().coredump_partition;
+        sim.state().flash[part.address as usize..(part.address + part.size) as usize].to_vec()
+    }
+
+    #[test]
+    pub fn test_download_retries_failed_block() {
+        let (sim, nag, path) = open("test_download_retries");
+        let mut transfers = 0;
+        let mut failed = false;
+        let closed_after_failure = Arc::new(AtomicBool::new(false));
+        let closed_c = closed_after_failure.clone();
+        sim.state().add_request_hook(move |req| {
+            if failed {
+                closed_c.store(req[0] == 0x37, Ordering::Relaxed);
+                failed = false;
+            }
+            if req[0] == 0x36 {
+                transfers += 1;
+                if transfers == 20 {
+                    failed = true;
+                    return Some(vec![0x7F, 0x36, NRC_CONDITIONS_NOT_CORRECT]);
+                }
+            }
+            None
+        });
+        let part = nag.get_coredump_flash_info().unwrap();
+        let opts = DownloadOptions { chunk_size: 0x4000, ..Default::default() };
+        let mut last_progress = None;
+        let res = nag.download_partition(&part, &path, &opts, |p| last_progress = Some(p)).unwrap();
+        assert_eq!(res.retries, 1);
+        assert_eq!(res.resumed_from, 0);
+        // Failed transfer is closed before the chunk is read again
+        assert!(closed_after_failure.load(Ordering::Relaxed));
+        // Verified by default
+        assert!(last_progress.unwrap().verifying);
+        assert_eq!(last_progress.unwrap().done, part.size);
+        let read = std::fs::read(&path).unwrap();
+        assert_eq!(read, expected(&sim));
+        assert_eq!(res.sha256, <[u8; 32]>::from(Sha256::digest(&read)));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    pub fn test_download_resume() {
+        let (sim, nag, path) = open("test_download_resume");
+        // Adapter is unplugged part way through the download
+        let mut transfers = 0;
+        sim.state().add_request_hook(move |req| {
+            if req[0] == 0x36 {
+                transfers += 1;
+                if transfers > 10 {
+                    return Some(vec![0x7F, 0x36, NRC_CONDITIONS_NOT_CORRECT]);
+                }
+            }
+            None
+        });
+        let part = nag.get_coredump_flash_info().unwrap();
+        let opts = DownloadOptions { chunk_size: 0x4000, max_retries: 1, ..Default::default() };
+        assert!(nag.download_partition(&part, &path, &opts, |_| {}).is_err());
+        let partial = std::fs::metadata(&path).unwrap().len();
+        assert!(partial > 0 && partial < part.size as u64);
+        assert_eq!(partial % opts.chunk_size as u64, 0);
+
+        sim.state().clear_request_hooks();
+        let res = nag.download_partition(&part, &path, &DownloadOptions { resume: true, ..opts }, |_| {}).unwrap();
+        assert_eq!(res.resumed_from as u64, partial);
+        assert_eq!(std::fs::read(&path).unwrap(), expected(&sim));
+
+        // Starting again without resuming overwrites the file
+        let res2 = nag.download_partition(&part, &path, &opts, |_| {}).unwrap();
+        assert_eq!(res2.resumed_from, 0);
+        assert_eq!(res2.sha256, res.sha256);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    pub fn test_download_verify_resumed_prefix() {
+        let (sim, nag, path) = open("test_download_verify");
+        let part = nag.get_coredump_flash_info().unwrap();
+        let opts = DownloadOptions { chunk_size: 0x4000, resume: true, ..Default::default() };
+        // File left from an earlier backup, which no longer matches the partition
+        let mut prefix = expected(&sim)[..0x8000].to_vec();
+        prefix[0x100] ^= 0xFF;
+        std::fs::write(&path, &prefix).unwrap();
+        match nag.download_partition(&part, &path, &opts, |_| {}) {
+            Err(DownloadError::VerifyFailed { file_sha256, partition_sha256 }) => {
+                assert_eq!(file_sha256, <[u8; 32]>::from(Sha256::digest(std::fs::read(&path).unwrap())));
+                assert_eq!(partition_sha256, <[u8; 32]>::from(Sha256::digest(expected(&sim))));
+            }
+            res => panic!("Expected the verification to fail, got {res:?}"),
+        }
+        // Not noticed without verifying
+        std::fs::write(&path, &prefix).unwrap();
+        let res = nag.download_partition(&part, &path, &DownloadOptions { verify: false, ..opts }, |_| {}).unwrap();
+        assert_eq!(res.resumed_from, 0x8000);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    pub fn test_read_coredump() {
+        let (sim, nag, _) = open("test_read_coredump");
+        let part = sim.state().coredump_partition;
+        let dump = CoreDump::build("0123456789abcdef", 0x3FFB1000, &[], &[(0x3FFB1000, XtensaRegisters::default())], &[]);
+        let start = part.address as usize;
+        sim.state().flash[start..start + dump.len()].copy_from_slice(&dump);
+        let mut last_progress = (0, 0);
+        assert_eq!(nag.read_coredump(|done, total| last_progress = (done, total)).unwrap(), dump);
+        assert_eq!(last_progress, (dump.len() as u32, dump.len() as u32));
+        assert_eq!(CoreDump::parse(&dump).unwrap().tasks.len(), 1);
+
+        assert!(nag.has_coredump().unwrap());
+        assert_eq!(nag.read_coredump_fingerprint().unwrap().as_deref(), Some(&dump[dump.len() - 32..]));
+
+        // Erased partition
+        sim.state().flash[start..start + part.size as usize].fill(0xFF);
+        assert!(!nag.has_coredump().unwrap());
+        let res = nag.read_coredump(|_, _| {}).unwrap();
+        assert_eq!(CoreDump::parse(&res), Err(CoreDumpError::Empty));
+        assert_eq!(nag.read_coredump_fingerprint().unwrap(), None);
+    }
+}
@@ -1,8 +1,13 @@
-use ecu_diagnostics::DiagServerResult;
+use ecu_diagnostics::{DiagError, DiagServerResult};
 
 use super::Nag52Diag;
 
-#[derive(Debug, Clone, Copy)]
+/// Largest block read by a single read memory request
+const READ_BLOCK_SIZE: u32 = 0xFE;
+/// Largest block written by a single write memory request
+const WRITE_BLOCK_SIZE: usize = 251;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum MemoryRegion {
     Sram0,
     Sram1,
@@ -31,6 +36,166 @@ impl MemoryRegion {
             MemoryRegion::EgsCalibration => 0x87_D000,
         }
     }
+
+    pub const ALL: [MemoryRegion; 5] = [
+        MemoryRegion::Sram0,
+        MemoryRegion::Sram1,
+        MemoryRegion::Sram2,
+        MemoryRegion::Psram,
+        MemoryRegion::EgsCalibration,
+    ];
+
+    /// Number of bytes which can be accessed, relative to [MemoryRegion::start_addr]
+    pub fn size(&self) -> u32 {
+        self.end_addr() - self.start_addr()
+    }
+
+    /// Address of the start of the region on the ESP32 memory bus, which is what the debug ELF uses.
+    /// The firmware's read/write memory by address handler maps the SRAM regions onto the internal
+    /// SRAM blocks (SRAM0 on the instruction bus, SRAM1 and SRAM2 on the data bus), and PSRAM onto the
+    /// external RAM. None for the EGS calibration, as it is a copy the firmware keeps itself
+    pub fn cpu_start_addr(&self) -> Option<u32> {
+        match self {
+            MemoryRegion::Sram0 => Some(0x4007_0000),
+            MemoryRegion::Sram1 => Some(0x3FFE_0000),
+            MemoryRegion::Sram2 => Some(0x3FFA_E000),
+            MemoryRegion::Psram => Some(0x3F80_0000),
+            MemoryRegion::EgsCalibration => None,
+        }
+    }
+
+    /// Finds the region which holds all `len` bytes at the ESP32 bus address `addr` (E.g. from the debug ELF),
+    /// returning it with the offset of `addr` within it
+    pub fn find_cpu_addr(addr: u32, len: u32) -> Option<(Self, u32)> {
+        Self::ALL.into_iter().find_map(|r| {
+            let offset = addr.checked_sub(r.cpu_start_addr()?)?;
+            (offset as u64 + len as u64 <= r.size() as u64).then_some((r, offset))
+        })
+    }
+
+    /// Finds the region which holds all `len` bytes at the absolute address `addr`,
+    /// returning it with the offset of `addr` within it
+    pub fn find(addr: u32, len: u32) -> Option<(Self, u32)> {
+        Self::ALL
+            .into_iter()
+            .find(|r| addr >= r.start_addr() && addr as u64 + len as u64 <= r.end_addr() as u64)
+            .map(|r| (r, addr - r.start_addr()))
+    }
+}
+
+impl std::fmt::Display for MemoryRegion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            MemoryRegion::Sram0 => "SRAM0",
+            MemoryRegion::Sram1 => "SRAM1",
+            MemoryRegion::Sram2 => "SRAM2",
+            MemoryRegion::Psram => "PSRAM",
+            MemoryRegion::EgsCalibration => "EGS calibration",
+        })
+    }
+}
+
+/// Interpretation of bytes in memory. All values are little endian
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MemoryValueType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+impl MemoryValueType {
+    pub const ALL: [MemoryValueType; 7] = [
+        MemoryValueType::U8,
+        MemoryValueType::I8,
+        MemoryValueType::U16,
+        MemoryValueType::I16,
+        MemoryValueType::U32,
+        MemoryValueType::I32,
+        MemoryValueType::F32,
+    ];
+
+    pub fn size(&self) -> usize {
+        match self {
+            MemoryValueType::U8 | MemoryValueType::I8 => 1,
+            MemoryValueType::U16 | MemoryValueType::I16 => 2,
+            MemoryValueType::U32 | MemoryValueType::I32 | MemoryValueType::F32 => 4,
+        }
+    }
+
+    /// Decodes the value at the start of `bytes`. Returns None if there are too few bytes
+    pub fn decode(&self, bytes: &[u8]) -> Option<f64> {
+        let b = bytes.get(..self.size())?;
+        Some(match self {
+            MemoryValueType::U8 => b[0] as f64,
+            MemoryValueType::I8 => b[0] as i8 as f64,
+            MemoryValueType::U16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+            MemoryValueType::I16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+            MemoryValueType::U32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            MemoryValueType::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            MemoryValueType::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+        })
+    }
+
+    /// Encodes `value`. Returns None if the value is not integral or out of range for integer types
+    pub fn encode(&self, value: f64) -> Option<Vec<u8>> {
+        fn int<T: TryFrom<i64>>(value: f64) -> Option<T> {
+            if value.fract() != 0.0 || !value.is_finite() {
+                return None;
+            }
+            T::try_from(value as i64).ok()
+        }
+        Some(match self {
+            MemoryValueType::U8 => vec![int::<u8>(value)?],
+            MemoryValueType::I8 => int::<i8>(value)?.to_le_bytes().to_vec(),
+            MemoryValueType::U16 => int::<u16>(value)?.to_le_bytes().to_vec(),
+            MemoryValueType::I16 => int::<i16>(value)?.to_le_bytes().to_vec(),
+            MemoryValueType::U32 => int::<u32>(value)?.to_le_bytes().to_vec(),
+            MemoryValueType::I32 => int::<i32>(value)?.to_le_bytes().to_vec(),
+            MemoryValueType::F32 => (value as f32).to_le_bytes().to_vec(),
+        })
+    }
+}
+
+impl std::fmt::Display for MemoryValueType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            MemoryValueType::U8 => "u8",
+            MemoryValueType::I8 => "i8",
+            MemoryValueType::U16 => "u16",
+            MemoryValueType::I16 => "i16",
+            MemoryValueType::U32 => "u32",
+            MemoryValueType::I32 => "i32",
+            MemoryValueType::F32 => "f32",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum MemoryWriteError {
+    /// Memory read back after writing differs from what was written
+    VerifyMismatch { addr: u32, expected: u8, actual: u8 },
+    Diag(DiagError),
+}
+
+impl std::fmt::Display for MemoryWriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::VerifyMismatch { addr, expected, actual } => {
+                write!(f, "Verification failed. 0x{addr:06X} reads 0x{actual:02X} after writing 0x{expected:02X}")
+            }
+            Self::Diag(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<DiagError> for MemoryWriteError {
+    fn from(e: DiagError) -> Self {
+        Self::Diag(e)
+    }
 }
 
 
@@ -76,4 +241,80 @@ impl Nag52Diag {
             })
         }
     }
-}
\ No newline at end of file
+
+    /// Reads `len` bytes at `pos` within `region`, in as many requests as needed
+    pub fn read_memory_block(&self, region: MemoryRegion, pos: u32, len: u32) -> DiagServerResult<Vec<u8>> {
+        let mut ret = Vec::with_capacity(len as usize);
+        while (ret.len() as u32) < len {
+            let read = std::cmp::min(READ_BLOCK_SIZE, len - ret.len() as u32);
+            let block = self.read_memory(region, pos + ret.len() as u32, read as u8)?;
+            // First byte is the positive response SID
+            match block.get(1..) {
+                Some(data) if data.len() == read as usize => ret.extend_from_slice(data),
+                _ => return Err(DiagError::InvalidResponseLength),
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Writes `data` at `pos` within `region`, in as many requests as needed, then reads it back to verify it
+    pub fn write_memory_verified(&self, region: MemoryRegion, pos: u32, data: &[u8]) -> Result<(), MemoryWriteError> {
+        for (idx, block) in data.chunks(WRITE_BLOCK_SIZE).enumerate() {
+            self.write_memory(region, pos + (idx * WRITE_BLOCK_SIZE) as u32, block)?;
+        }
+        let read = self.read_memory_block(region, pos, data.len() as u32)?;
+        match data.iter().zip(read.iter()).position(|(a, b)| a != b) {
+            Some(idx) => Err(MemoryWriteError::VerifyMismatch {
+                addr: region.start_addr() + pos + idx as u32,
+                expected: data[idx],
+                actual: read[idx],
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test_memory {
+    use super::*;
+    use crate::hw::sim::open_test_sim;
+
+    #[test]
+    pub fn test_value_types() {
+        assert_eq!(MemoryValueType::U16.decode(&[0xDC, 0x05, 0xFF]), Some(1500.0));
+        assert_eq!(MemoryValueType::I16.decode(&[0xD4, 0xFE]), Some(-300.0));
+        assert_eq!(MemoryValueType::F32.decode(&0.5f32.to_le_bytes()), Some(0.5));
+        assert_eq!(MemoryValueType::U32.decode(&[0, 0]), None);
+        assert_eq!(MemoryValueType::I16.encode(-300.0), Some(vec![0xD4, 0xFE]));
+        assert_eq!(MemoryValueType::U8.encode(256.0), None);
+        assert_eq!(MemoryValueType::U16.encode(1.5), None);
+        assert_eq!(MemoryValueType::F32.encode(1.5), Some(1.5f32.to_le_bytes().to_vec()));
+        assert_eq!(MemoryRegion::find(0x10_0010, 4), Some((MemoryRegion::Psram, 0x10)));
+        assert_eq!(MemoryRegion::find(0x4F_FFFE, 4), None);
+        assert_eq!(MemoryRegion::find_cpu_addr(0x3F80_0010, 4), Some((MemoryRegion::Psram, 0x10)));
+        assert_eq!(MemoryRegion::find_cpu_addr(0x3FBF_FFFC, 4), None);
+        assert_eq!(MemoryRegion::find_cpu_addr(0x3FFB_0000, 4), Some((MemoryRegion::Sram2, 0x2000)));
+        assert_eq!(MemoryRegion::find_cpu_addr(0x3FFE_0004, 2), Some((MemoryRegion::Sram1, 0x4)));
+        assert_eq!(MemoryRegion::find_cpu_addr(0x4007_0100, 4), Some((MemoryRegion::Sram0, 0x100)));
+        assert_eq!(MemoryRegion::find_cpu_addr(0x3FFC_DFFE, 4), None);
+        assert_eq!(MemoryRegion::find_cpu_addr(0x400D_0000, 4), None);
+    }
+
+    #[test]
+    pub fn test_read_write() {
+        let (sim, nag) = open_test_sim("test_memory_read_write");
+
+        let data: Vec<u8> = (0..600).map(|x| x as u8).collect();
+        nag.write_memory_verified(MemoryRegion::Psram, 0x100, &data).unwrap();
+        assert_eq!(nag.read_memory_block(MemoryRegion::Psram, 0x100, 600).unwrap(), data);
+        assert_eq!(sim.state().memory[&(MemoryRegion::Psram.start_addr() + 0x100 + 300)], 44);
+        assert!(nag.read_memory_block(MemoryRegion::Sram0, MemoryRegion::Sram0.size() - 2, 4).is_err());
+
+        // Read only memory
+        sim.state().add_request_hook(|req| (req[0] == 0x3D).then(|| vec![0x7D]));
+        assert!(matches!(
+            nag.write_memory_verified(MemoryRegion::Psram, 0x100, &[0xAA]),
+            Err(MemoryWriteError::VerifyMismatch { addr: 0x10_0100, expected: 0xAA, actual: 0 })
+        ));
+    }
+}
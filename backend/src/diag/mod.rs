@@ -1,7 +1,7 @@
 use core::fmt;
 use std::{
-    borrow::{Borrow, BorrowMut},
-    sync::{Arc, Mutex, mpsc::{self}},
+    borrow::BorrowMut,
+    sync::{Arc, Mutex, RwLock, Weak, mpsc::{self}},
 };
 
 use ecu_diagnostics::{hardware::{
@@ -20,20 +20,33 @@ use ecu_diagnostics::{kwp2000::*, DiagServerResult};
 use ecu_diagnostics::hardware::socketcan::{SocketCanDevice, SocketCanScanner};
 
 use crate::hw::{
+    replay::Nag52Replay,
+    sim::Nag52Sim,
+    sim_scanner::Nag52SimScanner,
     usb::{EspLogMessage, Nag52USB},
     usb_scanner::Nag52UsbScanner,
 };
 
-use self::device_modes::TcuDeviceMode;
+use self::{
+    device_modes::TcuDeviceMode,
+    supervisor::{ConnectionSupervisor, LinkCheck},
+    trace::TraceRecorder,
+};
 
 pub mod flash;
 pub mod ident;
 pub mod settings;
+pub mod settings_bundle;
+pub mod settings_migration;
 pub mod nvs;
 pub mod device_modes;
 pub mod module_settings_flash_store;
 pub mod calibration;
+pub mod calibration_db;
 pub mod memory;
+pub mod trace;
+pub mod supervisor;
+pub mod crash_monitor;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum AdapterType {
@@ -41,6 +54,8 @@ pub enum AdapterType {
     Passthru,
     #[cfg(target_os="linux")]
     SocketCAN,
+    Simulation,
+    Replay,
 }
 
 #[derive(Debug, Clone)]
@@ -70,6 +85,8 @@ pub enum AdapterHw {
     Passthru(PassthruDevice),
     #[cfg(target_os="linux")]
     SocketCAN(SocketCanDevice),
+    Simulation(Nag52Sim),
+    Replay(Nag52Replay),
 }
 
 impl fmt::Debug for AdapterHw {
@@ -79,6 +96,8 @@ impl fmt::Debug for AdapterHw {
             Self::Passthru(_) => f.debug_tuple("Passthru").finish(),
             #[cfg(target_os="linux")]
             Self::SocketCAN(_) => f.debug_tuple("SocketCAN").finish(),
+            Self::Simulation(_) => f.debug_tuple("Simulation").finish(),
+            Self::Replay(_) => f.debug_tuple("Replay").finish(),
         }
     }
 }
@@ -90,6 +109,8 @@ impl AdapterHw {
             AdapterType::Passthru => Self::Passthru(PassthruDevice::try_connect(info)?),
             #[cfg(target_os="linux")]
             AdapterType::SocketCAN => Self::SocketCAN(SocketCanDevice::try_connect(info)?),
+            AdapterType::Simulation => Self::Simulation(Nag52Sim::try_connect(info)?),
+            AdapterType::Replay => Self::Replay(Nag52Replay::try_connect(info)?),
         })
     }
 
@@ -99,6 +120,8 @@ impl AdapterHw {
             Self::Passthru(_) => AdapterType::Passthru,
             #[cfg(target_os="linux")]
             Self::SocketCAN(_) => AdapterType::SocketCAN,
+            Self::Simulation(_) => AdapterType::Simulation,
+            Self::Replay(_) => AdapterType::Replay,
         }
     }
 
@@ -108,6 +131,19 @@ impl AdapterHw {
             Self::Passthru(p) => p.create_iso_tp_channel(),
             #[cfg(target_os="linux")]
             Self::SocketCAN(s) => s.create_iso_tp_channel(),
+            Self::Simulation(s) => s.create_iso_tp_channel(),
+            Self::Replay(r) => r.create_iso_tp_channel(),
+        }
+    }
+
+    pub fn create_can_channel(&mut self) -> HardwareResult<Box<dyn CanChannel>> {
+        match self.borrow_mut() {
+            Self::Usb(u) => u.create_can_channel(),
+            Self::Passthru(p) => p.create_can_channel(),
+            #[cfg(target_os="linux")]
+            Self::SocketCAN(s) => s.create_can_channel(),
+            Self::Simulation(s) => s.create_can_channel(),
+            Self::Replay(r) => r.create_can_channel(),
         }
     }
 
@@ -117,6 +153,8 @@ impl AdapterHw {
             Self::Passthru(p) => p.get_info().clone(),
             #[cfg(target_os="linux")]
             Self::SocketCAN(s) => s.get_info().clone(),
+            Self::Simulation(s) => s.get_info().clone(),
+            Self::Replay(r) => r.get_info().clone(),
         }
     }
 
@@ -126,14 +164,27 @@ impl AdapterHw {
             Self::Passthru(p) => p.get_data_rate(),
             #[cfg(target_os="linux")]
             Self::SocketCAN(s) => s.get_data_rate(),
+            Self::Simulation(s) => s.get_data_rate(),
+            Self::Replay(r) => r.get_data_rate(),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        match self {
+            Self::Usb(u) => Nag52Endpoint::is_connected(u),
+            Self::Passthru(p) => Nag52Endpoint::is_connected(p),
+            #[cfg(target_os="linux")]
+            Self::SocketCAN(s) => Nag52Endpoint::is_connected(s),
+            Self::Simulation(s) => Nag52Endpoint::is_connected(s),
+            Self::Replay(r) => Nag52Endpoint::is_connected(r),
         }
     }
 
     pub fn read_log_msg(&self) -> Option<EspLogMessage> {
-        if let Self::Usb(nag) = self {
-            nag.read_msg()
-        } else {
-            None
+        match self {
+            Self::Usb(nag) => nag.read_msg(),
+            Self::Simulation(sim) => sim.read_msg(),
+            _ => None
         }
     }
 }
@@ -203,21 +254,75 @@ impl Nag52Endpoint for Nag52USB {
     }
 }
 
+impl Nag52Endpoint for Nag52Sim {
+
+    fn is_connected(&self) -> bool {
+        self.is_connected()
+    }
+
+    fn try_connect(info: &HardwareInfo) -> HardwareResult<Self> {
+        Nag52SimScanner::new().open_device_by_name(&info.name)
+    }
+
+    fn get_device_desc(&self) -> String {
+        format!("Simulated Ultimate-NAG52 ({})", self.get_info().name)
+    }
+
+    fn get_data_rate(&self) -> Option<(u32, u32)> {
+        Some(
+            (
+                self.tx_bytes.swap(0, std::sync::atomic::Ordering::Relaxed),
+                self.rx_bytes.swap(0, std::sync::atomic::Ordering::Relaxed)
+            )
+        )
+    }
+}
+
+impl Nag52Endpoint for Nag52Replay {
+
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    fn try_connect(info: &HardwareInfo) -> HardwareResult<Self> {
+        Nag52Replay::open(&info.name)
+    }
+
+    fn get_device_desc(&self) -> String {
+        format!("Replay of {}", self.get_info().name)
+    }
+
+    fn get_data_rate(&self) -> Option<(u32, u32)> {
+        Some(
+            (
+                self.tx_bytes.swap(0, std::sync::atomic::Ordering::Relaxed),
+                self.rx_bytes.swap(0, std::sync::atomic::Ordering::Relaxed)
+            )
+        )
+    }
+}
+
+/// Baud rate of the vehicle CAN bus the TCU is connected to
+pub const VEHICLE_CAN_BAUD: u32 = 500_000;
+
+type SharedRecorder = Arc<Mutex<Option<TraceRecorder>>>;
 
 #[derive(Debug, Clone)]
 pub struct NagAppLoggerInner {
-    sender: mpsc::Sender<ServerEvent>
+    sender: mpsc::Sender<ServerEvent>,
+    recorder: SharedRecorder
 }
 
 unsafe impl Send for NagAppLoggerInner{}
 unsafe impl Sync for NagAppLoggerInner{}
 
 impl NagAppLoggerInner {
-    pub fn new() -> (Self, mpsc::Receiver<ServerEvent>) {
+    pub fn new(recorder: SharedRecorder) -> (Self, mpsc::Receiver<ServerEvent>) {
         let (tx, rx) = mpsc::channel::<ServerEvent>();
         (
             Self {
-                sender: tx
+                sender: tx,
+                recorder
             },
             rx
         )
@@ -226,44 +331,146 @@ impl NagAppLoggerInner {
 
 impl DiagServerLogger for NagAppLoggerInner {
     fn on_event(&self, evt: ServerEvent) {
+        if let Ok(mut lock) = self.recorder.lock() {
+            if let Some(recorder) = lock.as_mut() {
+                if let Err(e) = recorder.record(&evt) {
+                    eprintln!("Failed to write trace, recording stopped: {e}");
+                    *lock = None;
+                }
+            }
+        }
         self.sender.send(evt);
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct NagAppLogger {
-    recv: Arc<mpsc::Receiver<ServerEvent>>
+    recv: Arc<mpsc::Receiver<ServerEvent>>,
+    recorder: SharedRecorder
 }
 
 impl NagAppLogger {
     pub fn new() -> (Self, NagAppLoggerInner) {
-        let (inner, recv) = NagAppLoggerInner::new();
+        let recorder = Arc::new(Mutex::new(None));
+        let (inner, recv) = NagAppLoggerInner::new(recorder.clone());
         (
             Self {
-                recv: Arc::new(recv)
+                recv: Arc::new(recv),
+                recorder
             },
             inner
         )
     }
 }
 
+/// CAN channel shared by all clones of a [Nag52Diag]
+#[derive(Clone, Default)]
+struct SharedCanChannel(Arc<Mutex<Option<Box<dyn CanChannel>>>>);
+
+impl fmt::Debug for SharedCanChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let open = self.0.lock().map(|c| c.is_some()).unwrap_or(false);
+        f.debug_struct("SharedCanChannel").field("open", &open).finish()
+    }
+}
+
+/// Adapter and diagnostic server, shared by all clones of a [Nag52Diag],
+/// so that a reconnect reaches every page using the connection
+#[derive(Default)]
+struct DiagLink {
+    endpoint: Option<AdapterHw>,
+    server: Option<Arc<DynamicDiagSession>>,
+}
+
+impl fmt::Debug for DiagLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DiagLink")
+            .field("endpoint", &self.endpoint)
+            .field("server", &self.server.is_some())
+            .finish()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Nag52Diag {
     device_mode: TcuDeviceMode,
     info: HardwareInfo,
-    endpoint: Option<AdapterHw>,
     endpoint_type: AdapterType,
-    server: Option<Arc<DynamicDiagSession>>,
+    link: Arc<RwLock<DiagLink>>,
     logger: NagAppLogger,
-    server_mutex: Arc<Mutex<()>>
+    logger_inner: NagAppLoggerInner,
+    server_mutex: Arc<Mutex<()>>,
+    reconnect_mutex: Arc<Mutex<()>>,
+    can: SharedCanChannel,
+    supervisor: Arc<ConnectionSupervisor>,
 }
 
 unsafe impl Sync for Nag52Diag {}
 unsafe impl Send for Nag52Diag {}
 
+/// Handle to a [Nag52Diag] that does not keep the connection open
+pub(crate) struct WeakNag52Diag {
+    device_mode: TcuDeviceMode,
+    info: HardwareInfo,
+    endpoint_type: AdapterType,
+    link: Weak<RwLock<DiagLink>>,
+    logger: NagAppLogger,
+    logger_inner: NagAppLoggerInner,
+    server_mutex: Arc<Mutex<()>>,
+    reconnect_mutex: Arc<Mutex<()>>,
+    can: Weak<Mutex<Option<Box<dyn CanChannel>>>>,
+    supervisor: Weak<ConnectionSupervisor>,
+}
+
+unsafe impl Send for WeakNag52Diag {}
+
+impl WeakNag52Diag {
+    pub(crate) fn upgrade(&self) -> Option<Nag52Diag> {
+        Some(Nag52Diag {
+            device_mode: self.device_mode,
+            info: self.info.clone(),
+            endpoint_type: self.endpoint_type,
+            link: self.link.upgrade()?,
+            logger: self.logger.clone(),
+            logger_inner: self.logger_inner.clone(),
+            server_mutex: self.server_mutex.clone(),
+            reconnect_mutex: self.reconnect_mutex.clone(),
+            can: SharedCanChannel(self.can.upgrade()?),
+            supervisor: self.supervisor.upgrade()?,
+        })
+    }
+}
+
 impl Nag52Diag {
     pub fn new(mut hw: AdapterHw) -> DiagServerResult<Self> {
+        let (logger, inner_logger) = NagAppLogger::new();
+        let kwp = Self::create_server(&mut hw, inner_logger.clone())?;
+
+        let mut s = Self {
+            device_mode: TcuDeviceMode::NORMAL,
+            info: hw.get_hw_info(),
+            endpoint_type: hw.get_type(),
+            link: Arc::new(RwLock::new(DiagLink {
+                endpoint: Some(hw),
+                server: Some(Arc::new(kwp)),
+            })),
+            logger,
+            logger_inner: inner_logger,
+            server_mutex: Arc::new(Mutex::new(())),
+            reconnect_mutex: Arc::new(Mutex::new(())),
+            can: SharedCanChannel::default(),
+            supervisor: Arc::new(ConnectionSupervisor::new()),
+        };
 
+        if let Ok(mode) = s.read_device_mode() {
+            s.device_mode = mode;
+        }
+        let weak = s.downgrade();
+        std::thread::spawn(move || supervisor::run(weak));
+        Ok(s)
+    }
+
+    fn create_server(hw: &mut AdapterHw, logger: NagAppLoggerInner) -> DiagServerResult<DynamicDiagSession> {
         let mut channel_cfg = IsoTPSettings {
             block_size: 0,
             st_min: 0,
@@ -304,53 +511,116 @@ impl Nag52Diag {
             name: "UN52DevMode".into(),
         });
 
-        let (logger, inner_logger) = NagAppLogger::new();
-
-        let kwp = DynamicDiagSession::new_over_iso_tp(
+        DynamicDiagSession::new_over_iso_tp(
             protocol,
             hw.create_isotp_channel().map_err(|e| DiagError::from(Arc::new(e)))?,
             channel_cfg,
             basic_opts,
             Some(adv_opts),
-            inner_logger
-        )?;
-
-        let mut s = Self {
-            device_mode: TcuDeviceMode::NORMAL,
-            info: hw.get_hw_info(),
-            endpoint_type: hw.get_type(),
-            endpoint: Some(hw),
-            server: Some(Arc::new(kwp)),
-            logger,
-            server_mutex: Arc::new(Mutex::new(()))
-        };
+            logger
+        )
+    }
 
-        if let Ok(mode) = s.read_device_mode() {
-            s.device_mode = mode;
+    pub(crate) fn downgrade(&self) -> WeakNag52Diag {
+        WeakNag52Diag {
+            device_mode: self.device_mode,
+            info: self.info.clone(),
+            endpoint_type: self.endpoint_type,
+            link: Arc::downgrade(&self.link),
+            logger: self.logger.clone(),
+            logger_inner: self.logger_inner.clone(),
+            server_mutex: self.server_mutex.clone(),
+            reconnect_mutex: self.reconnect_mutex.clone(),
+            can: Arc::downgrade(&self.can.0),
+            supervisor: Arc::downgrade(&self.supervisor),
         }
-        Ok(s)
     }
 
+    /// Closes the connection to the adapter, and opens it again. This is done
+    /// automatically by the connection supervisor when the link to the TCU is lost.
+    pub fn try_reconnect(&self) -> DiagServerResult<()> {
+        let _lock = self.reconnect_mutex.lock().map_err(|_| DiagError::ServerNotRunning)?;
+        self.rebuild_link()
+    }
 
-    pub fn try_reconnect(&mut self) -> DiagServerResult<()> {
+    fn rebuild_link(&self) -> DiagServerResult<()> {
+        let can_was_open = self.can.0.lock().map(|mut c| c.take().is_some()).unwrap_or(false);
         {
-            let _ = self.server.take();
-            let _ = self.endpoint.take();
+            let mut link = self.link.write().map_err(|_| DiagError::ServerNotRunning)?;
+            let _ = link.server.take();
+            let _ = link.endpoint.take();
         }
         // Now try to reconnect
 
-        println!("Trying to find {}", self.info.name);
-        let dev = AdapterHw::try_connect(&self.info, self.endpoint_type).map_err(|e| DiagError::from(Arc::new(e)))?;
-        *self = Self::new(dev)?;
+        eprintln!("Trying to find {}", self.info.name);
+        let mut dev = AdapterHw::try_connect(&self.info, self.endpoint_type).map_err(|e| DiagError::from(Arc::new(e)))?;
+        // Same logger, so recording a trace continues across reconnects
+        let server = Self::create_server(&mut dev, self.logger_inner.clone())?;
+        {
+            let mut link = self.link.write().map_err(|_| DiagError::ServerNotRunning)?;
+            link.endpoint = Some(dev);
+            link.server = Some(Arc::new(server));
+        }
+        if can_was_open {
+            let _ = self.open_can_sniffer();
+        }
         Ok(())
     }
 
+    /// Checks the adapter and the link to the TCU. If the server has seen an error,
+    /// the TCU is probed with tester present.
+    pub(crate) fn check_link(&self) -> LinkCheck {
+        let (connected, server) = match self.link.read() {
+            Ok(link) => match (&link.endpoint, &link.server) {
+                (Some(e), Some(s)) => (e.is_connected(), s.clone()),
+                _ => return LinkCheck::Lost("Not connected".into()),
+            },
+            Err(_) => return LinkCheck::Lost("Not connected".into()),
+        };
+        if !connected {
+            return LinkCheck::Lost("Adapter disconnected".into());
+        }
+        let session = server.get_current_diag_mode();
+        if server.is_ecu_connected() {
+            return LinkCheck::Ok(session);
+        }
+        match server.send_byte_array_with_response(&[0x3E, 0x01]) {
+            Ok(_) => LinkCheck::Ok(server.get_current_diag_mode()),
+            Err(e) => LinkCheck::Lost(format!("TCU is not responding: {e}")),
+        }
+    }
+
+    /// Reconnects, and restores a diagnostic session. Returns true if the session was restored
+    pub(crate) fn reconnect(&self, session: Option<&DiagSessionMode>) -> DiagServerResult<bool> {
+        {
+            let _lock = self.reconnect_mutex.lock().map_err(|_| DiagError::ServerNotRunning)?;
+            // Link may have been restored by someone else calling try_reconnect
+            if !matches!(self.check_link(), LinkCheck::Ok(_)) {
+                self.rebuild_link()?;
+            }
+        }
+        // Make sure the TCU is actually there
+        self.with_kwp(|k| k.send_byte_array_with_response(&[0x3E, 0x01]))?;
+        match session {
+            Some(s) => match self.with_kwp(|k| k.send_byte_array_with_response(&[0x10, s.id])) {
+                Ok(_) => Ok(true),
+                Err(e) => {
+                    eprintln!("Could not restore diag session {}: {e}", s.name);
+                    Ok(false)
+                }
+            },
+            None => Ok(false),
+        }
+    }
+
     pub fn with_kwp<F, X>(&self, mut kwp_fn: F) -> DiagServerResult<X>
     where
         F: FnMut(&DynamicDiagSession) -> DiagServerResult<X>,
     {
         if self.server_mutex.lock().is_ok() {
-            match self.server.borrow() {
+            // Don't hold the lock whilst talking to the TCU, so a reconnect can't block
+            let server = self.link.read().ok().and_then(|l| l.server.clone());
+            match server {
                 None => Err(DiagError::from(Arc::new(HardwareError::DeviceNotOpen))),
                 Some(s) => kwp_fn(&s),
             }
@@ -360,37 +630,73 @@ impl Nag52Diag {
     }
 
     pub fn get_data_rate(&self) -> Option<(u32, u32)> {
-        self.endpoint.as_ref().map(|x| x.get_data_rate()).unwrap_or_else(|| None)
+        self.link.read().ok()?.endpoint.as_ref()?.get_data_rate()
     }
 
     pub fn read_log_msg(&self) -> Option<EspLogMessage> {
-        self.endpoint.as_ref().map(|x| x.read_log_msg()).flatten()
+        self.link.read().ok()?.endpoint.as_ref()?.read_log_msg()
+    }
+
+    /// Opens a receive-only CAN channel on the adapter, for reading frames of the vehicle's CAN bus
+    /// with [Nag52Diag::read_can_msg]. Frames can not be transmitted. The bus always runs at
+    /// [VEHICLE_CAN_BAUD] with standard IDs, and adapters which can not listen at that rate fail to open
+    pub fn open_can_sniffer(&self) -> ChannelResult<()> {
+        let mut lock = self.can.0.lock()?;
+        if lock.is_none() {
+            let mut hw = self.link.read()?.endpoint.clone().ok_or(ChannelError::InterfaceNotOpen)?;
+            let mut channel = hw.create_can_channel().map_err(ChannelError::HardwareError)?;
+            channel.set_can_cfg(VEHICLE_CAN_BAUD, false)?;
+            channel.open()?;
+            *lock = Some(channel);
+        }
+        Ok(())
     }
 
-    pub fn read_can_msg(&self) -> Option<CanFrame> {
-        let hw = self.endpoint.as_ref()?;
-        if let AdapterHw::Usb(usb) = hw {
-            return usb.read_can();
+    pub fn close_can_sniffer(&self) {
+        if let Ok(mut lock) = self.can.0.lock() {
+            if let Some(mut channel) = lock.take() {
+                let _ = channel.close();
+            }
         }
-        None
+    }
+
+    pub fn read_can_msg(&self) -> Option<CanFrame> {
+        let mut lock = self.can.0.lock().ok()?;
+        lock.as_mut()?.read_packets(1, 0).ok()?.pop()
     }
 
     pub fn clear_can_buffer(&self) {
-        if let Some(hw) = self.endpoint.as_ref() {
-            if let AdapterHw::Usb(usb) = hw {
-                while usb.read_can().is_some(){}
+        if let Ok(mut lock) = self.can.0.lock() {
+            if let Some(channel) = lock.as_mut() {
+                let _ = channel.clear_rx_buffer();
             }
         }
     }
 
     pub fn has_logger(&self) -> bool {
-        self.endpoint_type == AdapterType::USB
+        matches!(self.endpoint_type, AdapterType::USB | AdapterType::Simulation)
     }
 
     pub fn get_server_event(&self) -> Option<ServerEvent> {
         self.logger.recv.try_recv().ok()
     }
 
+    /// Starts recording all diagnostic traffic to a trace file
+    pub fn start_recording<P: AsRef<std::path::Path>>(&self, path: P) -> std::io::Result<()> {
+        let recorder = TraceRecorder::create(path, &self.info.name)?;
+        *self.logger.recorder.lock().map_err(|e| std::io::Error::other(e.to_string()))? = Some(recorder);
+        Ok(())
+    }
+
+    /// Stops recording, returning how many records were written
+    pub fn stop_recording(&self) -> Option<usize> {
+        self.logger.recorder.lock().ok()?.take().map(|r| r.records())
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.logger.recorder.lock().map(|r| r.is_some()).unwrap_or(false)
+    }
+
 }
 
 #[cfg(test)]
@@ -406,7 +712,7 @@ pub mod test_diag {
     pub fn test_kwp_reconnect() {
         let scanner = Nag52UsbScanner::new();
         let dev = scanner.open_device_by_name("/dev/ttyUSB0").unwrap();
-        let mut kwp = match Nag52Diag::new(AdapterHw::Usb(dev)) {
+        let kwp = match Nag52Diag::new(AdapterHw::Usb(dev)) {
             Ok(kwp) => kwp,
             Err(e) => {
                 eprintln!("Error starting KWP {e}");
@@ -1,9 +1,10 @@
 use std::io::{Write, Read};
 
+use ecu_diagnostics::{DiagError, DiagServerResult};
 use flate2::{write::ZlibEncoder, Compression, bufread::ZlibDecoder};
 use packed_struct::prelude::PackedStruct;
 
-use super::settings::ModuleSettingsData;
+use super::{settings::ModuleSettingsData, Nag52Diag};
 
 const MODULE_SETING_FLASH_MAGIC: [u8; 4] = [0xDE, 0xAD, 0xBE, 0xEF];
 
@@ -14,6 +15,16 @@ pub enum MsFlashReadError {
     InvalidContentSize
 }
 
+impl std::fmt::Display for MsFlashReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidMagic => write!(f, "No MODULE_SETTINGS header"),
+            Self::UncompressFailure => write!(f, "MODULE_SETTINGS cannot be decompressed"),
+            Self::InvalidContentSize => write!(f, "MODULE_SETTINGS is truncated"),
+        }
+    }
+}
+
 #[derive(PackedStruct, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ModuleSettingsFlashHeader {
     // Reserved for ESP
@@ -29,25 +40,12 @@ const HEADER_SIZE: usize = std::mem::size_of::<ModuleSettingsFlashHeader>();
 impl ModuleSettingsFlashHeader {
     pub fn new_from_yml_content(f_str: &str) -> Option<(Self, Vec<u8>)> {
         let settings: ModuleSettingsData = serde_yaml::from_str(f_str).ok()?;
-        // Key magic calc algo
-        let mut cs: u32 = 0;
-        for key in settings.settings.iter().map(|x| &x.eeprom_key) {
-            if let Some(k) = key {
-                let b = k.as_str().as_bytes();
-                for (idx, byte) in b.iter().enumerate() {
-                    cs = cs.wrapping_add(idx as u32);
-                    cs = cs.wrapping_add(*byte as u32);
-                }
-            }
-        }
-
-
         let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
         encoder.write_all(f_str.as_bytes()).unwrap();
         let bytes = encoder.finish().unwrap();
         Some((Self {
             magic: MODULE_SETING_FLASH_MAGIC,
-            key_magic: cs,
+            key_magic: settings.key_magic(),
             length_compressed: bytes.len() as u32
         },
         bytes))
@@ -93,5 +91,171 @@ impl ModuleSettingsFlashHeader {
         tx.extend_from_slice(compressed);
         tx
     }
+}
+
+#[derive(Debug)]
+pub enum ModuleSettingsUploadError {
+    InvalidYml(String),
+    TooLarge { size: usize, max: u32 },
+    /// The EEPROM keys of the new MODULE_SETTINGS differ from the stored MODULE_SETTINGS
+    KeyMagicMismatch { stored: u32, new: u32 },
+    /// MODULE_SETTINGS read back from the TCU could not be decompressed
+    Verify(MsFlashReadError),
+    /// MODULE_SETTINGS read back from the TCU differs from what was written
+    VerifyMismatch,
+    Diag(DiagError),
+}
+
+impl std::fmt::Display for ModuleSettingsUploadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidYml(e) => write!(f, "Invalid MODULE_SETTINGS. {e}"),
+            Self::TooLarge { size, max } => write!(f, "Compressed MODULE_SETTINGS is {size} bytes, but only {max} bytes fit on the TCU"),
+            Self::KeyMagicMismatch { stored, new } => {
+                write!(f, "Key magic 0x{new:08X} differs from the stored key magic 0x{stored:08X}. MODULE_SETTINGS is for a different firmware")
+            }
+            Self::Verify(e) => write!(f, "Verification failed. {e}"),
+            Self::VerifyMismatch => write!(f, "Verification failed. MODULE_SETTINGS read back differs from what was written"),
+            Self::Diag(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<DiagError> for ModuleSettingsUploadError {
+    fn from(e: DiagError) -> Self {
+        Self::Diag(e)
+    }
+}
+
+/// MODULE_SETTINGS.yml, validated and compressed for writing to the TCU
+#[derive(Debug, Clone)]
+pub struct ModuleSettingsUpload {
+    pub settings: ModuleSettingsData,
+    pub header: ModuleSettingsFlashHeader,
+    yml: String,
+    compressed: Vec<u8>,
+}
+
+impl ModuleSettingsUpload {
+    pub fn new(yml: &str) -> Result<Self, ModuleSettingsUploadError> {
+        let settings: ModuleSettingsData = serde_yaml::from_str(yml).map_err(|e| ModuleSettingsUploadError::InvalidYml(e.to_string()))?;
+        // Only fails if the YML is invalid, which was checked above
+        let (header, compressed) = ModuleSettingsFlashHeader::new_from_yml_content(yml).unwrap();
+        Ok(Self { settings, header, yml: yml.to_string(), compressed })
+    }
+
+    pub fn tx_data(&self) -> Vec<u8> {
+        self.header.merge_to_tx_data(&self.compressed)
+    }
+}
 
-}
\ No newline at end of file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModuleSettingsUploadProgress {
+    Write { done: u32, total: u32 },
+    Verify { done: u32, total: u32 },
+}
+
+impl Nag52Diag {
+    /// Reads the header of the MODULE_SETTINGS stored in the descriptor partition.
+    /// Returns None if there is no valid header (E.g. the partition is blank)
+    pub fn read_module_settings_header(&self) -> DiagServerResult<Option<ModuleSettingsFlashHeader>> {
+        let part = self.get_module_settings_desc_partition();
+        let raw = self.read_flash_region(part.address, HEADER_SIZE as u32 + 1, |_| {})?;
+        Ok(ModuleSettingsFlashHeader::read_header_from_buffer(&raw).ok())
+    }
+
+    /// Writes MODULE_SETTINGS to the descriptor partition, then reads it back and checks that it decompresses
+    /// to the same YML. Unless `allow_key_change` is set, MODULE_SETTINGS with a different key magic than the
+    /// stored MODULE_SETTINGS is refused, as it belongs to a different firmware
+    pub fn upload_module_settings(
+        &self,
+        upload: &ModuleSettingsUpload,
+        allow_key_change: bool,
+        mut on_progress: impl FnMut(ModuleSettingsUploadProgress),
+    ) -> Result<(), ModuleSettingsUploadError> {
+        let tx = upload.tx_data();
+        let max = self.get_module_settings_desc_partition().size;
+        if tx.len() > max as usize {
+            return Err(ModuleSettingsUploadError::TooLarge { size: tx.len(), max });
+        }
+        if let Some(stored) = self.read_module_settings_header()? {
+            if stored.key_magic != upload.header.key_magic && !allow_key_change {
+                return Err(ModuleSettingsUploadError::KeyMagicMismatch { stored: stored.key_magic, new: upload.header.key_magic });
+            }
+        }
+        let total = tx.len() as u32;
+        let (address, bs) = self.begin_yml_ota(total)?;
+        let mut done = 0;
+        for (bid, block) in tx.chunks(bs as usize).enumerate() {
+            self.transfer_data(((bid + 1) & 0xFF) as u8, block)?;
+            done += block.len() as u32;
+            on_progress(ModuleSettingsUploadProgress::Write { done, total });
+        }
+        self.end_ota(false)?;
+
+        let read = self.read_flash_region(address, total, |done| on_progress(ModuleSettingsUploadProgress::Verify { done: done.min(total), total }))?;
+        let (_, yml) = ModuleSettingsFlashHeader::from_flash_bytes_to_yml_bytes(&read).map_err(ModuleSettingsUploadError::Verify)?;
+        if yml != upload.yml.as_bytes() {
+            return Err(ModuleSettingsUploadError::VerifyMismatch);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test_module_settings_flash_store {
+    use super::*;
+    use crate::hw::{sim::open_test_sim, sim_ecu::SIM_MODULE_SETTINGS_YML};
+
+    #[test]
+    pub fn test_upload() {
+        let (sim, nag) = open_test_sim("test_module_settings_upload");
+        let stored = nag.read_module_settings_header().unwrap().unwrap();
+        let yml = SIM_MODULE_SETTINGS_YML.replace("Torque converter clutch settings", "Converter clutch");
+        let upload = ModuleSettingsUpload::new(&yml).unwrap();
+        assert_eq!(upload.header.key_magic, stored.key_magic);
+
+        let mut progress = Vec::new();
+        nag.upload_module_settings(&upload, false, |p| progress.push(p)).unwrap();
+        assert!(matches!(progress.first(), Some(ModuleSettingsUploadProgress::Write { .. })));
+        assert!(matches!(progress.last(), Some(ModuleSettingsUploadProgress::Verify { done, total }) if done == total));
+
+        let part = nag.get_module_settings_desc_partition();
+        let flash = sim.state().flash[part.address as usize..(part.address + part.size) as usize].to_vec();
+        let (_, read) = ModuleSettingsFlashHeader::from_flash_bytes_to_yml_bytes(&flash).unwrap();
+        assert_eq!(read, yml.as_bytes());
+    }
+
+    #[test]
+    pub fn test_upload_key_magic() {
+        let (_sim, nag) = open_test_sim("test_module_settings_upload_key_magic");
+        let yml = SIM_MODULE_SETTINGS_YML.replace("TCC_A0", "TCC_A1");
+        let upload = ModuleSettingsUpload::new(&yml).unwrap();
+        assert!(matches!(
+            nag.upload_module_settings(&upload, false, |_| {}),
+            Err(ModuleSettingsUploadError::KeyMagicMismatch { .. })
+        ));
+        nag.upload_module_settings(&upload, true, |_| {}).unwrap();
+        assert_eq!(nag.read_module_settings_header().unwrap().unwrap().key_magic, upload.header.key_magic);
+
+        assert!(matches!(ModuleSettingsUpload::new("Enums: 1"), Err(ModuleSettingsUploadError::InvalidYml(_))));
+    }
+
+    #[test]
+    pub fn test_upload_too_large() {
+        let (_sim, nag) = open_test_sim("test_module_settings_upload_too_large");
+        // Comment that does not compress, so it cannot fit in the partition
+        let mut yml = format!("{SIM_MODULE_SETTINGS_YML}\n#");
+        let mut x: u32 = 1;
+        for _ in 0..0x40000 {
+            x = x.wrapping_mul(1664525).wrapping_add(1013904223);
+            yml.push(char::from(b'A' + (x >> 27) as u8));
+        }
+        let upload = ModuleSettingsUpload::new(&yml).unwrap();
+        let max = nag.get_module_settings_desc_partition().size;
+        assert!(matches!(
+            nag.upload_module_settings(&upload, false, |_| {}),
+            Err(ModuleSettingsUploadError::TooLarge { max: m, .. }) if m == max
+        ));
+    }
+}
@@ -1,6 +1,139 @@
-use std::{fs::File, io::Write, mem::size_of};
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
-pub enum DataType {
+//! ESP-IDF NVS (Non volatile storage) partition parser
+//!
+//! The TCU stores its EEPROM contents in an NVS partition. The partition is made of
+//! 4096 byte pages, each with a 32 byte header, a 32 byte entry state bitmap and 126 entries of 32 bytes.
+//! Values larger than 8 bytes (Strings and blobs) span multiple entries, and blobs may be
+//! split into chunks across pages, which are tied together by a blob index entry.
+//!
+//! See https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/storage/nvs_flash.html
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use serde::Serialize;
+
+pub const NVS_PAGE_SIZE: usize = 4096;
+pub const NVS_ENTRY_SIZE: usize = 32;
+pub const NVS_ENTRIES_PER_PAGE: usize = 126;
+/// Offset of the first entry within a page
+const ENTRIES_OFFSET: usize = 64;
+/// Namespace index of entries which define namespaces
+const NS_INDEX_NAMESPACES: u8 = 0;
+/// Used by namespace indexes (Namespaces are 1..=254)
+const NS_INDEX_ANY: u8 = 0xFF;
+pub const NVS_KEY_MAX_LEN: usize = 15;
+/// Namespace the TCU keeps its EEPROM items (Settings and maps) in
+pub const TCU_NVS_NAMESPACE: &str = "tcm";
+/// Strings cannot be split across pages
+pub const NVS_STR_MAX_LEN: usize = (NVS_ENTRIES_PER_PAGE - 1) * NVS_ENTRY_SIZE;
+/// Chunk indexes of a blob either start at 0 or 128
+const BLOB_MAX_CHUNKS: usize = 127;
+const PAGE_VERSION_2: u8 = 0xFE;
+
+/// CRC32 as used by NVS (`esp_rom_crc32_le(0xFFFFFFFF, ..)`, `zlib.crc32(data, 0xFFFFFFFF)` in nvs_partition_gen.py)
+pub fn nvs_crc32(data: &[u8]) -> u32 {
+    let mut h = crc32fast::Hasher::new_with_initial(0xFFFFFFFF);
+    h.update(data);
+    h.finalize()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum NvsPageState {
+    Uninitialized,
+    Active,
+    Full,
+    Freeing,
+    Corrupt,
+    Invalid(u32),
+}
+
+impl From<u32> for NvsPageState {
+    fn from(x: u32) -> Self {
+        match x {
+            0xFFFFFFFF => Self::Uninitialized,
+            0xFFFFFFFE => Self::Active,
+            0xFFFFFFFC => Self::Full,
+            0xFFFFFFF8 => Self::Freeing,
+            0xFFFFFFF0 => Self::Corrupt,
+            x => Self::Invalid(x),
+        }
+    }
+}
+
+impl NvsPageState {
+    /// Pages that hold valid entries
+    pub fn is_readable(&self) -> bool {
+        matches!(self, Self::Active | Self::Full | Self::Freeing)
+    }
+}
+
+/// State of an entry, from the page bitmap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvsEntryState {
+    Empty,
+    Written,
+    Erased,
+    Illegal,
+}
+
+impl From<u8> for NvsEntryState {
+    fn from(x: u8) -> Self {
+        match x & 0b11 {
+            0b11 => Self::Empty,
+            0b10 => Self::Written,
+            0b00 => Self::Erased,
+            _ => Self::Illegal,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum NvsItemType {
+    U8 = 0x01,
+    I8 = 0x11,
+    U16 = 0x02,
+    I16 = 0x12,
+    U32 = 0x04,
+    I32 = 0x14,
+    U64 = 0x08,
+    I64 = 0x18,
+    Str = 0x21,
+    /// Single page blob, written by old versions of NVS
+    BlobV1 = 0x41,
+    BlobData = 0x42,
+    BlobIndex = 0x48,
+}
+
+impl TryFrom<u8> for NvsItemType {
+    type Error = u8;
+    fn try_from(x: u8) -> Result<Self, Self::Error> {
+        Ok(match x {
+            0x01 => Self::U8,
+            0x11 => Self::I8,
+            0x02 => Self::U16,
+            0x12 => Self::I16,
+            0x04 => Self::U32,
+            0x14 => Self::I32,
+            0x08 => Self::U64,
+            0x18 => Self::I64,
+            0x21 => Self::Str,
+            0x41 => Self::BlobV1,
+            0x42 => Self::BlobData,
+            0x48 => Self::BlobIndex,
+            x => return Err(x),
+        })
+    }
+}
+
+impl NvsItemType {
+    /// Data is stored in the entries following the item header
+    pub fn is_variable_length(&self) -> bool {
+        matches!(self, Self::Str | Self::BlobV1 | Self::BlobData)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum NvsValue {
     U8(u8),
     I8(i8),
     U16(u16),
@@ -9,129 +142,1066 @@ pub enum DataType {
     I32(i32),
     U64(u64),
     I64(i64),
-    BlobIndex {
-        size: u32,
-        chunk_count: u8,
-        chunk_start: u8,
-        rsv: u16,
-    },
-    VariableData {
-        size: u16,
-        rsv: u16,
-        crc32: u32,
-    },
-}
-
-impl DataType {
-    pub fn from_entry(ty: u8, raw: u64) -> Self {
-        match ty {
-            0x01 => Self::U8((raw & 0xFF) as u8),
-            0x11 => Self::I8((raw & 0xFF) as i8),
-            0x02 => Self::U16((raw & 0xFFFF) as u16),
-            0x12 => Self::I16((raw & 0xFFFF) as i16),
-            0x04 => Self::U32((raw & 0xFFFFFFFF) as u32),
-            0x14 => Self::I32((raw & 0xFFFFFFFF) as i32),
-            0x18 => Self::I64(raw as i64),
-            0x42 | 0x21 => Self::VariableData {
-                size: ((raw) & 0xFFFF) as u16,
-                rsv: ((raw >> 16) & 0xFFFF) as u16,
-                crc32: ((raw >> 32) & 0xFFFFFFFF) as u32,
-            },
-            0x48 => Self::BlobIndex {
-                size: ((raw) & 0xFFFFFFFF) as u32,
-                chunk_count: ((raw >> 32) & 0xFF) as u8,
-                chunk_start: ((raw >> 40) & 0xFF) as u8,
-                rsv: ((raw >> 48) & 0xFFFF) as u16,
-            },
-            _ => Self::U64(raw),
-        }
-    }
-}
-
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
-#[repr(C, packed)]
-pub struct NvsEntry {
+    Str(String),
+    Blob(Vec<u8>),
+}
+
+impl NvsValue {
+    pub fn item_type(&self) -> NvsItemType {
+        match self {
+            NvsValue::U8(_) => NvsItemType::U8,
+            NvsValue::I8(_) => NvsItemType::I8,
+            NvsValue::U16(_) => NvsItemType::U16,
+            NvsValue::I16(_) => NvsItemType::I16,
+            NvsValue::U32(_) => NvsItemType::U32,
+            NvsValue::I32(_) => NvsItemType::I32,
+            NvsValue::U64(_) => NvsItemType::U64,
+            NvsValue::I64(_) => NvsItemType::I64,
+            NvsValue::Str(_) => NvsItemType::Str,
+            NvsValue::Blob(_) => NvsItemType::BlobIndex,
+        }
+    }
+}
+
+impl Display for NvsValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NvsValue::U8(x) => write!(f, "{x}"),
+            NvsValue::I8(x) => write!(f, "{x}"),
+            NvsValue::U16(x) => write!(f, "{x}"),
+            NvsValue::I16(x) => write!(f, "{x}"),
+            NvsValue::U32(x) => write!(f, "{x}"),
+            NvsValue::I32(x) => write!(f, "{x}"),
+            NvsValue::U64(x) => write!(f, "{x}"),
+            NvsValue::I64(x) => write!(f, "{x}"),
+            NvsValue::Str(s) => write!(f, "\"{s}\""),
+            NvsValue::Blob(b) => write!(f, "{} bytes {:02X?}", b.len(), b),
+        }
+    }
+}
+
+/// Location of an item in the partition
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct NvsLocation {
+    pub page: usize,
+    pub entry: usize,
+}
+
+impl Display for NvsLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "page {} entry {}", self.page, self.entry)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NvsPage {
+    pub state: NvsPageState,
+    pub seqno: u32,
+    pub version: u8,
+    /// Header CRC is valid
+    pub crc_ok: bool,
+    /// Entries in use (Including the data entries of strings and blobs)
+    pub written: usize,
+    /// Entries that held an old value, and are only freed by the garbage collector
+    pub erased: usize,
+    pub empty: usize,
+}
+
+/// One item header (Entry) as stored in a page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NvsRawEntry {
     pub ns: u8,
     pub ty: u8,
     pub span: u8,
     pub chunk_index: u8,
     pub crc: u32,
     pub key: [u8; 16],
-    pub data: u64,
+    pub data: [u8; 8],
 }
 
-impl NvsEntry {
-    pub fn get_key(&self) -> String {
-        String::from_utf8_lossy(&self.key).to_string()
+impl NvsRawEntry {
+    pub fn from_bytes(b: &[u8]) -> Self {
+        Self {
+            ns: b[0],
+            ty: b[1],
+            span: b[2],
+            chunk_index: b[3],
+            crc: u32::from_le_bytes(b[4..8].try_into().unwrap()),
+            key: b[8..24].try_into().unwrap(),
+            data: b[24..32].try_into().unwrap(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; NVS_ENTRY_SIZE] {
+        let mut b = [0; NVS_ENTRY_SIZE];
+        b[0] = self.ns;
+        b[1] = self.ty;
+        b[2] = self.span;
+        b[3] = self.chunk_index;
+        b[4..8].copy_from_slice(&self.crc.to_le_bytes());
+        b[8..24].copy_from_slice(&self.key);
+        b[24..32].copy_from_slice(&self.data);
+        b
+    }
+
+    /// CRC over the whole entry, excluding the CRC field itself
+    pub fn calc_crc(&self) -> u32 {
+        let b = self.to_bytes();
+        let mut crc_data = [0; NVS_ENTRY_SIZE - 4];
+        crc_data[..4].copy_from_slice(&b[..4]);
+        crc_data[4..].copy_from_slice(&b[8..]);
+        nvs_crc32(&crc_data)
+    }
+
+    pub fn key(&self) -> String {
+        let len = self.key.iter().position(|x| *x == 0).unwrap_or(self.key.len());
+        String::from_utf8_lossy(&self.key[..len]).to_string()
+    }
+
+    /// Size, and CRC of the data of a variable length item
+    fn var_data(&self) -> (usize, u32) {
+        (
+            u16::from_le_bytes([self.data[0], self.data[1]]) as usize,
+            u32::from_le_bytes(self.data[4..8].try_into().unwrap()),
+        )
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
-#[repr(C, packed)]
-pub struct NvsPage {
-    pub state: u32,
-    pub seqnr: u32,
-    pub unused: [u32; 5],
-    pub crc: u32,
-    pub bitmap: [u8; 32],
-    pub entries: [NvsEntry; 126],
+/// Key/value pair stored in the partition
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NvsItem {
+    pub namespace: String,
+    pub key: String,
+    pub value: NvsValue,
+    /// Location of the item header (For blobs, the blob index)
+    pub location: NvsLocation,
+    /// Item header (And all blob chunk headers) CRCs are valid
+    pub header_crc_ok: bool,
+    /// Data CRC of strings and blobs (All chunks) is valid. Always true for fixed size values
+    pub data_crc_ok: bool,
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+impl NvsItem {
+    pub fn crc_ok(&self) -> bool {
+        self.header_crc_ok && self.data_crc_ok
+    }
+}
+
+/// Problems found whilst parsing, which do not prevent the rest of the partition being read
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum NvsIssue {
+    PageHeaderCrc { page: usize },
+    InvalidPageState { page: usize, state: u32 },
+    IllegalEntryState(NvsLocation),
+    UnknownItemType { location: NvsLocation, ty: u8 },
+    /// Span of an item is 0, runs past the end of the page, or does not match its data size
+    InvalidSpan { location: NvsLocation, span: u8 },
+    UnknownNamespace { location: NvsLocation, ns: u8 },
+    MissingBlobChunk { namespace: String, key: String, chunk: u8 },
+    BlobSizeMismatch { namespace: String, key: String, expected: usize, actual: usize },
+}
+
+impl Display for NvsIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PageHeaderCrc { page } => write!(f, "Page {page} header CRC mismatch"),
+            Self::InvalidPageState { page, state } => write!(f, "Page {page} has invalid state 0x{state:08X}"),
+            Self::IllegalEntryState(loc) => write!(f, "Illegal entry state at {loc}"),
+            Self::UnknownItemType { location, ty } => write!(f, "Unknown item type 0x{ty:02X} at {location}"),
+            Self::InvalidSpan { location, span } => write!(f, "Invalid span {span} at {location}"),
+            Self::UnknownNamespace { location, ns } => write!(f, "Unknown namespace index {ns} at {location}"),
+            Self::MissingBlobChunk { namespace, key, chunk } => {
+                write!(f, "Blob {namespace}::{key} is missing chunk {chunk}")
+            }
+            Self::BlobSizeMismatch { namespace, key, expected, actual } => {
+                write!(f, "Blob {namespace}::{key} should be {expected} bytes, but its chunks are {actual} bytes")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NvsError {
+    /// Partition length is not a multiple of the page size, or is empty
+    InvalidLength(usize),
+    /// Keys and namespace names are limited to 15 characters
+    KeyTooLong(String),
+    /// Strings (Including the NUL terminator) must fit in a single page
+    StringTooLong { key: String, len: usize },
+    /// Blob is too large to be split into chunks
+    BlobTooLarge { key: String, len: usize },
+    TooManyNamespaces,
+    /// Not enough free pages for the item, even after compacting the partition
+    NoSpace,
+}
+
+impl Display for NvsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NvsError::InvalidLength(len) => {
+                write!(f, "NVS partition length {len} is not a multiple of the page size ({NVS_PAGE_SIZE})")
+            }
+            NvsError::KeyTooLong(key) => write!(f, "Key '{key}' is longer than {NVS_KEY_MAX_LEN} characters"),
+            NvsError::StringTooLong { key, len } => {
+                write!(f, "String '{key}' is {len} bytes long, the limit is {NVS_STR_MAX_LEN} bytes")
+            }
+            NvsError::BlobTooLarge { key, len } => write!(f, "Blob '{key}' is too large ({len} bytes)"),
+            NvsError::TooManyNamespaces => write!(f, "No free namespace indexes left"),
+            NvsError::NoSpace => write!(f, "Not enough free space in the NVS partition"),
+        }
+    }
+}
+
+/// Item read from a page, before blobs are reassembled
+struct ParsedEntry {
+    ns: u8,
+    key: String,
+    ty: NvsItemType,
+    chunk_index: u8,
+    data: [u8; 8],
+    /// Data of variable length items
+    var_data: Vec<u8>,
+    location: NvsLocation,
+    /// Number of entries used by the item
+    span: usize,
+    header_crc_ok: bool,
+    data_crc_ok: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct NvsPartition {
     pub pages: Vec<NvsPage>,
+    /// Namespace index to name
+    pub namespaces: BTreeMap<u8, String>,
+    pub items: Vec<NvsItem>,
+    pub issues: Vec<NvsIssue>,
 }
 
 impl NvsPartition {
-    pub fn new(data: Vec<u8>) -> Self {
-        let mut f = File::create("EEPROM.bin").unwrap();
-        f.write_all(&data).unwrap();
-        let mut offset = 0;
-        let mut page_n = 0;
-        println!("{:02X?}", &data[0..20]);
+    pub fn parse(data: &[u8]) -> Result<Self, NvsError> {
+        if data.is_empty() || !data.len().is_multiple_of(NVS_PAGE_SIZE) {
+            return Err(NvsError::InvalidLength(data.len()));
+        }
         let mut pages = Vec::new();
-        while offset < data.len() {
-            println!("Reading page {}", page_n);
-            let blk: [u8; size_of::<NvsPage>()] = data[offset..offset + size_of::<NvsPage>()]
-                .try_into()
-                .unwrap();
-            let page: NvsPage = unsafe { std::mem::transmute(blk) };
-            println!("page state {:02X}", unsafe { page.state });
-            pages.push(page);
-            offset += size_of::<NvsPage>();
-            let mut i = 0;
-            while i < 126 {
-                let bm = (page.bitmap[i / 4] >> ((i % 4) * 2)) & 0x03;
-                if bm == 2 {
-                    println!(
-                        "Key {} in page {}. Ty {:02X}, Span {} entries. ChkIdx: {}",
-                        page.entries[i].get_key(),
-                        page_n,
-                        page.entries[i].ty,
-                        page.entries[i].span,
-                        page.entries[i].chunk_index
-                    );
-                    let ty = DataType::from_entry(page.entries[i].ty, page.entries[i].data);
-
-                    if let DataType::VariableData { size, rsv, crc32 } = ty {
-                        let mut blob: Vec<u8> = vec![];
-                        for child in 1..=page.entries[i].span as usize {
-                            let re_interp: [u8; size_of::<NvsEntry>()] =
-                                unsafe { std::mem::transmute(page.entries[i + child]) };
-                            blob.extend_from_slice(&re_interp);
-                        }
-                        blob.resize(size as usize, 0);
-                        println!("Blob data: {:02X?}", blob);
-                    }
-                    i += page.entries[i].span as usize;
-                } else {
-                    println!("BM {:02X?}", page.entries[i]);
-                    i += 1;
+        let mut issues = Vec::new();
+        let mut entries = Vec::new();
+        for (page_idx, page) in data.chunks_exact(NVS_PAGE_SIZE).enumerate() {
+            let header = Self::parse_page(page);
+            if let NvsPageState::Invalid(state) = header.state {
+                issues.push(NvsIssue::InvalidPageState { page: page_idx, state });
+            } else if header.state.is_readable() && !header.crc_ok {
+                issues.push(NvsIssue::PageHeaderCrc { page: page_idx });
+            }
+            if header.state.is_readable() {
+                entries.push((header.seqno, Self::parse_page_entries(page, page_idx, &mut issues)));
+            }
+            pages.push(header);
+        }
+        // Newer pages hold newer values
+        entries.sort_by_key(|(seqno, _)| *seqno);
+        let entries: Vec<ParsedEntry> = entries.into_iter().flat_map(|(_, e)| e).collect();
+
+        let mut namespaces = BTreeMap::new();
+        for e in entries.iter().filter(|e| e.ns == NS_INDEX_NAMESPACES && e.ty == NvsItemType::U8) {
+            namespaces.insert(e.data[0], e.key.clone());
+        }
+
+        let mut items: Vec<NvsItem> = Vec::new();
+        for e in entries.iter().filter(|e| e.ns != NS_INDEX_NAMESPACES) {
+            let Some(namespace) = namespaces.get(&e.ns).cloned() else {
+                issues.push(NvsIssue::UnknownNamespace { location: e.location, ns: e.ns });
+                continue;
+            };
+            let (value, header_crc_ok, data_crc_ok) = match e.ty {
+                NvsItemType::BlobData => continue, // Read through the blob index
+                NvsItemType::BlobIndex => match Self::assemble_blob(e, &namespace, &entries, &mut issues) {
+                    Some(x) => x,
+                    None => continue,
+                },
+                NvsItemType::Str => {
+                    let len = e.var_data.iter().position(|x| *x == 0).unwrap_or(e.var_data.len());
+                    let s = String::from_utf8_lossy(&e.var_data[..len]).to_string();
+                    (NvsValue::Str(s), e.header_crc_ok, e.data_crc_ok)
+                }
+                NvsItemType::BlobV1 => (NvsValue::Blob(e.var_data.clone()), e.header_crc_ok, e.data_crc_ok),
+                ty => (Self::fixed_value(ty, e.data), e.header_crc_ok, true),
+            };
+            let item = NvsItem { namespace, key: e.key.clone(), value, location: e.location, header_crc_ok, data_crc_ok };
+            // A newer write of the same key replaces the older one
+            match items.iter_mut().find(|i| i.namespace == item.namespace && i.key == item.key) {
+                Some(existing) => *existing = item,
+                None => items.push(item),
+            }
+        }
+        Ok(Self { pages, namespaces, items, issues })
+    }
+
+    fn parse_page(page: &[u8]) -> NvsPage {
+        let state = u32::from_le_bytes(page[0..4].try_into().unwrap());
+        let crc = u32::from_le_bytes(page[28..32].try_into().unwrap());
+        let count = |s: NvsEntryState| (0..NVS_ENTRIES_PER_PAGE).filter(|i| Self::entry_state(page, *i) == s).count();
+        NvsPage {
+            state: NvsPageState::from(state),
+            seqno: u32::from_le_bytes(page[4..8].try_into().unwrap()),
+            version: page[8],
+            crc_ok: nvs_crc32(&page[4..28]) == crc,
+            written: count(NvsEntryState::Written),
+            erased: count(NvsEntryState::Erased),
+            empty: count(NvsEntryState::Empty),
+        }
+    }
+
+    fn entry_state(page: &[u8], idx: usize) -> NvsEntryState {
+        NvsEntryState::from(page[32 + idx / 4] >> ((idx % 4) * 2))
+    }
+
+    fn raw_entry(page: &[u8], idx: usize) -> NvsRawEntry {
+        let start = ENTRIES_OFFSET + idx * NVS_ENTRY_SIZE;
+        NvsRawEntry::from_bytes(&page[start..start + NVS_ENTRY_SIZE])
+    }
+
+    fn parse_page_entries(page: &[u8], page_idx: usize, issues: &mut Vec<NvsIssue>) -> Vec<ParsedEntry> {
+        let mut ret = Vec::new();
+        let mut idx = 0;
+        while idx < NVS_ENTRIES_PER_PAGE {
+            let location = NvsLocation { page: page_idx, entry: idx };
+            match Self::entry_state(page, idx) {
+                NvsEntryState::Written => {}
+                NvsEntryState::Illegal => {
+                    issues.push(NvsIssue::IllegalEntryState(location));
+                    idx += 1;
+                    continue;
+                }
+                _ => {
+                    idx += 1;
+                    continue;
+                }
+            }
+            let raw = Self::raw_entry(page, idx);
+            let header_crc_ok = raw.calc_crc() == raw.crc;
+            let ty = match NvsItemType::try_from(raw.ty) {
+                Ok(ty) => ty,
+                Err(ty) => {
+                    issues.push(NvsIssue::UnknownItemType { location, ty });
+                    idx += 1;
+                    continue;
                 }
+            };
+            let span = raw.span as usize;
+            let mut var_data = Vec::new();
+            let mut data_crc_ok = true;
+            let span_ok = if ty.is_variable_length() {
+                let (size, crc) = raw.var_data();
+                let ok = span >= 1 && span == 1 + size.div_ceil(NVS_ENTRY_SIZE) && idx + span <= NVS_ENTRIES_PER_PAGE;
+                if ok {
+                    let start = ENTRIES_OFFSET + (idx + 1) * NVS_ENTRY_SIZE;
+                    var_data = page[start..start + size].to_vec();
+                    data_crc_ok = nvs_crc32(&var_data) == crc;
+                }
+                ok
+            } else {
+                span == 1
+            };
+            if !span_ok {
+                issues.push(NvsIssue::InvalidSpan { location, span: raw.span });
+                // Header cannot be trusted, so the span cannot either
+                idx += 1;
+                continue;
             }
-            offset += size_of::<NvsPage>();
-            page_n += 1;
+            ret.push(ParsedEntry {
+                ns: raw.ns,
+                key: raw.key(),
+                ty,
+                chunk_index: raw.chunk_index,
+                data: raw.data,
+                var_data,
+                location,
+                span,
+                header_crc_ok,
+                data_crc_ok,
+            });
+            idx += span;
+        }
+        ret
+    }
+
+    fn fixed_value(ty: NvsItemType, d: [u8; 8]) -> NvsValue {
+        match ty {
+            NvsItemType::U8 => NvsValue::U8(d[0]),
+            NvsItemType::I8 => NvsValue::I8(d[0] as i8),
+            NvsItemType::U16 => NvsValue::U16(u16::from_le_bytes([d[0], d[1]])),
+            NvsItemType::I16 => NvsValue::I16(i16::from_le_bytes([d[0], d[1]])),
+            NvsItemType::U32 => NvsValue::U32(u32::from_le_bytes(d[..4].try_into().unwrap())),
+            NvsItemType::I32 => NvsValue::I32(i32::from_le_bytes(d[..4].try_into().unwrap())),
+            NvsItemType::U64 => NvsValue::U64(u64::from_le_bytes(d)),
+            _ => NvsValue::I64(i64::from_le_bytes(d)),
+        }
+    }
+
+    /// Reassembles a multi page blob from its index. Returns the value, and the CRC states
+    fn assemble_blob(
+        index: &ParsedEntry,
+        namespace: &str,
+        entries: &[ParsedEntry],
+        issues: &mut Vec<NvsIssue>,
+    ) -> Option<(NvsValue, bool, bool)> {
+        let size = u32::from_le_bytes(index.data[..4].try_into().unwrap()) as usize;
+        let chunk_count = index.data[4];
+        let chunk_start = index.data[5];
+        let mut blob = Vec::with_capacity(size);
+        let mut header_crc_ok = index.header_crc_ok;
+        let mut data_crc_ok = true;
+        for chunk in chunk_start..chunk_start.wrapping_add(chunk_count) {
+            // Last written chunk wins
+            let Some(c) = entries.iter().rev().find(|e| {
+                e.ty == NvsItemType::BlobData && e.ns == index.ns && e.key == index.key && e.chunk_index == chunk
+            }) else {
+                issues.push(NvsIssue::MissingBlobChunk {
+                    namespace: namespace.to_string(),
+                    key: index.key.clone(),
+                    chunk,
+                });
+                return None;
+            };
+            header_crc_ok &= c.header_crc_ok;
+            data_crc_ok &= c.data_crc_ok;
+            blob.extend_from_slice(&c.var_data);
+        }
+        if blob.len() != size {
+            issues.push(NvsIssue::BlobSizeMismatch {
+                namespace: namespace.to_string(),
+                key: index.key.clone(),
+                expected: size,
+                actual: blob.len(),
+            });
+        }
+        Some((NvsValue::Blob(blob), header_crc_ok, data_crc_ok))
+    }
+
+    pub fn get(&self, namespace: &str, key: &str) -> Option<&NvsItem> {
+        self.items.iter().find(|i| i.namespace == namespace && i.key == key)
+    }
+
+    /// Items within a namespace
+    pub fn namespace_items<'a>(&'a self, namespace: &'a str) -> impl Iterator<Item = &'a NvsItem> + 'a {
+        self.items.iter().filter(move |i| i.namespace == namespace)
+    }
+}
+
+const ENTRY_STATE_WRITTEN: u8 = 0b10;
+const ENTRY_STATE_ERASED: u8 = 0b00;
+const PAGE_STATE_ACTIVE: u32 = 0xFFFFFFFE;
+const PAGE_STATE_FULL: u32 = 0xFFFFFFFC;
+
+/// Writable NVS partition image, for building EEPROM images for new boards and patching existing ones.
+///
+/// Items are written the same way as the ESP-IDF NVS library does. A changed item is appended to the
+/// active page and its old entries are erased, so the rest of the image is left untouched.
+/// Like the NVS library, one page is always left uninitialized for the TCU's garbage collector.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NvsImage {
+    data: Vec<u8>,
+}
+
+impl NvsImage {
+    /// Erased partition of `size` bytes
+    pub fn new(size: usize) -> Result<Self, NvsError> {
+        Self::from_bytes(vec![0xFF; size])
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, NvsError> {
+        if data.is_empty() || !data.len().is_multiple_of(NVS_PAGE_SIZE) {
+            return Err(NvsError::InvalidLength(data.len()));
+        }
+        Ok(Self { data })
+    }
+
+    /// Builds a partition of `size` bytes containing `items` (Namespace, key, value)
+    pub fn build<'a>(
+        size: usize,
+        items: impl IntoIterator<Item = (&'a str, &'a str, &'a NvsValue)>,
+    ) -> Result<Self, NvsError> {
+        let mut ret = Self::new(size)?;
+        for (namespace, key, value) in items {
+            ret.write(namespace, key, value)?;
+        }
+        Ok(ret)
+    }
+
+    /// Builds a new partition with all the items of a parsed partition (EG: Restoring a backup)
+    pub fn from_partition(nvs: &NvsPartition, size: usize) -> Result<Self, NvsError> {
+        Self::build(size, nvs.items.iter().map(|i| (i.namespace.as_str(), i.key.as_str(), &i.value)))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn parse(&self) -> NvsPartition {
+        NvsPartition::parse(&self.data).expect("NvsImage length is validated")
+    }
+
+    /// Sets `key` in `namespace`, creating the namespace if it does not exist.
+    /// If the partition runs out of space, it is compacted, which drops erased entries
+    /// and any items which could not be read. The image is unchanged if an error is returned
+    pub fn set(&mut self, namespace: &str, key: &str, value: &NvsValue) -> Result<(), NvsError> {
+        let backup = self.data.clone();
+        let mut res = self.write(namespace, key, value);
+        if res == Err(NvsError::NoSpace) {
+            self.data = backup.clone();
+            res = self.compact().and_then(|_| self.write(namespace, key, value));
+        }
+        if res.is_err() {
+            self.data = backup;
+        }
+        res
+    }
+
+    /// Erases `key` from `namespace`. Returns false if the key does not exist
+    pub fn erase(&mut self, namespace: &str, key: &str) -> bool {
+        let Some(ns) = self.namespace_index(namespace) else {
+            return false;
+        };
+        let old = self.item_entries(ns, key);
+        self.erase_entries(&old);
+        !old.is_empty()
+    }
+
+    /// Rewrites the partition with only its readable items, freeing the space used by erased entries
+    pub fn compact(&mut self) -> Result<(), NvsError> {
+        *self = Self::from_partition(&self.parse(), self.data.len())?;
+        Ok(())
+    }
+
+    fn write(&mut self, namespace: &str, key: &str, value: &NvsValue) -> Result<(), NvsError> {
+        for k in [namespace, key] {
+            if k.len() > NVS_KEY_MAX_LEN {
+                return Err(NvsError::KeyTooLong(k.to_string()));
+            }
+        }
+        match value {
+            NvsValue::Str(s) if s.len() + 1 > NVS_STR_MAX_LEN => {
+                return Err(NvsError::StringTooLong { key: key.to_string(), len: s.len() + 1 })
+            }
+            NvsValue::Blob(b) if b.len() > BLOB_MAX_CHUNKS * NVS_STR_MAX_LEN => {
+                return Err(NvsError::BlobTooLarge { key: key.to_string(), len: b.len() })
+            }
+            _ => {}
+        }
+        // Like the NVS library, identical values are not rewritten
+        if self.parse().get(namespace, key).is_some_and(|i| i.crc_ok() && &i.value == value) {
+            return Ok(());
+        }
+        let ns = self.get_or_create_namespace(namespace)?;
+        let old = self.item_entries(ns, key);
+        self.write_value(ns, key, value)?;
+        self.erase_entries(&old);
+        Ok(())
+    }
+
+    fn page_count(&self) -> usize {
+        self.data.len() / NVS_PAGE_SIZE
+    }
+
+    fn page(&self, page: usize) -> &[u8] {
+        &self.data[page * NVS_PAGE_SIZE..(page + 1) * NVS_PAGE_SIZE]
+    }
+
+    /// Written entries of all readable pages
+    fn entries(&self) -> Vec<ParsedEntry> {
+        let mut issues = Vec::new();
+        (0..self.page_count())
+            .filter(|p| NvsPartition::parse_page(self.page(*p)).state.is_readable())
+            .flat_map(|p| NvsPartition::parse_page_entries(self.page(p), p, &mut issues))
+            .collect()
+    }
+
+    fn namespace_index(&self, namespace: &str) -> Option<u8> {
+        self.entries()
+            .iter()
+            .find(|e| e.ns == NS_INDEX_NAMESPACES && e.ty == NvsItemType::U8 && e.key == namespace)
+            .map(|e| e.data[0])
+    }
+
+    fn get_or_create_namespace(&mut self, namespace: &str) -> Result<u8, NvsError> {
+        if let Some(idx) = self.namespace_index(namespace) {
+            return Ok(idx);
+        }
+        let used: Vec<u8> = self
+            .entries()
+            .iter()
+            .filter(|e| e.ns == NS_INDEX_NAMESPACES && e.ty == NvsItemType::U8)
+            .map(|e| e.data[0])
+            .collect();
+        let idx = (1..NS_INDEX_ANY).find(|i| !used.contains(i)).ok_or(NvsError::TooManyNamespaces)?;
+        self.write_value(NS_INDEX_NAMESPACES, namespace, &NvsValue::U8(idx))?;
+        Ok(idx)
+    }
+
+    /// Locations and spans of all entries of an item (Including blob chunks)
+    fn item_entries(&self, ns: u8, key: &str) -> Vec<(NvsLocation, usize)> {
+        self.entries().into_iter().filter(|e| e.ns == ns && e.key == key).map(|e| (e.location, e.span)).collect()
+    }
+
+    fn erase_entries(&mut self, entries: &[(NvsLocation, usize)]) {
+        for (loc, span) in entries {
+            for idx in loc.entry..loc.entry + span {
+                self.set_entry_state(loc.page, idx, ENTRY_STATE_ERASED);
+            }
+        }
+    }
+
+    fn set_entry_state(&mut self, page: usize, idx: usize, state: u8) {
+        let byte = &mut self.data[page * NVS_PAGE_SIZE + 32 + idx / 4];
+        let shift = (idx % 4) * 2;
+        *byte = (*byte & !(0b11 << shift)) | (state << shift);
+    }
+
+    fn set_page_state(&mut self, page: usize, state: u32) {
+        let start = page * NVS_PAGE_SIZE;
+        self.data[start..start + 4].copy_from_slice(&state.to_le_bytes());
+    }
+
+    /// Active page with the highest sequence number, and its first free entry
+    fn active_page(&self) -> Option<(usize, usize)> {
+        let page = (0..self.page_count())
+            .map(|p| (p, NvsPartition::parse_page(self.page(p))))
+            .filter(|(_, h)| h.state == NvsPageState::Active)
+            .max_by_key(|(_, h)| h.seqno)?
+            .0;
+        let next = (0..NVS_ENTRIES_PER_PAGE)
+            .rev()
+            .find(|i| NvsPartition::entry_state(self.page(page), *i) != NvsEntryState::Empty)
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        Some((page, next))
+    }
+
+    /// Initializes a new active page, keeping at least one page uninitialized
+    fn open_page(&mut self) -> Result<usize, NvsError> {
+        let headers: Vec<NvsPage> = (0..self.page_count()).map(|p| NvsPartition::parse_page(self.page(p))).collect();
+        let mut free = headers.iter().enumerate().filter(|(_, h)| h.state == NvsPageState::Uninitialized);
+        let (page, _) = free.next().ok_or(NvsError::NoSpace)?;
+        if free.next().is_none() {
+            return Err(NvsError::NoSpace);
+        }
+        let seqno = headers
+            .iter()
+            .filter(|h| h.state != NvsPageState::Uninitialized)
+            .map(|h| h.seqno + 1)
+            .max()
+            .unwrap_or(0);
+        let start = page * NVS_PAGE_SIZE;
+        let p = &mut self.data[start..start + NVS_PAGE_SIZE];
+        p.fill(0xFF);
+        p[0..4].copy_from_slice(&PAGE_STATE_ACTIVE.to_le_bytes());
+        p[4..8].copy_from_slice(&seqno.to_le_bytes());
+        p[8] = PAGE_VERSION_2;
+        let crc = nvs_crc32(&p[4..28]);
+        p[28..32].copy_from_slice(&crc.to_le_bytes());
+        Ok(page)
+    }
+
+    /// Finds `span` free entries, marking the active page as full and opening a new page if needed
+    fn alloc(&mut self, span: usize) -> Result<(usize, usize), NvsError> {
+        if let Some((page, next)) = self.active_page() {
+            if next + span <= NVS_ENTRIES_PER_PAGE {
+                return Ok((page, next));
+            }
+            self.set_page_state(page, PAGE_STATE_FULL);
+        }
+        Ok((self.open_page()?, 0))
+    }
+
+    /// Data field of a variable length item
+    fn var_header(payload: &[u8]) -> [u8; 8] {
+        let mut data = [0xFF; 8];
+        data[..2].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+        data[4..].copy_from_slice(&nvs_crc32(payload).to_le_bytes());
+        data
+    }
+
+    /// Writes an item header, followed by the payload of variable length items
+    fn write_item(
+        &mut self,
+        ns: u8,
+        ty: NvsItemType,
+        key: &str,
+        chunk_index: u8,
+        data: [u8; 8],
+        payload: &[u8],
+    ) -> Result<(), NvsError> {
+        let span = 1 + payload.len().div_ceil(NVS_ENTRY_SIZE);
+        let (page, idx) = self.alloc(span)?;
+        let mut k = [0; 16];
+        k[..key.len()].copy_from_slice(key.as_bytes());
+        let mut entry = NvsRawEntry { ns, ty: ty as u8, span: span as u8, chunk_index, crc: 0, key: k, data };
+        entry.crc = entry.calc_crc();
+        let start = page * NVS_PAGE_SIZE + ENTRIES_OFFSET + idx * NVS_ENTRY_SIZE;
+        self.data[start..start + NVS_ENTRY_SIZE].copy_from_slice(&entry.to_bytes());
+        let payload_start = start + NVS_ENTRY_SIZE;
+        self.data[payload_start..payload_start + payload.len()].copy_from_slice(payload);
+        self.data[payload_start + payload.len()..start + span * NVS_ENTRY_SIZE].fill(0xFF);
+        for i in idx..idx + span {
+            self.set_entry_state(page, i, ENTRY_STATE_WRITTEN);
+        }
+        Ok(())
+    }
+
+    fn write_value(&mut self, ns: u8, key: &str, value: &NvsValue) -> Result<(), NvsError> {
+        let fixed = match value {
+            NvsValue::U8(x) => x.to_le_bytes().to_vec(),
+            NvsValue::I8(x) => x.to_le_bytes().to_vec(),
+            NvsValue::U16(x) => x.to_le_bytes().to_vec(),
+            NvsValue::I16(x) => x.to_le_bytes().to_vec(),
+            NvsValue::U32(x) => x.to_le_bytes().to_vec(),
+            NvsValue::I32(x) => x.to_le_bytes().to_vec(),
+            NvsValue::U64(x) => x.to_le_bytes().to_vec(),
+            NvsValue::I64(x) => x.to_le_bytes().to_vec(),
+            NvsValue::Str(s) => {
+                let mut payload = s.as_bytes().to_vec();
+                payload.push(0);
+                return self.write_item(ns, NvsItemType::Str, key, 0xFF, Self::var_header(&payload), &payload);
+            }
+            NvsValue::Blob(b) => return self.write_blob(ns, key, b),
+        };
+        let mut data = [0xFF; 8];
+        data[..fixed.len()].copy_from_slice(&fixed);
+        self.write_item(ns, value.item_type(), key, 0xFF, data, &[])
+    }
+
+    /// Writes a blob as chunks filling the free space of each page, followed by its index
+    fn write_blob(&mut self, ns: u8, key: &str, blob: &[u8]) -> Result<(), NvsError> {
+        let mut chunks = 0;
+        let mut written = 0;
+        loop {
+            // Room for at least the chunk header and one data entry
+            let (_, next) = self.alloc(2)?;
+            let len = (blob.len() - written).min((NVS_ENTRIES_PER_PAGE - next - 1) * NVS_ENTRY_SIZE);
+            let chunk = &blob[written..written + len];
+            self.write_item(ns, NvsItemType::BlobData, key, chunks, Self::var_header(chunk), chunk)?;
+            written += len;
+            chunks += 1;
+            if written == blob.len() {
+                break;
+            }
+            if chunks as usize == BLOB_MAX_CHUNKS {
+                return Err(NvsError::BlobTooLarge { key: key.to_string(), len: blob.len() });
+            }
+        }
+        let mut data = [0xFF; 8];
+        data[..4].copy_from_slice(&(blob.len() as u32).to_le_bytes());
+        data[4] = chunks;
+        data[5] = 0; // Chunk start
+        self.write_item(ns, NvsItemType::BlobIndex, key, 0xFF, data, &[])
+    }
+}
+
+#[cfg(test)]
+pub mod test_nvs {
+    use super::*;
+
+    /// Builds pages the way the ESP-IDF NVS library writes them
+    struct TestPageBuilder {
+        page: Vec<u8>,
+        next: usize,
+    }
+
+    impl TestPageBuilder {
+        fn new(seqno: u32) -> Self {
+            let mut page = vec![0xFF; NVS_PAGE_SIZE];
+            page[0..4].copy_from_slice(&0xFFFFFFFEu32.to_le_bytes());
+            page[4..8].copy_from_slice(&seqno.to_le_bytes());
+            page[8] = 0xFE;
+            let crc = nvs_crc32(&page[4..28]);
+            page[28..32].copy_from_slice(&crc.to_le_bytes());
+            Self { page, next: 0 }
+        }
+
+        fn set_state(&mut self, idx: usize, state: u8) {
+            let byte = &mut self.page[32 + idx / 4];
+            let shift = (idx % 4) * 2;
+            *byte = (*byte & !(0b11 << shift)) | (state << shift);
+        }
+
+        fn write_entry(&mut self, e: &NvsRawEntry) -> usize {
+            let idx = self.next;
+            let start = ENTRIES_OFFSET + idx * NVS_ENTRY_SIZE;
+            self.page[start..start + NVS_ENTRY_SIZE].copy_from_slice(&e.to_bytes());
+            self.set_state(idx, 0b10);
+            self.next += 1;
+            idx
+        }
+
+        fn entry(ns: u8, ty: NvsItemType, key: &str, chunk_index: u8, data: [u8; 8], span: u8) -> NvsRawEntry {
+            let mut k = [0; 16];
+            k[..key.len()].copy_from_slice(key.as_bytes());
+            let mut e = NvsRawEntry { ns, ty: ty as u8, span, chunk_index, crc: 0, key: k, data };
+            e.crc = e.calc_crc();
+            e
+        }
+
+        fn fixed(&mut self, ns: u8, ty: NvsItemType, key: &str, data: [u8; 8]) -> usize {
+            self.write_entry(&Self::entry(ns, ty, key, 0xFF, data, 1))
+        }
+
+        fn var(&mut self, ns: u8, ty: NvsItemType, key: &str, chunk_index: u8, value: &[u8]) -> usize {
+            let mut data = [0xFF; 8];
+            data[..2].copy_from_slice(&(value.len() as u16).to_le_bytes());
+            data[4..].copy_from_slice(&nvs_crc32(value).to_le_bytes());
+            let span = 1 + value.len().div_ceil(NVS_ENTRY_SIZE);
+            let idx = self.write_entry(&Self::entry(ns, ty, key, chunk_index, data, span as u8));
+            let mut padded = value.to_vec();
+            padded.resize((span - 1) * NVS_ENTRY_SIZE, 0xFF);
+            for chunk in padded.chunks(NVS_ENTRY_SIZE) {
+                let start = ENTRIES_OFFSET + self.next * NVS_ENTRY_SIZE;
+                self.page[start..start + NVS_ENTRY_SIZE].copy_from_slice(chunk);
+                self.set_state(self.next, 0b10);
+                self.next += 1;
+            }
+            idx
+        }
+
+        fn blob_index(&mut self, ns: u8, key: &str, size: u32, chunk_count: u8) -> usize {
+            let mut data = [0xFF; 8];
+            data[..4].copy_from_slice(&size.to_le_bytes());
+            data[4] = chunk_count;
+            data[5] = 0;
+            self.fixed(ns, NvsItemType::BlobIndex, key, data)
+        }
+    }
+
+    fn u64_data(x: u64) -> [u8; 8] {
+        x.to_le_bytes()
+    }
+
+    fn test_partition() -> Vec<u8> {
+        let blob: Vec<u8> = (0..200u32).map(|x| x as u8).collect();
+        let mut p0 = TestPageBuilder::new(0);
+        p0.fixed(0, NvsItemType::U8, "tcm", u64_data(1));
+        p0.fixed(0, NvsItemType::U8, "misc", u64_data(2));
+        p0.fixed(1, NvsItemType::U8, "u8", u64_data(0xAB));
+        p0.fixed(1, NvsItemType::I16, "i16", u64_data((-1234i16) as u16 as u64));
+        p0.fixed(1, NvsItemType::U32, "u32", u64_data(0xDEADBEEF));
+        p0.fixed(1, NvsItemType::I64, "i64", u64_data((-5i64) as u64));
+        p0.var(2, NvsItemType::Str, "name", 0xFF, b"Ultimate-NAG52\0");
+        p0.var(1, NvsItemType::BlobData, "adapt", 0, &blob[..120]);
+        // Value written again on the next page, replacing this one
+        p0.fixed(1, NvsItemType::U8, "counter", u64_data(1));
+        let mut p1 = TestPageBuilder::new(1);
+        p1.var(1, NvsItemType::BlobData, "adapt", 1, &blob[120..]);
+        p1.blob_index(1, "adapt", 200, 2);
+        p1.fixed(1, NvsItemType::U8, "counter", u64_data(2));
+        let mut ret = p0.page;
+        ret.extend_from_slice(&p1.page);
+        // Unused page
+        ret.extend_from_slice(&[0xFF; NVS_PAGE_SIZE]);
+        ret
+    }
+
+    #[test]
+    pub fn test_crc() {
+        // Same as zlib.crc32(b"hello", 0xFFFFFFFF) used by nvs_partition_gen.py
+        assert_eq!(nvs_crc32(b"hello"), 0x0FCDAE64);
+    }
+
+    #[test]
+    pub fn test_parse() {
+        let nvs = NvsPartition::parse(&test_partition()).unwrap();
+        assert_eq!(nvs.issues, vec![]);
+        assert_eq!(nvs.pages.len(), 3);
+        assert_eq!(nvs.pages[2].state, NvsPageState::Uninitialized);
+        assert_eq!(nvs.namespaces.get(&1).map(|s| s.as_str()), Some("tcm"));
+        assert_eq!(nvs.get("tcm", "u8").unwrap().value, NvsValue::U8(0xAB));
+        assert_eq!(nvs.get("tcm", "i16").unwrap().value, NvsValue::I16(-1234));
+        assert_eq!(nvs.get("tcm", "u32").unwrap().value, NvsValue::U32(0xDEADBEEF));
+        assert_eq!(nvs.get("tcm", "i64").unwrap().value, NvsValue::I64(-5));
+        assert_eq!(nvs.get("misc", "name").unwrap().value, NvsValue::Str("Ultimate-NAG52".into()));
+        let blob = nvs.get("tcm", "adapt").unwrap();
+        assert_eq!(blob.value, NvsValue::Blob((0..200u32).map(|x| x as u8).collect()));
+        assert_eq!(blob.location, NvsLocation { page: 1, entry: 4 });
+        assert_eq!(nvs.get("tcm", "counter").unwrap().value, NvsValue::U8(2));
+        assert_eq!(nvs.namespace_items("tcm").count(), 6);
+        assert!(nvs.items.iter().all(|i| i.crc_ok()));
+    }
+
+    #[test]
+    pub fn test_crc_errors() {
+        let mut data = test_partition();
+        // Corrupt the value of "u32" (page 0, entry 4)
+        data[ENTRIES_OFFSET + 4 * NVS_ENTRY_SIZE + 24] ^= 0x01;
+        // Corrupt the string data (page 0, entry 7 is the first data entry)
+        data[ENTRIES_OFFSET + 7 * NVS_ENTRY_SIZE] ^= 0x01;
+        // Corrupt the second blob chunk data (page 1, entry 1)
+        data[NVS_PAGE_SIZE + ENTRIES_OFFSET + NVS_ENTRY_SIZE] ^= 0x01;
+        let nvs = NvsPartition::parse(&data).unwrap();
+        let u32_item = nvs.get("tcm", "u32").unwrap();
+        assert!(!u32_item.header_crc_ok && u32_item.data_crc_ok);
+        let name = nvs.get("misc", "name").unwrap();
+        assert!(name.header_crc_ok && !name.data_crc_ok);
+        let blob = nvs.get("tcm", "adapt").unwrap();
+        assert!(blob.header_crc_ok && !blob.data_crc_ok);
+        assert!(nvs.get("tcm", "u8").unwrap().crc_ok());
+
+        // Page header CRC
+        let mut data = test_partition();
+        data[4] ^= 0x01;
+        let nvs = NvsPartition::parse(&data).unwrap();
+        assert!(nvs.issues.contains(&NvsIssue::PageHeaderCrc { page: 0 }));
+    }
+
+    #[test]
+    pub fn test_structure_errors() {
+        assert_eq!(NvsPartition::parse(&[0xFF; 100]), Err(NvsError::InvalidLength(100)));
+        assert_eq!(NvsPartition::parse(&[]), Err(NvsError::InvalidLength(0)));
+
+        // Missing second blob chunk. Erase page 1 entry 0
+        let mut data = test_partition();
+        data[NVS_PAGE_SIZE + 32] &= !0b11;
+        let nvs = NvsPartition::parse(&data).unwrap();
+        assert!(nvs.get("tcm", "adapt").is_none());
+        assert!(nvs.issues.contains(&NvsIssue::MissingBlobChunk {
+            namespace: "tcm".into(),
+            key: "adapt".into(),
+            chunk: 1
+        }));
+
+        // Span running past the end of the page, or garbage, must not panic
+        let mut data = test_partition();
+        data[ENTRIES_OFFSET + 6 * NVS_ENTRY_SIZE + 2] = 200;
+        let nvs = NvsPartition::parse(&data).unwrap();
+        assert!(nvs.issues.contains(&NvsIssue::InvalidSpan { location: NvsLocation { page: 0, entry: 6 }, span: 200 }));
+        let mut x: u32 = 1;
+        let garbage: Vec<u8> = (0..NVS_PAGE_SIZE * 4)
+            .map(|i| {
+                x = x.wrapping_mul(1103515245).wrapping_add(12345);
+                // Keep page states valid, so the entries are parsed
+                if i % NVS_PAGE_SIZE < 4 { 0xFE } else { (x >> 16) as u8 }
+            })
+            .collect();
+        let _ = NvsPartition::parse(&garbage).unwrap();
+    }
+
+    fn test_items() -> Vec<(&'static str, &'static str, NvsValue)> {
+        vec![
+            ("tcm", "u8", NvsValue::U8(0xAB)),
+            ("tcm", "i8", NvsValue::I8(-2)),
+            ("tcm", "u16", NvsValue::U16(0x1234)),
+            ("tcm", "i32", NvsValue::I32(-123456)),
+            ("tcm", "u64", NvsValue::U64(0x0102030405060708)),
+            ("misc", "name", NvsValue::Str("Ultimate-NAG52".into())),
+            ("misc", "empty", NvsValue::Blob(vec![])),
+            // Split across 3 pages
+            ("tcm", "adapt", NvsValue::Blob((0..9000u32).map(|x| (x * 13) as u8).collect())),
+        ]
+    }
+
+    fn build_test_image(pages: usize) -> NvsImage {
+        let items = test_items();
+        NvsImage::build(pages * NVS_PAGE_SIZE, items.iter().map(|(ns, k, v)| (*ns, *k, v))).unwrap()
+    }
+
+    #[test]
+    pub fn test_build() {
+        let image = build_test_image(5);
+        let nvs = image.parse();
+        assert_eq!(nvs.issues, vec![]);
+        for (ns, key, value) in test_items() {
+            let item = nvs.get(ns, key).unwrap();
+            assert_eq!(item.value, value, "{ns}::{key}");
+            assert!(item.crc_ok());
+        }
+        assert_eq!(nvs.items.len(), test_items().len());
+        let states: Vec<NvsPageState> = nvs.pages.iter().map(|p| p.state).collect();
+        assert_eq!(
+            states,
+            vec![NvsPageState::Full, NvsPageState::Full, NvsPageState::Active, NvsPageState::Uninitialized, NvsPageState::Uninitialized]
+        );
+        assert!(nvs.pages.iter().filter(|p| p.state.is_readable()).all(|p| p.crc_ok));
+        assert_eq!(nvs.pages.iter().map(|p| p.seqno).take(3).collect::<Vec<_>>(), vec![0, 1, 2]);
+        // Same layout as the page builder, which follows nvs_partition_gen.py
+        let mut page = TestPageBuilder::new(0);
+        page.fixed(0, NvsItemType::U8, "tcm", [1, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        page.fixed(1, NvsItemType::U8, "u8", [0xAB, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        let entries = ENTRIES_OFFSET..ENTRIES_OFFSET + 2 * NVS_ENTRY_SIZE;
+        assert_eq!(image.as_bytes()[entries.clone()], page.page[entries]);
+
+        // One page is always kept free
+        assert_eq!(
+            NvsImage::build(3 * NVS_PAGE_SIZE, test_items().iter().map(|(ns, k, v)| (*ns, *k, v))),
+            Err(NvsError::NoSpace)
+        );
+        assert_eq!(NvsImage::new(100), Err(NvsError::InvalidLength(100)));
+    }
+
+    #[test]
+    pub fn test_patch() {
+        let mut image = build_test_image(5);
+        let original = image.clone();
+        let old_u8 = image.parse().get("tcm", "u8").unwrap().location;
+        // Unchanged values are not rewritten
+        image.set("tcm", "u8", &NvsValue::U8(0xAB)).unwrap();
+        assert_eq!(image, original);
+
+        image.set("tcm", "u8", &NvsValue::U8(0x55)).unwrap();
+        image.set("tcm", "adapt", &NvsValue::Blob(vec![1, 2, 3])).unwrap();
+        image.set("new_ns", "str", &NvsValue::Str("Hello".into())).unwrap();
+        assert!(image.erase("tcm", "i8"));
+        assert!(!image.erase("tcm", "i8"));
+        assert!(!image.erase("nothing", "i8"));
+
+        let nvs = image.parse();
+        assert_eq!(nvs.issues, vec![]);
+        assert_eq!(nvs.get("tcm", "u8").unwrap().value, NvsValue::U8(0x55));
+        assert_eq!(nvs.get("tcm", "adapt").unwrap().value, NvsValue::Blob(vec![1, 2, 3]));
+        assert_eq!(nvs.get("new_ns", "str").unwrap().value, NvsValue::Str("Hello".into()));
+        assert_eq!(nvs.namespaces.get(&3).map(|s| s.as_str()), Some("new_ns"));
+        assert!(nvs.get("tcm", "i8").is_none());
+        assert_eq!(nvs.get("tcm", "u16").unwrap().value, NvsValue::U16(0x1234));
+        assert_eq!(nvs.items.len(), test_items().len());
+        // Old entry is erased in place, not moved
+        assert_eq!(NvsPartition::entry_state(image.page(old_u8.page), old_u8.entry), NvsEntryState::Erased);
+        let page_start = old_u8.page * NVS_PAGE_SIZE + ENTRIES_OFFSET;
+        assert_eq!(image.as_bytes()[page_start..page_start + 2 * NVS_ENTRY_SIZE], original.as_bytes()[page_start..page_start + 2 * NVS_ENTRY_SIZE]);
+
+        assert_eq!(image.set("tcm", "a_very_long_key_name", &NvsValue::U8(0)), Err(NvsError::KeyTooLong("a_very_long_key_name".into())));
+        let long_str = NvsValue::Str("x".repeat(NVS_STR_MAX_LEN));
+        assert!(matches!(image.set("tcm", "s", &long_str), Err(NvsError::StringTooLong { .. })));
+    }
+
+    #[test]
+    pub fn test_compact() {
+        let mut image = NvsImage::new(3 * NVS_PAGE_SIZE).unwrap();
+        image.set("tcm", "keep", &NvsValue::Str("Kept".into())).unwrap();
+        // Far more writes than fit in the 2 usable pages, so the partition has to be compacted
+        for i in 0..1000u32 {
+            image.set("tcm", "counter", &NvsValue::U32(i)).unwrap();
+        }
+        let nvs = image.parse();
+        assert_eq!(nvs.issues, vec![]);
+        assert_eq!(nvs.get("tcm", "counter").unwrap().value, NvsValue::U32(999));
+        assert_eq!(nvs.get("tcm", "keep").unwrap().value, NvsValue::Str("Kept".into()));
+        assert_eq!(nvs.pages[2].state, NvsPageState::Uninitialized);
+
+        // Does not fit at all, and the image is left unchanged
+        let before = image.clone();
+        let big = NvsValue::Blob(vec![0; 3 * NVS_PAGE_SIZE]);
+        assert_eq!(image.set("tcm", "big", &big), Err(NvsError::NoSpace));
+        assert_eq!(image, before);
+    }
+
+    #[test]
+    pub fn test_restore_backup() {
+        let nvs = NvsPartition::parse(&test_partition()).unwrap();
+        let restored = NvsImage::from_partition(&nvs, 4 * NVS_PAGE_SIZE).unwrap().parse();
+        assert_eq!(restored.issues, vec![]);
+        for item in &nvs.items {
+            assert_eq!(restored.get(&item.namespace, &item.key).unwrap().value, item.value);
         }
-        Self { pages }
+        assert_eq!(restored.items.len(), nvs.items.len());
     }
 }
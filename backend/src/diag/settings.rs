@@ -1,16 +1,26 @@
 use std::collections::HashMap;
 
+use ecu_diagnostics::{kwp2000::KwpCommand, DiagError, DiagServerResult};
 use serde::Deserialize;
 
+use super::Nag52Diag;
+
 #[derive(Debug, Clone)] 
 pub enum SettingsType {
     Bool(bool),
     F32(f32),
+    U32(u32),
+    I32(i32),
     U16(u16),
     I16(i16),
     U8(u8),
+    I8(i8),
     Enum { value: u8, mapping: EnumMap },
-    Struct { raw: Vec<u8>, s: SettingsData } 
+    Struct { raw: Vec<u8>, s: SettingsData },
+    /// Fixed size array (`DataType: type[N]`)
+    Array(Vec<SettingsType>),
+    /// Bytes of a setting that could not be decoded (E.g. a data type from a newer firmware)
+    Raw(Vec<u8>)
 }
 
 impl Into<Vec<u8>> for SettingsType {
@@ -18,13 +28,59 @@ impl Into<Vec<u8>> for SettingsType {
         match self {
             SettingsType::Bool(b) => vec![b as u8],
             SettingsType::F32(v) => v.to_le_bytes().to_vec(),
+            SettingsType::U32(v) => v.to_le_bytes().to_vec(),
+            SettingsType::I32(v) => v.to_le_bytes().to_vec(),
             SettingsType::U16(v) => v.to_le_bytes().to_vec(),
             SettingsType::I16(v) => v.to_le_bytes().to_vec(),
             SettingsType::U8(v) => vec![v],
+            SettingsType::I8(v) => v.to_le_bytes().to_vec(),
             SettingsType::Enum { value, mapping: _ } => vec![value],
             SettingsType::Struct { raw, s: _ } => {
                 raw.clone()
             },
+            SettingsType::Array(values) => values.into_iter().flat_map(Into::<Vec<u8>>::into).collect(),
+            SettingsType::Raw(raw) => raw,
+        }
+    }
+}
+
+impl SettingsType {
+    /// Numeric value of a scalar setting (Bools are 0 or 1, enums their raw value)
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            SettingsType::Bool(b) => Some(*b as u8 as f64),
+            SettingsType::F32(v) => Some(*v as f64),
+            SettingsType::U32(v) => Some(*v as f64),
+            SettingsType::I32(v) => Some(*v as f64),
+            SettingsType::U16(v) => Some(*v as f64),
+            SettingsType::I16(v) => Some(*v as f64),
+            SettingsType::U8(v) => Some(*v as f64),
+            SettingsType::I8(v) => Some(*v as f64),
+            SettingsType::Enum { value, .. } => Some(*value as f64),
+            SettingsType::Struct { .. } | SettingsType::Array(_) | SettingsType::Raw(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingsDecodeError {
+    /// Data type is not a primitive, and not defined in the enums or internal structures
+    UnknownType(String),
+    /// Setting lies (partly) outside of the coding string
+    OutOfRange { offset: usize, len: usize, available: usize },
+    /// Length of the setting does not match its data type
+    InvalidLength { data_type: String, expected: usize, actual: usize },
+    /// Internal structure contains itself (Directly or through other structures), so it would never stop nesting
+    RecursiveStruct(String),
+}
+
+impl std::fmt::Display for SettingsDecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownType(t) => write!(f, "Unknown data type '{t}'"),
+            Self::OutOfRange { offset, len, available } => write!(f, "Bytes {offset}..{} are outside of the coding string ({available} bytes)", offset + len),
+            Self::InvalidLength { data_type, expected, actual } => write!(f, "{data_type} should be {expected} bytes long, but is {actual} bytes"),
+            Self::RecursiveStruct(name) => write!(f, "Structure '{name}' contains itself"),
         }
     }
 }
@@ -58,43 +114,259 @@ pub struct SettingsVariable {
     #[serde(rename="OffsetBytes")]
     pub offset_bytes: usize,
     #[serde(rename="LengthBytes")]
-    pub size_bytes: usize
+    pub size_bytes: usize,
+    #[serde(rename="Min")]
+    pub min: Option<f64>,
+    #[serde(rename="Max")]
+    pub max: Option<f64>,
+    /// Valid values are multiples of this, counted from `min` (Or 0)
+    #[serde(rename="Step")]
+    pub step: Option<f64>,
+    #[serde(rename="Constraints", default)]
+    pub constraints: Vec<SettingsConstraint>
 }
 
-impl SettingsVariable {
-    pub fn to_settings_type(&self, raw: &[u8], enums: &[EnumMap], structs: &[SettingsData]) -> SettingsType {
-        let bytes = &raw[self.offset_bytes..self.offset_bytes + self.size_bytes];
-        match self.data_type.as_str() {
-            "bool" => SettingsType::Bool(bytes[0] != 0),
-            "float" => SettingsType::F32(f32::from_le_bytes(bytes.try_into().unwrap())),
-            "uint16_t" => SettingsType::U16(u16::from_le_bytes(bytes.try_into().unwrap())),
-            "int16_t" => SettingsType::I16(i16::from_le_bytes(bytes.try_into().unwrap())),
-            "uint8_t" => SettingsType::U8(bytes[0]),
-            name => {
-                for e in enums {
-                    if e.name == name {
-                        return SettingsType::Enum { value: bytes[0], mapping: e.clone() }
-                    }
-                }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum ConstraintOp {
+    #[serde(rename="<")]
+    Lt,
+    #[serde(rename="<=")]
+    Le,
+    #[serde(rename=">")]
+    Gt,
+    #[serde(rename=">=")]
+    Ge,
+    #[serde(rename="==")]
+    Eq,
+    #[serde(rename="!=")]
+    Ne,
+}
 
-                for s in structs {
-                    if s.name == name {
-                        return SettingsType::Struct { raw: bytes.to_vec(), s: s.clone() }
-                    }
-                }
+impl ConstraintOp {
+    pub fn check(&self, a: f64, b: f64) -> bool {
+        match self {
+            ConstraintOp::Lt => a < b,
+            ConstraintOp::Le => a <= b,
+            ConstraintOp::Gt => a > b,
+            ConstraintOp::Ge => a >= b,
+            ConstraintOp::Eq => a == b,
+            ConstraintOp::Ne => a != b,
+        }
+    }
+}
+
+impl std::fmt::Display for ConstraintOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ConstraintOp::Lt => "<",
+            ConstraintOp::Le => "<=",
+            ConstraintOp::Gt => ">",
+            ConstraintOp::Ge => ">=",
+            ConstraintOp::Eq => "==",
+            ConstraintOp::Ne => "!=",
+        })
+    }
+}
+
+/// Constraint between a setting and another setting of the same group (Or structure).
+///
+/// Without `Value`, the setting is compared with `Param` (`this <Op> Param`).
+/// With `Value`, this is a dependency: whilst the setting is non-zero, `Param <Op> Value` must hold
+#[derive(Debug, Clone, Deserialize)]
+pub struct SettingsConstraint {
+    #[serde(rename="Param")]
+    pub param: String,
+    #[serde(rename="Op")]
+    pub op: ConstraintOp,
+    #[serde(rename="Value")]
+    pub value: Option<f64>,
+}
 
-                // Check structs and enums
-                panic!("No settings variable data type found for '{}'", self.data_type);
+#[derive(Debug, Clone, PartialEq)]
+pub enum SettingsViolationKind {
+    BelowMin { value: f64, min: f64 },
+    AboveMax { value: f64, max: f64 },
+    NotOnStep { value: f64, step: f64 },
+    /// `value <op> other_value` does not hold
+    Constraint { value: f64, op: ConstraintOp, other: String, other_value: f64 },
+    /// Setting is in use, but `param <op> expected` does not hold
+    Dependency { param: String, op: ConstraintOp, expected: f64, actual: f64 },
+    Decode(SettingsDecodeError),
+}
+
+/// A setting of a coding string which violates its constraints from MODULE_SETTINGS
+#[derive(Debug, Clone, PartialEq)]
+pub struct SettingsViolation {
+    /// Setting name. Settings within structures are `struct.setting`, array elements `setting[idx]`
+    pub path: String,
+    pub kind: SettingsViolationKind,
+}
+
+impl std::fmt::Display for SettingsViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: ", self.path)?;
+        match &self.kind {
+            SettingsViolationKind::BelowMin { value, min } => write!(f, "{value} is below the minimum of {min}"),
+            SettingsViolationKind::AboveMax { value, max } => write!(f, "{value} is above the maximum of {max}"),
+            SettingsViolationKind::NotOnStep { value, step } => write!(f, "{value} is not a multiple of {step}"),
+            SettingsViolationKind::Constraint { value, op, other, other_value } => {
+                write!(f, "{value} must be {op} {other} ({other_value})")
+            }
+            SettingsViolationKind::Dependency { param, op, expected, actual } => {
+                write!(f, "Requires {param} {op} {expected}, but {param} is {actual}")
             }
+            SettingsViolationKind::Decode(e) => write!(f, "{e}"),
         }
     }
+}
 
-    pub fn insert_back_into_coding_string(&self, setting_ty: SettingsType, raw_coding_string: &mut [u8]) {
+impl SettingsVariable {
+    /// Clamps a value to `min`/`max`, and rounds it to the nearest `step`
+    pub fn snap_value(&self, v: f64) -> f64 {
+        let clamp = |v: f64| v.max(self.min.unwrap_or(f64::MIN)).min(self.max.unwrap_or(f64::MAX));
+        match self.step {
+            Some(step) if step > 0.0 => {
+                let base = self.min.unwrap_or_default();
+                clamp(base + ((clamp(v) - base) / step).round() * step)
+            }
+            _ => clamp(v),
+        }
+    }
+
+    fn check_value(&self, path: &str, value: &SettingsType, out: &mut Vec<SettingsViolation>) {
+        if let SettingsType::Array(values) = value {
+            for (idx, v) in values.iter().enumerate() {
+                self.check_value(&format!("{path}[{idx}]"), v, out);
+            }
+            return;
+        }
+        let Some(v) = value.as_f64() else {
+            return;
+        };
+        let mut violation = |kind| out.push(SettingsViolation { path: path.to_string(), kind });
+        if let Some(min) = self.min.filter(|min| v < *min) {
+            violation(SettingsViolationKind::BelowMin { value: v, min });
+        }
+        if let Some(max) = self.max.filter(|max| v > *max) {
+            violation(SettingsViolationKind::AboveMax { value: v, max });
+        }
+        if let Some(step) = self.step.filter(|s| *s > 0.0) {
+            let steps = (v - self.min.unwrap_or_default()) / step;
+            // Floats in the coding are only f32 accurate
+            if (steps - steps.round()).abs() > 1e-4 {
+                violation(SettingsViolationKind::NotOnStep { value: v, step });
+            }
+        }
+    }
+
+    /// Bytes of this setting within a coding string
+    pub fn raw_bytes<'a>(&self, raw: &'a [u8]) -> Result<&'a [u8], SettingsDecodeError> {
+        raw.get(self.offset_bytes..self.offset_bytes + self.size_bytes).ok_or(SettingsDecodeError::OutOfRange {
+            offset: self.offset_bytes,
+            len: self.size_bytes,
+            available: raw.len(),
+        })
+    }
+
+    pub fn to_settings_type(&self, raw: &[u8], enums: &[EnumMap], structs: &[SettingsData]) -> Result<SettingsType, SettingsDecodeError> {
+        decode_type(&self.data_type, self.raw_bytes(raw)?, enums, structs)
+    }
+
+    /// Like [SettingsVariable::to_settings_type], but settings which cannot be decoded are returned
+    /// as [SettingsType::Raw], along with the reason. Only fails if the setting is outside of the coding string
+    pub fn to_settings_type_or_raw(&self, raw: &[u8], enums: &[EnumMap], structs: &[SettingsData]) -> Result<(SettingsType, Option<SettingsDecodeError>), SettingsDecodeError> {
+        let bytes = self.raw_bytes(raw)?;
+        Ok(match decode_type(&self.data_type, bytes, enums, structs) {
+            Ok(v) => (v, None),
+            Err(e) => (SettingsType::Raw(bytes.to_vec()), Some(e)),
+        })
+    }
+
+    pub fn insert_back_into_coding_string(&self, setting_ty: SettingsType, raw_coding_string: &mut [u8]) -> Result<(), SettingsDecodeError> {
+        let available = raw_coding_string.len();
+        let dest = raw_coding_string.get_mut(self.offset_bytes..self.offset_bytes + self.size_bytes).ok_or(SettingsDecodeError::OutOfRange {
+            offset: self.offset_bytes,
+            len: self.size_bytes,
+            available,
+        })?;
         let raw: Vec<u8> = setting_ty.into();
-        raw_coding_string[self.offset_bytes..self.offset_bytes+self.size_bytes].copy_from_slice(&raw)
+        if raw.len() != dest.len() {
+            return Err(SettingsDecodeError::InvalidLength { data_type: self.data_type.clone(), expected: dest.len(), actual: raw.len() });
+        }
+        dest.copy_from_slice(&raw);
+        Ok(())
     }
 }
 
+/// Splits an array data type (`uint8_t[4]`) into its element type and length
+fn parse_array_type(data_type: &str) -> Option<(&str, usize)> {
+    let (ty, len) = data_type.trim().strip_suffix(']')?.split_once('[')?;
+    Some((ty.trim(), len.trim().parse().ok()?))
+}
+
+/// Returns the name of a structure which contains itself, when following the structures
+/// used by `s`. `parents` are the structures `s` is nested in
+fn find_recursive_struct<'a>(s: &'a SettingsData, structs: &'a [SettingsData], parents: &mut Vec<&'a str>) -> Option<&'a str> {
+    if parents.contains(&s.name.as_str()) {
+        return Some(&s.name);
+    }
+    parents.push(&s.name);
+    for p in &s.params {
+        let ty = parse_array_type(&p.data_type).map_or(p.data_type.as_str(), |(ty, _)| ty);
+        if let Some(name) = structs.iter().find(|c| c.name == ty).and_then(|c| find_recursive_struct(c, structs, parents)) {
+            return Some(name);
+        }
+    }
+    parents.pop();
+    None
+}
+
+fn decode_type(data_type: &str, bytes: &[u8], enums: &[EnumMap], structs: &[SettingsData]) -> Result<SettingsType, SettingsDecodeError> {
+    fn fixed<const N: usize>(data_type: &str, bytes: &[u8]) -> Result<[u8; N], SettingsDecodeError> {
+        bytes.try_into().map_err(|_| SettingsDecodeError::InvalidLength { data_type: data_type.to_string(), expected: N, actual: bytes.len() })
+    }
+    Ok(match data_type {
+        "bool" => SettingsType::Bool(fixed::<1>(data_type, bytes)?[0] != 0),
+        "float" => SettingsType::F32(f32::from_le_bytes(fixed(data_type, bytes)?)),
+        "uint32_t" => SettingsType::U32(u32::from_le_bytes(fixed(data_type, bytes)?)),
+        "int32_t" => SettingsType::I32(i32::from_le_bytes(fixed(data_type, bytes)?)),
+        "uint16_t" => SettingsType::U16(u16::from_le_bytes(fixed(data_type, bytes)?)),
+        "int16_t" => SettingsType::I16(i16::from_le_bytes(fixed(data_type, bytes)?)),
+        "uint8_t" => SettingsType::U8(fixed::<1>(data_type, bytes)?[0]),
+        "int8_t" => SettingsType::I8(i8::from_le_bytes(fixed(data_type, bytes)?)),
+        name => {
+            if let Some((elem_ty, len)) = parse_array_type(name) {
+                let elem_size = bytes.len().checked_div(len).unwrap_or_default();
+                if elem_size == 0 || elem_size * len != bytes.len() {
+                    return Err(SettingsDecodeError::InvalidLength { data_type: name.to_string(), expected: len * elem_size.max(1), actual: bytes.len() });
+                }
+                return bytes
+                    .chunks(elem_size)
+                    .map(|b| decode_type(elem_ty, b, enums, structs))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map(SettingsType::Array);
+            }
+
+            if let Some(e) = enums.iter().find(|e| e.name == name) {
+                return Ok(SettingsType::Enum { value: fixed::<1>(name, bytes)?[0], mapping: e.clone() })
+            }
+
+            if let Some(s) = structs.iter().find(|s| s.name == name) {
+                if let Some(recursive) = find_recursive_struct(s, structs, &mut Vec::new()) {
+                    return Err(SettingsDecodeError::RecursiveStruct(recursive.to_string()))
+                }
+                let len = s.params.iter().map(|p| p.offset_bytes + p.size_bytes).max().unwrap_or_default();
+                if len > bytes.len() {
+                    return Err(SettingsDecodeError::InvalidLength { data_type: name.to_string(), expected: len, actual: bytes.len() })
+                }
+                return Ok(SettingsType::Struct { raw: bytes.to_vec(), s: s.clone() })
+            }
+
+            return Err(SettingsDecodeError::UnknownType(name.to_string()))
+        }
+    })
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct SettingsData {
     #[serde(rename="Name")]
@@ -117,4 +389,399 @@ pub struct ModuleSettingsData {
     pub internal_structures: Vec<SettingsData>,
     #[serde(rename="Settings")]
     pub settings: Vec<SettingsData>
-}
\ No newline at end of file
+}
+
+impl ModuleSettingsData {
+    /// Hash of all EEPROM keys, which the TCU uses to check that MODULE_SETTINGS matches its firmware
+    pub fn key_magic(&self) -> u32 {
+        let mut cs: u32 = 0;
+        for k in self.settings.iter().filter_map(|x| x.eeprom_key.as_ref()) {
+            for (idx, byte) in k.as_bytes().iter().enumerate() {
+                cs = cs.wrapping_add(idx as u32);
+                cs = cs.wrapping_add(*byte as u32);
+            }
+        }
+        cs
+    }
+
+    /// Checks the coding string of `setting` against the range, step and dependency
+    /// constraints of its settings, returning every violation.
+    /// Settings which can only be shown as raw bytes are not checked, but structures
+    /// which contain themselves are reported, as MODULE_SETTINGS is broken
+    pub fn validate_coding(&self, setting: &SettingsData, coding: &[u8]) -> Vec<SettingsViolation> {
+        let mut out = Vec::new();
+        self.validate_params(&setting.params, coding, "", &mut out);
+        out
+    }
+
+    fn validate_params(&self, params: &[SettingsVariable], raw: &[u8], prefix: &str, out: &mut Vec<SettingsViolation>) {
+        let mut values = HashMap::new();
+        for var in params {
+            let path = format!("{prefix}{}", var.name);
+            match var.to_settings_type_or_raw(raw, &self.enums, &self.internal_structures) {
+                Ok((SettingsType::Struct { raw, s }, _)) => self.validate_params(&s.params, &raw, &format!("{path}."), out),
+                Ok((_, Some(e @ SettingsDecodeError::RecursiveStruct(_)))) => {
+                    out.push(SettingsViolation { path, kind: SettingsViolationKind::Decode(e) })
+                }
+                Ok((SettingsType::Array(elements), _)) => {
+                    for (idx, e) in elements.iter().enumerate() {
+                        match e {
+                            SettingsType::Struct { raw, s } => self.validate_params(&s.params, raw, &format!("{path}[{idx}]."), out),
+                            e => var.check_value(&format!("{path}[{idx}]"), e, out),
+                        }
+                    }
+                }
+                Ok((v, _)) => {
+                    var.check_value(&path, &v, out);
+                    if let Some(v) = v.as_f64() {
+                        values.insert(var.name.as_str(), v);
+                    }
+                }
+                Err(e) => out.push(SettingsViolation { path, kind: SettingsViolationKind::Decode(e) }),
+            }
+        }
+        for var in params {
+            let Some(value) = values.get(var.name.as_str()).copied() else {
+                continue;
+            };
+            for c in &var.constraints {
+                // Constraints on missing or non-scalar settings cannot be checked
+                let Some(other_value) = values.get(c.param.as_str()).copied() else {
+                    continue;
+                };
+                let kind = match c.value {
+                    None if !c.op.check(value, other_value) => {
+                        SettingsViolationKind::Constraint { value, op: c.op, other: format!("{prefix}{}", c.param), other_value }
+                    }
+                    Some(expected) if value != 0.0 && !c.op.check(other_value, expected) => {
+                        SettingsViolationKind::Dependency { param: format!("{prefix}{}", c.param), op: c.op, expected, actual: other_value }
+                    }
+                    _ => continue,
+                };
+                out.push(SettingsViolation { path: format!("{prefix}{}", var.name), kind });
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SettingsWriteError {
+    NoScnId(String),
+    /// Invalid settings. Paths are prefixed with the group name (`TCC.min_locking_rpm`)
+    Invalid(Vec<SettingsViolation>),
+    Diag(DiagError),
+    /// Writing a group failed after the groups in `written` were written
+    Partial { written: Vec<String>, failed: String, error: DiagError },
+}
+
+impl std::fmt::Display for SettingsWriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoScnId(name) => write!(f, "{name} has no SCN ID"),
+            Self::Invalid(v) => {
+                write!(f, "{} settings are invalid", v.len())?;
+                for v in v {
+                    write!(f, "\n{v}")?;
+                }
+                Ok(())
+            }
+            Self::Diag(e) => write!(f, "{e}"),
+            Self::Partial { written, failed, error } => {
+                write!(f, "Writing {failed} failed ({error}). Already written: {}", written.join(", "))
+            }
+        }
+    }
+}
+
+impl Nag52Diag {
+    /// Reads the current (Or default) coding string of a settings group
+    pub fn read_settings_coding(&self, scn_id: u8, default: bool) -> DiagServerResult<Vec<u8>> {
+        let id = if default { scn_id | 0b10000000 } else { scn_id };
+        self.with_kwp(|k| k.send_byte_array_with_response(&[KwpCommand::ReadDataByLocalIdentifier.into(), 0xFC, id]))
+            .and_then(|x| x.get(3..).map(|x| x.to_vec()).ok_or(DiagError::InvalidResponseLength))
+    }
+
+    /// Reads the current coding string of every settings group with an SCN ID, keyed by SCN ID
+    pub fn read_settings_codings(&self, yml: &ModuleSettingsData) -> DiagServerResult<HashMap<u8, Vec<u8>>> {
+        yml.settings
+            .iter()
+            .filter_map(|s| s.scn_id)
+            .map(|id| self.read_settings_coding(id, false).map(|c| (id, c)))
+            .collect()
+    }
+
+    /// Validates the coding string of `setting` (See [ModuleSettingsData::validate_coding]), then writes it to the TCU.
+    /// Nothing is written if any setting is invalid
+    pub fn write_settings_coding(&self, yml: &ModuleSettingsData, setting: &SettingsData, coding: &[u8]) -> Result<(), SettingsWriteError> {
+        self.write_settings_codings(yml, &[(setting, coding)]).map_err(|e| match e {
+            SettingsWriteError::Partial { error, .. } => SettingsWriteError::Diag(error),
+            e => e,
+        })
+    }
+
+    /// Writes the coding strings of several groups. Every group is validated before anything is written
+    pub fn write_settings_codings(&self, yml: &ModuleSettingsData, groups: &[(&SettingsData, &[u8])]) -> Result<(), SettingsWriteError> {
+        let mut violations = Vec::new();
+        for (setting, coding) in groups {
+            setting.scn_id.ok_or_else(|| SettingsWriteError::NoScnId(setting.name.clone()))?;
+            violations.extend(yml.validate_coding(setting, coding).into_iter().map(|mut v| {
+                v.path = format!("{}.{}", setting.name, v.path);
+                v
+            }));
+        }
+        if !violations.is_empty() {
+            return Err(SettingsWriteError::Invalid(violations));
+        }
+        let mut written = Vec::new();
+        for (setting, coding) in groups {
+            let res = self.with_kwp(|kwp| {
+                let mut tx = vec![KwpCommand::WriteDataByLocalIdentifier.into(), 0xFC, setting.scn_id.unwrap()];
+                tx.extend_from_slice(coding);
+                kwp.send_byte_array_with_response(&tx)
+            });
+            if let Err(error) = res {
+                return Err(SettingsWriteError::Partial { written, failed: setting.name.clone(), error });
+            }
+            written.push(setting.name.clone());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test_settings {
+    use super::*;
+    use crate::hw::{sim::open_test_sim, sim_ecu::SIM_MODULE_SETTINGS_YML};
+
+    const YML: &str = r#"
+Enums:
+  - Name: Mode
+    Mappings:
+      0:
+        Name: Off
+        Desc: Disabled
+      1:
+        Name: On
+        Desc: Enabled
+IStructs:
+  - Name: Point
+    Params:
+      - Name: x
+        DataType: int8_t
+        OffsetBytes: 0
+        LengthBytes: 1
+      - Name: y
+        DataType: uint8_t
+        OffsetBytes: 1
+        LengthBytes: 1
+  - Name: Line
+    Params:
+      - Name: start
+        DataType: Point
+        OffsetBytes: 0
+        LengthBytes: 2
+      - Name: end
+        DataType: Point
+        OffsetBytes: 2
+        LengthBytes: 2
+Settings:
+  - Name: TEST
+    SCN_ID: 1
+    Params:
+      - Name: counter
+        DataType: uint32_t
+        OffsetBytes: 0
+        LengthBytes: 4
+      - Name: offset
+        DataType: int32_t
+        OffsetBytes: 4
+        LengthBytes: 4
+      - Name: modes
+        DataType: Mode[2]
+        OffsetBytes: 8
+        LengthBytes: 2
+      - Name: line
+        DataType: Line
+        OffsetBytes: 10
+        LengthBytes: 4
+      - Name: future
+        DataType: uint64_t
+        OffsetBytes: 14
+        LengthBytes: 8
+"#;
+
+    fn coding() -> Vec<u8> {
+        let mut c = vec![0x78, 0x56, 0x34, 0x12, 0xFF, 0xFF, 0xFF, 0xFF, 0x01, 0x00, 0xFE, 0x02, 0x03, 0x04];
+        c.extend_from_slice(&[0xAA; 8]);
+        c
+    }
+
+    #[test]
+    pub fn test_decode() {
+        let yml: ModuleSettingsData = serde_yaml::from_str(YML).unwrap();
+        let params = &yml.settings[0].params;
+        let raw = coding();
+        let decode = |idx: usize| params[idx].to_settings_type(&raw, &yml.enums, &yml.internal_structures);
+
+        assert!(matches!(decode(0), Ok(SettingsType::U32(0x12345678))));
+        assert!(matches!(decode(1), Ok(SettingsType::I32(-1))));
+        match decode(2) {
+            Ok(SettingsType::Array(v)) => {
+                assert_eq!(v.len(), 2);
+                assert!(matches!(v[0], SettingsType::Enum { value: 1, .. }));
+                assert!(matches!(v[1], SettingsType::Enum { value: 0, .. }));
+            }
+            v => panic!("Expected array, got {v:?}"),
+        }
+        // Nested structure
+        let Ok(SettingsType::Struct { raw: line, s }) = decode(3) else { panic!("Expected struct") };
+        let Ok(SettingsType::Struct { raw: start, s: point }) = s.params[0].to_settings_type(&line, &yml.enums, &yml.internal_structures) else {
+            panic!("Expected struct")
+        };
+        assert!(matches!(point.params[0].to_settings_type(&start, &yml.enums, &yml.internal_structures), Ok(SettingsType::I8(-2))));
+        assert!(matches!(point.params[1].to_settings_type(&start, &yml.enums, &yml.internal_structures), Ok(SettingsType::U8(2))));
+
+        // Unknown type degrades to raw bytes
+        assert_eq!(decode(4).unwrap_err(), SettingsDecodeError::UnknownType("uint64_t".into()));
+        let (v, err) = params[4].to_settings_type_or_raw(&raw, &yml.enums, &yml.internal_structures).unwrap();
+        assert!(matches!(v, SettingsType::Raw(ref b) if b == &[0xAA; 8]));
+        assert!(err.is_some());
+
+        // Coding string from an older firmware is too short
+        assert!(matches!(
+            params[4].to_settings_type_or_raw(&raw[..16], &yml.enums, &yml.internal_structures),
+            Err(SettingsDecodeError::OutOfRange { offset: 14, len: 8, available: 16 })
+        ));
+    }
+
+    #[test]
+    pub fn test_invalid_length() {
+        let yml: ModuleSettingsData = serde_yaml::from_str(YML).unwrap();
+        let mut var = yml.settings[0].params[0].clone();
+        var.size_bytes = 2;
+        assert_eq!(
+            var.to_settings_type(&coding(), &yml.enums, &yml.internal_structures).unwrap_err(),
+            SettingsDecodeError::InvalidLength { data_type: "uint32_t".into(), expected: 4, actual: 2 }
+        );
+        var.data_type = "uint8_t[3]".into();
+        assert!(var.to_settings_type(&coding(), &yml.enums, &yml.internal_structures).is_err());
+        var.data_type = "uint8_t[0]".into();
+        assert!(var.to_settings_type(&coding(), &yml.enums, &yml.internal_structures).is_err());
+    }
+
+    #[test]
+    pub fn test_recursive_struct() {
+        let yml: ModuleSettingsData = serde_yaml::from_str(
+            r#"
+Enums: []
+IStructs:
+  - Name: Node
+    Params:
+      - Name: value
+        DataType: uint8_t
+        OffsetBytes: 0
+        LengthBytes: 1
+      - Name: children
+        DataType: Leaf[1]
+        OffsetBytes: 1
+        LengthBytes: 2
+  - Name: Leaf
+    Params:
+      - Name: next
+        DataType: Node
+        OffsetBytes: 0
+        LengthBytes: 2
+Settings:
+  - Name: TEST
+    Params:
+      - Name: root
+        DataType: Node
+        OffsetBytes: 0
+        LengthBytes: 3
+"#,
+        )
+        .unwrap();
+        let setting = &yml.settings[0];
+        let coding = [1, 2, 3];
+        assert_eq!(
+            setting.params[0].to_settings_type(&coding, &yml.enums, &yml.internal_structures).unwrap_err(),
+            SettingsDecodeError::RecursiveStruct("Node".into())
+        );
+        // Nothing recurses into the structure, and the broken layout is reported
+        let violations = yml.validate_coding(setting, &coding);
+        assert_eq!(violations.len(), 1);
+        assert!(matches!(violations[0].kind, SettingsViolationKind::Decode(SettingsDecodeError::RecursiveStruct(_))));
+        let values = yml.read_values(setting, &coding);
+        assert_eq!(values.keys().collect::<Vec<_>>(), ["root"]);
+        let mut written = coding;
+        yml.apply_values(setting, &mut written, &values);
+        assert_eq!(written, coding);
+    }
+
+    #[test]
+    pub fn test_insert_back() {
+        let yml: ModuleSettingsData = serde_yaml::from_str(YML).unwrap();
+        let params = &yml.settings[0].params;
+        let mut raw = coding();
+        params[0].insert_back_into_coding_string(SettingsType::U32(1), &mut raw).unwrap();
+        params[2].insert_back_into_coding_string(SettingsType::Array(vec![SettingsType::U8(0), SettingsType::U8(1)]), &mut raw).unwrap();
+        assert_eq!(&raw[..4], &[1, 0, 0, 0]);
+        assert_eq!(&raw[8..10], &[0, 1]);
+        assert!(params[0].insert_back_into_coding_string(SettingsType::U8(1), &mut raw).is_err());
+        assert!(params[4].insert_back_into_coding_string(SettingsType::Raw(vec![0; 8]), &mut raw[..10]).is_err());
+    }
+
+    #[test]
+    pub fn test_validate() {
+        let yml: ModuleSettingsData = serde_yaml::from_str(SIM_MODULE_SETTINGS_YML).unwrap();
+        let tcc = &yml.settings[0];
+        let mut coding = vec![0; 22];
+        assert!(yml.validate_coding(tcc, &coding).is_empty());
+
+        coding[1] = 2; // Closed, whilst the converter is disabled
+        coding[2..4].copy_from_slice(&5025u16.to_le_bytes());
+        coding[6..10].copy_from_slice(&10f32.to_le_bytes()); // new_min > new_max
+        let violations = yml.validate_coding(tcc, &coding);
+        let paths: Vec<&str> = violations.iter().map(|v| v.path.as_str()).collect();
+        assert_eq!(paths, ["min_locking_rpm", "min_locking_rpm", "slip_pedal_interp.new_max", "max_lock_mode"]);
+        assert!(matches!(violations[0].kind, SettingsViolationKind::AboveMax { max: 5000.0, .. }));
+        assert!(matches!(violations[1].kind, SettingsViolationKind::NotOnStep { step: 50.0, .. }));
+        assert!(matches!(violations[3].kind, SettingsViolationKind::Dependency { actual: 0.0, .. }));
+
+        coding[0] = 1;
+        coding[2..4].copy_from_slice(&1500u16.to_le_bytes());
+        coding[10..14].copy_from_slice(&10f32.to_le_bytes());
+        assert!(yml.validate_coding(tcc, &coding).is_empty());
+
+        // Coding string too short for the layout
+        assert!(matches!(yml.validate_coding(tcc, &coding[..4])[0].kind, SettingsViolationKind::Decode(_)));
+    }
+
+    #[test]
+    pub fn test_snap_value() {
+        let yml: ModuleSettingsData = serde_yaml::from_str(SIM_MODULE_SETTINGS_YML).unwrap();
+        let rpm = &yml.settings[0].params[2];
+        assert_eq!(rpm.snap_value(1520.0), 1500.0);
+        assert_eq!(rpm.snap_value(1530.0), 1550.0);
+        assert_eq!(rpm.snap_value(6000.0), 5000.0);
+    }
+
+    #[test]
+    pub fn test_write_settings_coding() {
+        let (sim, nag) = open_test_sim("test_write_settings_coding");
+        let yml: ModuleSettingsData = serde_yaml::from_str(SIM_MODULE_SETTINGS_YML).unwrap();
+        let tcc = &yml.settings[0];
+
+        let mut coding = vec![0; 22];
+        coding[1] = 1;
+        match nag.write_settings_coding(&yml, tcc, &coding) {
+            Err(SettingsWriteError::Invalid(v)) => assert_eq!(v.len(), 1),
+            x => panic!("Expected invalid coding, got {x:?}"),
+        }
+        assert_eq!(sim.state().scn_codings[&1].current, vec![0; 22]);
+
+        coding[0] = 1;
+        nag.write_settings_coding(&yml, tcc, &coding).unwrap();
+        assert_eq!(sim.state().scn_codings[&1].current, coding);
+    }
+}
@@ -0,0 +1,420 @@
+//! Settings bundles, which store the settings of every group on a TCU by name
+//! (Rather than by coding string offset), so they can be moved between TCUs
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use super::settings::{ModuleSettingsData, SettingsData, SettingsType, SettingsVariable};
+
+/// Value of a single setting, as stored in a bundle
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SettingsValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    /// Enum value name, or the hex bytes of a setting that could not be decoded
+    Text(String),
+    List(Vec<SettingsValue>),
+}
+
+impl std::fmt::Display for SettingsValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bool(b) => write!(f, "{b}"),
+            Self::Int(i) => write!(f, "{i}"),
+            Self::Float(v) => write!(f, "{v}"),
+            Self::Text(s) => write!(f, "{s}"),
+            Self::List(l) => write!(f, "[{}]", l.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ")),
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02X}")).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim();
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Largest integer an f32 holds exactly
+const F32_MAX_EXACT_INT: i64 = 1 << 24;
+
+impl SettingsValue {
+    pub fn from_settings_type(value: &SettingsType) -> Self {
+        match value {
+            SettingsType::Bool(b) => Self::Bool(*b),
+            // Shortest representation of the f32, rather than its exact f64 value (0.1, not 0.10000000149011612)
+            SettingsType::F32(v) => Self::Float(v.to_string().parse().unwrap_or(*v as f64)),
+            SettingsType::U32(v) => Self::Int(*v as i64),
+            SettingsType::I32(v) => Self::Int(*v as i64),
+            SettingsType::U16(v) => Self::Int(*v as i64),
+            SettingsType::I16(v) => Self::Int(*v as i64),
+            SettingsType::U8(v) => Self::Int(*v as i64),
+            SettingsType::I8(v) => Self::Int(*v as i64),
+            SettingsType::Enum { value, mapping } => match mapping.mappings.get(value) {
+                Some(desc) => Self::Text(desc.name.clone()),
+                None => Self::Int(*value as i64),
+            },
+            SettingsType::Array(values) => Self::List(values.iter().map(Self::from_settings_type).collect()),
+            SettingsType::Struct { raw, .. } | SettingsType::Raw(raw) => Self::Text(to_hex(raw)),
+        }
+    }
+
+    /// Converts the value into the type of `template`. Only conversions which do not lose
+    /// information are done (E.g. 300 cannot become a u8, and 1.5 cannot become an integer)
+    pub fn to_settings_type(&self, template: &SettingsType) -> Result<SettingsType, String> {
+        let int = || -> Result<i64, String> {
+            match self {
+                Self::Int(i) => Ok(*i),
+                Self::Bool(b) => Ok(*b as i64),
+                Self::Float(f) if f.fract() == 0.0 && f.abs() < i64::MAX as f64 => Ok(*f as i64),
+                v => Err(format!("{v} is not an integer")),
+            }
+        };
+        let ranged = |v: i64, min: i64, max: i64| -> Result<i64, String> {
+            if v < min || v > max {
+                Err(format!("{v} is outside of {min}..={max}"))
+            } else {
+                Ok(v)
+            }
+        };
+        Ok(match template {
+            SettingsType::Bool(_) => match self {
+                Self::Bool(b) => SettingsType::Bool(*b),
+                _ => SettingsType::Bool(ranged(int()?, 0, 1)? != 0),
+            },
+            SettingsType::F32(_) => match self {
+                Self::Float(f) if f.is_finite() && f.abs() <= f32::MAX as f64 => SettingsType::F32(*f as f32),
+                Self::Float(f) => return Err(format!("{f} does not fit a float")),
+                _ => SettingsType::F32(ranged(int()?, -F32_MAX_EXACT_INT, F32_MAX_EXACT_INT)? as f32),
+            },
+            SettingsType::U32(_) => SettingsType::U32(ranged(int()?, 0, u32::MAX as i64)? as u32),
+            SettingsType::I32(_) => SettingsType::I32(ranged(int()?, i32::MIN as i64, i32::MAX as i64)? as i32),
+            SettingsType::U16(_) => SettingsType::U16(ranged(int()?, 0, u16::MAX as i64)? as u16),
+            SettingsType::I16(_) => SettingsType::I16(ranged(int()?, i16::MIN as i64, i16::MAX as i64)? as i16),
+            SettingsType::U8(_) => SettingsType::U8(ranged(int()?, 0, u8::MAX as i64)? as u8),
+            SettingsType::I8(_) => SettingsType::I8(ranged(int()?, i8::MIN as i64, i8::MAX as i64)? as i8),
+            SettingsType::Enum { mapping, .. } => {
+                let value = match self {
+                    Self::Text(name) => mapping
+                        .mappings
+                        .iter()
+                        .find(|(_, d)| d.name.eq_ignore_ascii_case(name))
+                        .map(|(v, _)| *v)
+                        .ok_or_else(|| format!("{name} is not a value of {}", mapping.name))?,
+                    _ => ranged(int()?, 0, u8::MAX as i64)? as u8,
+                };
+                SettingsType::Enum { value, mapping: mapping.clone() }
+            }
+            SettingsType::Array(template) => match self {
+                Self::List(values) if values.len() == template.len() => SettingsType::Array(
+                    values.iter().zip(template).map(|(v, t)| v.to_settings_type(t)).collect::<Result<Vec<_>, _>>()?,
+                ),
+                Self::List(values) => return Err(format!("Expected {} values, got {}", template.len(), values.len())),
+                v => return Err(format!("{v} is not a list")),
+            },
+            SettingsType::Struct { raw, s } => match self {
+                Self::Text(t) => match from_hex(t) {
+                    Some(bytes) if bytes.len() == raw.len() => SettingsType::Struct { raw: bytes, s: s.clone() },
+                    _ => return Err(format!("Expected {} hex bytes", raw.len())),
+                },
+                v => return Err(format!("{v} is not hex bytes")),
+            },
+            SettingsType::Raw(raw) => match self {
+                Self::Text(t) => match from_hex(t) {
+                    Some(bytes) if bytes.len() == raw.len() => SettingsType::Raw(bytes),
+                    _ => return Err(format!("Expected {} hex bytes", raw.len())),
+                },
+                v => return Err(format!("{v} is not hex bytes")),
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldChange {
+    pub path: String,
+    pub old: SettingsValue,
+    pub new: SettingsValue,
+}
+
+/// Result of applying settings values to a coding string
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ApplyReport {
+    pub changed: Vec<FieldChange>,
+    /// Settings of the coding string without a value, which were left as they were
+    pub missing: Vec<String>,
+    /// Values which do not match any setting of the coding string
+    pub unused: Vec<String>,
+    /// Values which could not be converted to the type of their setting
+    pub errors: Vec<(String, String)>,
+}
+
+impl ModuleSettingsData {
+    /// Reads every setting of a coding string, keyed by its path. Structures are flattened
+    /// (`struct.setting`, or `array[idx].setting` for arrays of structures)
+    pub fn read_values(&self, setting: &SettingsData, coding: &[u8]) -> BTreeMap<String, SettingsValue> {
+        let mut out = BTreeMap::new();
+        self.read_params(&setting.params, coding, "", &mut out);
+        out
+    }
+
+    fn read_params(&self, params: &[SettingsVariable], raw: &[u8], prefix: &str, out: &mut BTreeMap<String, SettingsValue>) {
+        for var in params {
+            let path = format!("{prefix}{}", var.name);
+            match var.to_settings_type_or_raw(raw, &self.enums, &self.internal_structures) {
+                Ok((SettingsType::Struct { raw, s }, _)) => self.read_params(&s.params, &raw, &format!("{path}."), out),
+                Ok((SettingsType::Array(elements), _)) if elements.iter().any(|e| matches!(e, SettingsType::Struct { .. })) => {
+                    for (idx, e) in elements.iter().enumerate() {
+                        if let SettingsType::Struct { raw, s } = e {
+                            self.read_params(&s.params, raw, &format!("{path}[{idx}]."), out);
+                        }
+                    }
+                }
+                Ok((v, _)) => {
+                    out.insert(path, SettingsValue::from_settings_type(&v));
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Writes values (As returned by [ModuleSettingsData::read_values]) into a coding string by setting path
+    pub fn apply_values(&self, setting: &SettingsData, coding: &mut [u8], values: &BTreeMap<String, SettingsValue>) -> ApplyReport {
+        let mut report = ApplyReport::default();
+        let mut used = HashSet::new();
+        self.apply_params(&setting.params, coding, "", values, &mut report, &mut used);
+        report.unused = values.keys().filter(|k| !used.contains(k.as_str())).cloned().collect();
+        report
+    }
+
+    fn apply_params<'a>(
+        &self,
+        params: &[SettingsVariable],
+        raw: &mut [u8],
+        prefix: &str,
+        values: &'a BTreeMap<String, SettingsValue>,
+        report: &mut ApplyReport,
+        used: &mut HashSet<&'a str>,
+    ) {
+        for var in params {
+            let path = format!("{prefix}{}", var.name);
+            let new = match var.to_settings_type_or_raw(raw, &self.enums, &self.internal_structures) {
+                Ok((SettingsType::Struct { raw: mut s_raw, s }, _)) => {
+                    self.apply_params(&s.params, &mut s_raw, &format!("{path}."), values, report, used);
+                    SettingsType::Struct { raw: s_raw, s }
+                }
+                Ok((SettingsType::Array(elements), _)) if elements.iter().any(|e| matches!(e, SettingsType::Struct { .. })) => {
+                    let mut new = Vec::with_capacity(elements.len());
+                    for (idx, e) in elements.into_iter().enumerate() {
+                        new.push(match e {
+                            SettingsType::Struct { raw: mut s_raw, s } => {
+                                self.apply_params(&s.params, &mut s_raw, &format!("{path}[{idx}]."), values, report, used);
+                                SettingsType::Struct { raw: s_raw, s }
+                            }
+                            e => e,
+                        });
+                    }
+                    SettingsType::Array(new)
+                }
+                Ok((old, _)) => {
+                    let Some((key, value)) = values.get_key_value(&path) else {
+                        report.missing.push(path);
+                        continue;
+                    };
+                    used.insert(key.as_str());
+                    match value.to_settings_type(&old) {
+                        Ok(new) => {
+                            let (old, new_value) = (SettingsValue::from_settings_type(&old), SettingsValue::from_settings_type(&new));
+                            if old != new_value {
+                                report.changed.push(FieldChange { path, old, new: new_value });
+                            }
+                            new
+                        }
+                        Err(e) => {
+                            report.errors.push((path, e));
+                            continue;
+                        }
+                    }
+                }
+                Err(e) => {
+                    report.errors.push((path, e.to_string()));
+                    continue;
+                }
+            };
+            // Values are converted to the type of the setting, so they always fit
+            let _ = var.insert_back_into_coding_string(new, raw);
+        }
+    }
+}
+
+/// Settings of every group of a TCU
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SettingsBundle {
+    pub firmware_version: String,
+    /// Key magic of the MODULE_SETTINGS the bundle was created with (See [ModuleSettingsData::key_magic])
+    pub key_magic: u32,
+    /// Settings values, keyed by group name, then setting path
+    pub groups: BTreeMap<String, BTreeMap<String, SettingsValue>>,
+}
+
+/// Changes to a settings group when importing a bundle
+#[derive(Debug, Clone)]
+pub struct GroupImport {
+    pub name: String,
+    pub scn_id: u8,
+    /// New coding string
+    pub coding: Vec<u8>,
+    pub report: ApplyReport,
+}
+
+impl GroupImport {
+    pub fn is_changed(&self) -> bool {
+        !self.report.changed.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BundleImport {
+    pub groups: Vec<GroupImport>,
+    /// Groups of the bundle which the TCU does not have
+    pub missing_groups: Vec<String>,
+}
+
+impl BundleImport {
+    pub fn changed_groups(&self) -> impl Iterator<Item = &GroupImport> {
+        self.groups.iter().filter(|g| g.is_changed())
+    }
+}
+
+impl SettingsBundle {
+    /// Creates a bundle from the coding strings of a TCU, keyed by SCN ID
+    pub fn new(yml: &ModuleSettingsData, firmware_version: String, codings: &HashMap<u8, Vec<u8>>) -> Self {
+        let groups = yml
+            .settings
+            .iter()
+            .filter_map(|s| Some((s, codings.get(&s.scn_id?)?)))
+            .map(|(s, coding)| (s.name.clone(), yml.read_values(s, coding)))
+            .collect();
+        Self { firmware_version, key_magic: yml.key_magic(), groups }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn from_json(s: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(s)
+    }
+
+    /// Applies the bundle to the coding strings of a TCU (Keyed by SCN ID), returning the new coding
+    /// string and changes of every group. Groups are matched by name, and settings by path, so the bundle can
+    /// be imported into a TCU with a different MODULE_SETTINGS layout
+    pub fn import(&self, yml: &ModuleSettingsData, codings: &HashMap<u8, Vec<u8>>) -> BundleImport {
+        let mut groups = Vec::new();
+        for s in &yml.settings {
+            let (Some(scn_id), Some(values)) = (s.scn_id, self.groups.get(&s.name)) else {
+                continue;
+            };
+            let Some(mut coding) = codings.get(&scn_id).cloned() else {
+                continue;
+            };
+            let report = yml.apply_values(s, &mut coding, values);
+            groups.push(GroupImport { name: s.name.clone(), scn_id, coding, report });
+        }
+        let missing_groups = self
+            .groups
+            .keys()
+            .filter(|name| !groups.iter().any(|g| &g.name == *name))
+            .cloned()
+            .collect();
+        BundleImport { groups, missing_groups }
+    }
+}
+
+#[cfg(test)]
+pub mod test_settings_bundle {
+    use super::*;
+    use crate::hw::{sim::open_test_sim, sim_ecu::SIM_MODULE_SETTINGS_YML};
+
+    #[test]
+    pub fn test_value_conversion() {
+        let yml: ModuleSettingsData = serde_yaml::from_str(SIM_MODULE_SETTINGS_YML).unwrap();
+        let mode = SettingsType::Enum { value: 0, mapping: yml.enums[0].clone() };
+        assert!(matches!(SettingsValue::Text("closed".into()).to_settings_type(&mode), Ok(SettingsType::Enum { value: 2, .. })));
+        assert!(SettingsValue::Text("Locked".into()).to_settings_type(&mode).is_err());
+        assert!(matches!(SettingsValue::Int(200).to_settings_type(&SettingsType::U8(0)), Ok(SettingsType::U8(200))));
+        assert!(SettingsValue::Int(300).to_settings_type(&SettingsType::U8(0)).is_err());
+        assert!(SettingsValue::Float(1.5).to_settings_type(&SettingsType::I16(0)).is_err());
+        assert!(matches!(SettingsValue::Float(-2.0).to_settings_type(&SettingsType::I16(0)), Ok(SettingsType::I16(-2))));
+        assert!(matches!(SettingsValue::Int(3).to_settings_type(&SettingsType::F32(0.0)), Ok(SettingsType::F32(3.0))));
+        assert!(matches!(
+            SettingsValue::Text("00ff".into()).to_settings_type(&SettingsType::Raw(vec![0; 2])),
+            Ok(SettingsType::Raw(b)) if b == [0x00, 0xFF]
+        ));
+        assert_eq!(SettingsValue::from_settings_type(&SettingsType::F32(0.1)), SettingsValue::Float(0.1));
+    }
+
+    #[test]
+    pub fn test_bundle_roundtrip() {
+        let (sim, nag) = open_test_sim("test_bundle_roundtrip");
+        let yml: ModuleSettingsData = serde_yaml::from_str(SIM_MODULE_SETTINGS_YML).unwrap();
+        let mut tcc = vec![0; 22];
+        tcc[0] = 1;
+        tcc[1] = 2;
+        tcc[2..4].copy_from_slice(&1500u16.to_le_bytes());
+        tcc[10..14].copy_from_slice(&0.25f32.to_le_bytes());
+        sim.state().scn_codings.get_mut(&1).unwrap().current = tcc.clone();
+
+        let bundle = SettingsBundle::new(&yml, "1.0".into(), &nag.read_settings_codings(&yml).unwrap());
+        assert_eq!(bundle.key_magic, yml.key_magic());
+        assert_eq!(bundle.groups["TCC"]["max_lock_mode"], SettingsValue::Text("Closed".into()));
+        assert_eq!(bundle.groups["TCC"]["slip_pedal_interp.new_max"], SettingsValue::Float(0.25));
+        let bundle = SettingsBundle::from_json(&bundle.to_json()).unwrap();
+
+        // Import into a TCU with default settings
+        sim.state().scn_codings.get_mut(&1).unwrap().current = vec![0; 22];
+        let import = bundle.import(&yml, &nag.read_settings_codings(&yml).unwrap());
+        assert!(import.missing_groups.is_empty());
+        let changed: Vec<&GroupImport> = import.changed_groups().collect();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].name, "TCC");
+        assert_eq!(changed[0].report.changed.len(), 4);
+        assert_eq!(changed[0].coding, tcc);
+
+        let groups: Vec<(&SettingsData, &[u8])> = changed
+            .iter()
+            .map(|g| (yml.settings.iter().find(|s| s.name == g.name).unwrap(), g.coding.as_slice()))
+            .collect();
+        nag.write_settings_codings(&yml, &groups).unwrap();
+        assert_eq!(sim.state().scn_codings[&1].current, tcc);
+    }
+
+    #[test]
+    pub fn test_import_report() {
+        let yml: ModuleSettingsData = serde_yaml::from_str(SIM_MODULE_SETTINGS_YML).unwrap();
+        let codings = HashMap::from([(1, vec![0; 22]), (2, vec![0; 9])]);
+        let mut bundle = SettingsBundle::new(&yml, "1.0".into(), &codings);
+        let tcc = bundle.groups.get_mut("TCC").unwrap();
+        tcc.remove("enabled");
+        tcc.insert("removed_setting".into(), SettingsValue::Int(1));
+        tcc.insert("min_locking_rpm".into(), SettingsValue::Int(-5));
+        bundle.groups.insert("OLD".into(), BTreeMap::new());
+
+        let import = bundle.import(&yml, &codings);
+        assert_eq!(import.missing_groups, ["OLD"]);
+        let report = &import.groups[0].report;
+        assert_eq!(report.missing, ["enabled"]);
+        assert_eq!(report.unused, ["removed_setting"]);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].0, "min_locking_rpm");
+        assert!(import.changed_groups().next().is_none());
+    }
+}
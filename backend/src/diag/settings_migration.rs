@@ -0,0 +1,207 @@
+//! Migration of settings between MODULE_SETTINGS layouts, so settings survive a firmware
+//! update which adds, moves or resizes settings
+
+use std::collections::{BTreeMap, HashMap};
+
+use super::{
+    nvs::{NvsPartition, NvsValue},
+    settings::{ModuleSettingsData, SettingsData, SettingsType, SettingsVariable},
+    settings_bundle::{BundleImport, SettingsBundle},
+};
+
+/// Setting whose data type differs between the layouts, and whose value was converted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeChange {
+    pub group: String,
+    pub path: String,
+    pub from: String,
+    pub to: String,
+}
+
+impl std::fmt::Display for TypeChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}: {} -> {}", self.group, self.path, self.from, self.to)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SettingsMigration {
+    /// New coding strings. Settings which are new in the new layout keep their current value
+    /// ([ApplyReport::missing](super::settings_bundle::ApplyReport::missing)), settings which were removed
+    /// ([ApplyReport::unused](super::settings_bundle::ApplyReport::unused)) or could not be converted
+    /// ([ApplyReport::errors](super::settings_bundle::ApplyReport::errors)) are dropped
+    pub import: BundleImport,
+    pub converted: Vec<TypeChange>,
+}
+
+impl SettingsMigration {
+    /// Migrates the coding strings of the `old` layout (Keyed by their SCN ID) into the coding strings
+    /// of the `new` layout. Groups are matched by name, and settings by path (See [ModuleSettingsData::read_values])
+    pub fn new(
+        old: &ModuleSettingsData,
+        old_codings: &HashMap<u8, Vec<u8>>,
+        new: &ModuleSettingsData,
+        new_codings: &HashMap<u8, Vec<u8>>,
+    ) -> Self {
+        let bundle = SettingsBundle::new(old, String::new(), old_codings);
+        let import = bundle.import(new, new_codings);
+        let mut converted = Vec::new();
+        for g in &import.groups {
+            let (Some(old_group), Some(new_group)) = (find_group(old, &g.name), find_group(new, &g.name)) else {
+                continue;
+            };
+            let (Some(old_coding), Some(new_coding)) =
+                (old_group.scn_id.and_then(|id| old_codings.get(&id)), new_codings.get(&g.scn_id))
+            else {
+                continue;
+            };
+            let old_types = old.leaf_types(old_group, old_coding);
+            let new_types = new.leaf_types(new_group, new_coding);
+            for (path, to) in new_types {
+                match old_types.get(&path) {
+                    Some(from) if *from != to && !g.report.errors.iter().any(|(p, _)| *p == path) => {
+                        converted.push(TypeChange { group: g.name.clone(), path, from: from.clone(), to })
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Self { import, converted }
+    }
+}
+
+fn find_group<'a>(yml: &'a ModuleSettingsData, name: &str) -> Option<&'a SettingsData> {
+    yml.settings.iter().find(|s| s.name == name)
+}
+
+impl ModuleSettingsData {
+    /// Data type of every setting returned by [ModuleSettingsData::read_values], keyed by path
+    fn leaf_types(&self, setting: &SettingsData, coding: &[u8]) -> BTreeMap<String, String> {
+        let mut out = BTreeMap::new();
+        self.leaf_types_inner(&setting.params, coding, "", &mut out);
+        out
+    }
+
+    fn leaf_types_inner(&self, params: &[SettingsVariable], raw: &[u8], prefix: &str, out: &mut BTreeMap<String, String>) {
+        for var in params {
+            let path = format!("{prefix}{}", var.name);
+            match var.to_settings_type_or_raw(raw, &self.enums, &self.internal_structures) {
+                Ok((SettingsType::Struct { raw, s }, _)) => self.leaf_types_inner(&s.params, &raw, &format!("{path}."), out),
+                Ok((SettingsType::Array(elements), _)) if elements.iter().any(|e| matches!(e, SettingsType::Struct { .. })) => {
+                    for (idx, e) in elements.iter().enumerate() {
+                        if let SettingsType::Struct { raw, s } = e {
+                            self.leaf_types_inner(&s.params, raw, &format!("{path}[{idx}]."), out);
+                        }
+                    }
+                }
+                Ok(_) => {
+                    out.insert(path, var.data_type.clone());
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Reads the coding strings of every settings group from an NVS (EEPROM) partition, by
+    /// their EEPROM key. Keyed by SCN ID
+    pub fn codings_from_nvs(&self, nvs: &NvsPartition) -> HashMap<u8, Vec<u8>> {
+        self.settings
+            .iter()
+            .filter_map(|s| {
+                let key = s.eeprom_key.as_ref()?;
+                match &nvs.items.iter().find(|i| &i.key == key && i.crc_ok())?.value {
+                    NvsValue::Blob(b) => Some((s.scn_id?, b.clone())),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub mod test_settings_migration {
+    use super::*;
+    use crate::{
+        diag::nvs::NvsImage,
+        hw::sim_ecu::{SIM_MODULE_SETTINGS_YML, SIM_NVS_NAMESPACE},
+    };
+
+    /// TCC group of the simulated TCU, after a firmware update which moved `enabled`, widened `min_locking_rpm`,
+    /// narrowed `base_pressure_offset`, removed `slip_pedal_interp` and added `lock_delay_ms`
+    const NEW_YML: &str = r#"
+Enums:
+  - Name: TccLockMode
+    Mappings:
+      0:
+        Name: Open
+        Desc: Converter is always open
+      1:
+        Name: Slip
+        Desc: Converter may slip, but never fully lock
+      2:
+        Name: Closed
+        Desc: Converter may fully lock
+IStructs: []
+Settings:
+  - Name: TCC
+    SCN_ID: 1
+    EEPROM_KEY: TCC_A1
+    Params:
+      - Name: max_lock_mode
+        DataType: TccLockMode
+        OffsetBytes: 0
+        LengthBytes: 1
+      - Name: min_locking_rpm
+        DataType: uint32_t
+        OffsetBytes: 1
+        LengthBytes: 4
+      - Name: base_pressure_offset
+        DataType: int8_t
+        OffsetBytes: 5
+        LengthBytes: 1
+      - Name: enabled
+        DataType: bool
+        OffsetBytes: 6
+        LengthBytes: 1
+      - Name: lock_delay_ms
+        DataType: uint16_t
+        OffsetBytes: 7
+        LengthBytes: 2
+"#;
+
+    #[test]
+    pub fn test_migrate() {
+        let old: ModuleSettingsData = serde_yaml::from_str(SIM_MODULE_SETTINGS_YML).unwrap();
+        let new: ModuleSettingsData = serde_yaml::from_str(NEW_YML).unwrap();
+        let mut tcc = vec![0; 22];
+        tcc[0] = 1;
+        tcc[1] = 2;
+        tcc[2..4].copy_from_slice(&1500u16.to_le_bytes());
+        tcc[4..6].copy_from_slice(&(-300i16).to_le_bytes());
+        tcc[10..14].copy_from_slice(&0.5f32.to_le_bytes());
+
+        let nvs = NvsImage::build(0x6000, [(SIM_NVS_NAMESPACE, "TCC_A0", &NvsValue::Blob(tcc))]).unwrap().parse();
+        let old_codings = old.codings_from_nvs(&nvs);
+        assert_eq!(old_codings.len(), 1);
+
+        let mut new_default = vec![0; 9];
+        new_default[7..9].copy_from_slice(&250u16.to_le_bytes());
+        let migration = SettingsMigration::new(&old, &old_codings, &new, &HashMap::from([(1, new_default)]));
+
+        let tcc = &migration.import.groups[0];
+        let mut expected = vec![2];
+        expected.extend_from_slice(&1500u32.to_le_bytes());
+        // base_pressure_offset does not fit an i8, so it is dropped
+        expected.extend_from_slice(&[0, 1, 0xFA, 0x00]);
+        assert_eq!(tcc.coding, expected);
+        assert_eq!(tcc.report.missing, ["lock_delay_ms"]);
+        assert_eq!(tcc.report.errors.len(), 1);
+        assert_eq!(tcc.report.errors[0].0, "base_pressure_offset");
+        assert_eq!(tcc.report.unused.len(), 4);
+        assert!(tcc.report.unused.iter().all(|p| p.starts_with("slip_pedal_interp.")));
+        assert_eq!(
+            migration.converted,
+            [TypeChange { group: "TCC".into(), path: "min_locking_rpm".into(), from: "uint16_t".into(), to: "uint32_t".into() }]
+        );
+    }
+}
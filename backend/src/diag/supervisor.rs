@@ -0,0 +1,207 @@
+//! Connection supervisor
+//!
+//! Every [Nag52Diag] runs a supervisor thread, which watches the adapter and the ISO-TP link
+//! to the TCU. When the link is lost (Adapter unplugged, TCU rebooting after an OTA update or
+//! device mode change), it reconnects with exponential backoff, and restores the diagnostic
+//! session that was active before the link was lost.
+//!
+//! Since all clones of [Nag52Diag] share one connection, every page sees the restored link.
+//! State changes are published as [ConnectionEvent]s, see [Nag52Diag::subscribe_connection_events].
+
+use std::{
+    sync::{mpsc, Mutex, RwLock},
+    time::Duration,
+};
+
+use ecu_diagnostics::dynamic_diag::DiagSessionMode;
+
+use super::{Nag52Diag, WeakNag52Diag};
+
+/// How often the link is checked
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Delay before the first reconnect attempt, doubled after every failed attempt
+const BACKOFF_INITIAL: Duration = Duration::from_millis(250);
+const BACKOFF_MAX: Duration = Duration::from_secs(8);
+/// Basic (Default) KWP session, which does not need restoring after a reconnect
+const DEFAULT_SESSION_ID: u8 = 0x81;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    /// Link to the TCU was lost, and is being restored
+    Reconnecting { attempt: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// Link to the TCU was lost
+    Lost { reason: String },
+    /// Reconnect attempt failed, next attempt is made after `retry_in`
+    RetryFailed { attempt: u32, retry_in: Duration, error: String },
+    /// Link is restored. Contains the name of the restored diagnostic session,
+    /// if the TCU was in a non default session before the link was lost
+    Restored { session: Option<String> },
+}
+
+#[derive(Debug)]
+pub struct ConnectionSupervisor {
+    state: RwLock<ConnectionState>,
+    subscribers: Mutex<Vec<mpsc::Sender<ConnectionEvent>>>,
+}
+
+impl ConnectionSupervisor {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: RwLock::new(ConnectionState::Connected),
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state.read().map(|s| s.clone()).unwrap_or(ConnectionState::Connected)
+    }
+
+    pub fn subscribe(&self) -> mpsc::Receiver<ConnectionEvent> {
+        let (tx, rx) = mpsc::channel();
+        if let Ok(mut subs) = self.subscribers.lock() {
+            subs.push(tx);
+        }
+        rx
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        if let Ok(mut s) = self.state.write() {
+            *s = state;
+        }
+    }
+
+    fn publish(&self, evt: ConnectionEvent) {
+        if let Ok(mut subs) = self.subscribers.lock() {
+            // Drop subscribers that have gone away
+            subs.retain(|s| s.send(evt.clone()).is_ok());
+        }
+    }
+}
+
+/// Result of checking the link to the TCU
+pub(crate) enum LinkCheck {
+    /// Link is OK, with the current diagnostic session
+    Ok(Option<DiagSessionMode>),
+    Lost(String),
+}
+
+/// Supervisor thread. Only holds weak references to the connection,
+/// so it exits once every [Nag52Diag] using the connection is dropped.
+pub(crate) fn run(weak: WeakNag52Diag) {
+    let mut session: Option<DiagSessionMode> = None;
+    loop {
+        std::thread::sleep(POLL_INTERVAL);
+        let Some(nag) = weak.upgrade() else {
+            return;
+        };
+        match nag.check_link() {
+            LinkCheck::Ok(s) => session = s,
+            LinkCheck::Lost(reason) => {
+                eprintln!("Connection to {} lost: {reason}", nag.info.name);
+                nag.supervisor.set_state(ConnectionState::Reconnecting { attempt: 1 });
+                nag.supervisor.publish(ConnectionEvent::Lost { reason });
+                drop(nag);
+                if !reconnect_with_backoff(&weak, session.take()) {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Returns false if the connection was dropped whilst reconnecting
+fn reconnect_with_backoff(weak: &WeakNag52Diag, session: Option<DiagSessionMode>) -> bool {
+    let restore = session.filter(|s| s.id != DEFAULT_SESSION_ID);
+    let mut delay = BACKOFF_INITIAL;
+    let mut attempt = 1;
+    loop {
+        let Some(nag) = weak.upgrade() else {
+            return false;
+        };
+        nag.supervisor.set_state(ConnectionState::Reconnecting { attempt });
+        match nag.reconnect(restore.as_ref()) {
+            Ok(restored) => {
+                nag.supervisor.set_state(ConnectionState::Connected);
+                nag.supervisor.publish(ConnectionEvent::Restored {
+                    session: restored.then(|| restore.map(|s| s.name)).flatten(),
+                });
+                return true;
+            }
+            Err(e) => {
+                nag.supervisor.publish(ConnectionEvent::RetryFailed {
+                    attempt,
+                    retry_in: delay,
+                    error: e.to_string(),
+                });
+            }
+        }
+        drop(nag);
+        std::thread::sleep(delay);
+        delay = (delay * 2).min(BACKOFF_MAX);
+        attempt += 1;
+    }
+}
+
+impl Nag52Diag {
+    pub fn connection_state(&self) -> ConnectionState {
+        self.supervisor.state()
+    }
+
+    /// Returns a receiver for all future connection events
+    pub fn subscribe_connection_events(&self) -> mpsc::Receiver<ConnectionEvent> {
+        self.supervisor.subscribe()
+    }
+}
+
+#[cfg(test)]
+pub mod test_supervisor {
+    use std::time::Duration;
+
+    use crate::hw::sim::open_test_sim;
+
+    use super::{ConnectionEvent, ConnectionState};
+
+    #[test]
+    pub fn test_reconnect_restores_session() {
+        let (sim, nag) = open_test_sim("test_supervisor");
+        // Pages hold their own clones, which must see the restored link
+        let page_nag = nag.clone();
+        let events = nag.subscribe_connection_events();
+        nag.with_kwp(|k| k.send_byte_array_with_response(&[0x10, 0x92])).unwrap();
+        // Let the supervisor see the session before the TCU is unplugged
+        std::thread::sleep(Duration::from_millis(600));
+
+        sim.set_connected(false);
+        assert!(page_nag.query_ecu_data().is_err());
+        match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+            ConnectionEvent::Lost { .. } => {}
+            e => panic!("Unexpected event {e:?}"),
+        }
+        assert!(matches!(nag.connection_state(), ConnectionState::Reconnecting { .. }));
+        // At least one retry fails whilst unplugged
+        assert!(matches!(
+            events.recv_timeout(Duration::from_secs(5)).unwrap(),
+            ConnectionEvent::RetryFailed { attempt: 1, .. }
+        ));
+
+        // TCU reboots into the default session
+        sim.state().session = 0x81;
+        sim.set_connected(true);
+        let restored = loop {
+            match events.recv_timeout(Duration::from_secs(10)).unwrap() {
+                ConnectionEvent::Restored { session } => break session,
+                ConnectionEvent::RetryFailed { .. } => continue,
+                e => panic!("Unexpected event {e:?}"),
+            }
+        };
+        assert!(restored.is_some());
+        assert_eq!(nag.connection_state(), ConnectionState::Connected);
+        assert_eq!(sim.state().session, 0x92);
+        assert!(page_nag.query_ecu_data().is_ok());
+    }
+}
@@ -0,0 +1,210 @@
+//! Diagnostic session traces
+//!
+//! A trace is a JSON lines file. Each line is one [TraceRecord], containing
+//! a [ServerEvent] from the diagnostic server, and the time in milliseconds since
+//! recording began. Traces can be replayed with [crate::hw::replay::Nag52Replay].
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, LineWriter, Write},
+    path::Path,
+    sync::Arc,
+    time::Instant,
+};
+
+use ecu_diagnostics::{channel::ChannelError, dynamic_diag::ServerEvent};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Channel errors, as stored in a trace
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TraceChannelError {
+    IOError(String),
+    WriteTimeout,
+    ReadTimeout,
+    BufferEmpty,
+    BufferFull,
+    UnsupportedRequest,
+    InterfaceNotOpen,
+    HardwareError(String),
+    ConfigurationError,
+    Other(String),
+}
+
+impl From<&ChannelError> for TraceChannelError {
+    fn from(e: &ChannelError) -> Self {
+        match e {
+            ChannelError::IOError(e) => Self::IOError(e.to_string()),
+            ChannelError::WriteTimeout => Self::WriteTimeout,
+            ChannelError::ReadTimeout => Self::ReadTimeout,
+            ChannelError::BufferEmpty => Self::BufferEmpty,
+            ChannelError::BufferFull => Self::BufferFull,
+            ChannelError::UnsupportedRequest => Self::UnsupportedRequest,
+            ChannelError::InterfaceNotOpen => Self::InterfaceNotOpen,
+            ChannelError::HardwareError(e) => Self::HardwareError(e.to_string()),
+            ChannelError::ConfigurationError => Self::ConfigurationError,
+            ChannelError::Other(e) => Self::Other(e.clone()),
+        }
+    }
+}
+
+impl From<TraceChannelError> for ChannelError {
+    fn from(e: TraceChannelError) -> Self {
+        match e {
+            TraceChannelError::IOError(e) => {
+                ChannelError::IOError(Arc::new(std::io::Error::other(e)))
+            }
+            TraceChannelError::WriteTimeout => ChannelError::WriteTimeout,
+            TraceChannelError::ReadTimeout => ChannelError::ReadTimeout,
+            TraceChannelError::BufferEmpty => ChannelError::BufferEmpty,
+            TraceChannelError::BufferFull => ChannelError::BufferFull,
+            TraceChannelError::UnsupportedRequest => ChannelError::UnsupportedRequest,
+            TraceChannelError::InterfaceNotOpen => ChannelError::InterfaceNotOpen,
+            TraceChannelError::HardwareError(e) => ChannelError::Other(e),
+            TraceChannelError::ConfigurationError => ChannelError::ConfigurationError,
+            TraceChannelError::Other(e) => ChannelError::Other(e),
+        }
+    }
+}
+
+/// Bytes are stored as a hex string ("1A 86") to keep traces readable
+mod hex_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(data: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        let str = data.iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join(" ");
+        s.serialize_str(&str)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let str = String::deserialize(d)?;
+        str.split_whitespace()
+            .map(|b| u8::from_str_radix(b, 16).map_err(serde::de::Error::custom))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum TraceEvent {
+    /// First record of every trace
+    Info { device: String, created: String },
+    ServerStart,
+    ServerExit,
+    Send {
+        addr: u32,
+        #[serde(with = "hex_bytes")]
+        data: Vec<u8>,
+        error: Option<TraceChannelError>,
+    },
+    Recv {
+        addr: u32,
+        #[serde(with = "hex_bytes", default)]
+        data: Vec<u8>,
+        error: Option<TraceChannelError>,
+    },
+}
+
+impl From<&ServerEvent> for TraceEvent {
+    fn from(evt: &ServerEvent) -> Self {
+        match evt {
+            ServerEvent::ServerStart => Self::ServerStart,
+            ServerEvent::ServerExit => Self::ServerExit,
+            ServerEvent::BytesSendState(addr, data, res) => Self::Send {
+                addr: *addr,
+                data: data.clone(),
+                error: res.as_ref().err().map(|e| e.into()),
+            },
+            ServerEvent::BytesRecvState(addr, res) => match res {
+                Ok(data) => Self::Recv { addr: *addr, data: data.clone(), error: None },
+                Err(e) => Self::Recv { addr: *addr, data: vec![], error: Some(e.into()) },
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceRecord {
+    /// Milliseconds since recording started
+    pub t_ms: u64,
+    #[serde(flatten)]
+    pub event: TraceEvent,
+}
+
+#[derive(Debug)]
+pub enum TraceError {
+    Io(std::io::Error),
+    /// Line number and parse error
+    Parse(usize, String),
+}
+
+impl std::fmt::Display for TraceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceError::Io(e) => write!(f, "IO error: {e}"),
+            TraceError::Parse(line, e) => write!(f, "Invalid trace record on line {line}: {e}"),
+        }
+    }
+}
+
+impl From<std::io::Error> for TraceError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Writes [ServerEvent]s to a trace file as they happen.
+/// Each record is flushed straight away, so a trace survives the app crashing.
+#[derive(Debug)]
+pub struct TraceRecorder {
+    writer: LineWriter<File>,
+    start: Instant,
+    records: usize,
+}
+
+impl TraceRecorder {
+    pub fn create<P: AsRef<Path>>(path: P, device: &str) -> std::io::Result<Self> {
+        let mut s = Self {
+            writer: LineWriter::new(File::create(path)?),
+            start: Instant::now(),
+            records: 0,
+        };
+        s.write_event(TraceEvent::Info {
+            device: device.to_string(),
+            created: chrono::Local::now().to_rfc3339(),
+        })?;
+        Ok(s)
+    }
+
+    pub fn record(&mut self, evt: &ServerEvent) -> std::io::Result<()> {
+        self.write_event(evt.into())
+    }
+
+    fn write_event(&mut self, event: TraceEvent) -> std::io::Result<()> {
+        let record = TraceRecord { t_ms: self.start.elapsed().as_millis() as u64, event };
+        let line = serde_json::to_string(&record).map_err(std::io::Error::other)?;
+        writeln!(self.writer, "{line}")?;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Number of records written, including the info record
+    pub fn records(&self) -> usize {
+        self.records
+    }
+}
+
+pub fn load_trace<P: AsRef<Path>>(path: P) -> Result<Vec<TraceRecord>, TraceError> {
+    parse_trace(BufReader::new(File::open(path)?))
+}
+
+pub fn parse_trace<R: BufRead>(reader: R) -> Result<Vec<TraceRecord>, TraceError> {
+    let mut ret = Vec::new();
+    for (idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        ret.push(serde_json::from_str(&line).map_err(|e| TraceError::Parse(idx + 1, e.to_string()))?);
+    }
+    Ok(ret)
+}
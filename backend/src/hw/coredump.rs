@@ -0,0 +1,668 @@
+//! ESP-IDF core dump format
+//!
+//! When the TCU crashes, ESP-IDF writes a core dump to the coredump partition:
+//! * Header (Little endian u32s): total length, version, task count, TCB size and memory segment count.
+//!   Version 2.1 dumps add the chip revision
+//! * An Xtensa ELF core file. PT_LOAD segments hold the TCB and stack of every task,
+//!   PT_NOTE segments hold the registers of every task (NT_PRSTATUS), the ELF SHA-256 of the
+//!   firmware that crashed and the exception registers, including which task crashed
+//! * CRC32 or SHA-256 (Depending on the version) of everything before it
+//!
+//! The legacy binary format (Version 0.x) is not supported.
+
+use object::{
+    elf,
+    read::elf::{FileHeader, ProgramHeader},
+    Endianness,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use super::{debug_elf::DebugElf, esp_image::ESP_CHIP_ID_ESP32};
+
+pub const COREDUMP_VERSION_ELF_CRC32: u16 = 0x0100;
+pub const COREDUMP_VERSION_ELF_SHA256: u16 = 0x0101;
+pub const COREDUMP_VERSION_ELF_CRC32_V2_1: u16 = 0x0102;
+pub const COREDUMP_VERSION_ELF_SHA256_V2_1: u16 = 0x0103;
+const HEADER_LEN: usize = 20;
+const HEADER_LEN_V2_1: usize = 24;
+const HASH_LEN: usize = 32;
+
+const NOTE_NAME_CORE: &[u8] = b"CORE";
+const NOTE_NAME_INFO: &[u8] = b"ESP_CORE_DUMP_INFO";
+const NOTE_NAME_EXTRA_INFO: &[u8] = b"EXTRA_INFO";
+const NOTE_TYPE_INFO: u32 = 8266;
+const NOTE_TYPE_EXTRA_INFO: u32 = 677;
+
+/// Offsets within the Xtensa prstatus note. The task's TCB address is stored as its PID
+const PRSTATUS_PID_OFFSET: usize = 24;
+const PRSTATUS_REGS_OFFSET: usize = 72;
+/// a0-a15 come after pc, ps, lbeg, lend, lcount, sar, windowstart, windowbase and 56 reserved registers
+const PRSTATUS_AR_OFFSET: usize = PRSTATUS_REGS_OFFSET + 64 * 4;
+/// Register set holds 64 address registers, followed by a reserved word
+const PRSTATUS_LEN: usize = PRSTATUS_AR_OFFSET + 64 * 4 + 4;
+
+/// Offset of pcTaskName within a FreeRTOS TCB
+const TCB_NAME_OFFSET: u32 = 0x34;
+const TASK_NAME_LEN: usize = 16;
+const MAX_FRAMES: usize = 64;
+
+/// Xtensa special register numbers, as stored in the EXTRA_INFO note
+pub const XT_REG_EPC1: u32 = 177;
+pub const XT_REG_EPS2: u32 = 194;
+pub const XT_REG_EXCCAUSE: u32 = 232;
+pub const XT_REG_EXCVADDR: u32 = 238;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum CoreDumpChecksum {
+    Crc32(u32),
+    Sha256([u8; HASH_LEN]),
+}
+
+impl std::fmt::Display for CoreDumpChecksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Crc32(crc) => write!(f, "CRC32 0x{crc:08X}"),
+            Self::Sha256(hash) => write!(f, "SHA-256 {}", hex(hash)),
+        }
+    }
+}
+
+/// Registers of a task, from its NT_PRSTATUS note.
+/// ESP-IDF spills the register windows before dumping, so `ar` is the current window (a0-a15)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct XtensaRegisters {
+    pub pc: u32,
+    pub ps: u32,
+    pub lbeg: u32,
+    pub lend: u32,
+    pub lcount: u32,
+    pub sar: u32,
+    pub windowstart: u32,
+    pub windowbase: u32,
+    pub ar: [u32; 16],
+}
+
+impl XtensaRegisters {
+    pub fn sp(&self) -> u32 {
+        self.ar[1]
+    }
+
+    /// Name and value of every register, in the order espcoredump.py prints them
+    pub fn named(&self) -> Vec<(String, u32)> {
+        let mut ret = vec![
+            ("PC".to_string(), self.pc),
+            ("PS".to_string(), self.ps),
+            ("LBEG".to_string(), self.lbeg),
+            ("LEND".to_string(), self.lend),
+            ("LCOUNT".to_string(), self.lcount),
+            ("SAR".to_string(), self.sar),
+            ("WINDOWSTART".to_string(), self.windowstart),
+            ("WINDOWBASE".to_string(), self.windowbase),
+        ];
+        ret.extend(self.ar.iter().enumerate().map(|(idx, v)| (format!("A{idx}"), *v)));
+        ret
+    }
+
+    fn from_prstatus(desc: &[u8]) -> Self {
+        let mut ret = Self {
+            pc: read_u32(desc, PRSTATUS_REGS_OFFSET),
+            ps: read_u32(desc, PRSTATUS_REGS_OFFSET + 4),
+            lbeg: read_u32(desc, PRSTATUS_REGS_OFFSET + 8),
+            lend: read_u32(desc, PRSTATUS_REGS_OFFSET + 12),
+            lcount: read_u32(desc, PRSTATUS_REGS_OFFSET + 16),
+            sar: read_u32(desc, PRSTATUS_REGS_OFFSET + 20),
+            windowstart: read_u32(desc, PRSTATUS_REGS_OFFSET + 24),
+            windowbase: read_u32(desc, PRSTATUS_REGS_OFFSET + 28),
+            ar: [0; 16],
+        };
+        for (idx, reg) in ret.ar.iter_mut().enumerate() {
+            *reg = read_u32(desc, PRSTATUS_AR_OFFSET + idx * 4);
+        }
+        ret
+    }
+
+    fn to_prstatus(self, tcb: u32) -> Vec<u8> {
+        let mut ret = vec![0; PRSTATUS_LEN];
+        let regs = [self.pc, self.ps, self.lbeg, self.lend, self.lcount, self.sar, self.windowstart, self.windowbase];
+        ret[PRSTATUS_PID_OFFSET..PRSTATUS_PID_OFFSET + 4].copy_from_slice(&tcb.to_le_bytes());
+        for (idx, v) in regs.iter().chain(self.ar.iter()).enumerate() {
+            let offset = if idx < 8 { PRSTATUS_REGS_OFFSET + idx * 4 } else { PRSTATUS_AR_OFFSET + (idx - 8) * 4 };
+            ret[offset..offset + 4].copy_from_slice(&v.to_le_bytes());
+        }
+        ret
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct StackFrame {
+    pub pc: u32,
+    pub sp: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CoreDumpTask {
+    /// Address of the task's TCB, which identifies the task
+    pub tcb: u32,
+    /// Task name, from the TCB. Empty if the TCB is not in the dump
+    pub name: String,
+    pub regs: XtensaRegisters,
+    /// Backtrace, starting at the current PC
+    pub frames: Vec<StackFrame>,
+    pub crashed: bool,
+}
+
+/// Special register saved at the time of the crash
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ExtraReg {
+    pub index: u32,
+    pub value: u32,
+}
+
+impl ExtraReg {
+    pub fn name(&self) -> String {
+        match self.index {
+            XT_REG_EPC1..=183 => format!("EPC{}", self.index - XT_REG_EPC1 + 1),
+            XT_REG_EPS2..=199 => format!("EPS{}", self.index - XT_REG_EPS2 + 2),
+            XT_REG_EXCCAUSE => "EXCCAUSE".into(),
+            XT_REG_EXCVADDR => "EXCVADDR".into(),
+            x => format!("SR{x}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MemSegment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CoreDump {
+    /// Dump format version (Without the chip ID)
+    pub version: u16,
+    pub checksum: CoreDumpChecksum,
+    /// SHA-256 of the firmware's ELF file (As hex, may be truncated)
+    pub app_elf_sha256: String,
+    pub tasks: Vec<CoreDumpTask>,
+    pub exception_regs: Vec<ExtraReg>,
+    pub segments: Vec<MemSegment>,
+    /// Length of the dump, including header and checksum
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CoreDumpError {
+    /// Coredump partition is erased, so the TCU has not crashed
+    Empty,
+    TooShort(usize),
+    WrongChip(u16),
+    UnsupportedVersion(u16),
+    /// Header length does not fit in the buffer
+    Truncated { len: usize, available: usize },
+    CrcMismatch { stored: u32, calculated: u32 },
+    HashMismatch { stored: [u8; HASH_LEN], calculated: [u8; HASH_LEN] },
+    InvalidElf(String),
+}
+
+fn hex(b: &[u8]) -> String {
+    b.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+impl std::fmt::Display for CoreDumpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "Coredump partition is empty, no crash was recorded"),
+            Self::TooShort(len) => write!(f, "Coredump is only {len} bytes long, too short for a coredump header"),
+            Self::WrongChip(id) => write!(f, "Coredump is from chip ID 0x{id:04X}, but the TCU is an ESP32 (0x{ESP_CHIP_ID_ESP32:04X})"),
+            Self::UnsupportedVersion(v) => write!(f, "Unsupported coredump version 0x{v:04X}. Only ELF coredumps can be decoded"),
+            Self::Truncated { len, available } => {
+                write!(f, "Coredump is truncated. Header specifies {len} bytes, but only {available} bytes were read")
+            }
+            Self::CrcMismatch { stored, calculated } => write!(
+                f,
+                "CRC mismatch. Coredump stores 0x{stored:08X}, calculated 0x{calculated:08X}. The coredump is corrupt"
+            ),
+            Self::HashMismatch { stored, calculated } => write!(
+                f,
+                "SHA-256 mismatch. Coredump stores {}, calculated {}. The coredump is corrupt",
+                hex(stored),
+                hex(calculated)
+            ),
+            Self::InvalidElf(e) => write!(f, "Coredump ELF is invalid: {e}"),
+        }
+    }
+}
+
+impl From<object::read::Error> for CoreDumpError {
+    fn from(e: object::read::Error) -> Self {
+        Self::InvalidElf(e.to_string())
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    buf.get(offset..offset + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap())).unwrap_or(0)
+}
+
+/// Name of an Xtensa exception cause (EXCCAUSE)
+pub fn exception_cause_name(cause: u32) -> &'static str {
+    match cause {
+        0 => "IllegalInstruction",
+        1 => "Syscall",
+        2 => "InstructionFetchError",
+        3 => "LoadStoreError",
+        4 => "Level1Interrupt",
+        5 => "Alloca",
+        6 => "IntegerDivideByZero",
+        8 => "Privileged",
+        9 => "LoadStoreAlignment",
+        12 => "InstrPIFDataError",
+        13 => "LoadStorePIFDataError",
+        14 => "InstrPIFAddrError",
+        15 => "LoadStorePIFAddrError",
+        16 => "InstTLBMiss",
+        17 => "InstTLBMultiHit",
+        18 => "InstFetchPrivilege",
+        20 => "InstFetchProhibited",
+        24 => "LoadStoreTLBMiss",
+        25 => "LoadStoreTLBMultiHit",
+        26 => "LoadStorePrivilege",
+        28 => "LoadProhibited",
+        29 => "StoreProhibited",
+        32..=39 => "CoprocessorDisabled",
+        _ => "Unknown",
+    }
+}
+
+/// Converts a return address (a0) to the address of the call instruction.
+/// The top 2 bits of a0 hold the window increment, rather than the address
+fn return_addr_to_pc(a0: u32) -> u32 {
+    let pc = if a0 & 0x8000_0000 != 0 { (a0 & 0x3FFF_FFFF) | 0x4000_0000 } else { a0 };
+    pc.wrapping_sub(3)
+}
+
+impl CoreDump {
+    /// Parses and validates a coredump. Data after the dump (Such as erased flash) is ignored
+    pub fn parse(buf: &[u8]) -> Result<Self, CoreDumpError> {
+        if buf.len() >= 4 && read_u32(buf, 0) == 0xFFFF_FFFF {
+            return Err(CoreDumpError::Empty);
+        }
+        if buf.len() < HEADER_LEN {
+            return Err(CoreDumpError::TooShort(buf.len()));
+        }
+        let len = read_u32(buf, 0) as usize;
+        let chip = (read_u32(buf, 4) >> 16) as u16;
+        let version = read_u32(buf, 4) as u16;
+        if chip != ESP_CHIP_ID_ESP32 {
+            return Err(CoreDumpError::WrongChip(chip));
+        }
+        let (header_len, checksum_len) = match version {
+            COREDUMP_VERSION_ELF_CRC32 => (HEADER_LEN, 4),
+            COREDUMP_VERSION_ELF_SHA256 => (HEADER_LEN, HASH_LEN),
+            COREDUMP_VERSION_ELF_CRC32_V2_1 => (HEADER_LEN_V2_1, 4),
+            COREDUMP_VERSION_ELF_SHA256_V2_1 => (HEADER_LEN_V2_1, HASH_LEN),
+            v => return Err(CoreDumpError::UnsupportedVersion(v)),
+        };
+        if len > buf.len() {
+            return Err(CoreDumpError::Truncated { len, available: buf.len() });
+        }
+        if len < header_len + checksum_len {
+            return Err(CoreDumpError::TooShort(len));
+        }
+
+        let data_end = len - checksum_len;
+        let checksum = if checksum_len == 4 {
+            let stored = read_u32(buf, data_end);
+            let calculated = crc32fast::hash(&buf[..data_end]);
+            if stored != calculated {
+                return Err(CoreDumpError::CrcMismatch { stored, calculated });
+            }
+            CoreDumpChecksum::Crc32(stored)
+        } else {
+            let stored: [u8; HASH_LEN] = buf[data_end..len].try_into().unwrap();
+            let calculated: [u8; HASH_LEN] = Sha256::digest(&buf[..data_end]).into();
+            if stored != calculated {
+                return Err(CoreDumpError::HashMismatch { stored, calculated });
+            }
+            CoreDumpChecksum::Sha256(stored)
+        };
+
+        let mut ret = Self {
+            version,
+            checksum,
+            app_elf_sha256: String::new(),
+            tasks: vec![],
+            exception_regs: vec![],
+            segments: vec![],
+            len,
+        };
+        let crashed_tcb = ret.parse_elf(&buf[header_len..data_end])?;
+        for idx in 0..ret.tasks.len() {
+            let task = &ret.tasks[idx];
+            let name = ret.read_mem(task.tcb + TCB_NAME_OFFSET, TASK_NAME_LEN).map(|n| {
+                let n_len = n.iter().position(|x| *x == 0).unwrap_or(n.len());
+                String::from_utf8_lossy(&n[..n_len]).to_string()
+            });
+            let frames = ret.backtrace(&task.regs);
+            let crashed = Some(task.tcb) == crashed_tcb;
+            let task = &mut ret.tasks[idx];
+            task.name = name.unwrap_or_default();
+            task.frames = frames;
+            task.crashed = crashed;
+        }
+        Ok(ret)
+    }
+
+    /// Reads segments and notes from the ELF, returning the crashed task's TCB address
+    fn parse_elf(&mut self, data: &[u8]) -> Result<Option<u32>, CoreDumpError> {
+        let header = elf::FileHeader32::<Endianness>::parse(data)?;
+        let endian = header.endian()?;
+        if header.e_machine(endian) != elf::EM_XTENSA {
+            return Err(CoreDumpError::InvalidElf(format!("Machine type is {}, not Xtensa", header.e_machine(endian))));
+        }
+        let mut crashed_tcb = None;
+        for ph in header.program_headers(endian, data)? {
+            match ph.p_type(endian) {
+                elf::PT_LOAD => {
+                    let seg = ph.data(endian, data).map_err(|_| CoreDumpError::InvalidElf("Segment out of bounds".into()))?;
+                    self.segments.push(MemSegment { address: ph.p_vaddr(endian), data: seg.to_vec() });
+                }
+                elf::PT_NOTE => {
+                    let Some(mut notes) = ph.notes(endian, data)? else { continue };
+                    while let Some(note) = notes.next()? {
+                        let desc = note.desc();
+                        match (note.name(), note.n_type(endian)) {
+                            (NOTE_NAME_CORE, elf::NT_PRSTATUS) if desc.len() >= PRSTATUS_AR_OFFSET + 16 * 4 => {
+                                self.tasks.push(CoreDumpTask {
+                                    tcb: read_u32(desc, PRSTATUS_PID_OFFSET),
+                                    name: String::new(),
+                                    regs: XtensaRegisters::from_prstatus(desc),
+                                    frames: vec![],
+                                    crashed: false,
+                                });
+                            }
+                            (NOTE_NAME_INFO, NOTE_TYPE_INFO) if desc.len() > 4 => {
+                                let sha = &desc[4..];
+                                let sha_len = sha.iter().position(|x| *x == 0).unwrap_or(sha.len());
+                                self.app_elf_sha256 = String::from_utf8_lossy(&sha[..sha_len]).to_string();
+                            }
+                            (NOTE_NAME_EXTRA_INFO, NOTE_TYPE_EXTRA_INFO) if desc.len() >= 4 => {
+                                crashed_tcb = Some(read_u32(desc, 0));
+                                self.exception_regs = desc[4..]
+                                    .chunks_exact(8)
+                                    .map(|r| ExtraReg { index: read_u32(r, 0), value: read_u32(r, 4) })
+                                    .collect();
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(crashed_tcb)
+    }
+
+    /// Reads memory from the dumped segments. Returns None if the range is not in the dump
+    pub fn read_mem(&self, address: u32, len: usize) -> Option<&[u8]> {
+        self.segments.iter().find_map(|s| {
+            let start = address.checked_sub(s.address)? as usize;
+            s.data.get(start..start.checked_add(len)?)
+        })
+    }
+
+    pub fn read_u32(&self, address: u32) -> Option<u32> {
+        self.read_mem(address, 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    /// Walks the stack of a task, using the windowed ABI's base save area.
+    /// Each frame stores the caller's a0 (Return address) and a1 (Stack pointer) 16 and 12 bytes below its stack pointer.
+    /// The walk stops at a return address of 0, or once the stack is no longer in the dump
+    fn backtrace(&self, regs: &XtensaRegisters) -> Vec<StackFrame> {
+        let mut ret = vec![StackFrame { pc: regs.pc, sp: regs.sp() }];
+        let (mut sp, mut next_pc) = (regs.sp(), regs.ar[0]);
+        while next_pc != 0 && ret.len() < MAX_FRAMES {
+            let (Some(caller_a0), Some(caller_sp)) =
+                (self.read_u32(sp.wrapping_sub(16)), self.read_u32(sp.wrapping_sub(12)))
+            else {
+                break;
+            };
+            ret.push(StackFrame { pc: return_addr_to_pc(next_pc), sp: caller_sp });
+            // Stack grows down, so a caller below its callee means the stack is corrupt
+            if caller_sp <= sp {
+                break;
+            }
+            sp = caller_sp;
+            next_pc = caller_a0;
+        }
+        ret
+    }
+
+    pub fn crashed_task(&self) -> Option<&CoreDumpTask> {
+        self.tasks.iter().find(|t| t.crashed)
+    }
+
+    pub fn exception_reg(&self, index: u32) -> Option<u32> {
+        self.exception_regs.iter().find(|r| r.index == index).map(|r| r.value)
+    }
+
+    /// Returns true if the debug ELF is the one of the firmware that crashed
+    pub fn matches_elf(&self, elf: &DebugElf) -> bool {
+        !self.app_elf_sha256.is_empty() && elf.sha256_hex().starts_with(&self.app_elf_sha256)
+    }
+
+    /// Plain text summary of the crash. Backtraces are in the 'PC:SP' format printed by the
+    /// panic handler, so they can be pasted into ESP-IDF's addr2line tools
+    pub fn summary(&self) -> String {
+        let mut ret = String::new();
+        if let Some(task) = self.crashed_task() {
+            ret.push_str(&format!("Crashed task: {} (TCB 0x{:08X})\n", task.name, task.tcb));
+        }
+        if let Some(cause) = self.exception_reg(XT_REG_EXCCAUSE) {
+            ret.push_str(&format!("Exception: {} ({cause})\n", exception_cause_name(cause)));
+        }
+        for reg in &self.exception_regs {
+            ret.push_str(&format!("{}: 0x{:08X}\n", reg.name(), reg.value));
+        }
+        ret.push_str(&format!("Firmware ELF SHA-256: {}\n", self.app_elf_sha256));
+        for task in self.tasks.iter().filter(|t| t.crashed).chain(self.tasks.iter().filter(|t| !t.crashed)) {
+            let bt: Vec<String> = task.frames.iter().map(|f| format!("0x{:08x}:0x{:08x}", f.pc, f.sp)).collect();
+            ret.push_str(&format!("\nTask '{}' (TCB 0x{:08X})\nBacktrace: {}\n", task.name, task.tcb, bt.join(" ")));
+        }
+        ret
+    }
+
+    /// Builds an ELF (CRC32) coredump, as written by ESP-IDF.
+    /// Tasks are given as TCB address and registers, segments as address and memory contents
+    pub fn build(
+        app_elf_sha256: &str,
+        crashed_tcb: u32,
+        exception_regs: &[ExtraReg],
+        tasks: &[(u32, XtensaRegisters)],
+        segments: &[(u32, &[u8])],
+    ) -> Vec<u8> {
+        fn note(name: &[u8], ty: u32, desc: &[u8]) -> Vec<u8> {
+            let mut ret = Vec::new();
+            ret.extend_from_slice(&(name.len() as u32 + 1).to_le_bytes());
+            ret.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+            ret.extend_from_slice(&ty.to_le_bytes());
+            ret.extend_from_slice(name);
+            ret.resize((ret.len() + 4) & !3, 0);
+            ret.extend_from_slice(desc);
+            ret.resize((ret.len() + 3) & !3, 0);
+            ret
+        }
+        let mut notes: Vec<u8> =
+            tasks.iter().flat_map(|(tcb, regs)| note(NOTE_NAME_CORE, elf::NT_PRSTATUS, &regs.to_prstatus(*tcb))).collect();
+        let mut info = 1u32.to_le_bytes().to_vec();
+        info.extend_from_slice(app_elf_sha256.as_bytes());
+        info.resize(4 + 66, 0);
+        notes.extend(note(NOTE_NAME_INFO, NOTE_TYPE_INFO, &info));
+        let mut extra = crashed_tcb.to_le_bytes().to_vec();
+        extra.extend(exception_regs.iter().flat_map(|r| [r.index.to_le_bytes(), r.value.to_le_bytes()].concat()));
+        notes.extend(note(NOTE_NAME_EXTRA_INFO, NOTE_TYPE_EXTRA_INFO, &extra));
+
+        // ELF header, program headers, then the segment data
+        const EHDR_LEN: usize = 52;
+        const PHDR_LEN: usize = 32;
+        let phnum = 1 + segments.len();
+        let mut elf_data = vec![0; EHDR_LEN];
+        elf_data[0..4].copy_from_slice(&elf::ELFMAG);
+        elf_data[4] = elf::ELFCLASS32;
+        elf_data[5] = elf::ELFDATA2LSB;
+        elf_data[6] = elf::EV_CURRENT;
+        elf_data[16..18].copy_from_slice(&elf::ET_CORE.to_le_bytes());
+        elf_data[18..20].copy_from_slice(&elf::EM_XTENSA.to_le_bytes());
+        elf_data[20..24].copy_from_slice(&(elf::EV_CURRENT as u32).to_le_bytes());
+        elf_data[28..32].copy_from_slice(&(EHDR_LEN as u32).to_le_bytes());
+        elf_data[40..42].copy_from_slice(&(EHDR_LEN as u16).to_le_bytes());
+        elf_data[42..44].copy_from_slice(&(PHDR_LEN as u16).to_le_bytes());
+        elf_data[44..46].copy_from_slice(&(phnum as u16).to_le_bytes());
+        let mut offset = EHDR_LEN + phnum * PHDR_LEN;
+        let mut phdr = |ty: u32, vaddr: u32, len: usize| {
+            let fields = [ty, offset as u32, vaddr, vaddr, len as u32, len as u32, elf::PF_R | elf::PF_W, 4];
+            elf_data.extend(fields.iter().flat_map(|f| f.to_le_bytes()));
+            offset += len;
+        };
+        phdr(elf::PT_NOTE, 0, notes.len());
+        for (address, data) in segments {
+            phdr(elf::PT_LOAD, *address, data.len());
+        }
+        elf_data.extend_from_slice(&notes);
+        for (_, data) in segments {
+            elf_data.extend_from_slice(data);
+        }
+
+        let mut ret = Vec::new();
+        let len = HEADER_LEN + elf_data.len() + 4;
+        // TCB size is informational only
+        let header = [len as u32, COREDUMP_VERSION_ELF_CRC32 as u32, tasks.len() as u32, 0x164, segments.len() as u32];
+        ret.extend(header.iter().flat_map(|f| f.to_le_bytes()));
+        ret.extend_from_slice(&elf_data);
+        let crc = crc32fast::hash(&ret);
+        ret.extend_from_slice(&crc.to_le_bytes());
+        ret
+    }
+}
+
+#[cfg(test)]
+pub mod test_coredump {
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+
+    const TCB_MAIN: u32 = 0x3FFB_1000;
+    const TCB_IDLE: u32 = 0x3FFB_2000;
+    const STACK: u32 = 0x3FFB_8000;
+
+    fn tcb(name: &str) -> Vec<u8> {
+        let mut ret = vec![0; 0x164];
+        ret[TCB_NAME_OFFSET as usize..TCB_NAME_OFFSET as usize + name.len()].copy_from_slice(name.as_bytes());
+        ret
+    }
+
+    /// Crashed task is 3 calls deep: main_task -> app_main -> shift_gear (Which crashed)
+    fn test_dump() -> Vec<u8> {
+        let mut stack = vec![0u8; 0x100];
+        let mut put = |addr: u32, v: u32| {
+            let offset = (addr - STACK) as usize;
+            stack[offset..offset + 4].copy_from_slice(&v.to_le_bytes());
+        };
+        // shift_gear's frame (sp 0x3FFB8020) saves app_main's return address and sp
+        put(STACK + 0x20 - 16, 0x800D_2003);
+        put(STACK + 0x20 - 12, STACK + 0x60);
+        // app_main's frame saves main_task's, which is the end of the stack
+        put(STACK + 0x60 - 16, 0);
+        put(STACK + 0x60 - 12, STACK + 0xA0);
+
+        let mut crashed = XtensaRegisters { pc: 0x400D_1010, ps: 0x60F30, ..Default::default() };
+        crashed.ar[0] = 0x800D_1503;
+        crashed.ar[1] = STACK + 0x20;
+        let mut idle = XtensaRegisters { pc: 0x4008_1234, ..Default::default() };
+        idle.ar[1] = STACK + 0xC0;
+        let exc = [ExtraReg { index: XT_REG_EXCCAUSE, value: 29 }, ExtraReg { index: XT_REG_EXCVADDR, value: 0x10 }];
+        CoreDump::build(
+            "0123456789abcdef",
+            TCB_MAIN,
+            &exc,
+            &[(TCB_MAIN, crashed), (TCB_IDLE, idle)],
+            &[(TCB_MAIN, &tcb("main")), (TCB_IDLE, &tcb("IDLE0")), (STACK, &stack)],
+        )
+    }
+
+    #[test]
+    pub fn test_parse() {
+        let raw = test_dump();
+        let dump = CoreDump::parse(&raw).unwrap();
+        assert_eq!(dump.len, raw.len());
+        assert_eq!(dump.version, COREDUMP_VERSION_ELF_CRC32);
+        assert_eq!(dump.app_elf_sha256, "0123456789abcdef");
+        assert_eq!(dump.tasks.len(), 2);
+        assert_eq!(dump.tasks[1].name, "IDLE0");
+        assert!(!dump.tasks[1].crashed);
+        // Idle has no return address, so only the current PC is known
+        assert_eq!(dump.tasks[1].frames, vec![StackFrame { pc: 0x4008_1234, sp: STACK + 0xC0 }]);
+
+        let crashed = dump.crashed_task().unwrap();
+        assert_eq!(crashed.name, "main");
+        assert_eq!(crashed.regs.ps, 0x60F30);
+        assert_eq!(
+            crashed.frames,
+            vec![
+                StackFrame { pc: 0x400D_1010, sp: STACK + 0x20 },
+                StackFrame { pc: 0x400D_1500, sp: STACK + 0x60 },
+                StackFrame { pc: 0x400D_2000, sp: STACK + 0xA0 },
+            ]
+        );
+        assert_eq!(dump.exception_reg(XT_REG_EXCCAUSE).map(exception_cause_name), Some("StoreProhibited"));
+        assert_eq!(dump.exception_regs[1].name(), "EXCVADDR");
+        assert_eq!(ExtraReg { index: XT_REG_EPC1 + 1, value: 0 }.name(), "EPC2");
+
+        let summary = dump.summary();
+        assert!(summary.starts_with("Crashed task: main (TCB 0x3FFB1000)\nException: StoreProhibited (29)\n"));
+        assert!(summary.contains("Backtrace: 0x400d1010:0x3ffb8020 0x400d1500:0x3ffb8060 0x400d2000:0x3ffb80a0\n"));
+
+        // Rest of the partition is ignored
+        let mut padded = raw.clone();
+        padded.resize(0x10000, 0xFF);
+        assert_eq!(CoreDump::parse(&padded).unwrap(), dump);
+    }
+
+    #[test]
+    pub fn test_sha256() {
+        // Same dump, with a SHA-256 instead of the CRC
+        let mut raw = test_dump();
+        raw.truncate(raw.len() - 4);
+        raw[4..8].copy_from_slice(&(COREDUMP_VERSION_ELF_SHA256 as u32).to_le_bytes());
+        let len = raw.len() + 32;
+        raw[0..4].copy_from_slice(&(len as u32).to_le_bytes());
+        let hash: [u8; 32] = Sha256::digest(&raw).into();
+        raw.extend_from_slice(&hash);
+        let dump = CoreDump::parse(&raw).unwrap();
+        assert_eq!(dump.checksum, CoreDumpChecksum::Sha256(hash));
+        assert_eq!(dump.crashed_task().unwrap().frames.len(), 3);
+
+        raw[100] ^= 0x01;
+        assert!(matches!(CoreDump::parse(&raw), Err(CoreDumpError::HashMismatch { .. })));
+    }
+
+    #[test]
+    pub fn test_invalid() {
+        let raw = test_dump();
+        assert_eq!(CoreDump::parse(&[0xFF; 0x1000]), Err(CoreDumpError::Empty));
+        assert_eq!(CoreDump::parse(&raw[..10]), Err(CoreDumpError::TooShort(10)));
+        assert_eq!(
+            CoreDump::parse(&raw[..100]),
+            Err(CoreDumpError::Truncated { len: raw.len(), available: 100 })
+        );
+        let mut bad = raw.clone();
+        bad[200] ^= 0x01;
+        assert!(matches!(CoreDump::parse(&bad), Err(CoreDumpError::CrcMismatch { .. })));
+        let mut bad = raw.clone();
+        bad[4..8].copy_from_slice(&2u32.to_le_bytes()); // Binary format
+        assert_eq!(CoreDump::parse(&bad), Err(CoreDumpError::UnsupportedVersion(2)));
+        let mut bad = raw.clone();
+        bad[6] = 0x09; // ESP32-S3
+        assert_eq!(CoreDump::parse(&bad), Err(CoreDumpError::WrongChip(0x0009)));
+    }
+}
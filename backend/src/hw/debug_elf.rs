@@ -0,0 +1,527 @@
+//! Debug ELF of a firmware build
+//!
+//! Releases publish the ELF next to the firmware .bin. It maps code addresses (Such as a coredump backtrace)
+//! to function names and source lines, using the DWARF debug info if present, or the symbol table otherwise.
+//! It also lists the global variables of the firmware, so they can be watched by name.
+
+use std::{borrow::Cow, collections::HashMap, path::Path, sync::Arc};
+
+use addr2line::Context;
+use gimli::{AttributeValue, EndianArcSlice, Reader, RunTimeEndian, Unit, UnitOffset};
+use object::{Architecture, Object, ObjectSection, ObjectSymbol, SymbolKind};
+use sha2::{Digest, Sha256};
+
+use crate::diag::memory::{MemoryRegion, MemoryValueType};
+
+use super::firmware::FirmwareHeader;
+
+type DwarfReader = EndianArcSlice<RunTimeEndian>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfSymbolKind {
+    Function,
+    Data,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElfSymbol {
+    /// Demangled name
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+    pub kind: ElfSymbolKind,
+}
+
+/// Global (Or static) variable of the firmware
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElfVariable {
+    /// Demangled name
+    pub name: String,
+    /// Address on the ESP32 memory bus
+    pub address: u32,
+    pub size: u32,
+    /// Type name from the debug info
+    pub type_name: Option<String>,
+    /// How the value is stored, if the variable is a number whose type is in the debug info
+    pub value_type: Option<MemoryValueType>,
+}
+
+impl ElfVariable {
+    /// Region and offset the variable can be read from, if all of it lies within a [MemoryRegion]
+    pub fn memory_location(&self) -> Option<(MemoryRegion, u32)> {
+        MemoryRegion::find_cpu_addr(self.address, self.size.max(1))
+    }
+}
+
+/// Type of a variable from the debug info
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct DwarfType {
+    name: Option<String>,
+    value_type: Option<MemoryValueType>,
+}
+
+/// Source location of an address. Fields are None if the ELF has no info about them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+impl std::fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.function.as_deref().unwrap_or("??"))?;
+        if let Some(file) = &self.file {
+            write!(f, " at {file}:{}", self.line.map(|l| l.to_string()).unwrap_or("?".into()))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum DebugElfError {
+    Io(std::io::Error),
+    Elf(object::read::Error),
+    Dwarf(gimli::Error),
+    WrongArchitecture(Architecture),
+}
+
+impl std::fmt::Display for DebugElfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Could not read the ELF file: {e}"),
+            Self::Elf(e) => write!(f, "Not a valid ELF file: {e}"),
+            Self::Dwarf(e) => write!(f, "Invalid debug info: {e}"),
+            Self::WrongArchitecture(a) => write!(f, "ELF is built for {a:?}, not the TCU (Xtensa)"),
+        }
+    }
+}
+
+impl From<object::read::Error> for DebugElfError {
+    fn from(e: object::read::Error) -> Self {
+        Self::Elf(e)
+    }
+}
+
+impl From<gimli::Error> for DebugElfError {
+    fn from(e: gimli::Error) -> Self {
+        Self::Dwarf(e)
+    }
+}
+
+pub struct DebugElf {
+    dwarf: Context<DwarfReader>,
+    /// Sorted by address
+    symbols: Vec<ElfSymbol>,
+    /// Sorted by name
+    variables: Vec<ElfVariable>,
+    sha256: [u8; 32],
+}
+
+impl DebugElf {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, DebugElfError> {
+        Self::parse(&std::fs::read(path).map_err(DebugElfError::Io)?)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, DebugElfError> {
+        let file = object::File::parse(data)?;
+        if file.architecture() != Architecture::Xtensa {
+            return Err(DebugElfError::WrongArchitecture(file.architecture()));
+        }
+        let endian = if file.is_little_endian() { RunTimeEndian::Little } else { RunTimeEndian::Big };
+        let dwarf = gimli::Dwarf::load(|id| {
+            let data = file
+                .section_by_name(id.name())
+                .and_then(|s| s.uncompressed_data().ok())
+                .unwrap_or(Cow::Borrowed(&[]));
+            Ok::<_, gimli::Error>(DwarfReader::new(Arc::from(&*data), endian))
+        })?;
+
+        let mut symbols: Vec<ElfSymbol> = file
+            .symbols()
+            .filter_map(|s| {
+                let kind = match s.kind() {
+                    SymbolKind::Text => ElfSymbolKind::Function,
+                    SymbolKind::Data => ElfSymbolKind::Data,
+                    _ => return None,
+                };
+                let name = s.name().ok().filter(|n| !n.is_empty())?;
+                Some(ElfSymbol {
+                    name: addr2line::demangle_auto(Cow::from(name), None).into_owned(),
+                    address: s.address() as u32,
+                    size: s.size() as u32,
+                    kind,
+                })
+            })
+            .collect();
+        symbols.sort_by_key(|s| s.address);
+        let types = variable_types(&dwarf);
+        let mut variables: Vec<ElfVariable> = symbols
+            .iter()
+            .filter(|s| s.kind == ElfSymbolKind::Data)
+            .map(|s| {
+                let ty = types.get(&s.address).cloned().unwrap_or_default();
+                ElfVariable { name: s.name.clone(), address: s.address, size: s.size, type_name: ty.name, value_type: ty.value_type }
+            })
+            .collect();
+        variables.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Self { dwarf: Context::from_dwarf(dwarf)?, symbols, variables, sha256: Sha256::digest(data).into() })
+    }
+
+    /// Global and static variables, sorted by name
+    pub fn variables(&self) -> &[ElfVariable] {
+        &self.variables
+    }
+
+    pub fn variable(&self, name: &str) -> Option<&ElfVariable> {
+        self.variables.binary_search_by(|v| v.name.as_str().cmp(name)).ok().map(|idx| &self.variables[idx])
+    }
+
+    /// True if this is the ELF of the firmware with this header
+    pub fn matches_firmware(&self, header: &FirmwareHeader) -> bool {
+        self.sha256 == header.get_elf_sha()
+    }
+
+    /// SHA-256 of the ELF file, which the firmware stores in its app descriptor and coredumps
+    pub fn sha256_hex(&self) -> String {
+        self.sha256.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    /// Finds the function containing an address, using the symbol table
+    pub fn function_at(&self, address: u32) -> Option<&ElfSymbol> {
+        let idx = self.symbols.partition_point(|s| s.address <= address);
+        self.symbols[..idx]
+            .iter()
+            .rev()
+            .filter(|s| s.kind == ElfSymbolKind::Function)
+            .find(|s| (address as u64) < s.address as u64 + s.size.max(1) as u64)
+    }
+
+    /// Maps a code address to its source location. Inlined functions are listed first,
+    /// followed by the function they were inlined into
+    pub fn lookup(&self, address: u32) -> Vec<SourceLocation> {
+        let mut ret = Vec::new();
+        if let Ok(mut frames) = self.dwarf.find_frames(address as u64).skip_all_loads() {
+            while let Ok(Some(frame)) = frames.next() {
+                ret.push(SourceLocation {
+                    function: frame.function.and_then(|f| f.demangle().ok().map(|n| n.into_owned())),
+                    file: frame.location.as_ref().and_then(|l| l.file.map(String::from)),
+                    line: frame.location.and_then(|l| l.line),
+                });
+            }
+        }
+        // No DWARF function info, so fall back to the symbol table
+        if ret.last().is_none_or(|l| l.function.is_none()) {
+            if let Some(sym) = self.function_at(address) {
+                match ret.last_mut() {
+                    Some(l) => l.function = Some(sym.name.clone()),
+                    None => ret.push(SourceLocation { function: Some(sym.name.clone()), ..Default::default() }),
+                }
+            }
+        }
+        ret
+    }
+}
+
+/// Types of the variables in the debug info which have a fixed address, keyed by address.
+/// Types are only extra information, so units and variables with debug info that cannot be read are skipped
+fn variable_types(dwarf: &gimli::Dwarf<DwarfReader>) -> HashMap<u32, DwarfType> {
+    let mut ret = HashMap::new();
+    let mut units = dwarf.units();
+    while let Ok(Some(header)) = units.next() {
+        let Ok(unit) = dwarf.unit(header) else {
+            continue;
+        };
+        let mut entries = unit.entries();
+        while let Ok(Some((_, entry))) = entries.next_dfs() {
+            if let Ok(Some((address, ty))) = variable_type(dwarf, &unit, entry) {
+                ret.insert(address, ty);
+            }
+        }
+    }
+    ret
+}
+
+/// Address and type of a variable entry, if it is a variable at a fixed address
+fn variable_type(
+    dwarf: &gimli::Dwarf<DwarfReader>,
+    unit: &Unit<DwarfReader>,
+    entry: &gimli::DebuggingInformationEntry<DwarfReader>,
+) -> Result<Option<(u32, DwarfType)>, gimli::Error> {
+    if entry.tag() != gimli::DW_TAG_variable {
+        return Ok(None);
+    }
+    let Some(AttributeValue::Exprloc(expr)) = entry.attr_value(gimli::DW_AT_location)? else {
+        return Ok(None);
+    };
+    // Only variables at a fixed address, rather than on the stack or in a register
+    let mut ops = expr.operations(unit.encoding());
+    let address = match (ops.next()?, ops.next()?) {
+        (Some(gimli::Operation::Address { address }), None) => address as u32,
+        _ => return Ok(None),
+    };
+    // Definitions of variables declared in a class or namespace only point to the declaration
+    let ty = match (entry.attr_value(gimli::DW_AT_type)?, entry.attr_value(gimli::DW_AT_specification)?) {
+        (Some(AttributeValue::UnitRef(ty)), _) => ty,
+        (None, Some(AttributeValue::UnitRef(decl))) => match unit.entry(decl)?.attr_value(gimli::DW_AT_type)? {
+            Some(AttributeValue::UnitRef(ty)) => ty,
+            _ => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some((address, resolve_type(dwarf, unit, ty)?)))
+}
+
+/// Follows typedefs and qualifiers to find how a type is stored. The name is that of the outermost named type
+fn resolve_type(dwarf: &gimli::Dwarf<DwarfReader>, unit: &Unit<DwarfReader>, mut offset: UnitOffset) -> Result<DwarfType, gimli::Error> {
+    let mut ret = DwarfType::default();
+    // Limit the depth, in case of a loop in corrupt debug info
+    for _ in 0..16 {
+        let entry = unit.entry(offset)?;
+        if ret.name.is_none() {
+            if let Some(name) = entry.attr_value(gimli::DW_AT_name)? {
+                ret.name = Some(dwarf.attr_string(unit, name)?.to_string_lossy()?.into_owned());
+            }
+        }
+        let size = match entry.attr_value(gimli::DW_AT_byte_size)? {
+            Some(v) => v.udata_value(),
+            None => None,
+        };
+        match entry.tag() {
+            gimli::DW_TAG_typedef | gimli::DW_TAG_const_type | gimli::DW_TAG_volatile_type => {
+                match entry.attr_value(gimli::DW_AT_type)? {
+                    Some(AttributeValue::UnitRef(next)) => offset = next,
+                    _ => break,
+                }
+                continue;
+            }
+            gimli::DW_TAG_base_type => {
+                let encoding = match entry.attr_value(gimli::DW_AT_encoding)? {
+                    Some(AttributeValue::Encoding(e)) => Some(e),
+                    _ => None,
+                };
+                ret.value_type = match (encoding, size) {
+                    (Some(gimli::DW_ATE_float), Some(4)) => Some(MemoryValueType::F32),
+                    (Some(gimli::DW_ATE_signed | gimli::DW_ATE_signed_char), Some(s)) => signed_type(s),
+                    (Some(_), Some(s)) => unsigned_type(s),
+                    _ => None,
+                };
+            }
+            gimli::DW_TAG_enumeration_type => ret.value_type = size.and_then(unsigned_type),
+            gimli::DW_TAG_pointer_type => {
+                ret.name.get_or_insert("pointer".into());
+                ret.value_type = Some(MemoryValueType::U32);
+            }
+            _ => {}
+        }
+        break;
+    }
+    Ok(ret)
+}
+
+fn signed_type(size: u64) -> Option<MemoryValueType> {
+    match size {
+        1 => Some(MemoryValueType::I8),
+        2 => Some(MemoryValueType::I16),
+        4 => Some(MemoryValueType::I32),
+        _ => None,
+    }
+}
+
+fn unsigned_type(size: u64) -> Option<MemoryValueType> {
+    match size {
+        1 => Some(MemoryValueType::U8),
+        2 => Some(MemoryValueType::U16),
+        4 => Some(MemoryValueType::U32),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub mod test_debug_elf {
+    use object::{
+        write::{self, StandardSection, Symbol, SymbolSection},
+        BinaryFormat, Endianness, SymbolFlags, SymbolScope,
+    };
+
+    use super::*;
+    use crate::hw::coredump::{CoreDump, XtensaRegisters};
+
+    fn test_elf(arch: Architecture) -> Vec<u8> {
+        let mut obj = write::Object::new(BinaryFormat::Elf, arch, Endianness::Little);
+        let text = obj.section_id(StandardSection::Text);
+        obj.append_section_data(text, &[0; 0x40], 4);
+        let data = obj.section_id(StandardSection::Data);
+        obj.append_section_data(data, &[0; 0x10], 4);
+        for (name, value, size, kind, section) in [
+            ("app_main", 0x0, 0x10, SymbolKind::Text, text),
+            ("_Z10shift_geari", 0x10, 0x20, SymbolKind::Text, text),
+            // Ends past the 32 bit address space
+            ("wrap_around", 0xFFFF_FFF0, 0x20, SymbolKind::Text, text),
+            ("gear_counter", 0x0, 0x4, SymbolKind::Data, data),
+        ] {
+            obj.add_symbol(Symbol {
+                name: name.as_bytes().to_vec(),
+                value,
+                size,
+                kind,
+                scope: SymbolScope::Linkage,
+                weak: false,
+                section: SymbolSection::Section(section),
+                flags: SymbolFlags::None,
+            });
+        }
+        obj.write().unwrap()
+    }
+
+    #[test]
+    pub fn test_symbols() {
+        let elf = DebugElf::parse(&test_elf(Architecture::Xtensa)).unwrap();
+        assert_eq!(elf.function_at(0x4).unwrap().name, "app_main");
+        assert_eq!(elf.function_at(0x2F).unwrap().name, "shift_gear(int)");
+        assert_eq!(elf.function_at(0x30), None);
+        assert_eq!(elf.function_at(0xFFFF_FFFF).unwrap().name, "wrap_around");
+        // No debug info, so only the function name is known
+        assert_eq!(
+            elf.lookup(0x18),
+            vec![SourceLocation { function: Some("shift_gear(int)".into()), file: None, line: None }]
+        );
+        assert_eq!(elf.lookup(0x18)[0].to_string(), "shift_gear(int)");
+        assert_eq!(elf.lookup(0x100), vec![]);
+
+        assert!(matches!(
+            DebugElf::parse(&test_elf(Architecture::Arm)),
+            Err(DebugElfError::WrongArchitecture(Architecture::Arm))
+        ));
+        assert!(matches!(DebugElf::parse(&[0; 64]), Err(DebugElfError::Elf(_))));
+    }
+
+    /// ELF with debug info for the variables `engine_rpm` (volatile uint16_t), `atf_temp` (float), `shift_state`
+    /// (A structure) and `fw_name` (A string in flash), and a symbol without debug info, `no_debug_info`.
+    /// With `broken`, there is also `broken`, a variable with a location that cannot be decoded
+    fn test_elf_with_variables(broken: bool) -> Vec<u8> {
+        use gimli::write::{Address, AttributeValue, DwarfUnit, EndianVec, Expression, Sections};
+
+        let mut obj = write::Object::new(BinaryFormat::Elf, Architecture::Xtensa, Endianness::Little);
+        let mut dwarf = DwarfUnit::new(gimli::Encoding { format: gimli::Format::Dwarf32, version: 4, address_size: 4 });
+        let root = dwarf.unit.root();
+        let mut add = |tag, attrs: Vec<(gimli::DwAt, AttributeValue)>| {
+            let id = dwarf.unit.add(root, tag);
+            for (name, value) in attrs {
+                dwarf.unit.get_mut(id).set(name, value);
+            }
+            id
+        };
+        let name = |n: &str| (gimli::DW_AT_name, AttributeValue::String(n.as_bytes().to_vec()));
+        let ushort = add(gimli::DW_TAG_base_type, vec![
+            name("short unsigned int"),
+            (gimli::DW_AT_encoding, AttributeValue::Encoding(gimli::DW_ATE_unsigned)),
+            (gimli::DW_AT_byte_size, AttributeValue::Udata(2)),
+        ]);
+        let float = add(gimli::DW_TAG_base_type, vec![
+            name("float"),
+            (gimli::DW_AT_encoding, AttributeValue::Encoding(gimli::DW_ATE_float)),
+            (gimli::DW_AT_byte_size, AttributeValue::Udata(4)),
+        ]);
+        let uint16 = add(gimli::DW_TAG_typedef, vec![name("uint16_t"), (gimli::DW_AT_type, AttributeValue::UnitRef(ushort))]);
+        let volatile = add(gimli::DW_TAG_volatile_type, vec![(gimli::DW_AT_type, AttributeValue::UnitRef(uint16))]);
+        let shift_state = add(gimli::DW_TAG_structure_type, vec![name("ShiftState"), (gimli::DW_AT_byte_size, AttributeValue::Udata(12))]);
+        let char_array = add(gimli::DW_TAG_array_type, vec![]);
+        for (var, ty, address, size) in [
+            ("engine_rpm", volatile, 0x3FFB_2000, 2),
+            ("atf_temp", float, 0x3FFB_2004, 4),
+            ("shift_state", shift_state, 0x3F80_0100, 12),
+            ("fw_name", char_array, 0x3F40_0020, 32),
+        ] {
+            let mut location = Expression::new();
+            location.op_addr(Address::Constant(address));
+            add(gimli::DW_TAG_variable, vec![
+                name(var),
+                (gimli::DW_AT_type, AttributeValue::UnitRef(ty)),
+                (gimli::DW_AT_location, AttributeValue::Exprloc(location)),
+            ]);
+            add_data_symbol(&mut obj, var, address, size);
+        }
+        add_data_symbol(&mut obj, "no_debug_info", 0x3FFB_2008, 4);
+        if broken {
+            add(gimli::DW_TAG_variable, vec![
+                name("broken"),
+                (gimli::DW_AT_type, AttributeValue::UnitRef(float)),
+                // Not a valid DWARF operation
+                (gimli::DW_AT_location, AttributeValue::Exprloc(Expression::raw(vec![0xFF]))),
+            ]);
+            add_data_symbol(&mut obj, "broken", 0x3F80_0200, 4);
+        }
+
+        let mut sections = Sections::new(EndianVec::new(gimli::LittleEndian));
+        dwarf.write(&mut sections).unwrap();
+        sections
+            .for_each(|id, data| {
+                if !data.slice().is_empty() {
+                    let section = obj.add_section(Vec::new(), id.name().as_bytes().to_vec(), object::SectionKind::Debug);
+                    obj.append_section_data(section, data.slice(), 1);
+                }
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        obj.write().unwrap()
+    }
+
+    fn add_data_symbol(obj: &mut write::Object, name: &str, address: u64, size: u64) {
+        obj.add_symbol(Symbol {
+            name: name.as_bytes().to_vec(),
+            value: address,
+            size,
+            kind: SymbolKind::Data,
+            scope: SymbolScope::Linkage,
+            weak: false,
+            section: SymbolSection::Absolute,
+            flags: SymbolFlags::None,
+        });
+    }
+
+    #[test]
+    pub fn test_variables() {
+        let elf = DebugElf::parse(&test_elf_with_variables(false)).unwrap();
+        let names: Vec<&str> = elf.variables().iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, ["atf_temp", "engine_rpm", "fw_name", "no_debug_info", "shift_state"]);
+
+        let rpm = elf.variable("engine_rpm").unwrap();
+        assert_eq!(rpm.address, 0x3FFB_2000);
+        assert_eq!(rpm.size, 2);
+        assert_eq!(rpm.type_name.as_deref(), Some("uint16_t"));
+        assert_eq!(rpm.value_type, Some(MemoryValueType::U16));
+        assert_eq!(rpm.memory_location(), Some((MemoryRegion::Sram2, 0x4000)));
+
+        assert_eq!(elf.variable("atf_temp").unwrap().value_type, Some(MemoryValueType::F32));
+        let state = elf.variable("shift_state").unwrap();
+        assert_eq!((state.type_name.as_deref(), state.value_type), (Some("ShiftState"), None));
+        assert_eq!(state.memory_location(), Some((MemoryRegion::Psram, 0x100)));
+        // Constants in flash can not be read through the memory regions
+        assert_eq!(elf.variable("fw_name").unwrap().memory_location(), None);
+        let no_debug = elf.variable("no_debug_info").unwrap();
+        assert_eq!((no_debug.type_name.as_deref(), no_debug.value_type), (None, None));
+        assert!(elf.variable("app_main").is_none());
+    }
+
+    #[test]
+    pub fn test_variables_broken_debug_info() {
+        // Types are only extra information, so the ELF still loads
+        let elf = DebugElf::parse(&test_elf_with_variables(true)).unwrap();
+        assert_eq!(elf.variable("engine_rpm").unwrap().value_type, Some(MemoryValueType::U16));
+        let broken = elf.variable("broken").unwrap();
+        assert_eq!((broken.type_name.as_deref(), broken.value_type), (None, None));
+    }
+
+    #[test]
+    pub fn test_matches_coredump() {
+        let elf = DebugElf::parse(&test_elf(Architecture::Xtensa)).unwrap();
+        let dump = |sha: &str| {
+            CoreDump::parse(&CoreDump::build(sha, 0, &[], &[(0, XtensaRegisters::default())], &[])).unwrap()
+        };
+        // Firmware only stores the start of the hash
+        assert!(dump(&elf.sha256_hex()[..16]).matches_elf(&elf));
+        assert!(!dump("0123456789abcdef").matches_elf(&elf));
+        assert!(!dump("").matches_elf(&elf));
+    }
+}
@@ -0,0 +1,283 @@
+//! ESP32 application image format
+//!
+//! An app image (As produced by esptool / idf.py) is laid out as:
+//! * [EspImageHeader] (24 bytes)
+//! * Segments, each an 8 byte [EspSegmentHeader] followed by its data.
+//!   The first segment starts with the app descriptor ([super::firmware::FirmwareHeader])
+//! * Zero padding, then a 1 byte XOR checksum of all segment data, as the last byte of a 16 byte block
+//! * Optionally, a SHA-256 hash of everything before it
+//!
+//! The TCU only checks this once the whole image has been written to the OTA partition,
+//! so the image is validated before starting an update, which would erase the partition.
+
+use packed_struct::{prelude::PackedStruct, PackedStructSlice};
+use sha2::{Digest, Sha256};
+
+pub const ESP_IMAGE_MAGIC: u8 = 0xE9;
+/// Chip ID of the ESP32 (The TCU's chip)
+pub const ESP_CHIP_ID_ESP32: u16 = 0x0000;
+pub const ESP_IMAGE_MAX_SEGMENTS: u8 = 16;
+const CHECKSUM_SEED: u8 = 0xEF;
+const HASH_LEN: usize = 32;
+
+#[derive(PackedStruct, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EspImageHeader {
+    pub magic: u8,
+    pub segment_count: u8,
+    pub spi_mode: u8,
+    pub spi_speed_size: u8,
+    #[packed_field(endian = "lsb")]
+    pub entry_addr: u32,
+    pub wp_pin: u8,
+    pub spi_pin_drv: [u8; 3],
+    #[packed_field(endian = "lsb")]
+    pub chip_id: u16,
+    pub min_chip_rev: u8,
+    #[packed_field(endian = "lsb")]
+    pub min_chip_rev_full: u16,
+    #[packed_field(endian = "lsb")]
+    pub max_chip_rev_full: u16,
+    pub reserved: [u8; 4],
+    pub hash_appended: u8,
+}
+
+#[derive(PackedStruct, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EspSegmentHeader {
+    #[packed_field(endian = "lsb")]
+    pub load_addr: u32,
+    #[packed_field(endian = "lsb")]
+    pub data_len: u32,
+}
+
+const IMAGE_HEADER_SIZE: usize = 24;
+const SEGMENT_HEADER_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EspSegment {
+    pub load_addr: u32,
+    /// Offset of the segment data within the image
+    pub offset: usize,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EspImage {
+    pub header: EspImageHeader,
+    pub segments: Vec<EspSegment>,
+    pub checksum: u8,
+    pub sha256: Option<[u8; HASH_LEN]>,
+    /// Length of the image, including checksum and hash
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EspImageError {
+    /// Image is shorter than the image header
+    TooShort(usize),
+    InvalidMagic(u8),
+    WrongChip(u16),
+    InvalidSegmentCount(u8),
+    /// Segment index, offset of the segment header, bytes needed and bytes left in the image
+    SegmentTruncated { segment: usize, offset: usize, needed: usize, available: usize },
+    ChecksumMissing,
+    ChecksumMismatch { stored: u8, calculated: u8 },
+    HashMissing,
+    HashMismatch { stored: [u8; HASH_LEN], calculated: [u8; HASH_LEN] },
+}
+
+fn hex(b: &[u8]) -> String {
+    b.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+impl std::fmt::Display for EspImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooShort(len) => write!(f, "Image is only {len} bytes long, too short for an image header"),
+            Self::InvalidMagic(m) => {
+                write!(f, "Invalid image magic 0x{m:02X} (Expected 0x{ESP_IMAGE_MAGIC:02X}), not an ESP32 app image")
+            }
+            Self::WrongChip(id) => write!(f, "Image is built for chip ID 0x{id:04X}, but the TCU is an ESP32 (0x{ESP_CHIP_ID_ESP32:04X})"),
+            Self::InvalidSegmentCount(c) => {
+                write!(f, "Image has {c} segments (Must be between 1 and {ESP_IMAGE_MAX_SEGMENTS})")
+            }
+            Self::SegmentTruncated { segment, offset, needed, available } => write!(
+                f,
+                "Segment {segment} at offset 0x{offset:X} is truncated. It needs {needed} bytes, but only {available} bytes are left"
+            ),
+            Self::ChecksumMissing => write!(f, "Image is truncated, checksum is missing"),
+            Self::ChecksumMismatch { stored, calculated } => write!(
+                f,
+                "Checksum mismatch. Image stores 0x{stored:02X}, calculated 0x{calculated:02X}. The image is corrupt"
+            ),
+            Self::HashMissing => write!(f, "Image is truncated, SHA-256 hash is missing"),
+            Self::HashMismatch { stored, calculated } => write!(
+                f,
+                "SHA-256 mismatch. Image stores {}, calculated {}. The image is corrupt",
+                hex(stored),
+                hex(calculated)
+            ),
+        }
+    }
+}
+
+impl EspImage {
+    /// Parses and validates an app image. Data after the image is ignored
+    pub fn parse(buf: &[u8]) -> Result<Self, EspImageError> {
+        if buf.len() < IMAGE_HEADER_SIZE {
+            return Err(EspImageError::TooShort(buf.len()));
+        }
+        let header = EspImageHeader::unpack_from_slice(&buf[..IMAGE_HEADER_SIZE])
+            .map_err(|_| EspImageError::TooShort(buf.len()))?;
+        if header.magic != ESP_IMAGE_MAGIC {
+            return Err(EspImageError::InvalidMagic(header.magic));
+        }
+        if header.chip_id != ESP_CHIP_ID_ESP32 {
+            return Err(EspImageError::WrongChip(header.chip_id));
+        }
+        if header.segment_count == 0 || header.segment_count > ESP_IMAGE_MAX_SEGMENTS {
+            return Err(EspImageError::InvalidSegmentCount(header.segment_count));
+        }
+
+        let mut segments = Vec::with_capacity(header.segment_count as usize);
+        let mut checksum = CHECKSUM_SEED;
+        let mut pos = IMAGE_HEADER_SIZE;
+        for segment in 0..header.segment_count as usize {
+            let truncated = |needed: usize| EspImageError::SegmentTruncated {
+                segment,
+                offset: pos,
+                needed,
+                available: buf.len() - pos,
+            };
+            let seg_header = buf
+                .get(pos..pos + SEGMENT_HEADER_SIZE)
+                .and_then(|b| EspSegmentHeader::unpack_from_slice(b).ok())
+                .ok_or_else(|| truncated(SEGMENT_HEADER_SIZE))?;
+            let len = seg_header.data_len as usize;
+            let data = buf
+                .get(pos + SEGMENT_HEADER_SIZE..pos + SEGMENT_HEADER_SIZE + len)
+                .ok_or_else(|| truncated(SEGMENT_HEADER_SIZE + len))?;
+            checksum = data.iter().fold(checksum, |acc, b| acc ^ b);
+            segments.push(EspSegment { load_addr: seg_header.load_addr, offset: pos + SEGMENT_HEADER_SIZE, len });
+            pos += SEGMENT_HEADER_SIZE + len;
+        }
+
+        // Checksum is the last byte of a 16 byte block
+        let checksum_pos = pos + (15 - pos % 16);
+        let stored = *buf.get(checksum_pos).ok_or(EspImageError::ChecksumMissing)?;
+        if stored != checksum {
+            return Err(EspImageError::ChecksumMismatch { stored, calculated: checksum });
+        }
+        let mut len = checksum_pos + 1;
+
+        let sha256 = if header.hash_appended == 1 {
+            let stored: [u8; HASH_LEN] = buf
+                .get(len..len + HASH_LEN)
+                .ok_or(EspImageError::HashMissing)?
+                .try_into()
+                .unwrap();
+            let calculated: [u8; HASH_LEN] = Sha256::digest(&buf[..len]).into();
+            if stored != calculated {
+                return Err(EspImageError::HashMismatch { stored, calculated });
+            }
+            len += HASH_LEN;
+            Some(stored)
+        } else {
+            None
+        };
+
+        Ok(Self { header, segments, checksum, sha256, len })
+    }
+
+    /// Builds an image containing the given segments. The hash is always appended
+    pub fn build(entry_addr: u32, segments: &[(u32, &[u8])]) -> Vec<u8> {
+        let header = EspImageHeader {
+            magic: ESP_IMAGE_MAGIC,
+            segment_count: segments.len() as u8,
+            spi_mode: 0x02,
+            spi_speed_size: 0x20,
+            entry_addr,
+            wp_pin: 0xEE,
+            spi_pin_drv: [0; 3],
+            chip_id: ESP_CHIP_ID_ESP32,
+            min_chip_rev: 0,
+            min_chip_rev_full: 0,
+            max_chip_rev_full: 399,
+            reserved: [0; 4],
+            hash_appended: 1,
+        };
+        let mut ret = header.pack().unwrap().to_vec();
+        let mut checksum = CHECKSUM_SEED;
+        for (load_addr, data) in segments {
+            let seg = EspSegmentHeader { load_addr: *load_addr, data_len: data.len() as u32 };
+            ret.extend_from_slice(&seg.pack().unwrap());
+            ret.extend_from_slice(data);
+            checksum = data.iter().fold(checksum, |acc, b| acc ^ b);
+        }
+        ret.resize(ret.len() + (15 - ret.len() % 16), 0);
+        ret.push(checksum);
+        let hash: [u8; HASH_LEN] = Sha256::digest(&ret).into();
+        ret.extend_from_slice(&hash);
+        ret
+    }
+}
+
+#[cfg(test)]
+pub mod test_esp_image {
+    use super::{EspImage, EspImageError};
+
+    fn test_image() -> Vec<u8> {
+        let app: Vec<u8> = (0..1000u32).map(|x| (x * 7) as u8).collect();
+        EspImage::build(0x400D1234, &[(0x3F400020, &app), (0x3FFB0000, &[1, 2, 3]), (0x400D0020, &[0xAA; 64])])
+    }
+
+    #[test]
+    pub fn test_valid_image() {
+        let image = test_image();
+        let parsed = EspImage::parse(&image).unwrap();
+        assert_eq!(parsed.segments.len(), 3);
+        assert_eq!(parsed.segments[0].offset, 32);
+        assert_eq!(parsed.segments[1].len, 3);
+        assert_eq!(parsed.len, image.len());
+        assert!(parsed.sha256.is_some());
+        // Padding after the image (Such as a partition dump) is ignored
+        let mut padded = image.clone();
+        padded.resize(image.len() + 4096, 0xFF);
+        assert_eq!(EspImage::parse(&padded).unwrap(), parsed);
+    }
+
+    #[test]
+    pub fn test_truncated_image() {
+        let image = test_image();
+        for len in 0..image.len() {
+            assert!(EspImage::parse(&image[..len]).is_err(), "Image truncated to {len} bytes was accepted");
+        }
+        assert_eq!(EspImage::parse(&image[..10]), Err(EspImageError::TooShort(10)));
+        assert!(matches!(
+            EspImage::parse(&image[..500]),
+            Err(EspImageError::SegmentTruncated { segment: 0, offset: 24, needed: 1008, available: 476 })
+        ));
+        assert_eq!(EspImage::parse(&image[..image.len() - 1]), Err(EspImageError::HashMissing));
+    }
+
+    #[test]
+    pub fn test_corrupt_image() {
+        let image = test_image();
+        let mut bad = image.clone();
+        bad[0] = 0xEA;
+        assert_eq!(EspImage::parse(&bad), Err(EspImageError::InvalidMagic(0xEA)));
+        let mut bad = image.clone();
+        bad[12] = 0x09; // ESP32-S3
+        assert_eq!(EspImage::parse(&bad), Err(EspImageError::WrongChip(0x0009)));
+        let mut bad = image.clone();
+        bad[1] = 0;
+        assert_eq!(EspImage::parse(&bad), Err(EspImageError::InvalidSegmentCount(0)));
+        // Flipping a data bit breaks the checksum
+        let mut bad = image.clone();
+        bad[100] ^= 0x01;
+        assert!(matches!(EspImage::parse(&bad), Err(EspImageError::ChecksumMismatch { .. })));
+        // Swapping two data bytes keeps the checksum, but not the hash
+        let mut bad = image.clone();
+        bad.swap(100, 101);
+        assert!(matches!(EspImage::parse(&bad), Err(EspImageError::HashMismatch { .. })));
+    }
+}
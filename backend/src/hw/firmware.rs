@@ -4,6 +4,8 @@ use chrono::NaiveDateTime;
 use packed_struct::{prelude::PackedStruct, PackedStructSlice};
 use static_assertions::assert_eq_size;
 
+use super::esp_image::{EspImage, EspImageError};
+
 const HEADER_SIZE: usize = 256;
 const HEADER_MAGIC: [u8; 4] = [0x32, 0x54, 0xCD, 0xAB];
 assert_eq_size!([u8; HEADER_SIZE], FirmwareHeader);
@@ -64,52 +66,183 @@ impl FirmwareHeader {
         let str = format!("{} {}", self.get_date(), self.get_time().split("+").next().unwrap());
         NaiveDateTime::parse_from_str(&str, "%d %b %Y %H:%M:%S").ok()
     }
+
+    /// Anti-rollback version. The TCU will not boot an image with a lower secure version
+    pub fn get_secure_version(&self) -> u32 {
+        self.secure_version
+    }
+
+    /// SHA-256 of the ELF file the image was built from
+    pub fn get_elf_sha(&self) -> [u8; 32] {
+        self.app_elf_sha
+    }
+
+    pub fn get_branch(&self) -> FirmwareBranch {
+        let version = self.get_version();
+        if version.contains("main") {
+            FirmwareBranch::Main
+        } else if version.contains("dev") {
+            FirmwareBranch::Dev
+        } else {
+            FirmwareBranch::Other
+        }
+    }
+
+    /// Built from a tree with uncommitted changes
+    pub fn is_dirty(&self) -> bool {
+        self.get_version().contains("dirty")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirmwareBranch {
+    Main,
+    Dev,
+    Other,
+}
+
+impl std::fmt::Display for FirmwareBranch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FirmwareBranch::Main => "main",
+            FirmwareBranch::Dev => "dev",
+            FirmwareBranch::Other => "other",
+        })
+    }
+}
+
+/// Reasons not to flash a firmware, found by comparing it with the running firmware
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FirmwareIssue {
+    /// The TCU is already running this build
+    Identical,
+    /// The TCU would refuse to boot the new firmware
+    SecureVersionDowngrade { running: u32, new: u32 },
+    /// New firmware was built before the running firmware
+    OlderBuild,
+    BranchChange { running: FirmwareBranch, new: FirmwareBranch },
+    /// New firmware was built with uncommitted changes
+    Dirty,
+}
+
+impl FirmwareIssue {
+    /// Flashing is refused, rather than just warned about
+    pub fn is_blocking(&self) -> bool {
+        matches!(self, Self::SecureVersionDowngrade { .. })
+    }
+}
+
+impl std::fmt::Display for FirmwareIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Identical => write!(f, "The TCU is already running this firmware"),
+            Self::SecureVersionDowngrade { running, new } => write!(
+                f,
+                "Secure version would be downgraded from {running} to {new}. The TCU would refuse to boot this firmware"
+            ),
+            Self::OlderBuild => write!(f, "The new firmware is older than the current firmware! This can cause bootloops!"),
+            Self::BranchChange { running, new } => write!(
+                f,
+                "Firmware branch changes from {running} to {new}. Settings and calibrations may not be compatible"
+            ),
+            Self::Dirty => write!(f, "The new firmware was built with uncommitted changes, and is potentially unstable"),
+        }
+    }
+}
+
+/// Comparison of a firmware image with the firmware running on the TCU
+#[derive(Debug, Clone)]
+pub struct FirmwareComparison {
+    pub running: FirmwareHeader,
+    pub new: FirmwareHeader,
+    pub issues: Vec<FirmwareIssue>,
+}
+
+impl FirmwareComparison {
+    pub fn new(running: &FirmwareHeader, new: &FirmwareHeader) -> Self {
+        let mut issues = Vec::new();
+        let elf_sha = new.get_elf_sha();
+        if elf_sha != [0; 32] && elf_sha == running.get_elf_sha() {
+            issues.push(FirmwareIssue::Identical);
+        }
+        if new.get_secure_version() < running.get_secure_version() {
+            issues.push(FirmwareIssue::SecureVersionDowngrade {
+                running: running.get_secure_version(),
+                new: new.get_secure_version(),
+            });
+        }
+        if let (Some(old_ts), Some(new_ts)) = (running.get_build_timestamp(), new.get_build_timestamp()) {
+            if old_ts > new_ts {
+                issues.push(FirmwareIssue::OlderBuild);
+            }
+        }
+        if running.get_branch() != new.get_branch() {
+            issues.push(FirmwareIssue::BranchChange { running: running.get_branch(), new: new.get_branch() });
+        }
+        if new.is_dirty() {
+            issues.push(FirmwareIssue::Dirty);
+        }
+        Self { running: *running, new: *new, issues }
+    }
+
+    pub fn is_identical(&self) -> bool {
+        self.issues.contains(&FirmwareIssue::Identical)
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        self.issues.iter().any(|i| i.is_blocking())
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Firmware {
     pub raw: Vec<u8>,
     pub header: FirmwareHeader,
+    pub image: EspImage,
 }
 
 #[derive(Debug)]
 pub enum FirmwareLoadError {
     NotValid(String),
+    InvalidImage(EspImageError),
     IoError(std::io::Error),
 }
 
+impl std::fmt::Display for FirmwareLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FirmwareLoadError::NotValid(e) => write!(f, "Invalid firmware: {e}"),
+            FirmwareLoadError::InvalidImage(e) => write!(f, "Invalid firmware image: {e}"),
+            FirmwareLoadError::IoError(e) => write!(f, "IO error: {e}"),
+        }
+    }
+}
+
+impl From<EspImageError> for FirmwareLoadError {
+    fn from(e: EspImageError) -> Self {
+        Self::InvalidImage(e)
+    }
+}
+
 impl From<std::io::Error> for FirmwareLoadError {
     fn from(f: std::io::Error) -> Self {
         Self::IoError(f)
     }
 }
 
+/// Loads an app image, validating the whole image (See [EspImage::parse]) so that
+/// truncated or corrupt images are rejected before an OTA update is started
 pub fn load_binary(buf: Vec<u8>) -> FirwmareLoadResult<Firmware> {
-    // Todo find a nicer way to do this!
-    let mut header_start_idx = 0;
-    loop {
-        let tmp = &buf[header_start_idx..];
-        if tmp.len() < HEADER_MAGIC.len() || header_start_idx > 50 {
-            return Err(FirmwareLoadError::NotValid(
-                "Could not find header magic".into(),
-            ));
-        }
-        if tmp[..HEADER_MAGIC.len()] == HEADER_MAGIC {
-            break; // Found!
-        }
-        header_start_idx += 1;
-    }
-
-    if buf[header_start_idx..].len() < HEADER_SIZE {
+    let image = EspImage::parse(&buf)?;
+    // App descriptor is at the start of the first segment
+    let app_desc = &buf[image.segments[0].offset..];
+    if image.segments[0].len < HEADER_SIZE || app_desc[..HEADER_MAGIC.len()] != HEADER_MAGIC {
         return Err(FirmwareLoadError::NotValid(
-            "Could not find header magic".into(),
+            "Could not find app descriptor in the first segment".into(),
         ));
     }
-    // Ok, read the header
-    let header: FirmwareHeader =
-        FirmwareHeader::unpack_from_slice(&buf[header_start_idx..header_start_idx + HEADER_SIZE])
-            .unwrap();
-    Ok(Firmware { raw: buf, header })
+    let header: FirmwareHeader = FirmwareHeader::unpack_from_slice(&app_desc[..HEADER_SIZE]).unwrap();
+    Ok(Firmware { raw: buf, header, image })
 }
 
 pub fn load_binary_from_path(path: String) -> FirwmareLoadResult<Firmware> {
@@ -118,3 +251,47 @@ pub fn load_binary_from_path(path: String) -> FirwmareLoadResult<Firmware> {
     f.read_to_end(&mut buf)?;
     load_binary(buf)
 }
+
+#[cfg(test)]
+pub mod test_firmware {
+    use packed_struct::PackedStructSlice;
+
+    use crate::hw::sim_ecu::sim_firmware_header;
+
+    use super::{FirmwareBranch, FirmwareComparison, FirmwareHeader, FirmwareIssue};
+
+    fn header(version: &str, date: &str, secure_version: u32, sha: u8) -> FirmwareHeader {
+        let raw = sim_firmware_header(version, date, "12:00:00", secure_version, [sha; 32]);
+        FirmwareHeader::unpack_from_slice(&raw).unwrap()
+    }
+
+    #[test]
+    pub fn test_compare_firmware() {
+        let running = header("main-1.5.0", "17 Oct 2026", 1, 0xAA);
+        assert_eq!(running.get_branch(), FirmwareBranch::Main);
+
+        let cmp = FirmwareComparison::new(&running, &header("main-1.6.0", "18 Oct 2026", 1, 0xBB));
+        assert!(cmp.issues.is_empty());
+
+        let cmp = FirmwareComparison::new(&running, &running);
+        assert_eq!(cmp.issues, vec![FirmwareIssue::Identical]);
+        assert!(cmp.is_identical() && !cmp.is_blocked());
+
+        let cmp = FirmwareComparison::new(&running, &header("main-1.4.0", "17 Sep 2026", 0, 0xBB));
+        assert_eq!(
+            cmp.issues,
+            vec![FirmwareIssue::SecureVersionDowngrade { running: 1, new: 0 }, FirmwareIssue::OlderBuild]
+        );
+        assert!(cmp.is_blocked());
+
+        let cmp = FirmwareComparison::new(&running, &header("dev-1.6.0-dirty", "18 Oct 2026", 1, 0xBB));
+        assert_eq!(
+            cmp.issues,
+            vec![
+                FirmwareIssue::BranchChange { running: FirmwareBranch::Main, new: FirmwareBranch::Dev },
+                FirmwareIssue::Dirty
+            ]
+        );
+        assert!(!cmp.is_blocked());
+    }
+}
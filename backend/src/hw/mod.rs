@@ -1,3 +1,12 @@
+pub mod coredump;
+pub mod debug_elf;
+pub mod esp_image;
 pub mod firmware;
+pub mod partition_table;
+pub mod replay;
 pub mod usb;
+pub mod usb_decoder;
 pub mod usb_scanner;
+pub mod sim;
+pub mod sim_ecu;
+pub mod sim_scanner;
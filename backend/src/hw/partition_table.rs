@@ -0,0 +1,100 @@
+//! ESP-IDF partition table
+//!
+//! The table is stored at [PARTITION_TABLE_ADDR] in flash, as a list of 32 byte entries:
+//! * Magic (0xAA, 0x50), type, subtype
+//! * Offset and size (Little endian u32)
+//! * Label (16 bytes, NUL padded)
+//! * Flags (u32)
+//!
+//! The entries are followed by an MD5 entry (Magic 0xEB, 0xEB), then erased flash.
+
+use crate::diag::flash::PartitionInfo;
+
+pub const PARTITION_TABLE_ADDR: u32 = 0x8000;
+pub const PARTITION_TABLE_MAX_LEN: u32 = 0xC00;
+const ENTRY_MAGIC: [u8; 2] = [0xAA, 0x50];
+const ENTRY_SIZE: usize = 32;
+
+pub const PARTITION_TYPE_APP: u8 = 0x00;
+pub const PARTITION_TYPE_DATA: u8 = 0x01;
+pub const PARTITION_SUBTYPE_NVS: u8 = 0x02;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EspPartition {
+    pub label: String,
+    pub ty: u8,
+    pub subtype: u8,
+    pub info: PartitionInfo,
+    pub flags: u32,
+}
+
+impl EspPartition {
+    pub fn new(label: &str, ty: u8, subtype: u8, address: u32, size: u32) -> Self {
+        Self { label: label.to_string(), ty, subtype, info: PartitionInfo { address, size }, flags: 0 }
+    }
+
+    pub fn is_nvs(&self) -> bool {
+        self.ty == PARTITION_TYPE_DATA && self.subtype == PARTITION_SUBTYPE_NVS
+    }
+
+    fn to_bytes(&self) -> [u8; ENTRY_SIZE] {
+        let mut ret = [0; ENTRY_SIZE];
+        ret[0..2].copy_from_slice(&ENTRY_MAGIC);
+        ret[2] = self.ty;
+        ret[3] = self.subtype;
+        ret[4..8].copy_from_slice(&self.info.address.to_le_bytes());
+        ret[8..12].copy_from_slice(&self.info.size.to_le_bytes());
+        let label = &self.label.as_bytes()[..self.label.len().min(15)];
+        ret[12..12 + label.len()].copy_from_slice(label);
+        ret[28..32].copy_from_slice(&self.flags.to_le_bytes());
+        ret
+    }
+}
+
+/// Parses the partition table, stopping at the first entry that is not a partition
+pub fn parse_partition_table(buf: &[u8]) -> Vec<EspPartition> {
+    buf.chunks_exact(ENTRY_SIZE)
+        .take_while(|e| e[0..2] == ENTRY_MAGIC)
+        .map(|e| {
+            let label_len = e[12..28].iter().position(|x| *x == 0).unwrap_or(16);
+            EspPartition {
+                label: String::from_utf8_lossy(&e[12..12 + label_len]).to_string(),
+                ty: e[2],
+                subtype: e[3],
+                info: PartitionInfo {
+                    address: u32::from_le_bytes(e[4..8].try_into().unwrap()),
+                    size: u32::from_le_bytes(e[8..12].try_into().unwrap()),
+                },
+                flags: u32::from_le_bytes(e[28..32].try_into().unwrap()),
+            }
+        })
+        .collect()
+}
+
+/// Builds a partition table. The MD5 entry is not written (ESP-IDF treats it as optional)
+pub fn build_partition_table(partitions: &[EspPartition]) -> Vec<u8> {
+    let mut ret: Vec<u8> = partitions.iter().flat_map(|p| p.to_bytes()).collect();
+    ret.resize(PARTITION_TABLE_MAX_LEN as usize, 0xFF);
+    ret
+}
+
+#[cfg(test)]
+pub mod test_partition_table {
+    use super::*;
+
+    #[test]
+    pub fn test_partition_table() {
+        let partitions = vec![
+            EspPartition::new("nvs", PARTITION_TYPE_DATA, PARTITION_SUBTYPE_NVS, 0x9000, 0x4000),
+            EspPartition::new("ota_0", PARTITION_TYPE_APP, 0x10, 0x10000, 0x180000),
+        ];
+        let mut table = build_partition_table(&partitions);
+        assert_eq!(table.len(), PARTITION_TABLE_MAX_LEN as usize);
+        assert_eq!(parse_partition_table(&table), partitions);
+        assert!(parse_partition_table(&table)[0].is_nvs());
+        // MD5 entry ends the table
+        table[64..66].copy_from_slice(&[0xEB, 0xEB]);
+        assert_eq!(parse_partition_table(&table).len(), 2);
+        assert_eq!(parse_partition_table(&[0xFF; 64]), vec![]);
+    }
+}
@@ -0,0 +1,326 @@
+use ecu_diagnostics::{
+    channel::{CanChannel, ChannelError, ChannelResult, IsoTPChannel, PayloadChannel},
+    hardware::{HardwareCapabilities, HardwareError, HardwareInfo, HardwareResult},
+};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::diag::trace::{load_trace, TraceChannelError, TraceEvent, TraceRecord};
+
+/// One request sent to the ECU, and every response read back for it.
+/// (Multiple responses occur when the ECU replies with ResponsePending)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceExchange {
+    pub request: Vec<u8>,
+    pub responses: Vec<Result<Vec<u8>, TraceChannelError>>,
+}
+
+/// Groups the records of a trace into request/response exchanges
+pub fn trace_to_exchanges(records: &[TraceRecord]) -> Vec<TraceExchange> {
+    let mut ret: Vec<TraceExchange> = Vec::new();
+    for r in records {
+        match &r.event {
+            TraceEvent::Send { data, error: None, .. } => ret.push(TraceExchange {
+                request: data.clone(),
+                responses: vec![],
+            }),
+            TraceEvent::Recv { data, error, .. } => {
+                if let Some(ex) = ret.last_mut() {
+                    ex.responses.push(match error {
+                        Some(e) => Err(e.clone()),
+                        None => Ok(data.clone()),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    ret
+}
+
+#[derive(Debug)]
+struct ReplayState {
+    exchanges: Vec<TraceExchange>,
+    used: Vec<bool>,
+    /// Position of the last exchange replayed
+    cursor: usize,
+    queue: VecDeque<Result<Vec<u8>, TraceChannelError>>,
+    unmatched: Vec<Vec<u8>>,
+}
+
+impl ReplayState {
+    /// Finds the response for a request. Exchanges are replayed in the order
+    /// they were recorded, if a request was already replayed (Such as tester present)
+    /// then the closest previous exchange with the same request is reused.
+    fn find(&mut self, req: &[u8]) -> Option<usize> {
+        let n = self.exchanges.len();
+        let next_unused = (0..n)
+            .map(|i| (self.cursor + i) % n)
+            .find(|i| !self.used[*i] && self.exchanges[*i].request == req);
+        let idx = next_unused.or_else(|| {
+            (0..n)
+                .map(|i| (self.cursor + n - i) % n)
+                .find(|i| self.exchanges[*i].request == req)
+        })?;
+        self.used[idx] = true;
+        self.cursor = idx;
+        Some(idx)
+    }
+}
+
+/// Replays a recorded diagnostic session, answering each request
+/// with the response recorded for it. This includes NRCs and read errors.
+#[derive(Clone)]
+pub struct Nag52Replay {
+    info: HardwareInfo,
+    state: Arc<Mutex<ReplayState>>,
+    tx_id: u32,
+    rx_id: u32,
+    pub tx_bytes: Arc<AtomicU32>,
+    pub rx_bytes: Arc<AtomicU32>,
+}
+
+impl std::fmt::Debug for Nag52Replay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Nag52Replay").field("name", &self.info.name).finish()
+    }
+}
+
+impl Nag52Replay {
+    /// Opens a trace file. The name of the device is the path of the trace
+    pub fn open(path: &str) -> HardwareResult<Self> {
+        let records = load_trace(path).map_err(|e| HardwareError::APIError {
+            code: 99,
+            desc: e.to_string(),
+        })?;
+        Ok(Self::from_records(path, &records))
+    }
+
+    pub fn from_records(name: &str, records: &[TraceRecord]) -> Self {
+        let exchanges = trace_to_exchanges(records);
+        Self {
+            info: HardwareInfo {
+                name: name.to_string(),
+                vendor: Some("Ultimate-NAG52 trace replay".to_string()),
+                device_fw_version: None,
+                api_version: None,
+                library_version: None,
+                library_location: None,
+                capabilities: HardwareCapabilities {
+                    iso_tp: true,
+                    can: false,
+                    kline: false,
+                    kline_kwp: false,
+                    sae_j1850: false,
+                    sci: false,
+                    ip: false,
+                },
+            },
+            state: Arc::new(Mutex::new(ReplayState {
+                used: vec![false; exchanges.len()],
+                exchanges,
+                cursor: 0,
+                queue: VecDeque::new(),
+                unmatched: Vec::new(),
+            })),
+            tx_id: 0,
+            rx_id: 0,
+            tx_bytes: Arc::new(AtomicU32::new(0)),
+            rx_bytes: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    pub fn exchange_count(&self) -> usize {
+        self.state.lock().map(|s| s.exchanges.len()).unwrap_or_default()
+    }
+
+    /// Requests that were sent, but do not appear in the trace
+    pub fn unmatched_requests(&self) -> Vec<Vec<u8>> {
+        self.state.lock().map(|s| s.unmatched.clone()).unwrap_or_default()
+    }
+}
+
+impl ecu_diagnostics::hardware::Hardware for Nag52Replay {
+    fn create_iso_tp_channel(&mut self) -> HardwareResult<Box<dyn IsoTPChannel>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn create_can_channel(&mut self) -> HardwareResult<Box<dyn CanChannel>> {
+        Err(HardwareError::ChannelNotSupported)
+    }
+
+    fn is_iso_tp_channel_open(&self) -> bool {
+        true
+    }
+
+    fn is_can_channel_open(&self) -> bool {
+        false
+    }
+
+    fn read_battery_voltage(&mut self) -> Option<f32> {
+        None
+    }
+
+    fn read_ignition_voltage(&mut self) -> Option<f32> {
+        None
+    }
+
+    fn get_info(&self) -> &HardwareInfo {
+        &self.info
+    }
+
+    fn is_connected(&self) -> bool {
+        true
+    }
+}
+
+impl PayloadChannel for Nag52Replay {
+    fn open(&mut self) -> ChannelResult<()> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> ChannelResult<()> {
+        Ok(())
+    }
+
+    fn set_ids(&mut self, send: u32, recv: u32) -> ChannelResult<()> {
+        self.tx_id = send;
+        self.rx_id = recv;
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, _timeout_ms: u32) -> ChannelResult<Vec<u8>> {
+        let mut state = self.state.lock()?;
+        match state.queue.pop_front() {
+            Some(Ok(data)) => {
+                self.rx_bytes.fetch_add(data.len() as u32, Ordering::Relaxed);
+                Ok(data)
+            }
+            Some(Err(e)) => Err(e.into()),
+            None => Err(ChannelError::BufferEmpty),
+        }
+    }
+
+    fn write_bytes(
+        &mut self,
+        _addr: u32,
+        _ext_id: Option<u8>,
+        buffer: &[u8],
+        _timeout_ms: u32,
+    ) -> ChannelResult<()> {
+        let mut state = self.state.lock()?;
+        self.tx_bytes.fetch_add(buffer.len() as u32, Ordering::Relaxed);
+        match state.find(buffer) {
+            Some(idx) => {
+                let responses = state.exchanges[idx].responses.clone();
+                state.queue.extend(responses);
+                Ok(())
+            }
+            // Recordings usually start after the session was opened, so session control
+            // and tester present are acknowledged even when they are not in the trace
+            None if matches!(buffer.first(), Some(0x10 | 0x3E)) => {
+                let mut resp = buffer.to_vec();
+                resp[0] += 0x40;
+                state.queue.push_back(Ok(resp));
+                Ok(())
+            }
+            None => {
+                state.unmatched.push(buffer.to_vec());
+                Err(ChannelError::Other(format!("Request {:02X?} is not in the trace", buffer)))
+            }
+        }
+    }
+
+    fn clear_rx_buffer(&mut self) -> ChannelResult<()> {
+        self.state.lock()?.queue.clear();
+        Ok(())
+    }
+
+    fn clear_tx_buffer(&mut self) -> ChannelResult<()> {
+        Ok(())
+    }
+
+    fn read_write_bytes(
+        &mut self,
+        addr: u32,
+        ext_id: Option<u8>,
+        buffer: &[u8],
+        write_timeout_ms: u32,
+        read_timeout_ms: u32,
+    ) -> ChannelResult<Vec<u8>> {
+        self.write_bytes(addr, ext_id, buffer, write_timeout_ms)?;
+        self.read_bytes(read_timeout_ms)
+    }
+}
+
+impl IsoTPChannel for Nag52Replay {
+    fn set_iso_tp_cfg(&mut self, _cfg: ecu_diagnostics::channel::IsoTPSettings) -> ChannelResult<()> {
+        Ok(()) // Don't care
+    }
+}
+
+#[cfg(test)]
+pub mod test_replay {
+    use ecu_diagnostics::{channel::PayloadChannel, DiagError};
+
+    use crate::{
+        diag::{trace::parse_trace, AdapterHw, Nag52Diag},
+        hw::sim::open_test_sim,
+    };
+
+    use super::Nag52Replay;
+
+    #[test]
+    pub fn test_record_replay() {
+        let path = std::env::temp_dir().join(format!("un52_trace_{}.jsonl", std::process::id()));
+        let (sim, nag) = open_test_sim("test_record_replay");
+        sim.state().add_request_hook(|req| {
+            if req == [0x21, 0xE1] {
+                Some(vec![0x7F, 0x21, 0x22])
+            } else {
+                None
+            }
+        });
+        nag.start_recording(&path).unwrap();
+        assert!(nag.is_recording());
+        let mode = nag.read_device_mode().unwrap();
+        let ident = nag.query_ecu_data().unwrap();
+        let fw = nag.get_running_fw_info().unwrap();
+        assert!(nag.get_ecu_sn().is_err());
+        assert!(nag.stop_recording().unwrap() > 1);
+        drop(nag);
+
+        let replay = Nag52Replay::open(&path.to_string_lossy()).unwrap();
+        assert!(replay.exchange_count() > 0);
+        let nag = Nag52Diag::new(AdapterHw::Replay(replay.clone())).unwrap();
+        assert_eq!(nag.read_device_mode().unwrap(), mode);
+        assert_eq!(nag.query_ecu_data().unwrap(), ident);
+        assert_eq!(nag.get_running_fw_info().unwrap().get_version(), fw.get_version());
+        match nag.get_ecu_sn() {
+            Err(DiagError::ECUError { code, def: _ }) => assert_eq!(code, 0x22),
+            x => panic!("Unexpected response {x:?}"),
+        }
+        assert_eq!(replay.unmatched_requests(), Vec::<Vec<u8>>::new());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    pub fn test_unmatched_request() {
+        let trace = concat!(
+            "{\"t_ms\":0,\"type\":\"Info\",\"device\":\"test\",\"created\":\"\"}\n",
+            "{\"t_ms\":1,\"type\":\"Send\",\"addr\":2017,\"data\":\"1A 86\",\"error\":null}\n",
+            "{\"t_ms\":2,\"type\":\"Recv\",\"addr\":2025,\"data\":\"7F 1A 11\",\"error\":null}\n",
+        );
+        let records = parse_trace(trace.as_bytes()).unwrap();
+        let mut replay = Nag52Replay::from_records("test", &records);
+        assert_eq!(replay.exchange_count(), 1);
+        assert_eq!(replay.read_write_bytes(0, None, &[0x1A, 0x86], 0, 0).unwrap(), vec![0x7F, 0x1A, 0x11]);
+        assert!(replay.write_bytes(0, None, &[0x1A, 0x87], 0).is_err());
+        assert_eq!(replay.unmatched_requests(), vec![vec![0x1A, 0x87]]);
+    }
+}
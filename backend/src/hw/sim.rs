@@ -0,0 +1,376 @@
+use ecu_diagnostics::{
+    channel::{CanChannel, ChannelError, ChannelResult, IsoTPChannel, PayloadChannel},
+    hardware::{HardwareCapabilities, HardwareError, HardwareInfo, HardwareResult},
+};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use super::{sim_ecu::SimTcuState, usb::EspLogMessage};
+
+/// Simulated Ultimate-NAG52, communicating with a virtual TCU in process.
+///
+/// All clones of a [Nag52Sim] talk to the same virtual TCU.
+#[derive(Clone)]
+pub struct Nag52Sim {
+    info: HardwareInfo,
+    state: Arc<Mutex<SimTcuState>>,
+    rx_diag: Arc<(Mutex<VecDeque<Vec<u8>>>, Condvar)>,
+    tx_id: u32,
+    rx_id: u32,
+    pub tx_bytes: Arc<AtomicU32>,
+    pub rx_bytes: Arc<AtomicU32>,
+}
+
+impl std::fmt::Debug for Nag52Sim {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Nag52Sim").field("name", &self.info.name).finish()
+    }
+}
+
+impl Nag52Sim {
+    pub fn new(name: &str) -> Self {
+        Self::with_state(name, SimTcuState::new())
+    }
+
+    pub fn with_state(name: &str, state: SimTcuState) -> Self {
+        Self {
+            info: HardwareInfo {
+                name: name.to_string(),
+                vendor: Some("Ultimate-NAG52 simulator".to_string()),
+                device_fw_version: None,
+                api_version: None,
+                library_version: None,
+                library_location: None,
+                capabilities: HardwareCapabilities {
+                    iso_tp: true,
+                    can: false,
+                    kline: false,
+                    kline_kwp: false,
+                    sae_j1850: false,
+                    sci: false,
+                    ip: false,
+                },
+            },
+            state: Arc::new(Mutex::new(state)),
+            rx_diag: Arc::new((Mutex::new(VecDeque::new()), Condvar::new())),
+            tx_id: 0,
+            rx_id: 0,
+            tx_bytes: Arc::new(AtomicU32::new(0)),
+            rx_bytes: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    /// Locks the virtual TCU's state, so it can be inspected or scripted
+    pub fn state(&self) -> MutexGuard<'_, SimTcuState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Simulates plugging or unplugging the TCU
+    pub fn set_connected(&self, connected: bool) {
+        self.state().connected = connected;
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state().connected
+    }
+
+    pub fn read_msg(&self) -> Option<EspLogMessage> {
+        let mut state = self.state();
+        if state.connected {
+            state.pop_log()
+        } else {
+            None
+        }
+    }
+}
+
+impl ecu_diagnostics::hardware::Hardware for Nag52Sim {
+    fn create_iso_tp_channel(&mut self) -> HardwareResult<Box<dyn IsoTPChannel>> {
+        if self.is_connected() {
+            Ok(Box::new(self.clone()))
+        } else {
+            Err(HardwareError::DeviceNotFound)
+        }
+    }
+
+    fn create_can_channel(&mut self) -> HardwareResult<Box<dyn CanChannel>> {
+        Err(HardwareError::ChannelNotSupported)
+    }
+
+    fn is_iso_tp_channel_open(&self) -> bool {
+        self.is_connected()
+    }
+
+    fn is_can_channel_open(&self) -> bool {
+        false
+    }
+
+    fn read_battery_voltage(&mut self) -> Option<f32> {
+        None
+    }
+
+    fn read_ignition_voltage(&mut self) -> Option<f32> {
+        None
+    }
+
+    fn get_info(&self) -> &HardwareInfo {
+        &self.info
+    }
+
+    fn is_connected(&self) -> bool {
+        Nag52Sim::is_connected(self)
+    }
+}
+
+impl PayloadChannel for Nag52Sim {
+    fn open(&mut self) -> ChannelResult<()> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> ChannelResult<()> {
+        Ok(())
+    }
+
+    fn set_ids(&mut self, send: u32, recv: u32) -> ChannelResult<()> {
+        self.tx_id = send;
+        self.rx_id = recv;
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, timeout_ms: u32) -> ChannelResult<Vec<u8>> {
+        let (queue, cvar) = &*self.rx_diag;
+        let guard = queue.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut guard, _) = cvar
+            .wait_timeout_while(guard, Duration::from_millis(timeout_ms as u64), |q| q.is_empty())
+            .unwrap_or_else(|e| e.into_inner());
+        guard.pop_front().ok_or(ChannelError::BufferEmpty)
+    }
+
+    fn write_bytes(
+        &mut self,
+        addr: u32,
+        _ext_id: Option<u8>,
+        buffer: &[u8],
+        _timeout_ms: u32,
+    ) -> ChannelResult<()> {
+        let (resp, delay) = {
+            let mut state = self.state();
+            if !state.connected {
+                return Err(ChannelError::IOError(Arc::new(std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "Simulated TCU is disconnected",
+                ))));
+            }
+            if addr != self.tx_id {
+                // Not addressed to the TCU
+                return Ok(());
+            }
+            (state.handle_request(buffer), state.response_delay)
+        };
+        self.tx_bytes.fetch_add(buffer.len() as u32, Ordering::Relaxed);
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+        self.rx_bytes.fetch_add(resp.len() as u32, Ordering::Relaxed);
+        let (queue, cvar) = &*self.rx_diag;
+        queue.lock().unwrap_or_else(|e| e.into_inner()).push_back(resp);
+        cvar.notify_all();
+        Ok(())
+    }
+
+    fn clear_rx_buffer(&mut self) -> ChannelResult<()> {
+        self.rx_diag.0.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        Ok(())
+    }
+
+    fn clear_tx_buffer(&mut self) -> ChannelResult<()> {
+        Ok(())
+    }
+
+    fn read_write_bytes(
+        &mut self,
+        addr: u32,
+        ext_id: Option<u8>,
+        buffer: &[u8],
+        write_timeout_ms: u32,
+        read_timeout_ms: u32,
+    ) -> ChannelResult<Vec<u8>> {
+        self.write_bytes(addr, ext_id, buffer, write_timeout_ms)?;
+        self.read_bytes(read_timeout_ms)
+    }
+}
+
+impl IsoTPChannel for Nag52Sim {
+    fn set_iso_tp_cfg(&mut self, _cfg: ecu_diagnostics::channel::IsoTPSettings) -> ChannelResult<()> {
+        Ok(()) // Don't care
+    }
+}
+
+/// Creates a simulator, registers it with [super::sim_scanner::Nag52SimScanner]
+/// and connects to it, as every backend test needs a TCU to talk to.
+#[cfg(test)]
+pub(crate) fn open_test_sim(name: &str) -> (Nag52Sim, crate::diag::Nag52Diag) {
+    let sim = Nag52Sim::new(name);
+    super::sim_scanner::Nag52SimScanner::register(sim.clone());
+    let nag = crate::diag::Nag52Diag::new(crate::diag::AdapterHw::Simulation(sim.clone())).unwrap();
+    (sim, nag)
+}
+
+#[cfg(test)]
+pub mod test_sim {
+    use ecu_diagnostics::{hardware::HardwareScanner, DiagError};
+
+    use crate::{
+        diag::{
+            device_modes::TcuDeviceMode,
+            ident::{EgsMode, PCBVersion},
+            nvs::{NvsPartition, NvsValue},
+        },
+        hw::{
+            esp_image::EspImageError,
+            firmware::{load_binary, FirmwareLoadError},
+            sim_ecu::{sim_firmware_header, sim_firmware_image, SIM_NVS_NAMESPACE, SIM_NVS_PARTITION},
+            sim_scanner::Nag52SimScanner,
+        },
+    };
+
+    use super::open_test_sim;
+
+    #[test]
+    pub fn test_ident() {
+        let (_sim, nag) = open_test_sim("test_ident");
+        let ident = nag.query_ecu_data().unwrap();
+        assert_eq!(ident.egs_mode, EgsMode::EGS52);
+        assert_eq!(ident.board_ver, PCBVersion::OnePointThree);
+        assert_eq!(nag.get_ecu_sn().unwrap(), "SIM000000001");
+        let fw = nag.get_running_fw_info().unwrap();
+        assert_eq!(fw.get_version(), "SIM-main-1.5.0");
+        assert!(fw.get_build_timestamp().is_some());
+    }
+
+    #[test]
+    pub fn test_device_mode() {
+        let (sim, nag) = open_test_sim("test_device_mode");
+        assert_eq!(nag.read_device_mode().unwrap(), TcuDeviceMode::NORMAL);
+        nag.set_device_mode(TcuDeviceMode::SLAVE, false).unwrap();
+        assert_eq!(nag.read_device_mode().unwrap(), TcuDeviceMode::SLAVE);
+        nag.return_mode_control_to_ecu().unwrap();
+        assert_eq!(nag.read_device_mode().unwrap(), TcuDeviceMode::NORMAL);
+        nag.set_device_mode(TcuDeviceMode::CANLOGGER, true).unwrap();
+        sim.state().boot();
+        assert_eq!(nag.read_device_mode().unwrap(), TcuDeviceMode::CANLOGGER);
+    }
+
+    #[test]
+    pub fn test_scripted_response() {
+        let (sim, nag) = open_test_sim("test_scripted_response");
+        sim.state().add_request_hook(|req| {
+            if req == [0x21, 0xE1] {
+                Some(vec![0x7F, 0x21, 0x22])
+            } else {
+                None
+            }
+        });
+        match nag.get_ecu_sn() {
+            Err(DiagError::ECUError { code, def: _ }) => assert_eq!(code, 0x22),
+            x => panic!("Unexpected response {x:?}"),
+        }
+        assert!(nag.query_ecu_data().is_ok());
+        assert!(sim.state().request_log.contains(&vec![0x1A, 0x86]));
+    }
+
+    #[test]
+    pub fn test_scn_coding() {
+        let (sim, nag) = open_test_sim("test_scn_coding");
+        let len = sim.state().scn_codings[&1].current.len();
+        let mut coding = nag.with_kwp(|k| k.send_byte_array_with_response(&[0x21, 0xFC, 0x01])).unwrap();
+        assert_eq!(coding.len(), len + 3);
+        coding[3] = 1;
+        let mut tx = vec![0x3B, 0xFC, 0x01];
+        tx.extend_from_slice(&coding[3..]);
+        nag.with_kwp(|k| k.send_byte_array_with_response(&tx)).unwrap();
+        assert_eq!(sim.state().scn_codings[&1].current[0], 1);
+        // Default coding is untouched
+        let default = nag.with_kwp(|k| k.send_byte_array_with_response(&[0x21, 0xFC, 0x81])).unwrap();
+        assert_eq!(default[3], 0);
+    }
+
+    #[test]
+    pub fn test_ota_update() {
+        let (_sim, nag) = open_test_sim("test_ota_update");
+        let header = sim_firmware_header("SIM-main-1.6.0", "18 Oct 2026", "09:00:00", 0, [0xBB; 32]);
+        let image = sim_firmware_image(&header, 0x3000);
+        let fw = load_binary(image.clone()).unwrap();
+
+        let (_addr, bs) = nag.begin_ota(fw.raw.len() as u32).unwrap();
+        for (bid, block) in fw.raw.chunks(bs as usize).enumerate() {
+            nag.transfer_data(((bid + 1) & 0xFF) as u8, block).unwrap();
+        }
+        nag.end_ota(true).unwrap();
+        assert_eq!(nag.get_running_fw_info().unwrap().get_version(), "SIM-main-1.6.0");
+
+        // Read the new firmware back
+        let part = nag.get_running_partition_flash_info().unwrap();
+        nag.begin_download(&part).unwrap();
+        let mut read = Vec::new();
+        let mut counter = 0u8;
+        while read.len() < image.len() {
+            counter = counter.wrapping_add(1);
+            read.extend_from_slice(&nag.read_data(counter).unwrap());
+        }
+        nag.end_ota(false).unwrap();
+        assert_eq!(read[..image.len()], image[..]);
+    }
+
+    #[test]
+    pub fn test_ota_corrupt_image() {
+        let (_sim, nag) = open_test_sim("test_ota_corrupt_image");
+        let header = sim_firmware_header("SIM-main-1.6.0", "18 Oct 2026", "09:00:00", 0, [0xBB; 32]);
+        let mut image = sim_firmware_image(&header, 0x3000);
+        image[0x1000] ^= 0xFF;
+        assert!(matches!(
+            load_binary(image.clone()),
+            Err(FirmwareLoadError::InvalidImage(EspImageError::ChecksumMismatch { .. }))
+        ));
+
+        // TCU rejects the image as well, if it is written anyway
+        let (_addr, bs) = nag.begin_ota(image.len() as u32).unwrap();
+        for (bid, block) in image.chunks(bs as usize).enumerate() {
+            nag.transfer_data(((bid + 1) & 0xFF) as u8, block).unwrap();
+        }
+        assert!(nag.end_ota(true).is_err());
+        assert_eq!(nag.get_running_fw_info().unwrap().get_version(), "SIM-main-1.5.0");
+    }
+
+    #[test]
+    pub fn test_read_nvs() {
+        let (_sim, nag) = open_test_sim("test_read_nvs");
+        let info = nag.get_nvs_partition_info().unwrap();
+        assert_eq!(info, SIM_NVS_PARTITION);
+        let nvs = NvsPartition::parse(&nag.read_flash_region(info.address, info.size, |_| {}).unwrap()).unwrap();
+        assert_eq!(nvs.issues, vec![]);
+        assert_eq!(nvs.get(SIM_NVS_NAMESPACE, "DEV_MODE").unwrap().value, NvsValue::U16(TcuDeviceMode::NORMAL.bits()));
+        assert!(matches!(nvs.get(SIM_NVS_NAMESPACE, "TCC_A0").unwrap().value, NvsValue::Blob(_)));
+    }
+
+    #[test]
+    pub fn test_reconnect() {
+        let (sim, nag) = open_test_sim("test_reconnect");
+        sim.set_connected(false);
+        assert!(nag.query_ecu_data().is_err());
+        assert!(nag.try_reconnect().is_err());
+        sim.set_connected(true);
+        nag.try_reconnect().unwrap();
+        assert!(nag.query_ecu_data().is_ok());
+        // Check the scanner returns the same virtual TCU
+        let dev = Nag52SimScanner::new().open_device_by_name("test_reconnect").unwrap();
+        dev.state().serial_number = "SIM_RECONNECT".into();
+        assert_eq!(nag.get_ecu_sn().unwrap(), "SIM_RECONNECT");
+    }
+}
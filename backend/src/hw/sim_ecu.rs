@@ -0,0 +1,1001 @@
+//! Virtual Ultimate-NAG52 TCU
+//!
+//! Models just enough of the TCU firmware to answer every KWP2000 request
+//! that [crate::diag::Nag52Diag] and the config app send. All state is public
+//! so that tests (or the app) can script the ECU's behaviour.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use flate2::Crc;
+use packed_struct::{PackedStruct, PackedStructSlice};
+
+use crate::diag::{
+    calibration::EgsStoredCalibration, device_modes::TcuDeviceMode, flash::PartitionInfo,
+    memory::MemoryRegion, module_settings_flash_store::ModuleSettingsFlashHeader,
+    nvs::{NvsImage, NvsValue, NVS_KEY_MAX_LEN, TCU_NVS_NAMESPACE},
+    settings::ModuleSettingsData,
+};
+
+use super::{
+    coredump::{CoreDump, ExtraReg, XtensaRegisters, XT_REG_EXCCAUSE, XT_REG_EXCVADDR},
+    esp_image::EspImage,
+    partition_table::{build_partition_table, EspPartition, PARTITION_TABLE_ADDR, PARTITION_TYPE_APP, PARTITION_TYPE_DATA},
+    usb::{EspLogLevel, EspLogMessage},
+};
+
+/// MODULE_SETTINGS.yml served by the simulated TCU unless another one is loaded
+pub const SIM_MODULE_SETTINGS_YML: &str = include_str!("sim_module_settings.yml");
+
+pub const SIM_FLASH_SIZE: usize = 0x400000;
+const SIM_BLOCK_SIZE: u16 = 0x0FFA;
+pub const SIM_NVS_PARTITION: PartitionInfo = PartitionInfo { address: 0x9000, size: 0x4000 };
+/// NVS namespace the simulated TCU stores its EEPROM items in
+pub const SIM_NVS_NAMESPACE: &str = TCU_NVS_NAMESPACE;
+const MAX_LOG_BACKLOG: usize = 500;
+/// Requests kept in [SimTcuState::request_log]
+pub const MAX_REQUEST_LOG: usize = 1000;
+
+// KWP2000 negative response codes used by the simulator
+pub const NRC_SERVICE_NOT_SUPPORTED: u8 = 0x11;
+pub const NRC_SUBFUNCTION_NOT_SUPPORTED: u8 = 0x12;
+pub const NRC_CONDITIONS_NOT_CORRECT: u8 = 0x22;
+pub const NRC_REQUEST_SEQUENCE_ERROR: u8 = 0x24;
+pub const NRC_REQUEST_OUT_OF_RANGE: u8 = 0x31;
+
+/// A request hook. Returning `Some` replaces the response the virtual TCU would give.
+pub type SimRequestHook = Box<dyn FnMut(&[u8]) -> Option<Vec<u8>> + Send>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimMap {
+    pub key: String,
+    pub x_values: Vec<i16>,
+    pub y_values: Vec<i16>,
+    /// Map currently in use (RAM)
+    pub current: Vec<i16>,
+    /// Program default
+    pub default: Vec<i16>,
+    /// Map stored in EEPROM
+    pub eeprom: Vec<i16>,
+}
+
+impl SimMap {
+    pub fn new(key: &str, x_values: Vec<i16>, y_values: Vec<i16>, default: Vec<i16>) -> Self {
+        Self {
+            key: key.to_string(),
+            x_values,
+            y_values,
+            current: default.clone(),
+            eeprom: default.clone(),
+            default,
+        }
+    }
+
+    fn meta_bytes(&self) -> Vec<u8> {
+        let mut ret = Vec::new();
+        ret.extend_from_slice(&(self.x_values.len() as u16).to_le_bytes());
+        ret.extend_from_slice(&(self.y_values.len() as u16).to_le_bytes());
+        ret.extend_from_slice(&(self.key.len() as u16).to_le_bytes());
+        for v in self.x_values.iter().chain(self.y_values.iter()) {
+            ret.extend_from_slice(&v.to_le_bytes());
+        }
+        ret.extend_from_slice(self.key.as_bytes());
+        ret
+    }
+}
+
+fn map_data_bytes(data: &[i16]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(data.len() * 2);
+    for v in data {
+        ret.extend_from_slice(&v.to_le_bytes());
+    }
+    ret
+}
+
+/// SCN coding string of a MODULE_SETTINGS group
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimScnCoding {
+    pub current: Vec<u8>,
+    pub default: Vec<u8>,
+    /// NVS key the coding is stored under
+    pub eeprom_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SimTransfer {
+    Download { addr: u32, size: u32, written: u32 },
+    Upload { addr: u32, size: u32, read: u32 },
+}
+
+pub struct SimTcuState {
+    /// When false, the TCU stops responding (As if it was unplugged)
+    pub connected: bool,
+    /// Artificial delay before each response
+    pub response_delay: Duration,
+    /// Current KWP session ID
+    pub session: u8,
+    /// Part number (BCD)
+    pub part_number: [u8; 5],
+    /// EGS variant diag info (0x0251, 0x0252 or 0x0253)
+    pub diag_variant: u16,
+    /// PCB hardware build week and year (BCD)
+    pub hw_date: (u8, u8),
+    /// Software build week and year (BCD)
+    pub sw_date: (u8, u8),
+    /// Production year, month, day (BCD)
+    pub production_date: (u8, u8, u8),
+    pub serial_number: String,
+    /// Active device mode
+    pub device_mode: TcuDeviceMode,
+    /// Device mode restored on boot
+    pub eeprom_device_mode: TcuDeviceMode,
+    /// Raw TcmCoreConfig (0xFE)
+    pub core_config: Vec<u8>,
+    /// Raw TcmEfuseConfig (0xFD)
+    pub efuse_config: Vec<u8>,
+    /// Firmware header of the running firmware (0x28)
+    pub fw_header: Vec<u8>,
+    pub running_partition: PartitionInfo,
+    pub next_ota_partition: PartitionInfo,
+    pub coredump_partition: PartitionInfo,
+    pub embed_file: PartitionInfo,
+    pub flash: Vec<u8>,
+    /// Memory written via 0x3D, or preloaded. Unset addresses read back as 0
+    pub memory: HashMap<u32, u8>,
+    pub maps: BTreeMap<u8, SimMap>,
+    pub scn_codings: BTreeMap<u8, SimScnCoding>,
+    /// RLI responses that replace the generated live data
+    pub rli_overrides: HashMap<u8, Vec<u8>>,
+    /// The last [MAX_REQUEST_LOG] requests received, in order
+    pub request_log: VecDeque<Vec<u8>>,
+    hooks: Vec<SimRequestHook>,
+    logs: VecDeque<EspLogMessage>,
+    transfer: Option<SimTransfer>,
+    last_download_ok: bool,
+    pending_boot: Option<PartitionInfo>,
+    boot_time: Instant,
+}
+
+impl std::fmt::Debug for SimTcuState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SimTcuState")
+            .field("connected", &self.connected)
+            .field("session", &self.session)
+            .field("device_mode", &self.device_mode)
+            .finish()
+    }
+}
+
+fn pad_str<const N: usize>(s: &str) -> [u8; N] {
+    let mut ret = [0u8; N];
+    let b = s.as_bytes();
+    let len = b.len().min(N);
+    ret[..len].copy_from_slice(&b[..len]);
+    ret
+}
+
+/// Builds a raw firmware header as it would be found in an app image
+pub fn sim_firmware_header(version: &str, date: &str, time: &str, secure_version: u32, elf_sha: [u8; 32]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(256);
+    ret.extend_from_slice(&0xABCD5432u32.to_le_bytes());
+    ret.extend_from_slice(&secure_version.to_le_bytes());
+    ret.extend_from_slice(&[0; 8]);
+    ret.extend_from_slice(&pad_str::<32>(version));
+    ret.extend_from_slice(&pad_str::<32>("ultimate-nag52-fw"));
+    ret.extend_from_slice(&pad_str::<16>(time));
+    ret.extend_from_slice(&pad_str::<16>(date));
+    ret.extend_from_slice(&pad_str::<32>("v5.1.2"));
+    ret.extend_from_slice(&elf_sha);
+    ret.extend_from_slice(&[0; 80]);
+    ret
+}
+
+/// Builds a complete app image (See [EspImage]) around a firmware header, as produced by idf.py
+pub fn sim_firmware_image(fw_header: &[u8], len: usize) -> Vec<u8> {
+    let mut app = fw_header.to_vec();
+    app.resize(len.max(app.len()), 0x55);
+    EspImage::build(0x400D1000, &[(0x3F400020, &app)])
+}
+
+/// Builds the coredump of a StoreProhibited exception in the 'main' task, one call deep
+pub fn sim_coredump() -> Vec<u8> {
+    const TCB: u32 = 0x3FFB1000;
+    const STACK: u32 = 0x3FFB8000;
+    let mut tcb = vec![0u8; 0x164];
+    tcb[0x34..0x38].copy_from_slice(b"main");
+    // Base save area of the crashed frame holds the caller's a0 (0, ending the backtrace) and sp
+    let mut stack = vec![0u8; 0x80];
+    stack[0x14..0x18].copy_from_slice(&(STACK + 0x60).to_le_bytes());
+    let mut regs = XtensaRegisters { pc: 0x400D1010, ps: 0x60F30, ..Default::default() };
+    regs.ar[0] = 0x800D1503;
+    regs.ar[1] = STACK + 0x20;
+    let exc = [ExtraReg { index: XT_REG_EXCCAUSE, value: 29 }, ExtraReg { index: XT_REG_EXCVADDR, value: 0 }];
+    CoreDump::build("aaaaaaaaaaaaaaaa", TCB, &exc, &[(TCB, regs)], &[(TCB, &tcb), (STACK, &stack)])
+}
+
+/// Creates an uncompressed zip archive containing a single file, like the
+/// container stored in the TCU's embed partition.
+pub fn sim_embed_container(name: &str, data: &[u8]) -> Vec<u8> {
+    let mut crc = Crc::new();
+    crc.update(data);
+    let crc = crc.sum();
+    let mut local = Vec::new();
+    local.extend_from_slice(&0x04034b50u32.to_le_bytes());
+    local.extend_from_slice(&[20, 0, 0, 0, 0, 0, 0, 0, 0x21, 0]); // Version, flags, stored, time, date
+    local.extend_from_slice(&crc.to_le_bytes());
+    local.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    local.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    local.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    local.extend_from_slice(&0u16.to_le_bytes());
+    local.extend_from_slice(name.as_bytes());
+    local.extend_from_slice(data);
+
+    let mut central = Vec::new();
+    central.extend_from_slice(&0x02014b50u32.to_le_bytes());
+    central.extend_from_slice(&[20, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0x21, 0]);
+    central.extend_from_slice(&crc.to_le_bytes());
+    central.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    central.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    central.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    central.extend_from_slice(&[0; 12]); // Extra, comment, disk, attributes
+    central.extend_from_slice(&0u32.to_le_bytes()); // Local header offset
+    central.extend_from_slice(name.as_bytes());
+
+    let mut ret = local;
+    let central_offset = ret.len() as u32;
+    ret.extend_from_slice(&central);
+    ret.extend_from_slice(&0x06054b50u32.to_le_bytes());
+    ret.extend_from_slice(&[0, 0, 0, 0, 1, 0, 1, 0]);
+    ret.extend_from_slice(&(central.len() as u32).to_le_bytes());
+    ret.extend_from_slice(&central_offset.to_le_bytes());
+    ret.extend_from_slice(&0u16.to_le_bytes());
+    ret
+}
+
+fn default_core_config() -> Vec<u8> {
+    let mut ret = Vec::with_capacity(28);
+    ret.push(0); // is_large_nag
+    ret.extend_from_slice(&2870u16.to_le_bytes()); // diff_ratio
+    ret.extend_from_slice(&2080u16.to_le_bytes()); // wheel_circumference
+    ret.push(0); // is_four_matic
+    ret.extend_from_slice(&1000u16.to_le_bytes()); // transfer_case_high_ratio
+    ret.extend_from_slice(&1000u16.to_le_bytes()); // transfer_case_low_ratio
+    ret.push(0); // default_profile (Standard)
+    ret.extend_from_slice(&4500u16.to_le_bytes()); // red_line_dieselrpm
+    ret.extend_from_slice(&6000u16.to_le_bytes()); // red_line_petrolrpm
+    ret.push(0); // engine_type (Diesel)
+    ret.push(2); // egs_can_type (EGS52)
+    ret.push(0); // shifter_style (EWM)
+    ret.push(0); // io_0_usage
+    ret.push(60); // input_sensor_pulses_per_rev
+    ret.push(0); // output_pulse_width_per_kmh
+    ret.push(0); // mosfet_purpose
+    ret.push(80); // throttle_max_open_angle
+    ret.extend_from_slice(&0u16.to_le_bytes()); // c_eng
+    ret.extend_from_slice(&0u16.to_le_bytes()); // engine_drag_torque
+    ret.push(0); // jeep_chrysler
+    ret
+}
+
+fn default_maps() -> BTreeMap<u8, SimMap> {
+    let pedal: Vec<i16> = vec![0, 10, 20, 30, 50, 70, 90, 100];
+    let gears: Vec<i16> = vec![1, 2, 3, 4];
+    let mut ret = BTreeMap::new();
+    let shift_maps = [
+        (0x01, "UPSHIFT_A"),
+        (0x02, "UPSHIFT_C"),
+        (0x03, "UPSHIFT_S"),
+        (0x04, "DNSHIFT_A"),
+        (0x05, "DNSHIFT_C"),
+        (0x06, "DNSHIFT_S"),
+    ];
+    for (id, key) in shift_maps {
+        let upshift = id <= 0x03;
+        let mut data = Vec::new();
+        for g in &gears {
+            for p in &pedal {
+                let base = if upshift { 1500 } else { 900 };
+                data.push(base + (*g * 150) + (*p * 25));
+            }
+        }
+        ret.insert(id, SimMap::new(key, pedal.clone(), gears.clone(), data));
+    }
+    let temps: Vec<i16> = vec![-20, 5, 25, 60];
+    let clutches: Vec<i16> = vec![1, 2, 3, 4, 5];
+    let mut fill_time = Vec::new();
+    let mut fill_pressure = Vec::new();
+    for c in &clutches {
+        for t in &temps {
+            fill_time.push(600 - (*t * 4) + (*c * 10));
+            fill_pressure.push(1300 + (*c * 50));
+        }
+    }
+    ret.insert(0x0A, SimMap::new("FILL_TIME", temps.clone(), clutches.clone(), fill_time));
+    ret.insert(0x0B, SimMap::new("FILL_PRESSURE", vec![1], clutches.clone(), fill_pressure.clone()[..5].to_vec()));
+    ret.insert(0x0C, SimMap::new("LOW_FILL_PRESSURE", vec![1], clutches, vec![800; 5]));
+    let load: Vec<i16> = vec![0, 25, 50, 75, 100];
+    let overlap = [
+        (0x10, "UPSHIFT_OVERLAP_A"),
+        (0x11, "DNSHIFT_OVERLAP_A"),
+        (0x12, "UPSHIFT_OVERLAP_S"),
+        (0x13, "DNSHIFT_OVERLAP_S"),
+        (0x14, "UPSHIFT_OVERLAP_C"),
+        (0x15, "DNSHIFT_OVERLAP_C"),
+        (0x16, "UPSHIFT_OVERLAP_W"),
+        (0x17, "DNSHIFT_OVERLAP_W"),
+        (0x18, "UPSHIFT_OVERLAP_M"),
+        (0x19, "DNSHIFT_OVERLAP_M"),
+    ];
+    for (id, key) in overlap {
+        let data = gears.iter().flat_map(|g| load.iter().map(move |l| 300 + g * 20 + l * 2)).collect();
+        ret.insert(id, SimMap::new(key, load.clone(), gears.clone(), data));
+    }
+    let tcc_gears: Vec<i16> = vec![1, 2, 3, 4, 5];
+    ret.insert(0x09, SimMap::new("TCC_PWM", vec![-20, 0, 20, 40, 60, 80, 100, 120], vec![1], vec![1000, 1100, 1200, 1300, 1400, 1500, 1600, 1700]));
+    ret.insert(0xA0, SimMap::new("TCC_ADAPT_SLIP", load.clone(), tcc_gears.clone(), vec![0; 25]));
+    ret.insert(0xA1, SimMap::new("TCC_ADAPT_LOCK", load.clone(), tcc_gears.clone(), vec![0; 25]));
+    ret.insert(0xB0, SimMap::new("TCC_RPM_SLIP", load, tcc_gears, vec![100; 25]));
+    ret
+}
+
+impl SimTcuState {
+    pub fn new() -> Self {
+        let mut flash = vec![0xFF; SIM_FLASH_SIZE];
+        let fw_header = sim_firmware_header("SIM-main-1.5.0", "17 Oct 2026", "12:00:00", 0, [0xAA; 32]);
+        let running_partition = PartitionInfo { address: 0x10000, size: 0x180000 };
+        let image = sim_firmware_image(&fw_header, 0x1000);
+        flash[0x10000..0x10000 + image.len()].copy_from_slice(&image);
+
+        let mut memory = HashMap::new();
+        let cal_len = EgsStoredCalibration::packed_bytes_size(None).unwrap_or_default();
+        let mut cal = vec![0u8; cal_len];
+        if cal_len >= 8 {
+            cal[0..4].copy_from_slice(&0xDEADBEEFu32.to_le_bytes());
+            cal[4..6].copy_from_slice(&(cal_len as u16).to_le_bytes());
+            let crc = cal[8..].iter().enumerate().fold(0u16, |acc, (idx, b)| {
+                acc.wrapping_add(*b as u16).wrapping_add(idx as u16)
+            });
+            cal[6..8].copy_from_slice(&crc.to_le_bytes());
+        }
+        for (idx, b) in cal.iter().enumerate() {
+            memory.insert(MemoryRegion::EgsCalibration.start_addr() + idx as u32, *b);
+        }
+
+        let mut s = Self {
+            connected: true,
+            response_delay: Duration::ZERO,
+            session: 0x81,
+            part_number: [0x03, 0x35, 0x45, 0x68, 0x32],
+            diag_variant: 0x0252,
+            hw_date: (0x49, 0x22),
+            sw_date: (0x42, 0x26),
+            production_date: (0x23, 0x06, 0x15),
+            serial_number: "SIM000000001".into(),
+            device_mode: TcuDeviceMode::NORMAL,
+            eeprom_device_mode: TcuDeviceMode::NORMAL,
+            core_config: default_core_config(),
+            efuse_config: vec![3, 15, 24, 6, 23],
+            fw_header,
+            running_partition,
+            next_ota_partition: PartitionInfo { address: 0x190000, size: 0x180000 },
+            coredump_partition: PartitionInfo { address: 0x310000, size: 0x10000 },
+            embed_file: PartitionInfo { address: 0x320000, size: 0 },
+            flash,
+            memory,
+            maps: default_maps(),
+            scn_codings: BTreeMap::new(),
+            rli_overrides: HashMap::new(),
+            request_log: VecDeque::new(),
+            hooks: Vec::new(),
+            logs: VecDeque::new(),
+            transfer: None,
+            last_download_ok: false,
+            pending_boot: None,
+            boot_time: Instant::now(),
+        };
+        s.load_module_settings(SIM_MODULE_SETTINGS_YML).expect("Built in MODULE_SETTINGS is invalid");
+        s.write_partition_table();
+        s.write_nvs();
+        s.boot();
+        s
+    }
+
+    fn write_partition_table(&mut self) {
+        let table = build_partition_table(&[
+            EspPartition::new("nvs", PARTITION_TYPE_DATA, 0x02, SIM_NVS_PARTITION.address, SIM_NVS_PARTITION.size),
+            EspPartition::new("otadata", PARTITION_TYPE_DATA, 0x00, 0xD000, 0x2000),
+            EspPartition::new("phy_init", PARTITION_TYPE_DATA, 0x01, 0xF000, 0x1000),
+            EspPartition::new("ota_0", PARTITION_TYPE_APP, 0x10, 0x10000, 0x180000),
+            EspPartition::new("ota_1", PARTITION_TYPE_APP, 0x11, 0x190000, 0x180000),
+            EspPartition::new("coredump", PARTITION_TYPE_DATA, 0x03, 0x310000, 0x10000),
+            EspPartition::new("embed", PARTITION_TYPE_DATA, 0x81, 0x320000, 0x10000),
+            EspPartition::new("module_settings", PARTITION_TYPE_DATA, 0x82, 0x330000, 0x19000),
+        ]);
+        let start = PARTITION_TABLE_ADDR as usize;
+        self.flash[start..start + table.len()].copy_from_slice(&table);
+    }
+
+    /// Writes the NVS partition with the EEPROM maps, SCN codings and device mode.
+    /// This is a snapshot, later changes to the state are not written back to it
+    pub fn write_nvs(&mut self) {
+        let mut items: Vec<(String, NvsValue)> = vec![("DEV_MODE".into(), NvsValue::U16(self.eeprom_device_mode.bits()))];
+        for map in self.maps.values().filter(|m| m.key.len() <= NVS_KEY_MAX_LEN) {
+            items.push((map.key.clone(), NvsValue::Blob(map_data_bytes(&map.eeprom))));
+        }
+        for coding in self.scn_codings.values() {
+            if let Some(key) = &coding.eeprom_key {
+                items.push((key.clone(), NvsValue::Blob(coding.current.clone())));
+            }
+        }
+        let image = NvsImage::build(
+            SIM_NVS_PARTITION.size as usize,
+            items.iter().map(|(k, v)| (SIM_NVS_NAMESPACE, k.as_str(), v)),
+        )
+        .expect("Simulated NVS items fit in the partition");
+        let start = SIM_NVS_PARTITION.address as usize;
+        self.flash[start..start + image.as_bytes().len()].copy_from_slice(image.as_bytes());
+    }
+
+    /// Replaces the MODULE_SETTINGS.yml of the TCU. This rebuilds the embed
+    /// container, the module settings flash partition and all SCN coding strings
+    /// (Zero filled).
+    pub fn load_module_settings(&mut self, yml: &str) -> Result<(), String> {
+        let settings: ModuleSettingsData = serde_yaml::from_str(yml).map_err(|e| e.to_string())?;
+        let container = sim_embed_container("MODULE_SETTINGS.yml", yml.as_bytes());
+        // Embed partition is 64KB
+        if container.len() > 0x10000 {
+            return Err("MODULE_SETTINGS too large for embed partition".into());
+        }
+        let start = self.embed_file.address as usize;
+        self.flash[start..start + container.len()].copy_from_slice(&container);
+        self.embed_file.size = container.len() as u32;
+
+        if let Some((header, compressed)) = ModuleSettingsFlashHeader::new_from_yml_content(yml) {
+            let tx = header.merge_to_tx_data(&compressed);
+            if tx.len() <= 0x19000 {
+                self.flash[0x330000..0x330000 + tx.len()].copy_from_slice(&tx);
+            }
+        }
+
+        self.scn_codings.clear();
+        for setting in &settings.settings {
+            if let Some(id) = setting.scn_id {
+                let len = setting
+                    .params
+                    .iter()
+                    .map(|p| p.offset_bytes + p.size_bytes)
+                    .max()
+                    .unwrap_or_default();
+                self.scn_codings.insert(
+                    id,
+                    SimScnCoding { current: vec![0; len], default: vec![0; len], eeprom_key: setting.eeprom_key.clone() },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a hook which is consulted before the TCU handles a request.
+    /// Hooks are called in the order they were added, the first to return a response wins
+    pub fn add_request_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(&[u8]) -> Option<Vec<u8>> + Send + 'static,
+    {
+        self.hooks.push(Box::new(hook))
+    }
+
+    pub fn clear_request_hooks(&mut self) {
+        self.hooks.clear()
+    }
+
+    /// Queues a log message, as if the TCU printed it over USB
+    pub fn push_log(&mut self, lvl: EspLogLevel, tag: &str, msg: &str) {
+        if self.logs.len() >= MAX_LOG_BACKLOG {
+            self.logs.pop_front();
+        }
+        self.logs.push_back(EspLogMessage {
+            lvl,
+            timestamp: self.uptime_ms() as u128,
+            tag: tag.to_string(),
+            msg: msg.to_string(),
+        });
+    }
+
+    pub fn pop_log(&mut self) -> Option<EspLogMessage> {
+        self.logs.pop_front()
+    }
+
+    pub fn uptime_ms(&self) -> u64 {
+        self.boot_time.elapsed().as_millis() as u64
+    }
+
+    /// Location of the stored coredump. Like the firmware, the size is 0 if no valid coredump is stored
+    pub fn coredump_image(&self) -> PartitionInfo {
+        let start = self.coredump_partition.address as usize;
+        let len = u32::from_le_bytes(self.flash[start..start + 4].try_into().unwrap());
+        let size = if len > self.coredump_partition.size { 0 } else { len };
+        PartitionInfo { address: self.coredump_partition.address, size }
+    }
+
+    /// Simulates a crash, which stores [sim_coredump] and reboots the virtual TCU
+    pub fn crash(&mut self) {
+        self.push_log(EspLogLevel::Error, "MAIN", "Simulated crash");
+        let dump = sim_coredump();
+        let start = self.coredump_partition.address as usize;
+        self.flash[start..start + self.coredump_partition.size as usize].fill(0xFF);
+        self.flash[start..start + dump.len()].copy_from_slice(&dump);
+        self.boot();
+    }
+
+    /// Reboots the virtual TCU
+    pub fn boot(&mut self) {
+        if let Some(part) = self.pending_boot.take() {
+            let start = part.address as usize + 0x20;
+            self.fw_header = self.flash[start..start + 256].to_vec();
+            self.next_ota_partition = self.running_partition;
+            self.running_partition = part;
+        }
+        self.boot_time = Instant::now();
+        self.session = 0x81;
+        self.transfer = None;
+        self.device_mode = self.eeprom_device_mode;
+        self.push_log(EspLogLevel::Info, "cpu_start", "Pro cpu start user code");
+        self.push_log(EspLogLevel::Info, "MAIN", "Simulated Ultimate-NAG52 starting");
+    }
+
+    pub fn handle_request(&mut self, req: &[u8]) -> Vec<u8> {
+        if self.request_log.len() >= MAX_REQUEST_LOG {
+            self.request_log.pop_front();
+        }
+        self.request_log.push_back(req.to_vec());
+        for hook in self.hooks.iter_mut() {
+            if let Some(resp) = hook(req) {
+                return resp;
+            }
+        }
+        if req.is_empty() {
+            return vec![0x7F, 0x00, NRC_SERVICE_NOT_SUPPORTED];
+        }
+        let sid = req[0];
+        let args = &req[1..];
+        let res = match sid {
+            0x10 => self.start_session(args),
+            0x11 => {
+                self.boot();
+                Ok(vec![args.first().copied().unwrap_or(0x01)])
+            }
+            0x1A => self.read_ecu_ident(args),
+            0x21 => self.read_local_ident(args),
+            0x23 => self.read_memory(args),
+            0x24 => self.read_flash(args),
+            0x30 => self.io_control(args),
+            0x31 => self.start_routine(args),
+            0x33 => self.routine_results(args),
+            0x34 => self.request_download(args),
+            0x35 => self.request_upload(args),
+            0x36 => self.transfer_data(args),
+            0x37 => self.transfer_exit(),
+            0x3B => self.write_local_ident(args),
+            0x3D => self.write_memory(args),
+            0x3E => Ok(args.to_vec()),
+            _ => Err(NRC_SERVICE_NOT_SUPPORTED),
+        };
+        match res {
+            Ok(mut data) => {
+                data.insert(0, sid.wrapping_add(0x40));
+                data
+            }
+            Err(nrc) => vec![0x7F, sid, nrc],
+        }
+    }
+
+    fn start_session(&mut self, args: &[u8]) -> Result<Vec<u8>, u8> {
+        match args.first() {
+            Some(id @ (0x81 | 0x85 | 0x89 | 0x90 | 0x92 | 0x93)) => {
+                self.session = *id;
+                Ok(vec![*id])
+            }
+            _ => Err(NRC_SUBFUNCTION_NOT_SUPPORTED),
+        }
+    }
+
+    fn read_ecu_ident(&mut self, args: &[u8]) -> Result<Vec<u8>, u8> {
+        if args != [0x86] {
+            return Err(NRC_REQUEST_OUT_OF_RANGE);
+        }
+        let mut ret = vec![0x86];
+        ret.extend_from_slice(&self.part_number);
+        ret.extend_from_slice(&[self.hw_date.0, self.hw_date.1, self.sw_date.0, self.sw_date.1]);
+        ret.push(0x08); // Supplier
+        ret.extend_from_slice(&self.diag_variant.to_be_bytes());
+        ret.push(0x00);
+        ret.extend_from_slice(&[self.production_date.0, self.production_date.1, self.production_date.2]);
+        Ok(ret)
+    }
+
+    fn read_local_ident(&mut self, args: &[u8]) -> Result<Vec<u8>, u8> {
+        let id = *args.first().ok_or(NRC_SUBFUNCTION_NOT_SUPPORTED)?;
+        let data = match id {
+            0x19 => return self.map_command(&args[1..]),
+            0xFC => {
+                let scn_id = *args.get(1).ok_or(NRC_SUBFUNCTION_NOT_SUPPORTED)?;
+                let coding = self.scn_codings.get(&(scn_id & 0x7F)).ok_or(NRC_REQUEST_OUT_OF_RANGE)?;
+                let mut ret = vec![scn_id];
+                if scn_id & 0x80 != 0 {
+                    ret.extend_from_slice(&coding.default);
+                } else {
+                    ret.extend_from_slice(&coding.current);
+                }
+                ret
+            }
+            0x20..=0x31 if self.rli_overrides.contains_key(&id) => self.rli_overrides[&id].clone(),
+            0x20..=0x27 | 0x30 | 0x31 => self.live_data(id).ok_or(NRC_REQUEST_OUT_OF_RANGE)?,
+            0x28 => self.fw_header.clone(),
+            0x29 => self.coredump_image().pack().unwrap().to_vec(),
+            0x2A => self.running_partition.pack().unwrap().to_vec(),
+            0x2B => self.next_ota_partition.pack().unwrap().to_vec(),
+            0x2C => self.embed_file.pack().unwrap().to_vec(),
+            0xE1 => self.serial_number.as_bytes().to_vec(),
+            0xFB => (EgsStoredCalibration::packed_bytes_size(None).unwrap_or_default() as u16)
+                .to_le_bytes()
+                .to_vec(),
+            0xFD => self.efuse_config.clone(),
+            0xFE => self.core_config.clone(),
+            _ => return Err(NRC_REQUEST_OUT_OF_RANGE),
+        };
+        let mut ret = vec![id];
+        ret.extend_from_slice(&data);
+        Ok(ret)
+    }
+
+    fn write_local_ident(&mut self, args: &[u8]) -> Result<Vec<u8>, u8> {
+        let id = *args.first().ok_or(NRC_SUBFUNCTION_NOT_SUPPORTED)?;
+        match id {
+            0x19 => {
+                self.map_command(&args[1..])?;
+                Ok(vec![id])
+            }
+            0xFC => {
+                let scn_id = *args.get(1).ok_or(NRC_SUBFUNCTION_NOT_SUPPORTED)?;
+                let coding = self.scn_codings.get_mut(&scn_id).ok_or(NRC_REQUEST_OUT_OF_RANGE)?;
+                if args.len() - 2 != coding.current.len() {
+                    return Err(NRC_REQUEST_OUT_OF_RANGE);
+                }
+                coding.current = args[2..].to_vec();
+                Ok(vec![id, scn_id])
+            }
+            0xFD | 0xFE => {
+                let target = if id == 0xFD { &mut self.efuse_config } else { &mut self.core_config };
+                if args.len() - 1 != target.len() {
+                    return Err(NRC_REQUEST_OUT_OF_RANGE);
+                }
+                *target = args[1..].to_vec();
+                Ok(vec![id])
+            }
+            _ => Err(NRC_REQUEST_OUT_OF_RANGE),
+        }
+    }
+
+    fn map_command(&mut self, args: &[u8]) -> Result<Vec<u8>, u8> {
+        if args.len() < 2 {
+            return Err(NRC_SUBFUNCTION_NOT_SUPPORTED);
+        }
+        let map = self.maps.get_mut(&args[0]).ok_or(NRC_REQUEST_OUT_OF_RANGE)?;
+        let data = match args[1] {
+            1 => map_data_bytes(&map.current),
+            2 => map_data_bytes(&map.default),
+            3 => {
+                if args.len() < 4 {
+                    return Err(NRC_SUBFUNCTION_NOT_SUPPORTED);
+                }
+                let len = u16::from_le_bytes([args[2], args[3]]) as usize;
+                let payload = &args[4..];
+                if len != payload.len() || len != map.current.len() * 2 {
+                    return Err(NRC_REQUEST_OUT_OF_RANGE);
+                }
+                map.current = payload
+                    .chunks_exact(2)
+                    .map(|c| i16::from_le_bytes([c[0], c[1]]))
+                    .collect();
+                vec![]
+            }
+            4 => {
+                map.eeprom = map.current.clone();
+                vec![]
+            }
+            5 => {
+                map.current = map.default.clone();
+                vec![]
+            }
+            6 => {
+                map.current = map.eeprom.clone();
+                vec![]
+            }
+            7 => map.meta_bytes(),
+            8 => map_data_bytes(&map.eeprom),
+            _ => return Err(NRC_SUBFUNCTION_NOT_SUPPORTED),
+        };
+        let mut ret = (data.len() as u16).to_le_bytes().to_vec();
+        ret.extend_from_slice(&data);
+        Ok(ret)
+    }
+
+    fn io_control(&mut self, args: &[u8]) -> Result<Vec<u8>, u8> {
+        match args {
+            [0x10, 0x00] => {
+                self.device_mode = self.eeprom_device_mode;
+                Ok(args.to_vec())
+            }
+            [0x10, 0x01] => {
+                let mut ret = args.to_vec();
+                ret.extend_from_slice(&self.device_mode.bits().to_be_bytes());
+                Ok(ret)
+            }
+            [0x10, store @ (0x07 | 0x08), hi, lo] => {
+                let mode = TcuDeviceMode::from_bits_retain(u16::from_be_bytes([*hi, *lo]));
+                self.device_mode = mode;
+                if *store == 0x08 {
+                    self.eeprom_device_mode = mode;
+                }
+                Ok(vec![0x10, *store])
+            }
+            _ => Err(NRC_SUBFUNCTION_NOT_SUPPORTED),
+        }
+    }
+
+    fn start_routine(&mut self, args: &[u8]) -> Result<Vec<u8>, u8> {
+        match args.first() {
+            // Flash check
+            Some(0xE1) => Ok(vec![0xE1, if self.last_download_ok { 0x00 } else { 0x01 }]),
+            Some(id) => Ok(vec![*id]),
+            None => Err(NRC_SUBFUNCTION_NOT_SUPPORTED),
+        }
+    }
+
+    fn routine_results(&mut self, args: &[u8]) -> Result<Vec<u8>, u8> {
+        match args.first() {
+            Some(0xDE) => {
+                // Solenoid test results
+                let mut ret = vec![0xDE];
+                ret.extend_from_slice(&40i16.to_le_bytes()); // ATF temp
+                for _ in 0..6 {
+                    ret.extend_from_slice(&5u16.to_le_bytes()); // Off currents
+                }
+                for _ in 0..6 {
+                    ret.extend_from_slice(&12500u16.to_le_bytes()); // Battery voltage
+                    ret.extend_from_slice(&2800u16.to_le_bytes()); // On current
+                }
+                Ok(ret)
+            }
+            Some(_) => Err(NRC_REQUEST_SEQUENCE_ERROR),
+            None => Err(NRC_SUBFUNCTION_NOT_SUPPORTED),
+        }
+    }
+
+    fn parse_transfer_request(&self, args: &[u8]) -> Result<(u32, u32), u8> {
+        if self.session != 0x85 {
+            return Err(NRC_CONDITIONS_NOT_CORRECT);
+        }
+        if args.len() != 7 {
+            return Err(NRC_SUBFUNCTION_NOT_SUPPORTED);
+        }
+        let addr = u32::from_be_bytes([0, args[0], args[1], args[2]]);
+        let size = u32::from_be_bytes([0, args[4], args[5], args[6]]);
+        if (addr + size) as usize > self.flash.len() {
+            return Err(NRC_REQUEST_OUT_OF_RANGE);
+        }
+        Ok((addr, size))
+    }
+
+    fn request_download(&mut self, args: &[u8]) -> Result<Vec<u8>, u8> {
+        let (addr, size) = self.parse_transfer_request(args)?;
+        // Erase target region
+        self.flash[addr as usize..(addr + size) as usize].fill(0xFF);
+        self.transfer = Some(SimTransfer::Download { addr, size, written: 0 });
+        self.last_download_ok = false;
+        Ok(SIM_BLOCK_SIZE.to_be_bytes().to_vec())
+    }
+
+    fn request_upload(&mut self, args: &[u8]) -> Result<Vec<u8>, u8> {
+        let (addr, size) = self.parse_transfer_request(args)?;
+        self.transfer = Some(SimTransfer::Upload { addr, size, read: 0 });
+        Ok(SIM_BLOCK_SIZE.to_be_bytes().to_vec())
+    }
+
+    fn transfer_data(&mut self, args: &[u8]) -> Result<Vec<u8>, u8> {
+        let blk = *args.first().ok_or(NRC_SUBFUNCTION_NOT_SUPPORTED)?;
+        match self.transfer.as_mut() {
+            Some(SimTransfer::Download { addr, size, written }) => {
+                let data = &args[1..];
+                if *written + data.len() as u32 > *size {
+                    return Err(NRC_REQUEST_OUT_OF_RANGE);
+                }
+                let start = (*addr + *written) as usize;
+                self.flash[start..start + data.len()].copy_from_slice(data);
+                *written += data.len() as u32;
+                Ok(vec![blk])
+            }
+            Some(SimTransfer::Upload { addr, size, read }) => {
+                let to_read = std::cmp::min((SIM_BLOCK_SIZE - 2) as u32, *size - *read);
+                let start = (*addr + *read) as usize;
+                *read += to_read;
+                let mut ret = vec![blk];
+                ret.extend_from_slice(&self.flash[start..start + to_read as usize]);
+                Ok(ret)
+            }
+            None => Err(NRC_REQUEST_SEQUENCE_ERROR),
+        }
+    }
+
+    fn transfer_exit(&mut self) -> Result<Vec<u8>, u8> {
+        match self.transfer.take() {
+            Some(SimTransfer::Download { addr, size, written }) => {
+                self.last_download_ok = written == size;
+                if addr == self.next_ota_partition.address {
+                    // Like esp_ota_end, the image written must be valid
+                    let image = &self.flash[addr as usize..(addr + size) as usize];
+                    self.last_download_ok &= EspImage::parse(image).is_ok();
+                }
+                if self.last_download_ok && addr == self.next_ota_partition.address {
+                    self.pending_boot = Some(PartitionInfo { address: addr, size: self.next_ota_partition.size });
+                }
+                Ok(vec![])
+            }
+            Some(SimTransfer::Upload { .. }) => {
+                self.last_download_ok = true;
+                Ok(vec![])
+            }
+            None => Err(NRC_REQUEST_SEQUENCE_ERROR),
+        }
+    }
+
+    fn read_flash(&mut self, args: &[u8]) -> Result<Vec<u8>, u8> {
+        if args.len() != 5 {
+            return Err(NRC_SUBFUNCTION_NOT_SUPPORTED);
+        }
+        let addr = u32::from_be_bytes([args[0], args[1], args[2], args[3]]) as usize;
+        let len = args[4] as usize;
+        self.flash.get(addr..addr + len).map(|x| x.to_vec()).ok_or(NRC_REQUEST_OUT_OF_RANGE)
+    }
+
+    fn read_memory(&mut self, args: &[u8]) -> Result<Vec<u8>, u8> {
+        if args.len() != 4 {
+            return Err(NRC_SUBFUNCTION_NOT_SUPPORTED);
+        }
+        let addr = u32::from_be_bytes([0, args[0], args[1], args[2]]);
+        Ok((addr..addr + args[3] as u32)
+            .map(|a| self.memory.get(&a).copied().unwrap_or_default())
+            .collect())
+    }
+
+    fn write_memory(&mut self, args: &[u8]) -> Result<Vec<u8>, u8> {
+        if args.len() < 4 || args.len() - 4 != args[3] as usize {
+            return Err(NRC_SUBFUNCTION_NOT_SUPPORTED);
+        }
+        let addr = u32::from_be_bytes([0, args[0], args[1], args[2]]);
+        for (idx, b) in args[4..].iter().enumerate() {
+            self.memory.insert(addr + idx as u32, *b);
+        }
+        Ok(vec![])
+    }
+
+    /// Generates live data for an RLI, based on a car driving around
+    fn live_data(&self, id: u8) -> Option<Vec<u8>> {
+        let t = self.uptime_ms() as f32 / 1000.0;
+        let wave = |period: f32| ((t * std::f32::consts::TAU / period).sin() + 1.0) / 2.0;
+        let engine_rpm = (800.0 + 2200.0 * wave(20.0)) as u16;
+        let gear = 1 + (wave(40.0) * 4.0).min(3.99) as u16;
+        let ratio = [357, 219, 141, 100, 83][gear as usize - 1];
+        let input_rpm = engine_rpm.saturating_sub(30);
+        let output_rpm = (input_rpm as u32 * 100 / ratio) as u16;
+        let atf_temp = 40 + (t / 10.0).min(45.0) as u16;
+        let pedal = (wave(20.0) * 250.0) as u8;
+        let mut b: Vec<u8> = Vec::new();
+        match id {
+            0x20 => {
+                b.extend_from_slice(&input_rpm.to_le_bytes()); // n2
+                b.extend_from_slice(&input_rpm.to_le_bytes()); // n3
+                b.extend_from_slice(&input_rpm.to_le_bytes()); // calculated
+                b.extend_from_slice(&ratio.to_le_bytes());
+                b.extend_from_slice(&ratio.to_le_bytes());
+                b.extend_from_slice(&13800u16.to_le_bytes()); // v_batt
+                b.extend_from_slice(&(atf_temp as u32).to_le_bytes());
+                b.push(0); // parking_lock
+                b.extend_from_slice(&output_rpm.to_le_bytes());
+            }
+            0x21 => {
+                for pwm in [1200u16, 900, 2000, 0, 0, 0] {
+                    b.extend_from_slice(&pwm.to_le_bytes());
+                }
+                for cur in [600u16, 450, 1000, 600, 450, 0, 0, 0, 0, 0] {
+                    b.extend_from_slice(&cur.to_le_bytes());
+                }
+            }
+            0x22 => {
+                b.push(pedal);
+                b.extend_from_slice(&50u16.to_le_bytes()); // min torque
+                b.extend_from_slice(&500u16.to_le_bytes()); // max torque
+                b.extend_from_slice(&(100 + pedal as u16).to_le_bytes()); // static torque
+                b.extend_from_slice(&(100 + pedal as u16).to_le_bytes()); // driver torque
+                b.extend_from_slice(&output_rpm.to_le_bytes());
+                b.extend_from_slice(&output_rpm.to_le_bytes());
+                b.push(0); // profile input
+                b.push(6); // selector (D)
+                b.push(0); // paddles
+                b.extend_from_slice(&engine_rpm.to_le_bytes());
+                b.extend_from_slice(&(pedal as u16 * 10).to_le_bytes()); // fuel flow
+                b.extend_from_slice(&u16::MAX.to_le_bytes()); // egs torque req
+                b.push(0); // ctrl type
+                b.push(0); // bounds
+                b.extend_from_slice(&25i16.to_le_bytes());
+                b.extend_from_slice(&(atf_temp as i16 + 10).to_le_bytes());
+                b.extend_from_slice(&(atf_temp as i16 + 5).to_le_bytes());
+            }
+            0x23 => {
+                b.extend_from_slice(&150u16.to_le_bytes());
+                b.extend_from_slice(&80u16.to_le_bytes());
+                b.extend_from_slice(&180_000u32.to_le_bytes());
+                b.extend_from_slice(&320_000u32.to_le_bytes());
+                b.extend_from_slice(&3_900_000u32.to_le_bytes());
+                b.extend_from_slice(&4_194_304u32.to_le_bytes());
+                b.extend_from_slice(&18u32.to_le_bytes());
+            }
+            0x24 => {
+                b.extend_from_slice(&1200u16.to_le_bytes());
+                b.extend_from_slice(&1250u16.to_le_bytes());
+                b.extend_from_slice(&30i16.to_le_bytes());
+                b.extend_from_slice(&32i16.to_le_bytes());
+                b.extend_from_slice(&30u16.to_le_bytes());
+                b.extend_from_slice(&(pedal as u16).to_le_bytes());
+                b.extend_from_slice(&(pedal as u16).to_le_bytes());
+                b.extend_from_slice(&[1, 1, 0]);
+                b.extend_from_slice(&(engine_rpm as u32 * 10).to_le_bytes());
+                b.extend_from_slice(&(engine_rpm as u32).to_le_bytes());
+                b.extend_from_slice(&(pedal as i16 / 3).to_le_bytes());
+            }
+            0x25 => {
+                b.push(0);
+                for p in [0u16, 0, 1800, 7000, 1500, 1500, 1200, 0, 0, 0, 0] {
+                    b.extend_from_slice(&p.to_le_bytes());
+                }
+            }
+            0x27 => {
+                b.extend_from_slice(&1500u16.to_le_bytes());
+                b.extend_from_slice(&1200u16.to_le_bytes());
+                b.extend_from_slice(&1200u16.to_le_bytes());
+                b.push(0);
+                b.extend_from_slice(&input_rpm.to_le_bytes());
+                b.extend_from_slice(&engine_rpm.to_le_bytes());
+                b.extend_from_slice(&output_rpm.to_le_bytes());
+                b.extend_from_slice(&(100 + pedal as i16).to_le_bytes());
+                b.extend_from_slice(&(100 + pedal as i16).to_le_bytes());
+                b.extend_from_slice(&0i16.to_le_bytes());
+                b.push(atf_temp as u8);
+                b.push(((gear as u8) << 4) | gear as u8);
+                b.push(0);
+            }
+            0x30 => {
+                for _ in 0..6 {
+                    b.extend_from_slice(&0i16.to_le_bytes());
+                }
+            }
+            0x31 => {
+                b.extend_from_slice(&[0, 0, 0, 0]);
+                b.extend_from_slice(&0u16.to_le_bytes());
+                for _ in 0..6 {
+                    b.extend_from_slice(&0i16.to_le_bytes());
+                }
+            }
+            _ => return None,
+        }
+        Some(b)
+    }
+}
+
+impl Default for SimTcuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
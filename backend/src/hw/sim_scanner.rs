@@ -0,0 +1,82 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, OnceLock},
+};
+
+use ecu_diagnostics::hardware::{HardwareError, HardwareInfo, HardwareResult, HardwareScanner};
+
+use super::sim::Nag52Sim;
+
+pub const DEFAULT_SIM_DEVICE: &str = "Simulated Ultimate-NAG52";
+
+/// Virtual TCUs 'plugged in' to this process. Reopening a device by name
+/// returns the same virtual TCU, so reconnecting keeps its state.
+fn registry() -> &'static Mutex<BTreeMap<String, Nag52Sim>> {
+    static DEVICES: OnceLock<Mutex<BTreeMap<String, Nag52Sim>>> = OnceLock::new();
+    DEVICES.get_or_init(|| {
+        let mut devices = BTreeMap::new();
+        devices.insert(DEFAULT_SIM_DEVICE.to_string(), Nag52Sim::new(DEFAULT_SIM_DEVICE));
+        Mutex::new(devices)
+    })
+}
+
+pub struct Nag52SimScanner {
+    devices: Vec<HardwareInfo>,
+}
+
+impl Nag52SimScanner {
+    pub fn new() -> Self {
+        let devices = registry()
+            .lock()
+            .map(|d| d.values().map(|x| ecu_diagnostics::hardware::Hardware::get_info(x).clone()).collect())
+            .unwrap_or_default();
+        Self { devices }
+    }
+
+    /// Registers a virtual TCU, replacing any existing TCU with the same name
+    pub fn register(device: Nag52Sim) {
+        let name = ecu_diagnostics::hardware::Hardware::get_info(&device).name.clone();
+        if let Ok(mut d) = registry().lock() {
+            d.insert(name, device);
+        }
+    }
+
+    /// Removes a virtual TCU
+    pub fn unregister(name: &str) {
+        if let Ok(mut d) = registry().lock() {
+            d.remove(name);
+        }
+    }
+}
+
+impl Default for Nag52SimScanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HardwareScanner<Nag52Sim> for Nag52SimScanner {
+    fn list_devices(&self) -> Vec<HardwareInfo> {
+        self.devices.clone()
+    }
+
+    fn open_device_by_index(&self, idx: usize) -> HardwareResult<Nag52Sim> {
+        match self.devices.get(idx) {
+            Some(info) => self.open_device_by_name(&info.name),
+            None => Err(HardwareError::DeviceNotFound),
+        }
+    }
+
+    fn open_device_by_name(&self, name: &str) -> HardwareResult<Nag52Sim> {
+        let dev = registry()
+            .lock()
+            .ok()
+            .and_then(|d| d.get(name).cloned())
+            .ok_or(HardwareError::DeviceNotFound)?;
+        if dev.is_connected() {
+            Ok(dev)
+        } else {
+            Err(HardwareError::DeviceNotFound)
+        }
+    }
+}
@@ -1,21 +1,35 @@
 use ecu_diagnostics::{
-    channel::{ChannelError, IsoTPChannel, PayloadChannel, CanChannel, CanFrame},
+    channel::{ChannelError, IsoTPChannel, PayloadChannel, PacketChannel, CanChannel, CanFrame},
     hardware::{HardwareError, HardwareInfo, HardwareResult},
 };
 use serialport::{SerialPort, UsbPortInfo};
+use super::usb_decoder::{DecoderStats, LineRingBuffer, UsbFrame, UsbLineDecoder};
+use crate::diag::VEHICLE_CAN_BAUD;
 use std::{
-    io::Write,
-    panic::catch_unwind,
+    io::{ErrorKind, Write},
     sync::{
         atomic::{AtomicBool, Ordering, AtomicU32},
         mpsc::{self},
         Arc, Mutex,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-
-#[derive(Debug, Clone, Copy)]
+/// Baud rate of the TCU's USB serial port
+pub const USB_BAUD_RATE: u32 = 921600;
+/// Maximum throughput of the USB serial port in each direction, in bytes/sec (8N1 framing)
+pub const USB_MAX_BYTES_PER_SEC: u32 = USB_BAUD_RATE / 10;
+
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+const READ_CHUNK_SIZE: usize = 4096;
+/// Longest line the TCU can send is a 4KB diag message as hex
+const RING_BUFFER_SIZE: usize = 16384;
+/// Discarded lines are summarised in the log at most this often, so a noisy line does not flood it
+const MALFORMED_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// Tag of the log messages the adapter adds about the USB link itself
+pub const USB_LOG_TAG: &str = "USB";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EspLogLevel {
     Debug,
     Info,
@@ -23,7 +37,7 @@ pub enum EspLogLevel {
     Error,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct EspLogMessage {
     pub lvl: EspLogLevel,
     pub timestamp: u128,
@@ -31,6 +45,19 @@ pub struct EspLogMessage {
     pub msg: String,
 }
 
+/// Formatted as saved by the log view: '1234 II - (TAG) Message'
+impl std::fmt::Display for EspLogMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lvl = match self.lvl {
+            EspLogLevel::Debug => "DD",
+            EspLogLevel::Info => "II",
+            EspLogLevel::Warn => "WW",
+            EspLogLevel::Error => "EE",
+        };
+        write!(f, "{} {lvl} - ({}) {}", self.timestamp, self.tag, self.msg)
+    }
+}
+
 #[derive(Clone)]
 pub struct Nag52USB {
     port: Arc<Mutex<Option<Box<dyn SerialPort>>>>,
@@ -38,31 +65,21 @@ pub struct Nag52USB {
     rx_diag: Arc<mpsc::Receiver<(u32, Vec<u8>)>>,
     rx_log: Arc<mpsc::Receiver<EspLogMessage>>,
     rx_can: Arc<mpsc::Receiver<CanFrame>>,
+    can_open: Arc<AtomicBool>,
     is_running: Arc<AtomicBool>,
     tx_id: u32,
     rx_id: u32,
     pub tx_bytes: Arc<AtomicU32>,
-    pub rx_bytes: Arc<AtomicU32>
+    pub rx_bytes: Arc<AtomicU32>,
+    decoder_stats: Arc<Mutex<DecoderStats>>,
 }
 
 unsafe impl Sync for Nag52USB {}
 unsafe impl Send for Nag52USB {}
 
-fn between<'a>(source: &'a str, start: &'a str, end: &'a str) -> &'a str {
-    let start_position = source.find(start);
-
-    if start_position.is_some() {
-        let start_position = start_position.unwrap() + start.len();
-        let source = &source[start_position..];
-        let end_position = source.find(end).unwrap_or_default();
-        return &source[..end_position];
-    }
-    return "";
-}
-
 impl Nag52USB {
     pub fn new(path: &str, _info: UsbPortInfo) -> HardwareResult<Self> {
-        let mut port = serialport::new(path, 921600)
+        let port = serialport::new(path, USB_BAUD_RATE)
             .flow_control(serialport::FlowControl::None)
             .timeout(Duration::from_millis(1500))
             .open()
@@ -77,111 +94,80 @@ impl Nag52USB {
 
         let is_running = Arc::new(AtomicBool::new(true));
         let is_running_r = is_running.clone();
-        let is_running_rr = is_running.clone();
-        port.clear(serialport::ClearBuffer::All);
-        let mut port_clone = port.try_clone().unwrap();
+        let can_open = Arc::new(AtomicBool::new(false));
+        let can_open_r = can_open.clone();
+        let _ = port.clear(serialport::ClearBuffer::All);
+        let mut port_clone = port.try_clone().map_err(|e| HardwareError::APIError {
+            code: 99,
+            desc: e.to_string(),
+        })?;
+        // Short timeout so the reader notices when the adapter is dropped
+        let _ = port_clone.set_timeout(READ_TIMEOUT);
 
         let tx_bytes = Arc::new(AtomicU32::new(0));
         let rx_bytes = Arc::new(AtomicU32::new(0));
         let rx_bytes_t = rx_bytes.clone();
 
+        let decoder_stats = Arc::new(Mutex::new(DecoderStats::default()));
+        let decoder_stats_t = decoder_stats.clone();
 
-        let (tx_line, rx_line) = mpsc::channel::<String>();
-
-        let process_thread = std::thread::spawn(move || {
-            while is_running_rr.load(Ordering::Relaxed) {
-                for mut line in rx_line.iter() {
-                    if line.starts_with("#") || line.starts_with("07E9") {
-                        // First char is #, diag message
-                        // Diag message
-                        if line.starts_with("#") {
-                            line.remove(0);
+        // Blocks on the port, and decodes every complete line as soon as it arrives
+        let _reader_thread = std::thread::spawn(move || {
+            println!("Serial reader start");
+            let mut decoder = UsbLineDecoder::new();
+            let mut ring = LineRingBuffer::new(RING_BUFFER_SIZE);
+            let mut read_buf = [0u8; READ_CHUNK_SIZE];
+            let mut line = Vec::with_capacity(256);
+            // Link messages reuse the timestamp of the last TCU message, so they do not look like a reboot
+            let mut last_timestamp = 0;
+            let link_msg = |timestamp: u128, lvl: EspLogLevel, msg: String| EspLogMessage {
+                lvl,
+                timestamp,
+                tag: USB_LOG_TAG.to_string(),
+                msg,
+            };
+            let mut reported_malformed = 0;
+            let mut last_report = Instant::now();
+            while is_running_r.load(Ordering::Relaxed) {
+                let n = match port_clone.read(&mut read_buf) {
+                    Ok(n) => n,
+                    Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => continue,
+                    Err(e) => {
+                        let _ = read_tx_log.send(link_msg(last_timestamp, EspLogLevel::Error, format!("Serial read failed: {e}")));
+                        is_running_r.store(false, Ordering::Relaxed);
+                        break;
+                    }
+                };
+                rx_bytes_t.fetch_add(n as u32, Ordering::Relaxed);
+                ring.push(&read_buf[..n]);
+                while ring.next_line(&mut line) {
+                    let l = String::from_utf8_lossy(&line);
+                    match decoder.decode_line(&l) {
+                        Ok(UsbFrame::Diag { can_id, payload }) => {
+                            let _ = read_tx_diag.send((can_id, payload));
                         }
-                        if line.len() % 2 != 0 {
-                            eprintln!("Discarding invalid diag msg '{}'", line);
-                        } else {
-                            let can_id = u32::from_str_radix(&line[0..4], 16).unwrap();
-                            if let Ok(p) = catch_unwind(|| {
-                                let payload: Vec<u8> = (4..line.len())
-                                    .step_by(2)
-                                    .map(|i| u8::from_str_radix(&line[i..i + 2], 16).unwrap())
-                                    .collect();
-                                payload
-                            }) {
-                                read_tx_diag.send((can_id, p));
-                            }
+                        // Only queue frames if someone is reading them
+                        Ok(UsbFrame::Can { id, data }) if can_open_r.load(Ordering::Relaxed) => {
+                            let _ = read_tx_can.send(CanFrame::new(id as u32, &data, false));
                         }
-                    } else if line.starts_with("CF->") {
-                        line = line.replace("CF->0x", "");
-                        if line.len() % 2 != 0 || line.len() > 20 {
-                            continue; // Corrupt
+                        Ok(UsbFrame::Can { .. }) => {}
+                        Ok(UsbFrame::Log(msg)) => {
+                            last_timestamp = msg.timestamp;
+                            let _ = read_tx_log.send(msg);
                         }
-                        let cid = u16::from_str_radix(&line[0..4], 16).unwrap();
-                        let data: Vec<u8> = (4..line.len())
-                                    .step_by(2)
-                                    .map(|i| u8::from_str_radix(&line[i..i + 2], 16).unwrap())
-                                    .collect();
-                        let cf = CanFrame::new(cid as u32, &data, false);
-                        let _ = read_tx_can.send(cf);
-                    } else {
-                        let lvl = match line.chars().next().unwrap_or(' ') {
-                            'I' => EspLogLevel::Info,
-                            'W' => EspLogLevel::Warn,
-                            'E' => EspLogLevel::Error,
-                            'D' => EspLogLevel::Debug,
-                            _ => {
-                                //println!("Malformed log line {line}");
-                                continue
-                            }
-                        };
-                        let timestamp = match u32::from_str_radix(between(&line, "(", ")"), 10) {
-                            Ok(ts) => ts,
-                            Err(_) => {
-                                println!("Malformed log line {line}");
-                                continue
-                            }
-                        };
-
-                        let tag = between(&line, ") ", ": ");
-                        let msg = &line[line.find(&format!("{tag}: ")).unwrap()+(tag.len()+2)..];
-                        let _ = read_tx_log.send(EspLogMessage { 
-                            lvl, 
-                            timestamp: timestamp as u128, 
-                            tag: tag.to_string(), 
-                            msg: msg.to_string()
-                        });
+                        // Counted in the decoder stats
+                        Err(_) => {}
                     }
                 }
-            }
-        });
-
-        // Create 2 threads, one to read the port, one to write to it
-        let reader_thread = std::thread::spawn(move || {
-            println!("Serial reader start");
-            let mut read_buf = Vec::new();
-            while is_running_r.load(Ordering::Relaxed) {
-                // read again in case value changed
-                let btr = port_clone.bytes_to_read().unwrap_or_default() as usize;
-                let mut r = vec![0x00; btr];
-                let actual_read = port_clone.read(&mut r[..btr]).unwrap_or_default();
-                rx_bytes_t.fetch_add(actual_read as u32, Ordering::Relaxed);
-                read_buf.extend_from_slice(&r[0..actual_read]);
-                if read_buf.len() == 0 {
-                    std::thread::sleep(Duration::from_millis(1));
-                    continue;
-                }
-
-                let mut read_idx = usize::MAX;
-                for x in 0..read_buf.len() {
-                    if read_buf[x] == 0x0A { // \n
-                        read_idx = x;
-                        break;
-                    }
+                let stats = decoder.stats();
+                if stats.malformed > reported_malformed && last_report.elapsed() >= MALFORMED_REPORT_INTERVAL {
+                    let msg = format!("Discarded {} malformed lines", stats.malformed - reported_malformed);
+                    let _ = read_tx_log.send(link_msg(last_timestamp, EspLogLevel::Warn, msg));
+                    reported_malformed = stats.malformed;
+                    last_report = Instant::now();
                 }
-                if read_idx != usize::MAX {
-                    let line = String::from_utf8_lossy(&read_buf[0..read_idx]).to_string();
-                    read_buf = read_buf[read_idx+1..].to_vec();
-                    tx_line.send(line).unwrap();
+                if let Ok(mut s) = decoder_stats_t.lock() {
+                    *s = stats;
                 }
             }
             println!("Serial reader stop");
@@ -199,7 +185,7 @@ impl Nag52USB {
                 library_location: None,
                 capabilities: ecu_diagnostics::hardware::HardwareCapabilities {
                     iso_tp: true,
-                    can: false,
+                    can: true,
                     kline: false,
                     kline_kwp: false,
                     sae_j1850: false,
@@ -210,10 +196,12 @@ impl Nag52USB {
             rx_diag: Arc::new(read_rx_diag),
             rx_log: Arc::new(read_rx_log),
             rx_can: Arc::new(read_rx_can),
+            can_open,
             tx_id: 0,
             rx_id: 0,
             tx_bytes,
             rx_bytes,
+            decoder_stats,
         })
     }
 
@@ -225,8 +213,9 @@ impl Nag52USB {
         self.rx_log.try_recv().ok()
     }
 
-    pub fn read_can(&self) -> Option<CanFrame> {
-        self.rx_can.try_recv().ok()
+    /// Counts of lines received from the TCU, including malformed lines
+    pub fn decoder_stats(&self) -> DecoderStats {
+        self.decoder_stats.lock().map(|s| *s).unwrap_or_default()
     }
 }
 
@@ -246,7 +235,13 @@ impl ecu_diagnostics::hardware::Hardware for Nag52USB {
 
     fn create_can_channel(&mut self) -> HardwareResult<Box<dyn CanChannel>>
     {
-        Err(HardwareError::ChannelNotSupported)
+        if self.can_open.load(Ordering::Relaxed) {
+            return Err(HardwareError::ConflictingChannel);
+        }
+        Ok(Box::new(Nag52UsbCanChannel {
+            rx_can: self.rx_can.clone(),
+            is_open: self.can_open.clone(),
+        }))
     }
 
     fn is_iso_tp_channel_open(&self) -> bool {
@@ -254,7 +249,7 @@ impl ecu_diagnostics::hardware::Hardware for Nag52USB {
     }
 
     fn is_can_channel_open(&self) -> bool {
-        false
+        self.can_open.load(Ordering::Relaxed)
     }
 
     fn read_battery_voltage(&mut self) -> Option<f32> {
@@ -375,3 +370,119 @@ impl IsoTPChannel for Nag52USB {
         Ok(()) // Don't care
     }
 }
+
+/// Receive-only CAN channel of the USB adapter. Frames are received from the TCU whilst it is
+/// in CAN logger mode. The TCU only accepts diagnostic messages over USB, so frames
+/// cannot be transmitted ([PacketChannel::write_packets] always fails), and the bus
+/// configuration is that of the vehicle ([VEHICLE_CAN_BAUD], standard IDs).
+pub struct Nag52UsbCanChannel {
+    rx_can: Arc<mpsc::Receiver<CanFrame>>,
+    is_open: Arc<AtomicBool>,
+}
+
+unsafe impl Sync for Nag52UsbCanChannel {}
+unsafe impl Send for Nag52UsbCanChannel {}
+
+impl Drop for Nag52UsbCanChannel {
+    fn drop(&mut self) {
+        self.is_open.store(false, Ordering::Relaxed);
+    }
+}
+
+impl PacketChannel<CanFrame> for Nag52UsbCanChannel {
+    fn open(&mut self) -> ecu_diagnostics::channel::ChannelResult<()> {
+        // Discard anything left over from the last time the channel was open
+        while self.rx_can.try_recv().is_ok() {}
+        self.is_open.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn close(&mut self) -> ecu_diagnostics::channel::ChannelResult<()> {
+        self.is_open.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn write_packets(&mut self, _packets: Vec<CanFrame>, _timeout_ms: u32) -> ecu_diagnostics::channel::ChannelResult<()> {
+        Err(ChannelError::UnsupportedRequest)
+    }
+
+    fn read_packets(&mut self, max: usize, timeout_ms: u32) -> ecu_diagnostics::channel::ChannelResult<Vec<CanFrame>> {
+        if !self.is_open.load(Ordering::Relaxed) {
+            return Err(ChannelError::InterfaceNotOpen);
+        }
+        let mut ret = Vec::new();
+        if timeout_ms != 0 {
+            if let Ok(f) = self.rx_can.recv_timeout(Duration::from_millis(timeout_ms as u64)) {
+                ret.push(f);
+            }
+        }
+        while ret.len() < max {
+            match self.rx_can.try_recv() {
+                Ok(f) => ret.push(f),
+                Err(_) => break,
+            }
+        }
+        if ret.is_empty() {
+            Err(ChannelError::BufferEmpty)
+        } else {
+            Ok(ret)
+        }
+    }
+
+    fn clear_rx_buffer(&mut self) -> ecu_diagnostics::channel::ChannelResult<()> {
+        while self.rx_can.try_recv().is_ok() {}
+        Ok(())
+    }
+
+    fn clear_tx_buffer(&mut self) -> ecu_diagnostics::channel::ChannelResult<()> {
+        Ok(())
+    }
+}
+
+impl CanChannel for Nag52UsbCanChannel {
+    fn set_can_cfg(&mut self, baud: u32, use_extended: bool) -> ecu_diagnostics::channel::ChannelResult<()> {
+        // The TCU logs whatever is on its own CAN bus, which can not be reconfigured
+        if baud != VEHICLE_CAN_BAUD || use_extended {
+            return Err(ChannelError::UnsupportedRequest);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test_usb_can {
+    use std::sync::{atomic::AtomicBool, mpsc, Arc};
+
+    use ecu_diagnostics::channel::{CanChannel, CanFrame, ChannelError, Packet, PacketChannel};
+
+    use super::Nag52UsbCanChannel;
+    use crate::diag::VEHICLE_CAN_BAUD;
+
+    #[test]
+    #[allow(clippy::arc_with_non_send_sync)] // Same as Nag52USB::new
+    pub fn test_read_packets() {
+        let (tx, rx) = mpsc::channel();
+        let is_open = Arc::new(AtomicBool::new(false));
+        let mut channel = Nag52UsbCanChannel { rx_can: Arc::new(rx), is_open: is_open.clone() };
+        assert!(matches!(channel.read_packets(1, 0), Err(ChannelError::InterfaceNotOpen)));
+        channel.set_can_cfg(VEHICLE_CAN_BAUD, false).unwrap();
+        assert!(matches!(channel.set_can_cfg(250_000, false), Err(ChannelError::UnsupportedRequest)));
+        assert!(matches!(channel.set_can_cfg(VEHICLE_CAN_BAUD, true), Err(ChannelError::UnsupportedRequest)));
+        tx.send(CanFrame::new(0x100, &[0], false)).unwrap();
+        channel.open().unwrap();
+        assert!(is_open.load(std::sync::atomic::Ordering::Relaxed));
+        // Stale frame from before opening is discarded
+        assert!(matches!(channel.read_packets(10, 0), Err(ChannelError::BufferEmpty)));
+        for i in 0..5u8 {
+            tx.send(CanFrame::new(0x418, &[i], false)).unwrap();
+        }
+        let frames = channel.read_packets(3, 10).unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].get_address(), 0x418);
+        assert_eq!(frames[2].get_data(), &[2]);
+        assert_eq!(channel.read_packets(10, 10).unwrap().len(), 2);
+        assert!(matches!(channel.write_packets(frames, 0), Err(ChannelError::UnsupportedRequest)));
+        drop(channel);
+        assert!(!is_open.load(std::sync::atomic::Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,436 @@
+//! Decoder for the line based protocol spoken by the TCU over USB.
+//!
+//! Every line the TCU sends is one of:
+//! * A diagnostic message: `#07E9<payload hex>` (Older firmware omits the `#`)
+//! * A CAN frame (CAN logger mode): `CF->0x<id hex><data hex>`
+//! * An ESP log message: `I (1234) TAG: Message`
+//!
+//! The serial link is not error free, so the decoder never trusts a line.
+//! Malformed lines are returned as a [DecodeError], and counted in [DecoderStats].
+
+use super::usb::{EspLogLevel, EspLogMessage};
+
+/// A decoded line from the TCU
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsbFrame {
+    Diag { can_id: u32, payload: Vec<u8> },
+    Can { id: u16, data: Vec<u8> },
+    Log(EspLogMessage),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Line is empty
+    Empty,
+    /// Line does not match any known format (Such as ESP ROM bootloader output)
+    Unrecognised,
+    /// Hex data has an odd number of characters
+    OddLength,
+    /// Line is too short to contain a CAN ID
+    TooShort,
+    /// CAN frame has more than 8 data bytes
+    TooLong,
+    /// Invalid hex character at this position in the line
+    InvalidHex(usize),
+    /// Log message has no valid timestamp
+    InvalidTimestamp,
+    /// Log message has no `TAG: ` section
+    MissingTag,
+}
+
+impl DecodeError {
+    /// Returns true if the line looked like a frame, but was corrupt
+    pub fn is_malformed(&self) -> bool {
+        !matches!(self, DecodeError::Empty | DecodeError::Unrecognised)
+    }
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Empty => write!(f, "Empty line"),
+            DecodeError::Unrecognised => write!(f, "Unrecognised line"),
+            DecodeError::OddLength => write!(f, "Odd number of hex characters"),
+            DecodeError::TooShort => write!(f, "Line too short"),
+            DecodeError::TooLong => write!(f, "CAN frame longer than 8 bytes"),
+            DecodeError::InvalidHex(pos) => write!(f, "Invalid hex at position {pos}"),
+            DecodeError::InvalidTimestamp => write!(f, "Invalid log timestamp"),
+            DecodeError::MissingTag => write!(f, "Log message has no tag"),
+        }
+    }
+}
+
+/// Line counters, by result
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecoderStats {
+    pub lines: u64,
+    pub diag_frames: u64,
+    pub can_frames: u64,
+    pub log_messages: u64,
+    /// Empty or unrecognised lines
+    pub ignored: u64,
+    /// Lines that looked like a frame, but were corrupt
+    pub malformed: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UsbLineDecoder {
+    stats: DecoderStats,
+}
+
+impl UsbLineDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stats(&self) -> DecoderStats {
+        self.stats
+    }
+
+    /// Decodes one line (Without the trailing newline), updating the stats
+    pub fn decode_line(&mut self, line: &str) -> Result<UsbFrame, DecodeError> {
+        let res = decode_line(line);
+        self.stats.lines += 1;
+        match &res {
+            Ok(UsbFrame::Diag { .. }) => self.stats.diag_frames += 1,
+            Ok(UsbFrame::Can { .. }) => self.stats.can_frames += 1,
+            Ok(UsbFrame::Log(_)) => self.stats.log_messages += 1,
+            Err(e) if e.is_malformed() => self.stats.malformed += 1,
+            Err(_) => self.stats.ignored += 1,
+        }
+        res
+    }
+}
+
+/// Decodes one line without keeping any stats
+pub fn decode_line(line: &str) -> Result<UsbFrame, DecodeError> {
+    let line = line.trim_end_matches('\r');
+    if line.is_empty() {
+        Err(DecodeError::Empty)
+    } else if let Some(hex) = line.strip_prefix('#') {
+        decode_diag(hex, 1)
+    } else if line.starts_with("07E9") {
+        decode_diag(line, 0)
+    } else if let Some(hex) = line.strip_prefix("CF->0x") {
+        decode_can(hex, 6)
+    } else {
+        decode_log(line)
+    }
+}
+
+/// Decodes a hex string. `offset` is the position of `hex` in the line, for error reporting
+fn decode_hex(hex: &str, offset: usize) -> Result<Vec<u8>, DecodeError> {
+    let bytes = hex.as_bytes();
+    if !bytes.len().is_multiple_of(2) {
+        return Err(DecodeError::OddLength);
+    }
+    let nibble = |i: usize| match bytes[i] {
+        c @ b'0'..=b'9' => Ok(c - b'0'),
+        c @ b'a'..=b'f' => Ok(c - b'a' + 10),
+        c @ b'A'..=b'F' => Ok(c - b'A' + 10),
+        _ => Err(DecodeError::InvalidHex(offset + i)),
+    };
+    (0..bytes.len())
+        .step_by(2)
+        .map(|i| Ok((nibble(i)? << 4) | nibble(i + 1)?))
+        .collect()
+}
+
+fn decode_diag(hex: &str, offset: usize) -> Result<UsbFrame, DecodeError> {
+    if hex.len() < 4 {
+        return Err(DecodeError::TooShort);
+    }
+    let bytes = decode_hex(hex, offset)?;
+    Ok(UsbFrame::Diag {
+        can_id: u16::from_be_bytes([bytes[0], bytes[1]]) as u32,
+        payload: bytes[2..].to_vec(),
+    })
+}
+
+fn decode_can(hex: &str, offset: usize) -> Result<UsbFrame, DecodeError> {
+    if hex.len() < 4 {
+        return Err(DecodeError::TooShort);
+    }
+    if hex.len() > 20 {
+        return Err(DecodeError::TooLong);
+    }
+    let bytes = decode_hex(hex, offset)?;
+    Ok(UsbFrame::Can {
+        id: u16::from_be_bytes([bytes[0], bytes[1]]),
+        data: bytes[2..].to_vec(),
+    })
+}
+
+fn decode_log(line: &str) -> Result<UsbFrame, DecodeError> {
+    let lvl = match line.as_bytes()[0] {
+        b'I' => EspLogLevel::Info,
+        b'W' => EspLogLevel::Warn,
+        b'E' => EspLogLevel::Error,
+        b'D' => EspLogLevel::Debug,
+        _ => return Err(DecodeError::Unrecognised),
+    };
+    // 'I (1234) TAG: Message'
+    let rest = line[1..].strip_prefix(" (").ok_or(DecodeError::Unrecognised)?;
+    let (ts, rest) = rest.split_once(") ").ok_or(DecodeError::InvalidTimestamp)?;
+    let timestamp = ts.parse::<u128>().map_err(|_| DecodeError::InvalidTimestamp)?;
+    let (tag, msg) = rest.split_once(": ").ok_or(DecodeError::MissingTag)?;
+    Ok(UsbFrame::Log(EspLogMessage {
+        lvl,
+        timestamp,
+        tag: tag.to_string(),
+        msg: msg.to_string(),
+    }))
+}
+
+/// Fixed size ring buffer that splits the raw serial stream into lines.
+///
+/// Bytes are copied in once, and lines are copied out once into a caller provided
+/// buffer, so reading does not allocate once the line buffer has grown.
+#[derive(Debug, Clone)]
+pub struct LineRingBuffer {
+    buf: Box<[u8]>,
+    /// Index of the first byte in the buffer
+    head: usize,
+    len: usize,
+    /// Bytes after head which are already known to not contain a newline
+    scanned: usize,
+    overflows: u64,
+}
+
+impl LineRingBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buf: vec![0; capacity.max(1)].into_boxed_slice(),
+            head: 0,
+            len: 0,
+            scanned: 0,
+            overflows: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of times a line was longer than the buffer, and had to be discarded
+    pub fn overflows(&self) -> u64 {
+        self.overflows
+    }
+
+    /// Adds bytes read from the serial port. If the buffer fills up, the oldest line is dropped.
+    /// If the buffer does not contain a complete line at all, that partial line is garbage and is discarded.
+    pub fn push(&mut self, mut data: &[u8]) {
+        let cap = self.buf.len();
+        while !data.is_empty() {
+            if self.len == cap {
+                self.overflows += 1;
+                match self.find_newline() {
+                    Some(idx) => self.consume(idx + 1),
+                    None => self.clear(),
+                }
+            }
+            let tail = (self.head + self.len) % cap;
+            let n = data.len().min(cap - self.len).min(cap - tail);
+            self.buf[tail..tail + n].copy_from_slice(&data[..n]);
+            self.len += n;
+            data = &data[n..];
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.head = (self.head + n) % self.buf.len();
+        self.len -= n;
+        self.scanned = 0;
+    }
+
+    fn find_newline(&mut self) -> Option<usize> {
+        let cap = self.buf.len();
+        while self.scanned < self.len {
+            if self.buf[(self.head + self.scanned) % cap] == b'\n' {
+                return Some(self.scanned);
+            }
+            self.scanned += 1;
+        }
+        None
+    }
+
+    /// Copies the next complete line (Without the newline) into `line`.
+    /// Returns false if there is no complete line in the buffer.
+    pub fn next_line(&mut self, line: &mut Vec<u8>) -> bool {
+        let Some(idx) = self.find_newline() else {
+            return false;
+        };
+        let cap = self.buf.len();
+        line.clear();
+        let first = idx.min(cap - self.head);
+        line.extend_from_slice(&self.buf[self.head..self.head + first]);
+        line.extend_from_slice(&self.buf[..idx - first]);
+        self.consume(idx + 1);
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+        self.scanned = 0;
+    }
+}
+
+#[cfg(test)]
+pub mod test_usb_decoder {
+    use super::*;
+
+    const VALID_LINES: &str = include_str!("usb_lines_valid.txt");
+    const CORRUPT_LINES: &str = include_str!("usb_lines_corrupt.txt");
+
+    fn corpus(s: &str) -> impl Iterator<Item = &str> {
+        s.lines().filter(|l| !l.starts_with("//"))
+    }
+
+    #[test]
+    pub fn test_decode_frames() {
+        assert_eq!(
+            decode_line("#07E95A8001020304"),
+            Ok(UsbFrame::Diag { can_id: 0x07E9, payload: vec![0x5A, 0x80, 0x01, 0x02, 0x03, 0x04] })
+        );
+        assert_eq!(
+            decode_line("07E97F2178"),
+            Ok(UsbFrame::Diag { can_id: 0x07E9, payload: vec![0x7F, 0x21, 0x78] })
+        );
+        assert_eq!(
+            decode_line("CF->0x0418aa5501\r"),
+            Ok(UsbFrame::Can { id: 0x0418, data: vec![0xAA, 0x55, 0x01] })
+        );
+        assert_eq!(
+            decode_line("W (10523) SHIFTER: Shift 2->3 took: 520ms"),
+            Ok(UsbFrame::Log(EspLogMessage {
+                lvl: EspLogLevel::Warn,
+                timestamp: 10523,
+                tag: "SHIFTER".into(),
+                msg: "Shift 2->3 took: 520ms".into()
+            }))
+        );
+        assert_eq!(decode_line("#07E9"), Ok(UsbFrame::Diag { can_id: 0x07E9, payload: vec![] }));
+    }
+
+    #[test]
+    pub fn test_decode_errors() {
+        assert_eq!(decode_line(""), Err(DecodeError::Empty));
+        assert_eq!(decode_line("#07E95"), Err(DecodeError::OddLength));
+        assert_eq!(decode_line("#07E9ZZ"), Err(DecodeError::InvalidHex(5)));
+        assert_eq!(decode_line("#07"), Err(DecodeError::TooShort));
+        assert_eq!(decode_line("CF->0x04180102030405060708FF"), Err(DecodeError::TooLong));
+        assert_eq!(decode_line("I (12a) MAIN: x"), Err(DecodeError::InvalidTimestamp));
+        assert_eq!(decode_line("E (12) no tag here"), Err(DecodeError::MissingTag));
+        assert_eq!(decode_line("ets Jul 29 2019 12:21:46"), Err(DecodeError::Unrecognised));
+    }
+
+    #[test]
+    pub fn test_corpus() {
+        let mut decoder = UsbLineDecoder::new();
+        let mut n_valid = 0;
+        for line in corpus(VALID_LINES) {
+            assert!(decoder.decode_line(line).is_ok(), "Valid line rejected: {line:?}");
+            n_valid += 1;
+        }
+        let mut n_corrupt = 0;
+        for line in corpus(CORRUPT_LINES) {
+            assert!(decoder.decode_line(line).is_err(), "Corrupt line accepted: {line:?}");
+            n_corrupt += 1;
+        }
+        let stats = decoder.stats();
+        assert_eq!(stats.lines, n_valid + n_corrupt);
+        assert_eq!(stats.diag_frames + stats.can_frames + stats.log_messages, n_valid);
+        assert_eq!(stats.malformed + stats.ignored, n_corrupt);
+        assert!(stats.malformed > 0 && stats.ignored > 0);
+    }
+
+    /// Truncates and corrupts every corpus line, the decoder must never panic
+    #[test]
+    pub fn test_mutated_corpus() {
+        let mut decoder = UsbLineDecoder::new();
+        let replacements = ['\u{FFFD}', 'G', '#', ' ', ')', ':', '\0', 'é'];
+        for line in corpus(VALID_LINES).chain(corpus(CORRUPT_LINES)) {
+            let chars: Vec<char> = line.chars().collect();
+            for i in 0..=chars.len() {
+                let truncated: String = chars[..i].iter().collect();
+                let _ = decoder.decode_line(&truncated);
+                for r in replacements {
+                    let mut mutated = chars.clone();
+                    if i < mutated.len() {
+                        mutated[i] = r;
+                    } else {
+                        mutated.push(r);
+                    }
+                    let _ = decoder.decode_line(&mutated.iter().collect::<String>());
+                }
+            }
+        }
+        assert!(decoder.stats().malformed > 0);
+    }
+
+    #[test]
+    pub fn test_ring_buffer_lines() {
+        let mut ring = LineRingBuffer::new(32);
+        let mut line = Vec::new();
+        ring.push(b"#07E9");
+        assert!(!ring.next_line(&mut line));
+        ring.push(b"5A\nI (1) A: b\nCF");
+        assert!(ring.next_line(&mut line));
+        assert_eq!(line, b"#07E95A");
+        assert!(ring.next_line(&mut line));
+        assert_eq!(line, b"I (1) A: b");
+        assert!(!ring.next_line(&mut line));
+        // Wraps around the end of the buffer
+        ring.push(b"->0x01F0\n#07E97E01\n");
+        assert!(ring.next_line(&mut line));
+        assert_eq!(line, b"CF->0x01F0");
+        assert!(ring.next_line(&mut line));
+        assert_eq!(line, b"#07E97E01");
+        assert!(ring.is_empty());
+        assert_eq!(ring.overflows(), 0);
+    }
+
+    #[test]
+    pub fn test_ring_buffer_overflow() {
+        let mut ring = LineRingBuffer::new(8);
+        let mut line = Vec::new();
+        // Garbage without a newline is discarded once the buffer is full
+        ring.push(b"0123456789ABC\nOK\n");
+        assert_eq!(ring.overflows(), 2);
+        assert!(ring.next_line(&mut line));
+        assert_eq!(line, b"OK");
+        // Undrained lines are dropped oldest first
+        let mut ring = LineRingBuffer::new(8);
+        ring.push(b"AA\nBB\nCC\nDD\n");
+        assert_eq!(ring.overflows(), 2);
+        assert!(ring.next_line(&mut line));
+        assert_eq!(line, b"CC");
+        assert!(ring.next_line(&mut line));
+        assert_eq!(line, b"DD");
+        assert!(!ring.next_line(&mut line));
+    }
+
+    /// Feeding the corpus in random sized chunks must give the same lines as splitting it directly
+    #[test]
+    pub fn test_ring_buffer_chunks() {
+        let expected: Vec<&str> = VALID_LINES.lines().collect();
+        for chunk in [1, 3, 7, 64, 128] {
+            let mut ring = LineRingBuffer::new(256);
+            let mut line = Vec::new();
+            let mut lines = Vec::new();
+            for c in VALID_LINES.as_bytes().chunks(chunk) {
+                ring.push(c);
+                while ring.next_line(&mut line) {
+                    lines.push(String::from_utf8(line.clone()).unwrap());
+                }
+            }
+            assert_eq!(lines, expected);
+            assert_eq!(ring.overflows(), 0);
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use std::sync::Arc;
+
+use backend::diag::calibration::{diff_calibrations, CalibrationDatabase, CalibrationFieldDiff, CalibrationKind};
+use eframe::egui::{self, Align2, Color32, FontId, RichText, ScrollArea, Sense, Vec2};
+
+use crate::window::{InterfacePage, PageAction};
+
+const CELL_SIZE: Vec2 = Vec2::new(44.0, 18.0);
+
+pub struct CalibrationDiffPage {
+    db: Arc<CalibrationDatabase>,
+    kind: CalibrationKind,
+    /// Indexes of the compared records, within [CalibrationDatabase::records]
+    a: Option<usize>,
+    b: Option<usize>,
+    filter: String,
+    only_changed: bool,
+    diff: Vec<CalibrationFieldDiff>,
+}
+
+impl CalibrationDiffPage {
+    pub fn new(db: Arc<CalibrationDatabase>) -> Self {
+        Self {
+            db,
+            kind: CalibrationKind::Hydraulic,
+            a: None,
+            b: None,
+            filter: String::new(),
+            only_changed: true,
+            diff: Vec::new(),
+        }
+    }
+
+    fn update_diff(&mut self) {
+        self.diff = match (self.a, self.b) {
+            (Some(a), Some(b)) => match (self.db.record_fields(self.kind, a), self.db.record_fields(self.kind, b)) {
+                (Some(a), Some(b)) => diff_calibrations(&a, &b),
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        };
+    }
+
+    fn record_label(&self, idx: usize) -> String {
+        match self.db.records(self.kind).get(idx) {
+            Some((name, pns)) => match pns.len() {
+                0 => name.to_string(),
+                1 => format!("{name} ({})", pns[0]),
+                n => format!("{name} ({} +{})", pns[0], n - 1),
+            },
+            None => "Select a record".into(),
+        }
+    }
+
+    /// Record picker. Returns true if the selection changed
+    fn make_picker(&self, ui: &mut egui::Ui, id: &str, selected: &mut Option<usize>) -> bool {
+        let mut changed = false;
+        let label = selected.map(|i| self.record_label(i)).unwrap_or("Select a record".into());
+        egui::ComboBox::from_id_salt(id).selected_text(label).width(250.0).show_ui(ui, |ui| {
+            for (idx, (name, pns)) in self.db.records(self.kind).iter().enumerate() {
+                let filter = self.filter.to_uppercase();
+                if !name.to_uppercase().contains(&filter) && !pns.iter().any(|p| p.contains(&filter)) {
+                    continue;
+                }
+                if ui.selectable_label(*selected == Some(idx), self.record_label(idx)).clicked() {
+                    *selected = Some(idx);
+                    changed = true;
+                }
+            }
+        });
+        changed
+    }
+
+    fn make_users(&self, ui: &mut egui::Ui, id: &str, selected: Option<usize>) {
+        let records = self.db.records(self.kind);
+        let Some((name, pns)) = selected.and_then(|i| records.get(i)) else {
+            return;
+        };
+        let users = self.db.record_users(self.kind, name, pns);
+        egui::CollapsingHeader::new(format!("Used by {} chassis ({} EGS part numbers)", users.len(), pns.len()))
+            .id_salt(id)
+            .show(ui, |ui| {
+                ScrollArea::vertical().id_salt(format!("{id}-scroll")).max_height(150.0).show(ui, |ui| {
+                    for (pn, chassis) in &users {
+                        ui.label(format!("{pn}: {} ({})", chassis.chassis, chassis.gearbox));
+                    }
+                    let unused: Vec<&String> = pns.iter().filter(|p| !users.iter().any(|(u, _)| u == p)).collect();
+                    if !unused.is_empty() {
+                        ui.label(format!("Also valid for: {}", unused.iter().map(|p| p.as_str()).collect::<Vec<_>>().join(", ")));
+                    }
+                });
+            });
+    }
+}
+
+/// Blue (Low) to red (High)
+fn scale_color(t: f32) -> Color32 {
+    let t = t.clamp(0.0, 1.0);
+    Color32::from_rgb((255.0 * t) as u8, (160.0 * (1.0 - (2.0 * t - 1.0).abs())) as u8, (255.0 * (1.0 - t)) as u8)
+}
+
+/// Grey if unchanged, otherwise blue (Lower) or red (Higher), stronger for larger changes
+fn delta_color(delta: i64, max_abs: i64) -> Color32 {
+    if delta == 0 {
+        return Color32::from_gray(90);
+    }
+    let t = 0.3 + 0.7 * (delta.abs() as f32 / max_abs.max(1) as f32);
+    let v = (255.0 * t) as u8;
+    if delta > 0 { Color32::from_rgb(v, 40, 40) } else { Color32::from_rgb(40, 40, v) }
+}
+
+fn heatmap(ui: &mut egui::Ui, title: &str, values: &[i64], shape: (usize, usize), color: impl Fn(i64) -> Color32) {
+    ui.vertical(|ui| {
+        ui.strong(title);
+        let (rows, cols) = shape;
+        for row in 0..rows {
+            ui.horizontal(|ui| {
+                ui.spacing_mut().item_spacing = Vec2::new(1.0, 1.0);
+                for col in 0..cols {
+                    let Some(v) = values.get(row * cols + col) else {
+                        continue;
+                    };
+                    let (rect, resp) = ui.allocate_exact_size(CELL_SIZE, Sense::hover());
+                    ui.painter().rect_filled(rect, 0.0, color(*v));
+                    ui.painter().text(rect.center(), Align2::CENTER_CENTER, v.to_string(), FontId::monospace(10.0), Color32::WHITE);
+                    resp.on_hover_text(format!("Row {row}, column {col}: {v}"));
+                }
+            });
+        }
+    });
+}
+
+fn make_array_diff(ui: &mut egui::Ui, d: &CalibrationFieldDiff) {
+    let changed = d.changed();
+    let mut title = RichText::new(format!("{} ({changed} of {} values differ)", d.name, d.a.len()));
+    if changed != 0 {
+        title = title.color(Color32::ORANGE);
+    }
+    egui::CollapsingHeader::new(title).id_salt(format!("cal-diff-{}", d.name)).default_open(changed != 0).show(ui, |ui| {
+        let min = d.a.iter().chain(d.b.iter()).min().copied().unwrap_or_default();
+        let max = d.a.iter().chain(d.b.iter()).max().copied().unwrap_or_default();
+        let scale = |v: i64| scale_color(if max == min { 0.5 } else { (v - min) as f32 / (max - min) as f32 });
+        let deltas = d.deltas();
+        let max_abs = deltas.iter().map(|x| x.abs()).max().unwrap_or_default();
+        ScrollArea::horizontal().id_salt(format!("cal-diff-scroll-{}", d.name)).show(ui, |ui| {
+            ui.horizontal_top(|ui| {
+                heatmap(ui, "A", &d.a, d.shape, scale);
+                ui.add_space(10.0);
+                heatmap(ui, "B", &d.b, d.shape, scale);
+                ui.add_space(10.0);
+                heatmap(ui, "B - A", &deltas, d.shape, |v| delta_color(v, max_abs));
+            });
+        });
+    });
+}
+
+impl InterfacePage for CalibrationDiffPage {
+    fn make_ui(&mut self, ui: &mut egui::Ui, _frame: &eframe::Frame) -> PageAction {
+        ui.horizontal(|row| {
+            row.strong("Calibration type");
+            let mut kind = self.kind;
+            egui::ComboBox::from_id_salt("cal-diff-kind").selected_text(kind.to_string()).show_ui(row, |ui| {
+                for k in CalibrationKind::ALL {
+                    ui.selectable_value(&mut kind, k, k.to_string());
+                }
+            });
+            if kind != self.kind {
+                self.kind = kind;
+                self.a = None;
+                self.b = None;
+                self.update_diff();
+            }
+            row.strong("Filter by name or EGS PN");
+            row.text_edit_singleline(&mut self.filter);
+        });
+        let (mut a, mut b) = (self.a, self.b);
+        let mut changed = false;
+        ui.columns(2, |cols| {
+            cols[0].horizontal(|row| {
+                row.strong("A");
+                changed |= self.make_picker(row, "cal-diff-a", &mut a);
+            });
+            self.make_users(&mut cols[0], "cal-diff-users-a", a);
+            cols[1].horizontal(|row| {
+                row.strong("B");
+                changed |= self.make_picker(row, "cal-diff-b", &mut b);
+            });
+            self.make_users(&mut cols[1], "cal-diff-users-b", b);
+        });
+        if changed {
+            self.a = a;
+            self.b = b;
+            self.update_diff();
+        }
+        ui.separator();
+        if self.diff.is_empty() {
+            ui.label("Select two records to compare");
+            return PageAction::None;
+        }
+        let n_changed = self.diff.iter().filter(|d| d.changed() != 0).count();
+        ui.horizontal(|row| {
+            if n_changed == 0 {
+                row.colored_label(Color32::GREEN, "Records are identical");
+            } else {
+                row.label(format!("{n_changed} of {} fields differ", self.diff.len()));
+            }
+            row.checkbox(&mut self.only_changed, "Only show fields that differ");
+        });
+        ScrollArea::vertical().id_salt("cal-diff-fields").show(ui, |ui| {
+            let shown: Vec<&CalibrationFieldDiff> =
+                self.diff.iter().filter(|d| !self.only_changed || d.changed() != 0).collect();
+            egui::Grid::new("cal-diff-scalars").striped(true).num_columns(4).show(ui, |ui| {
+                ui.strong("Field");
+                ui.strong("A");
+                ui.strong("B");
+                ui.strong("B - A");
+                ui.end_row();
+                for d in shown.iter().filter(|d| !d.is_array) {
+                    let delta = d.deltas().first().copied().unwrap_or_default();
+                    ui.label(&d.name);
+                    ui.monospace(d.a.first().map(|v| v.to_string()).unwrap_or_default());
+                    ui.monospace(d.b.first().map(|v| v.to_string()).unwrap_or_default());
+                    match delta {
+                        0 => ui.label(""),
+                        d => ui.colored_label(if d > 0 { Color32::RED } else { Color32::LIGHT_BLUE }, format!("{d:+}")),
+                    };
+                    ui.end_row();
+                }
+            });
+            for d in shown.iter().filter(|d| d.is_array) {
+                make_array_diff(ui, d);
+            }
+        });
+        PageAction::None
+    }
+
+    fn get_title(&self) -> &'static str {
+        "Calibration comparison"
+    }
+
+    fn should_show_statusbar(&self) -> bool {
+        true
+    }
+}
@@ -1,23 +1,18 @@
-use std::{borrow::{Borrow, BorrowMut}, cmp::min, fs::File, io::{Read, Write}, thread::JoinHandle};
+use std::{borrow::{Borrow, BorrowMut}, cmp::min, fs::File, io::{Read, Write}, sync::Arc, thread::JoinHandle};
 
 use config_app_macros::include_base64;
-use eframe::egui::{Color32, Label, RichText, Window};
+use eframe::egui::{Color32, Label, RichText, Ui, Window};
 use egui_extras::Column;
 use packed_struct::PackedStructSlice;
 
 use crate::window::{InterfacePage, PageAction};
 
-use backend::{diag::{calibration::*, memory::MemoryRegion, Nag52Diag}, ecu_diagnostics::{kwp2000::KwpSessionType, DiagError}, serde_yaml};
+use super::cal_diff::CalibrationDiffPage;
 
+use backend::{diag::{calibration::*, memory::MemoryRegion, Nag52Diag}, serde_yaml};
 
-const EGS_DB_BYTES: &[u8] = include_bytes!("../../../../egs_db.bin"); 
 
-pub enum CalibrationSection {
-    Hyraulic,
-    Mechanical,
-    TorqueConverter,
-    ShiftAlgo
-}
+const EGS_DB_BYTES: &[u8] = include_bytes!("../../../../egs_db.bin"); 
 
 #[derive(Debug, Clone)]
 pub struct EgsLinkedData {
@@ -31,51 +26,49 @@ pub struct EgsLinkedData {
 }
 
 pub struct EgsConfigPage {
-    pub db: Result<CalibrationDatabase, String>,
+    pub db: Result<Arc<CalibrationDatabase>, String>,
     pub linked: Result<Vec<EgsLinkedData>, String>,
     pub viewing_cal: Option<EgsLinkedData>,
-    pub calibration_contents: Result<Vec<u8>, String>,
+    pub calibration: Result<StoredCalibration, String>,
     pub res: Option<JoinHandle<Result<Vec<u8>, String>>>,
     pub nag: Nag52Diag,
     pub gb_input: String,
     pub egs_pn: String,
     pub chassis_input: String,
-    pub editing_cal: Option<CalibrationSection>
+    pub editing_cal: Option<CalibrationKind>
 }
 
-fn sign_and_crc(egs: &mut EgsStoredCalibration) {
-    let egs_bytes = &egs.pack_to_vec().unwrap()[8..];
-    // CRC check
-    let mut crc: u16 = 0;
-    for i in 0..egs_bytes.len() {
-        let b = egs_bytes[i];
-        crc = crc.wrapping_add(b as u16);
-        crc = crc.wrapping_add(i as u16);
-    }
-    egs.crc = crc;
-    egs.len = EgsStoredCalibration::packed_bytes_size(None).unwrap() as u16;
-    println!("Size W {}", egs.len);
-    egs.magic = 0xDEADBEEF;
+/// Shows the status of a stored calibration. Returns None if its name is invalid,
+/// otherwise if the load/save button was clicked
+fn make_cal_status<T>(ui: &mut Ui, set: &StoredCalibrationSet<T>) -> Option<bool> {
+    let Some(name) = &set.name else {
+        ui.colored_label(Color32::RED, format!("{} calibration NOT FOUND", set.kind));
+        return None;
+    };
+    let clicked = ui.horizontal(|row| {
+        match set.database_name() {
+            Some((pn, cal)) if set.matches_name() => {
+                // MB calibration
+                row.colored_label(Color32::GREEN, format!("{} calibration: '{cal}' from {pn}", set.kind));
+            }
+            Some((pn, cal)) => {
+                row.colored_label(Color32::RED, format!("{} calibration: '{cal}' from {pn} does not match the database. It is corrupted or was modified", set.kind));
+            }
+            None => {
+                row.colored_label(Color32::GREEN, format!("Custom {} calibration in use: '{name}'", set.kind.to_string().to_lowercase()));
+            }
+        }
+        if let Some(m) = set.matches.first().filter(|_| !set.matches_name()) {
+            row.label(format!("(Identical to '{}' from {})", m.name, m.valid_egs_pns.join(", ")));
+        }
+        row.button("Load/Save from file").clicked()
+    }).inner;
+    Some(clicked)
 }
 
 impl EgsConfigPage {
     pub fn new(nag: Nag52Diag) -> Self {
-        let db = match lz4_compression::decompress::decompress(EGS_DB_BYTES).map_err(|e| {
-            match e {
-                lz4_compression::decompress::Error::UnexpectedEnd => "LZ4 decompress failed. Unexpected End",
-                lz4_compression::decompress::Error::InvalidDeduplicationOffset => "LZ4 decompress failed. Invalid deduplication offset",
-            }.to_string()
-        }) {
-            Err(e) => Err(e),
-            Ok(bytes) => {
-                match bincode::serde::decode_from_slice::<CalibrationDatabase, _>(&bytes, bincode::config::legacy()) {
-                    Ok((d, _)) => Ok(d),
-                    Err(e) => {
-                        Err(e.to_string())
-                    }
-                }
-            }
-        };
+        let db = CalibrationDatabase::decode(EGS_DB_BYTES).map(Arc::new).map_err(|e| e.to_string());
 
         let linked = match &db {
             Err(e) => Err(e.clone()),
@@ -102,45 +95,7 @@ impl EgsConfigPage {
 
         let nag_c = nag.clone();
         let r = std::thread::spawn(move || {
-            let len = EgsStoredCalibration::packed_bytes_size(None).unwrap() as u32;
-            let mut i = 0;
-            let mut res: Vec<u8> = Vec::new();
-            let size = match nag_c.with_kwp(|kwp| {
-                kwp.kwp_set_session(KwpSessionType::ExtendedDiagnostics.into())?;
-                kwp.kwp_read_custom_local_identifier(0xFB)
-            }) {
-                Ok(res) => {
-                    let size = u16::from_le_bytes(res.try_into().unwrap());
-                    if size != len as u16 {
-                        Err("Mismatch calibration size! Either your Firmware or Configuration app is out of date".to_string())
-                    } else {
-                        Ok(size)
-                    }
-                },
-                Err(DiagError::ECUError { code, def }) => {
-                    Err("TCU does not support calibration. Please update firmware".to_string())
-                },
-                Err(e) => {
-                    Err(format!("Error trying to download flash contents. {e}"))
-                }
-            };
-            if size.is_ok() {
-                while i < len {
-                    let read = min(0xFE, len - i);
-                    match nag_c.read_memory(MemoryRegion::EgsCalibration, i, read as u8) {
-                        Ok(c) => {
-                            res.extend_from_slice(&c[1..]);
-                        },
-                        Err(e) => {
-                            return Err(format!("Error downloading flash contents. {e}"));
-                        }
-                    }
-                    i += read;
-                }
-                Ok(res)
-            } else {
-                Err(size.err().unwrap())
-            }
+            nag_c.read_calibration_bytes().map_err(|e| e.to_string())
         });
 
         Self {
@@ -148,7 +103,7 @@ impl EgsConfigPage {
             linked,
             viewing_cal: None,
             nag,
-            calibration_contents: Ok(Vec::new()),
+            calibration: Err("Not read".into()),
             res: Some(r),
             gb_input: String::default(),
             egs_pn: String::default(),
@@ -195,10 +150,19 @@ impl InterfacePage for EgsConfigPage {
             }
         }
         if take {
-            self.calibration_contents = self.res.take().unwrap().join().unwrap()
+            let empty_db = CalibrationDatabase::default();
+            let db = self.db.as_deref().unwrap_or(&empty_db);
+            self.calibration = self.res.take().unwrap().join().unwrap()
+                .and_then(|bytes| StoredCalibration::parse(&bytes, db).map_err(|e| e.to_string()));
         }
 
-        if let Err(e) = &self.calibration_contents {
+        // Comparing only needs the database, so it is available even if the TCU has no calibration
+        if let Ok(db) = &self.db {
+            if ui.button("Compare calibrations").clicked() {
+                action = PageAction::Add(Box::new(CalibrationDiffPage::new(db.clone())));
+            }
+        }
+        if let Err(e) = &self.calibration {
             ui.vertical_centered(|ui| {
                 ui.strong("Failed to initialize calibration");
                 ui.label(e);
@@ -209,101 +173,35 @@ impl InterfacePage for EgsConfigPage {
                 ui.label(e)
             });
         } else {
-            let flash = self.calibration_contents.as_mut().unwrap();
-            let mut interpreted = EgsStoredCalibration::unpack_from_slice(&flash).unwrap();
+            let stored = self.calibration.as_mut().unwrap();
+            let mut interpreted = stored.raw.clone();
             let db = self.db.as_ref().unwrap();
             // Show calibrations that are not valid
             ui.hyperlink_to("Watch tutorial video for help", include_base64!("aHR0cHM6Ly95b3V0dS5iZS9ENlZmNWlqekpndw"));
             ui.strong("Status of calibration data (Your TCU):");
+            for fault in &stored.faults {
+                ui.colored_label(Color32::RED, format!("Calibration block is corrupted, the TCU will not use it: {fault}"));
+            }
             let mut error_counter = 0;
-            match String::from_utf8(interpreted.hydr_cal_name.to_vec()) {
-                Ok(name) => {
-                    let parts_maybe = name.split(".").collect::<Vec<&str>>();
-                    ui.horizontal(|row| {
-                        if parts_maybe.len() == 2 && parts_maybe[0].starts_with("A") {
-                            // MB calibration
-                            row.colored_label(Color32::GREEN,  format!("Hydraulic calibration: '{}' from {}", parts_maybe[1], parts_maybe[0]));
-                        } else {
-                            row.colored_label(Color32::GREEN,  format!("Custom Hydraulic calibration in use: '{name}'"));
-                        }
-                        if row.button("Load/Save from file").clicked() {
-                            self.editing_cal = Some(CalibrationSection::Hyraulic)
-                        }
-                    });
-                },
-                Err(_) => {
-                    error_counter += 1;
-                    ui.colored_label(Color32::RED, "Hydraulic calibration NOT FOUND");
-                }
-            };
-            match String::from_utf8(interpreted.mech_cal_name.to_vec()) {
-                Ok(name) => {
-                    let parts_maybe = name.split(".").collect::<Vec<&str>>();
-                    ui.horizontal(|row| {
-                        if parts_maybe.len() == 2 && parts_maybe[0].starts_with("A") {
-                            // MB calibration
-                            row.colored_label(Color32::GREEN,  format!("Mechanical calibration: '{}' from {}", parts_maybe[1], parts_maybe[0]));
-                        } else {
-                            row.colored_label(Color32::GREEN,  format!("Custom mechanical calibration in use: '{name}'"));
-                        }
-                        if row.button("Load/Save from file").clicked() {
-                            self.editing_cal = Some(CalibrationSection::Mechanical)
-                        }
-                    });
-                },
-                Err(_) => {
-                    error_counter += 1;
-                    ui.colored_label(Color32::RED, "Mechanical calibration NOT FOUND");
+            for (kind, clicked) in [
+                (CalibrationKind::Hydraulic, make_cal_status(ui, &stored.hydraulic)),
+                (CalibrationKind::Mechanical, make_cal_status(ui, &stored.mechanical)),
+                (CalibrationKind::TorqueConverter, make_cal_status(ui, &stored.torque_converter)),
+                (CalibrationKind::ShiftAlgo, make_cal_status(ui, &stored.shift_algo)),
+            ] {
+                match clicked {
+                    Some(true) => self.editing_cal = Some(kind),
+                    Some(false) => {}
+                    None => error_counter += 1,
                 }
-            };
-            match String::from_utf8(interpreted.tcc_cal_name.to_vec()) {
-                Ok(name) => {
-                    let parts_maybe = name.split(".").collect::<Vec<&str>>();
-                    ui.horizontal(|row| {
-                        if parts_maybe.len() == 2 && parts_maybe[0].starts_with("A") {
-                            // MB calibration
-                            row.colored_label(Color32::GREEN,  format!("TCC properties calibration: '{}' from {}", parts_maybe[1], parts_maybe[0]));;
-                        } else {
-                            row.colored_label(Color32::GREEN,  format!("Custom TCC properties calibration in use: '{name}'"));
-                        }
-                        if row.button("Load/Save from file").clicked() {
-                            self.editing_cal = Some(CalibrationSection::TorqueConverter)
-                        }
-                    });
-                },
-                Err(_) => {
-                    error_counter += 1;
-                    ui.colored_label(Color32::RED, "TCC properties calibration NOT FOUND");
-                }
-            };
-
-            match String::from_utf8(interpreted.shift_algo_cal_name.to_vec()) {
-                Ok(name) => {
-                    let parts_maybe = name.split(".").collect::<Vec<&str>>();
-                    ui.horizontal(|row| {
-                        if parts_maybe.len() == 2 && parts_maybe[0].starts_with("A") {
-                            // MB calibration
-                            row.colored_label(Color32::GREEN,  format!("Shift algo pack calibration: '{}' from {}", parts_maybe[1], parts_maybe[0]));;
-                        } else {
-                            row.colored_label(Color32::GREEN,  format!("Custom shift algo pack calibration in use: '{name}'"));
-                        }
-                        if row.button("Load/Save from file").clicked() {
-                            self.editing_cal = Some(CalibrationSection::ShiftAlgo)
-                        }
-                    });
-                },
-                Err(_) => {
-                    error_counter += 1;
-                    ui.colored_label(Color32::RED, "Shift algo pack calibration NOT FOUND");
-                }
-            };
+            }
 
             if error_counter == 0 {
                 // We can save!
                 if ui.button("Apply calibrations").clicked() {
-                    sign_and_crc(&mut interpreted);
-                    interpreted.pack_to_slice(flash).unwrap();
-                    Self::write_calibration(self.nag.clone(), flash.clone());
+                    interpreted.sign();
+                    *stored = StoredCalibration::new(interpreted.clone(), db);
+                    Self::write_calibration(self.nag.clone(), interpreted.pack_to_vec().unwrap());
                 }
             } else {
                 ui.label("There are errors in the calibration data. Please correct the errors above.");
@@ -384,47 +282,33 @@ impl InterfacePage for EgsConfigPage {
                     .open(&mut open)
                     .show(ui.ctx(), |ui| {
                         let mut modified = false;
+                        let mut name_res = Ok(());
                         if ui.button("Use hydraulic calibration").clicked() {
                             interpreted.hydr_cal = hydr.data;
-                            let name = format!("{}.{}", linked_data.pn, linked_data.hydr);
-                            assert!(name.len() <= 16);
-                            interpreted.hydr_cal_name.fill(0);
-                            interpreted.hydr_cal_name[0..name.len()].copy_from_slice(name.as_bytes());
+                            name_res = interpreted.set_name(CalibrationKind::Hydraulic, &CalibrationDatabase::stored_name(&linked_data.pn, &linked_data.hydr));
                             modified = true;
                         }
                         if ui.button("Use mechanical calibration").clicked() {
                             interpreted.mech_cal = mech.data;
-                            let name = format!("{}.{}", linked_data.pn, linked_data.mech);
-                            assert!(name.len() <= 16);
-                            interpreted.mech_cal_name.fill(0);
-                            interpreted.mech_cal_name[0..name.len()].copy_from_slice(name.as_bytes());
+                            name_res = interpreted.set_name(CalibrationKind::Mechanical, &CalibrationDatabase::stored_name(&linked_data.pn, &linked_data.mech));
                             modified = true;
                         }
                         if ui.button("Use torque converter calibration").clicked() {
                             interpreted.tcc_cal = tcc.data;
-                            let name = if linked_data.tcc == "NO NAME" {
-                                "NN"
-                            } else {
-                                &linked_data.tcc
-                            };
-                            let name = format!("{}.{}", linked_data.pn, name);
-                            assert!(name.len() <= 16);
-                            interpreted.tcc_cal_name.fill(0);
-                            interpreted.tcc_cal_name[0..name.len()].copy_from_slice(name.as_bytes());
+                            name_res = interpreted.set_name(CalibrationKind::TorqueConverter, &CalibrationDatabase::stored_name(&linked_data.pn, &linked_data.tcc));
                             modified = true;
                         }
                         if ui.button("Use Shift algo pack calibration").clicked() {
                             interpreted.shift_algo_cal = shift_algo.data;
-                            let name = format!("{}.{}", linked_data.pn, linked_data.shift_algo);
-                            assert!(name.len() <= 16);
-                            interpreted.shift_algo_cal_name.fill(0);
-                            interpreted.shift_algo_cal_name[0..name.len()].copy_from_slice(name.as_bytes());
+                            name_res = interpreted.set_name(CalibrationKind::ShiftAlgo, &CalibrationDatabase::stored_name(&linked_data.pn, &linked_data.shift_algo));
                             modified = true;
                         }
-                        if modified {
+                        if let Err(e) = name_res {
+                            action = PageAction::SendNotification { text: e.to_string(), kind: egui_notify::ToastLevel::Error };
+                        } else if modified {
                             // Sign and save
-                            sign_and_crc(&mut interpreted);
-                            interpreted.pack_to_slice(flash).unwrap();
+                            interpreted.sign();
+                            *stored = StoredCalibration::new(interpreted.clone(), db);
                         }
                     });
                 if !open {
@@ -444,10 +328,10 @@ impl InterfacePage for EgsConfigPage {
                         if ui.button("Save to YML").clicked() {
                             if let Some(f) = rfd::FileDialog::new().add_filter("yml", &["yml"]).save_file() {
                                 let dump = match editing {
-                                    CalibrationSection::Hyraulic => serde_yaml::to_string(&interpreted.hydr_cal).unwrap(),
-                                    CalibrationSection::Mechanical => serde_yaml::to_string(&interpreted.mech_cal).unwrap(),
-                                    CalibrationSection::TorqueConverter => serde_yaml::to_string(&interpreted.tcc_cal).unwrap(),
-                                    CalibrationSection::ShiftAlgo => serde_yaml::to_string(&interpreted.shift_algo_cal).unwrap(),
+                                    CalibrationKind::Hydraulic => serde_yaml::to_string(&interpreted.hydr_cal).unwrap(),
+                                    CalibrationKind::Mechanical => serde_yaml::to_string(&interpreted.mech_cal).unwrap(),
+                                    CalibrationKind::TorqueConverter => serde_yaml::to_string(&interpreted.tcc_cal).unwrap(),
+                                    CalibrationKind::ShiftAlgo => serde_yaml::to_string(&interpreted.shift_algo_cal).unwrap(),
                                 };
                                 let mut r = File::create(f).unwrap();
                                 r.write_all(dump.as_bytes()).unwrap();
@@ -460,10 +344,10 @@ impl InterfacePage for EgsConfigPage {
                                 r.read_to_end(&mut buf).unwrap();
                                 let contents = String::from_utf8(buf).unwrap();
                                 let res = match editing {
-                                    CalibrationSection::Hyraulic => serde_yaml::from_str::<EgsHydraulicConfiguration>(&contents).map(|x| interpreted.hydr_cal = x),
-                                    CalibrationSection::Mechanical => serde_yaml::from_str::<EgsMechanicalConfiguration>(&contents).map(|x| interpreted.mech_cal = x),
-                                    CalibrationSection::TorqueConverter => serde_yaml::from_str::<EgsTorqueConverterConfiguration>(&contents).map(|x| interpreted.tcc_cal = x),
-                                    CalibrationSection::ShiftAlgo => serde_yaml::from_str::<EgsShiftMapConfiguration>(&contents).map(|x| interpreted.shift_algo_cal = x),
+                                    CalibrationKind::Hydraulic => serde_yaml::from_str::<EgsHydraulicConfiguration>(&contents).map(|x| interpreted.hydr_cal = x),
+                                    CalibrationKind::Mechanical => serde_yaml::from_str::<EgsMechanicalConfiguration>(&contents).map(|x| interpreted.mech_cal = x),
+                                    CalibrationKind::TorqueConverter => serde_yaml::from_str::<EgsTorqueConverterConfiguration>(&contents).map(|x| interpreted.tcc_cal = x),
+                                    CalibrationKind::ShiftAlgo => serde_yaml::from_str::<EgsShiftMapConfiguration>(&contents).map(|x| interpreted.shift_algo_cal = x),
                                 };
                                 if let Err(e) = res {
                                     let msg = format!("Failed to load calibrations: {}", e.to_string());
@@ -472,17 +356,14 @@ impl InterfacePage for EgsConfigPage {
                                     let n = f.file_name().unwrap();
                                     let sl= n.to_string_lossy();
                                     let cal_name = sl.split(".yml").next().unwrap();
-                                    let mut buf = cal_name.as_bytes().to_vec();
-                                    buf.resize(16, 0x00);
-                                    match editing {
-                                        CalibrationSection::Hyraulic => interpreted.hydr_cal_name = buf.try_into().unwrap(),
-                                        CalibrationSection::Mechanical => interpreted.mech_cal_name = buf.try_into().unwrap(),
-                                        CalibrationSection::TorqueConverter => interpreted.tcc_cal_name = buf.try_into().unwrap(),
-                                        CalibrationSection::ShiftAlgo => interpreted.shift_algo_cal_name = buf.try_into().unwrap()
+                                    if let Err(e) = interpreted.set_name(*editing, cal_name) {
+                                        let msg = format!("Failed to load calibrations: {e}. Rename the file");
+                                        action = PageAction::SendNotification { text: msg, kind: egui_notify::ToastLevel::Error };
+                                    } else {
+                                        // Sign and save
+                                        interpreted.sign();
+                                        *stored = StoredCalibration::new(interpreted.clone(), db);
                                     }
-                                    // Sign and save
-                                    sign_and_crc(&mut interpreted);
-                                    interpreted.pack_to_slice(flash).unwrap();
                                 }
                             }
                         }
@@ -18,6 +18,7 @@ use self::cfg_structs::{
 
 use super::{StatusText};
 
+pub mod cal_diff;
 pub mod cfg_structs;
 pub mod egs_config;
 pub struct ConfigPage {
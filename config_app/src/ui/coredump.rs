@@ -0,0 +1,288 @@
+use std::{collections::HashMap, sync::{Arc, RwLock}};
+
+use backend::{
+    diag::Nag52Diag,
+    hw::{
+        coredump::{exception_cause_name, CoreDump, CoreDumpError, CoreDumpTask, XT_REG_EPC1, XT_REG_EXCCAUSE, XT_REG_EXCVADDR},
+        debug_elf::DebugElf,
+    },
+};
+use eframe::egui::{self, Color32, ProgressBar, RichText, ScrollArea};
+
+use crate::window::{InterfacePage, PageAction};
+
+use super::save_file;
+
+#[derive(Debug, Clone)]
+enum CoredumpLoadState {
+    Msg(String),
+    Read { done: u32, total: u32 },
+    Ready,
+    Err(String),
+}
+
+struct CoredumpData {
+    /// Where the coredump came from (TCU or file name)
+    source: String,
+    raw: Vec<u8>,
+    dump: Result<CoreDump, CoreDumpError>,
+}
+
+pub struct CoredumpPage {
+    nag: Nag52Diag,
+    state: Arc<RwLock<CoredumpLoadState>>,
+    data: Arc<RwLock<Option<CoredumpData>>>,
+    /// File name and contents of the debug ELF
+    elf: Option<(String, DebugElf)>,
+    /// Source location of every address looked up in the ELF so far
+    locations: HashMap<u32, String>,
+}
+
+impl CoredumpPage {
+    pub fn new(nag: Nag52Diag, ctx: egui::Context) -> Self {
+        let ret = Self {
+            nag,
+            state: Arc::new(RwLock::new(CoredumpLoadState::Msg("Init".into()))),
+            data: Arc::new(RwLock::new(None)),
+            elf: None,
+            locations: HashMap::new(),
+        };
+        ret.read_from_tcu(ctx);
+        ret
+    }
+
+    fn read_from_tcu(&self, ctx: egui::Context) {
+        let nag = self.nag.clone();
+        let state = self.state.clone();
+        let data = self.data.clone();
+        std::thread::spawn(move || {
+            let set_state = |s: CoredumpLoadState| {
+                *state.write().unwrap() = s;
+                ctx.request_repaint();
+            };
+            set_state(CoredumpLoadState::Msg("Reading coredump".into()));
+            match nag.read_coredump(|done, total| set_state(CoredumpLoadState::Read { done, total })) {
+                Ok(raw) => {
+                    let dump = CoreDump::parse(&raw);
+                    *data.write().unwrap() = Some(CoredumpData { source: "TCU".into(), raw, dump });
+                    set_state(CoredumpLoadState::Ready);
+                }
+                Err(e) => set_state(CoredumpLoadState::Err(format!("Could not read the coredump: {e}"))),
+            }
+        });
+    }
+
+    fn open_file(&mut self) -> Option<PageAction> {
+        let path = rfd::FileDialog::new().set_title("Open coredump").add_filter("bin", &["bin"]).pick_file()?;
+        match std::fs::read(&path) {
+            Ok(raw) => {
+                let dump = CoreDump::parse(&raw);
+                let source = path.file_name().map(|f| f.to_string_lossy().to_string()).unwrap_or_default();
+                *self.data.write().unwrap() = Some(CoredumpData { source, raw, dump });
+                *self.state.write().unwrap() = CoredumpLoadState::Ready;
+                None
+            }
+            Err(e) => Some(PageAction::SendNotification {
+                text: format!("Could not read {}: {e}", path.display()),
+                kind: egui_notify::ToastLevel::Error,
+            }),
+        }
+    }
+
+    fn load_elf(&mut self) -> Option<PageAction> {
+        let path = rfd::FileDialog::new().set_title("Open debug ELF").add_filter("elf", &["elf"]).pick_file()?;
+        match DebugElf::load(&path) {
+            Ok(elf) => {
+                let name = path.file_name().map(|f| f.to_string_lossy().to_string()).unwrap_or_default();
+                self.elf = Some((name, elf));
+                self.locations.clear();
+                None
+            }
+            Err(e) => Some(PageAction::SendNotification {
+                text: format!("Could not load {}: {e}", path.display()),
+                kind: egui_notify::ToastLevel::Error,
+            }),
+        }
+    }
+}
+
+/// Source location of a code address, or an empty string if no ELF is loaded
+fn location(locations: &mut HashMap<u32, String>, elf: Option<&DebugElf>, pc: u32) -> String {
+    let Some(elf) = elf else {
+        return String::new();
+    };
+    locations
+        .entry(pc)
+        .or_insert_with(|| {
+            let frames: Vec<String> = elf.lookup(pc).iter().map(|l| l.to_string()).collect();
+            match frames.is_empty() {
+                true => "??".into(),
+                false => frames.join(", inlined into "),
+            }
+        })
+        .clone()
+}
+
+fn make_task(ui: &mut egui::Ui, task: &CoreDumpTask, locations: &mut HashMap<u32, String>, elf: Option<&DebugElf>) {
+    let name = if task.name.is_empty() { "Unknown".to_string() } else { task.name.clone() };
+    let mut title = RichText::new(format!("Task '{name}' (TCB 0x{:08X})", task.tcb));
+    if task.crashed {
+        title = title.color(Color32::RED).strong();
+    }
+    egui::CollapsingHeader::new(title)
+        .id_salt(format!("cd-task-{:08X}", task.tcb))
+        .default_open(task.crashed)
+        .show(ui, |ui| {
+            egui::Grid::new(format!("cd-bt-{:08X}", task.tcb)).striped(true).num_columns(4).show(ui, |ui| {
+                ui.strong("#");
+                ui.strong("PC");
+                ui.strong("SP");
+                ui.strong("Location");
+                ui.end_row();
+                for (idx, frame) in task.frames.iter().enumerate() {
+                    ui.label(idx.to_string());
+                    ui.monospace(format!("0x{:08X}", frame.pc));
+                    ui.monospace(format!("0x{:08X}", frame.sp));
+                    ui.label(location(locations, elf, frame.pc));
+                    ui.end_row();
+                }
+            });
+            ui.collapsing("Registers", |ui| {
+                egui::Grid::new(format!("cd-regs-{:08X}", task.tcb)).striped(true).show(ui, |ui| {
+                    for (idx, (name, value)) in task.regs.named().iter().enumerate() {
+                        ui.label(name);
+                        ui.monospace(format!("0x{value:08X}"));
+                        if idx % 4 == 3 {
+                            ui.end_row();
+                        }
+                    }
+                });
+            });
+        });
+}
+
+impl InterfacePage for CoredumpPage {
+    fn make_ui(&mut self, ui: &mut egui::Ui, _frame: &eframe::Frame) -> PageAction {
+        let mut action = None;
+        let state = self.state.read().unwrap().clone();
+        ui.horizontal(|ui| {
+            if ui.button("Read from TCU").clicked() {
+                self.read_from_tcu(ui.ctx().clone());
+            }
+            if ui.button("Open coredump file").clicked() {
+                action = self.open_file();
+            }
+            if ui.button("Load debug ELF").clicked() {
+                action = self.load_elf();
+            }
+            match &self.elf {
+                Some((name, _)) => ui.label(format!("Debug ELF: {name}")),
+                None => ui.label("Load the debug ELF of the firmware to see function names and source lines"),
+            };
+        });
+        ui.separator();
+        match state {
+            CoredumpLoadState::Msg(txt) => {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.label(txt);
+                });
+                return action.unwrap_or(PageAction::None);
+            }
+            CoredumpLoadState::Read { done, total } => {
+                ui.add(ProgressBar::new(done as f32 / total as f32).show_percentage().text("Reading coredump"));
+                return action.unwrap_or(PageAction::None);
+            }
+            CoredumpLoadState::Err(e) => {
+                ui.colored_label(Color32::RED, e);
+                return action.unwrap_or(PageAction::None);
+            }
+            CoredumpLoadState::Ready => {}
+        }
+
+        let guard = self.data.read().unwrap();
+        let Some(data) = guard.as_ref() else {
+            return action.unwrap_or(PageAction::None);
+        };
+        let dump = match &data.dump {
+            Ok(dump) => dump,
+            Err(CoreDumpError::Empty) => {
+                ui.label(format!("No coredump stored ({})", data.source));
+                return action.unwrap_or(PageAction::None);
+            }
+            Err(e) => {
+                ui.colored_label(Color32::RED, format!("Could not decode the coredump ({}): {e}", data.source));
+                if ui.button("Save raw coredump").clicked() {
+                    action = save_file("Save raw coredump", "coredump.bin", "bin", &data.raw);
+                }
+                return action.unwrap_or(PageAction::None);
+            }
+        };
+
+        let elf = self.elf.as_ref().map(|(_, e)| e);
+        egui::Grid::new("cd-info").striped(true).show(ui, |ui| {
+            ui.label("Source");
+            ui.label(&data.source);
+            ui.end_row();
+            ui.label("Version");
+            ui.label(format!("{}.{}", dump.version >> 8, dump.version & 0xFF));
+            ui.end_row();
+            ui.label("Checksum");
+            ui.label(dump.checksum.to_string());
+            ui.end_row();
+            ui.label("Firmware ELF SHA-256");
+            ui.monospace(&dump.app_elf_sha256);
+            ui.end_row();
+            if let Some(task) = dump.crashed_task() {
+                ui.label("Crashed task");
+                ui.colored_label(Color32::RED, &task.name);
+                ui.end_row();
+            }
+            if let Some(cause) = dump.exception_reg(XT_REG_EXCCAUSE) {
+                ui.label("Exception");
+                ui.label(format!("{} ({cause})", exception_cause_name(cause)));
+                ui.end_row();
+            }
+            if let Some(addr) = dump.exception_reg(XT_REG_EXCVADDR) {
+                ui.label("Exception address (EXCVADDR)");
+                ui.monospace(format!("0x{addr:08X}"));
+                ui.end_row();
+            }
+            if let Some(pc) = dump.exception_reg(XT_REG_EPC1) {
+                ui.label("Exception PC (EPC1)");
+                ui.monospace(format!("0x{pc:08X} {}", location(&mut self.locations, elf, pc)));
+                ui.end_row();
+            }
+        });
+        if let Some(elf) = elf {
+            if !dump.matches_elf(elf) {
+                ui.colored_label(
+                    Color32::ORANGE,
+                    format!(
+                        "Debug ELF (SHA-256 {}) is not from the firmware that crashed, so locations are likely wrong",
+                        &elf.sha256_hex()[..16]
+                    ),
+                );
+            }
+        }
+        if ui.button("Save raw coredump").clicked() {
+            action = save_file("Save raw coredump", "coredump.bin", "bin", &data.raw);
+        }
+        ui.separator();
+        ScrollArea::vertical().show(ui, |ui| {
+            // Crashed task first
+            for task in dump.tasks.iter().filter(|t| t.crashed).chain(dump.tasks.iter().filter(|t| !t.crashed)) {
+                make_task(ui, task, &mut self.locations, elf);
+            }
+        });
+        action.unwrap_or(PageAction::None)
+    }
+
+    fn get_title(&self) -> &'static str {
+        "Coredump viewer"
+    }
+
+    fn should_show_statusbar(&self) -> bool {
+        true
+    }
+}
@@ -8,7 +8,7 @@ use backend::{
         },
         DiagError, DiagServerResult,
     },
-    hw::usb_scanner::Nag52UsbScanner,
+    hw::{replay::Nag52Replay, sim_scanner::Nag52SimScanner, usb_scanner::Nag52UsbScanner},
 };
 
 #[cfg(target_os="linux")]
@@ -33,6 +33,8 @@ pub struct Launcher {
     pt_scanner: PassthruScanner,
     #[cfg(target_os="linux")]
     scan_scanner: SocketCanScanner,
+    sim_scanner: Nag52SimScanner,
+    replay_devices: Vec<HardwareInfo>,
     selected_device: String,
     curr_api_type: AdapterType,
     curr_dev_list: Vec<HardwareInfo>,
@@ -48,6 +50,8 @@ impl Launcher {
             pt_scanner: PassthruScanner::new(),
             #[cfg(target_os="linux")]
             scan_scanner: SocketCanScanner::new(),
+            sim_scanner: Nag52SimScanner::new(),
+            replay_devices: vec![],
             selected_device: String::new(),
             curr_api_type: AdapterType::USB,
             curr_dev_list: vec![],
@@ -96,6 +100,30 @@ impl InterfacePage for Launcher {
                 "SocketCAN device",
             );
         }
+        ui.radio_value(
+            &mut self.curr_api_type,
+            AdapterType::Simulation,
+            "Simulated TCU (No hardware required)",
+        );
+        ui.radio_value(
+            &mut self.curr_api_type,
+            AdapterType::Replay,
+            "Replay recorded session",
+        );
+        if self.curr_api_type == AdapterType::Replay && ui.button("Open trace file...").clicked() {
+            if let Some(path) = rfd::FileDialog::new().add_filter("trace", &["jsonl"]).pick_file() {
+                match Nag52Replay::open(&path.to_string_lossy()) {
+                    Ok(r) => {
+                        let info = r.get_info().clone();
+                        self.replay_devices.retain(|x| x.name != info.name);
+                        self.selected_device = info.name.clone();
+                        self.replay_devices.push(info);
+                        self.launch_err = None;
+                    }
+                    Err(e) => self.launch_err = Some(format!("Cannot open trace: {}", e)),
+                }
+            }
+        }
         ui.heading("Devices");
 
         let dev_list = match self.curr_api_type {
@@ -103,6 +131,8 @@ impl InterfacePage for Launcher {
             #[cfg(target_os="linux")]
             AdapterType::SocketCAN => Self::get_device_list(&self.scan_scanner),
             AdapterType::USB => Self::get_device_list(&self.usb_scanner),
+            AdapterType::Simulation => Self::get_device_list(&self.sim_scanner),
+            AdapterType::Replay => self.replay_devices.clone(),
         };
         self.curr_dev_list = dev_list.clone();
 
@@ -134,6 +164,7 @@ impl InterfacePage for Launcher {
         if ui.button("Refresh device list").clicked() {
             self.pt_scanner = PassthruScanner::new();
             self.usb_scanner = Nag52UsbScanner::new();
+            self.sim_scanner = Nag52SimScanner::new();
             #[cfg(target_os="linux")]
             {
                 self.scan_scanner = SocketCanScanner::new();
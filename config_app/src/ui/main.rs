@@ -12,6 +12,8 @@ use std::sync::Arc;
 use crate::window::{InterfacePage, PageAction};
 
 use super::configuration::egs_config;
+use super::memory_inspector::MemoryInspectorPage;
+use super::nvs_browser::NvsBrowserPage;
 use super::settings_ui_gen::TcuAdvSettingsUi;
 use super::updater::UpdatePage;
 use super::{
@@ -168,7 +170,19 @@ impl InterfacePage for MainPage {
             if v.button("TCU Program settings").on_hover_text("CAUTION. DANGEROUS!").clicked() {
                 create_page = Some(PageAction::Add(Box::new(TcuAdvSettingsUi::new(
                     self.diag_server.clone(),
-                    ctx,
+                    ctx.clone(),
+                ))));
+            }
+            if v.button("EEPROM browser").clicked() {
+                create_page = Some(PageAction::Add(Box::new(NvsBrowserPage::new(
+                    self.diag_server.clone(),
+                    ctx.clone(),
+                ))));
+            }
+            if v.button("Memory inspector").on_hover_text("Live TCU memory, for firmware debugging").clicked() {
+                create_page = Some(PageAction::Add(Box::new(MemoryInspectorPage::new(
+                    self.diag_server.clone(),
+                    ctx.clone(),
                 ))));
             }
             if v.button("Configure EGS compatibility data").clicked() {
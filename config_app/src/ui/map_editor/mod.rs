@@ -16,7 +16,7 @@ use egui_extras::Column;
 use plotters::{prelude::{IntoDrawingArea, ChartBuilder}, series::SurfaceSeries};
 use serde::Serialize;
 mod help_view;
-mod map_list;
+pub mod map_list;
 use crate::{plot_backend::{into_rgba_color, EguiPlotBackend}, ui::map_editor::map_list::MapType, window::PageAction};
 use map_list::MAP_ARRAY;
 use plotters::prelude::*;
@@ -85,48 +85,52 @@ fn read_u16(a: &[u8]) -> DiagServerResult<(&[u8], u16)> {
     Ok((&a[2..], r))
 }
 
+/// Reads the X and Y values, and the EEPROM key of a map
+fn read_map_meta(map_id: MapType, nag: &Nag52Diag) -> DiagServerResult<(Vec<i16>, Vec<i16>, String)> {
+    let ecu_response = nag.with_kwp(|server| {
+        server
+            .send_byte_array_with_response(&[
+                KwpCommand::ReadDataByLocalIdentifier.into(),
+                0x19,
+                map_id as u8,
+                MapCmd::ReadMeta as u8,
+                0x00,
+                0x00,
+            ])
+            .map(|mut x| {
+                x.drain(0..1);
+                x
+            })
+    })?;
+    let (data, data_len) = read_u16(&ecu_response)?;
+    if data.len() != data_len as usize {
+        return Err(DiagError::InvalidResponseLength);
+    }
+    let (data, x_element_count) = read_u16(data)?;
+    let (data, y_element_count) = read_u16(data)?;
+    let (mut data, key_len) = read_u16(data)?;
+    if data.len() as u16 != ((x_element_count + y_element_count) * 2) + key_len {
+        return Err(DiagError::InvalidResponseLength);
+    }
+    let mut x_elements: Vec<i16> = Vec::new();
+    let mut y_elements: Vec<i16> = Vec::new();
+    for _ in 0..x_element_count {
+        let (d, v) = read_i16(data)?;
+        x_elements.push(v);
+        data = d;
+    }
+    for _ in 0..y_element_count {
+        let (d, v) = read_i16(data)?;
+        y_elements.push(v);
+        data = d;
+    }
+    let key = String::from_utf8(data.to_vec()).map_err(|_| DiagError::InvalidResponseLength)?;
+    Ok((x_elements, y_elements, key))
+}
+
 impl Map {
     pub fn new(map_id: MapType, nag: Nag52Diag, meta: MapData) -> DiagServerResult<Self> {
-        // Read metadata
-
-        let ecu_response = nag.with_kwp(|server| {
-            server
-                .send_byte_array_with_response(&[
-                    KwpCommand::ReadDataByLocalIdentifier.into(),
-                    0x19,
-                    map_id as u8,
-                    MapCmd::ReadMeta as u8,
-                    0x00,
-                    0x00,
-                ])
-                .map(|mut x| {
-                    x.drain(0..1);
-                    x
-                })
-        })?;
-        let (data, data_len) = read_u16(&ecu_response)?;
-        if data.len() != data_len as usize {
-            return Err(DiagError::InvalidResponseLength);
-        }
-        let (data, x_element_count) = read_u16(data)?;
-        let (data, y_element_count) = read_u16(data)?;
-        let (mut data, key_len) = read_u16(data)?;
-        if data.len() as u16 != ((x_element_count + y_element_count) * 2) + key_len {
-            return Err(DiagError::InvalidResponseLength);
-        }
-        let mut x_elements: Vec<i16> = Vec::new();
-        let mut y_elements: Vec<i16> = Vec::new();
-        for _ in 0..x_element_count {
-            let (d, v) = read_i16(data)?;
-            x_elements.push(v);
-            data = d;
-        }
-        for _ in 0..y_element_count {
-            let (d, v) = read_i16(data)?;
-            y_elements.push(v);
-            data = d;
-        }
-        let key = String::from_utf8(data.to_vec()).unwrap();
+        let (x_elements, y_elements, key) = read_map_meta(map_id, &nag)?;
 
         let mut default: Vec<i16> = Vec::new();
         let mut current: Vec<i16> = Vec::new();
@@ -726,6 +730,25 @@ impl MapEditor {
             error: None,
         }
     }
+
+    /// Opens the editor with `map` already loaded
+    pub fn with_map(mut self, map: MapType) -> Self {
+        if let Some(found_map_info) = MAP_ARRAY.iter().find(|x| x.id == map) {
+            match Map::new(map, self.nag.clone(), found_map_info.clone()) {
+                Ok(m) => self.loaded_map = Some(m),
+                Err(e) => self.error = Some(e.to_string()),
+            }
+        }
+        self
+    }
+}
+
+/// Name and EEPROM key of every map the TCU knows about. Maps that fail to respond are skipped
+pub fn read_map_eeprom_keys(nag: &Nag52Diag) -> Vec<(MapType, &'static str, String)> {
+    MAP_ARRAY
+        .iter()
+        .filter_map(|m| read_map_meta(m.id, nag).ok().map(|(_, _, key)| (m.id, m.name, key)))
+        .collect()
 }
 
 
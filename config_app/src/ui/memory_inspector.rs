@@ -0,0 +1,580 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use backend::{
+    diag::{
+        memory::{MemoryRegion, MemoryValueType},
+        Nag52Diag,
+    },
+    ecu_diagnostics::kwp2000::KwpSessionType,
+    hw::debug_elf::{DebugElf, ElfVariable},
+};
+use eframe::egui::{self, Color32, DragValue, RichText, ScrollArea};
+use egui_notify::ToastLevel;
+use egui_plot::{Legend, Line, Plot, PlotPoints};
+
+use crate::window::{InterfacePage, PageAction};
+
+/// Bytes shown (And read) at a time
+const PAGE_SIZE: u32 = 256;
+const ROW_SIZE: usize = 16;
+/// Polled values kept per watch, for plotting
+const MAX_HISTORY: usize = 1000;
+/// Variables listed when searching the debug ELF
+const MAX_SEARCH_RESULTS: usize = 50;
+
+#[derive(Debug, Clone)]
+enum PageState {
+    Reading,
+    Ready { region: MemoryRegion, offset: u32, data: Vec<u8> },
+    Err(String),
+}
+
+/// Value in TCU memory which is polled while the page is open
+#[derive(Debug, Clone)]
+pub struct MemoryWatch {
+    id: u64,
+    pub name: String,
+    pub region: MemoryRegion,
+    /// Offset within [MemoryWatch::region]
+    pub offset: u32,
+    pub ty: MemoryValueType,
+    /// Last polled value, or why it could not be read
+    pub value: Option<Result<f64, String>>,
+    /// Polled values, as seconds since the page was opened and the value
+    history: VecDeque<[f64; 2]>,
+    plot: bool,
+}
+
+pub struct MemoryInspectorPage {
+    nag: Nag52Diag,
+    ctx: egui::Context,
+    region: MemoryRegion,
+    /// Offset of the shown page within [MemoryInspectorPage::region]
+    offset: u32,
+    page: Arc<RwLock<PageState>>,
+    /// Absolute address to jump to
+    goto: u32,
+    /// Selected bytes, as offsets within [MemoryInspectorPage::region]. The first is where the selection started
+    selection: Option<(u32, u32)>,
+    write_enabled: bool,
+    write_ty: MemoryValueType,
+    write_value: String,
+    /// Set while a write is running in the background
+    writing: Arc<AtomicBool>,
+    /// Outcome of the last write, shown once it finishes
+    write_result: Arc<RwLock<Option<(String, ToastLevel)>>>,
+    watches: Arc<RwLock<Vec<MemoryWatch>>>,
+    next_watch_id: u64,
+    polling: Arc<AtomicBool>,
+    poll_ms: Arc<AtomicU64>,
+    running: Arc<AtomicBool>,
+    start: Instant,
+    /// Loaded debug ELF, and if it is of the firmware running on the TCU (None if unknown)
+    elf: Option<(String, DebugElf, Option<bool>)>,
+    variable_filter: String,
+}
+
+impl MemoryInspectorPage {
+    pub fn new(nag: Nag52Diag, ctx: egui::Context) -> Self {
+        let _ = nag.with_kwp(|kwp| kwp.kwp_set_session(KwpSessionType::ExtendedDiagnostics.into()));
+        let ret = Self {
+            nag,
+            ctx,
+            region: MemoryRegion::Sram0,
+            offset: 0,
+            page: Arc::new(RwLock::new(PageState::Reading)),
+            goto: MemoryRegion::Sram0.start_addr(),
+            selection: None,
+            write_enabled: false,
+            write_ty: MemoryValueType::U8,
+            write_value: String::new(),
+            writing: Arc::new(AtomicBool::new(false)),
+            write_result: Arc::new(RwLock::new(None)),
+            watches: Arc::new(RwLock::new(Vec::new())),
+            next_watch_id: 0,
+            polling: Arc::new(AtomicBool::new(true)),
+            poll_ms: Arc::new(AtomicU64::new(250)),
+            running: Arc::new(AtomicBool::new(true)),
+            start: Instant::now(),
+            elf: None,
+            variable_filter: String::new(),
+        };
+        ret.read_page();
+        ret.start_polling();
+        ret
+    }
+
+    fn read_page(&self) {
+        let nag = self.nag.clone();
+        let page = self.page.clone();
+        let ctx = self.ctx.clone();
+        let (region, offset) = (self.region, self.offset);
+        *page.write().unwrap() = PageState::Reading;
+        std::thread::spawn(move || {
+            let len = PAGE_SIZE.min(region.size() - offset);
+            *page.write().unwrap() = match nag.read_memory_block(region, offset, len) {
+                Ok(data) => PageState::Ready { region, offset, data },
+                Err(e) => PageState::Err(format!("Could not read {region} at 0x{:06X}. {e}", region.start_addr() + offset)),
+            };
+            ctx.request_repaint();
+        });
+    }
+
+    /// Writes (And verifies) `bytes` at `offset` within the shown region. The result is
+    /// picked up by [InterfacePage::make_ui], which then reads the page again
+    fn write_memory(&self, offset: u32, bytes: Vec<u8>) {
+        let nag = self.nag.clone();
+        let ctx = self.ctx.clone();
+        let writing = self.writing.clone();
+        let write_result = self.write_result.clone();
+        let region = self.region;
+        writing.store(true, Ordering::Relaxed);
+        std::thread::spawn(move || {
+            let addr = region.start_addr() + offset;
+            *write_result.write().unwrap() = Some(match nag.write_memory_verified(region, offset, &bytes) {
+                Ok(()) => (format!("Wrote {} bytes at 0x{addr:06X}", bytes.len()), ToastLevel::Success),
+                Err(e) => (format!("Write failed. {e}"), ToastLevel::Error),
+            });
+            writing.store(false, Ordering::Relaxed);
+            ctx.request_repaint();
+        });
+    }
+
+    fn start_polling(&self) {
+        let nag = self.nag.clone();
+        let ctx = self.ctx.clone();
+        let watches = self.watches.clone();
+        let polling = self.polling.clone();
+        let poll_ms = self.poll_ms.clone();
+        let running = self.running.clone();
+        let start = self.start;
+        std::thread::spawn(move || {
+            while running.load(Ordering::Relaxed) {
+                if polling.load(Ordering::Relaxed) {
+                    let to_read: Vec<(u64, MemoryRegion, u32, MemoryValueType)> =
+                        watches.read().unwrap().iter().map(|w| (w.id, w.region, w.offset, w.ty)).collect();
+                    for (id, region, offset, ty) in to_read {
+                        let value = match nag.read_memory_block(region, offset, ty.size() as u32) {
+                            Ok(b) => ty.decode(&b).ok_or("Short read".to_string()),
+                            Err(e) => Err(e.to_string()),
+                        };
+                        // The watch may have been removed while it was read
+                        if let Some(w) = watches.write().unwrap().iter_mut().find(|w| w.id == id) {
+                            if let Ok(v) = value {
+                                if w.history.len() == MAX_HISTORY {
+                                    w.history.pop_front();
+                                }
+                                w.history.push_back([start.elapsed().as_secs_f64(), v]);
+                            }
+                            w.value = Some(value);
+                        }
+                    }
+                    ctx.request_repaint();
+                }
+                std::thread::sleep(Duration::from_millis(poll_ms.load(Ordering::Relaxed)));
+            }
+        });
+    }
+
+    /// Shows the page at `offset` within `region`
+    fn go_to(&mut self, region: MemoryRegion, offset: u32) {
+        let offset = (offset / PAGE_SIZE) * PAGE_SIZE;
+        if region != self.region || offset != self.offset {
+            self.region = region;
+            self.offset = offset;
+            self.read_page();
+        }
+    }
+
+    pub fn add_watch(&mut self, name: String, region: MemoryRegion, offset: u32, ty: MemoryValueType) {
+        self.watches.write().unwrap().push(MemoryWatch {
+            id: self.next_watch_id,
+            name,
+            region,
+            offset,
+            ty,
+            value: None,
+            history: VecDeque::new(),
+            plot: false,
+        });
+        self.next_watch_id += 1;
+    }
+
+    fn make_hex_view(&mut self, ui: &mut egui::Ui, offset: u32, data: &[u8]) {
+        let selected = |off: u32| self.selection.is_some_and(|(a, b)| off >= a.min(b) && off <= a.max(b));
+        let mut clicked = None;
+        egui::Grid::new("mem-hex").spacing([4.0, 2.0]).show(ui, |ui| {
+            ui.label("");
+            for col in 0..ROW_SIZE {
+                ui.monospace(format!("{col:02X}"));
+            }
+            ui.end_row();
+            for (row, bytes) in data.chunks(ROW_SIZE).enumerate() {
+                let row_off = offset + (row * ROW_SIZE) as u32;
+                ui.monospace(RichText::new(format!("{:06X}", self.region.start_addr() + row_off)).color(Color32::GRAY));
+                for (col, b) in bytes.iter().enumerate() {
+                    let off = row_off + col as u32;
+                    if ui.selectable_label(selected(off), RichText::new(format!("{b:02X}")).monospace()).clicked() {
+                        clicked = Some((off, ui.input(|i| i.modifiers.shift)));
+                    }
+                }
+                for _ in bytes.len()..ROW_SIZE {
+                    ui.label("");
+                }
+                let ascii: String =
+                    bytes.iter().map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' }).collect();
+                ui.monospace(ascii);
+                ui.end_row();
+            }
+        });
+        match (clicked, self.selection) {
+            (Some((off, true)), Some((start, _))) => self.selection = Some((start, off)),
+            (Some((off, _)), _) => self.selection = Some((off, off)),
+            _ => {}
+        }
+    }
+
+    /// Typed interpretation of the bytes at the start of the selection
+    fn make_selection(&mut self, ui: &mut egui::Ui, offset: u32, data: &[u8]) -> PageAction {
+        let Some((a, b)) = self.selection else {
+            ui.label("Click a byte to select it, shift-click to select a range");
+            return PageAction::None;
+        };
+        let (start, end) = (a.min(b), a.max(b));
+        let addr = self.region.start_addr() + start;
+        ui.strong(format!("Selection: 0x{addr:06X} ({} bytes)", end - start + 1));
+        let bytes = start.checked_sub(offset).and_then(|i| data.get(i as usize..)).unwrap_or_default();
+        let mut watch = None;
+        egui::Grid::new("mem-selection").striped(true).show(ui, |ui| {
+            for ty in MemoryValueType::ALL {
+                ui.label(ty.to_string());
+                match ty.decode(bytes) {
+                    Some(v) => ui.monospace(v.to_string()),
+                    None => ui.label("-"),
+                };
+                if ui.button("Watch").clicked() {
+                    watch = Some(ty);
+                }
+                ui.end_row();
+            }
+        });
+        if let Some(ty) = watch {
+            self.add_watch(format!("0x{addr:06X}"), self.region, start, ty);
+        }
+        ui.add_space(10.0);
+        ui.checkbox(&mut self.write_enabled, "Enable writing to memory");
+        if !self.write_enabled {
+            return PageAction::None;
+        }
+        if self.region == MemoryRegion::EgsCalibration {
+            ui.label("The EGS calibration can only be changed from the EGS compatibility page");
+            return PageAction::None;
+        }
+        ui.label(
+            RichText::new("WARNING. Writes change the memory of the running firmware, and can make the TCU misbehave or crash")
+                .color(Color32::RED),
+        );
+        let mut action = PageAction::None;
+        ui.horizontal(|row| {
+            egui::ComboBox::from_id_salt("mem-write-ty").selected_text(self.write_ty.to_string()).show_ui(row, |ui| {
+                for ty in MemoryValueType::ALL {
+                    ui.selectable_value(&mut self.write_ty, ty, ty.to_string());
+                }
+            });
+            row.text_edit_singleline(&mut self.write_value);
+            let writing = self.writing.load(Ordering::Relaxed);
+            if row.add_enabled(!writing, egui::Button::new(format!("Write at 0x{addr:06X}"))).clicked() {
+                let encoded = self.write_value.trim().parse::<f64>().ok().and_then(|v| self.write_ty.encode(v));
+                match encoded {
+                    None => {
+                        action = PageAction::SendNotification {
+                            text: format!("'{}' is not a valid {}", self.write_value, self.write_ty),
+                            kind: ToastLevel::Error,
+                        }
+                    }
+                    Some(bytes) => self.write_memory(start, bytes),
+                }
+            }
+            if writing {
+                row.spinner();
+            }
+        });
+        action
+    }
+
+    fn make_watches(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|row| {
+            row.strong("Watch list");
+            let mut polling = self.polling.load(Ordering::Relaxed);
+            if row.checkbox(&mut polling, "Poll every").changed() {
+                self.polling.store(polling, Ordering::Relaxed);
+            }
+            let mut ms = self.poll_ms.load(Ordering::Relaxed);
+            if row.add(DragValue::new(&mut ms).range(20..=5000).suffix(" ms")).changed() {
+                self.poll_ms.store(ms, Ordering::Relaxed);
+            }
+        });
+        let mut watches = self.watches.write().unwrap();
+        if watches.is_empty() {
+            ui.label("Select bytes above and press 'Watch' to add them");
+            return;
+        }
+        let mut remove = None;
+        let mut show = None;
+        egui::Grid::new("mem-watches").striped(true).num_columns(6).show(ui, |ui| {
+            ui.strong("Name");
+            ui.strong("Address");
+            ui.strong("Type");
+            ui.strong("Value");
+            ui.strong("Plot");
+            ui.end_row();
+            for (idx, w) in watches.iter_mut().enumerate() {
+                ui.add(egui::TextEdit::singleline(&mut w.name).desired_width(150.0));
+                if ui.link(format!("{} 0x{:06X}", w.region, w.region.start_addr() + w.offset)).clicked() {
+                    show = Some((w.region, w.offset));
+                }
+                egui::ComboBox::from_id_salt(format!("mem-watch-ty-{}", w.id)).selected_text(w.ty.to_string()).show_ui(ui, |ui| {
+                    for ty in MemoryValueType::ALL {
+                        if ui.selectable_value(&mut w.ty, ty, ty.to_string()).changed() {
+                            w.history.clear();
+                        }
+                    }
+                });
+                match &w.value {
+                    Some(Ok(v)) => ui.monospace(v.to_string()),
+                    Some(Err(e)) => ui.colored_label(Color32::RED, e),
+                    None => ui.label("-"),
+                };
+                ui.checkbox(&mut w.plot, "");
+                if ui.button("Remove").clicked() {
+                    remove = Some(idx);
+                }
+                ui.end_row();
+            }
+        });
+        if let Some(idx) = remove {
+            watches.remove(idx);
+        }
+        if watches.iter().any(|w| w.plot) {
+            Plot::new("mem-watch-plot").height(250.0).legend(Legend::default()).x_axis_label("Seconds").show(ui, |plot| {
+                for w in watches.iter().filter(|w| w.plot) {
+                    plot.line(Line::new(w.name.clone(), w.history.iter().copied().collect::<PlotPoints>()).id(egui::Id::new(w.id)));
+                }
+            });
+        }
+        drop(watches);
+        if let Some((region, offset)) = show {
+            self.go_to(region, offset);
+            self.selection = Some((offset, offset));
+        }
+    }
+}
+
+impl MemoryInspectorPage {
+    fn load_elf(&mut self) -> PageAction {
+        let Some(path) = rfd::FileDialog::new().set_title("Open debug ELF").add_filter("elf", &["elf"]).pick_file() else {
+            return PageAction::None;
+        };
+        match DebugElf::load(&path) {
+            Ok(elf) => {
+                let name = path.file_name().map(|f| f.to_string_lossy().to_string()).unwrap_or_default();
+                let matches = self.nag.get_running_fw_info().ok().map(|h| elf.matches_firmware(&h));
+                self.elf = Some((name, elf, matches));
+                PageAction::None
+            }
+            Err(e) => PageAction::SendNotification {
+                text: format!("Could not load {}: {e}", path.display()),
+                kind: egui_notify::ToastLevel::Error,
+            },
+        }
+    }
+
+    /// Search for variables in the debug ELF by name
+    fn make_variables(&mut self, ui: &mut egui::Ui) -> PageAction {
+        let mut action = PageAction::None;
+        ui.horizontal(|row| {
+            row.strong("Variables");
+            if row.button("Load debug ELF").clicked() {
+                action = self.load_elf();
+            }
+            match &self.elf {
+                Some((name, _, _)) => row.label(name),
+                None => row.label("The debug ELF of a firmware release can be downloaded on the updater page"),
+            };
+        });
+        let Some((_, elf, matches)) = &self.elf else {
+            return action;
+        };
+        if *matches == Some(false) {
+            ui.colored_label(Color32::RED, "This ELF is not of the firmware running on the TCU. Variable addresses will be wrong");
+        }
+        ui.horizontal(|row| {
+            row.label("Search");
+            row.text_edit_singleline(&mut self.variable_filter);
+        });
+        let filter = self.variable_filter.to_lowercase();
+        if filter.is_empty() {
+            ui.label(format!("{} variables. Search for them by name", elf.variables().len()));
+            return action;
+        }
+        let found: Vec<&ElfVariable> =
+            elf.variables().iter().filter(|v| v.name.to_lowercase().contains(&filter)).take(MAX_SEARCH_RESULTS).collect();
+        let mut watch = None;
+        let mut show = None;
+        ScrollArea::vertical().id_salt("mem-variables").max_height(200.0).show(ui, |ui| {
+            egui::Grid::new("mem-variables-grid").striped(true).num_columns(5).show(ui, |ui| {
+                ui.strong("Name");
+                ui.strong("Address");
+                ui.strong("Size");
+                ui.strong("Type");
+                ui.end_row();
+                for v in found {
+                    ui.label(&v.name);
+                    ui.monospace(format!("0x{:08X}", v.address));
+                    ui.label(v.size.to_string());
+                    ui.label(v.type_name.as_deref().unwrap_or("?"));
+                    let Some((region, offset)) = v.memory_location() else {
+                        ui.colored_label(Color32::GRAY, "Not in a readable memory region");
+                        ui.end_row();
+                        continue;
+                    };
+                    ui.horizontal(|row| {
+                        // Without debug info, assume unsigned numbers
+                        let ty = v.value_type.or(MemoryValueType::ALL
+                            .into_iter()
+                            .find(|t| matches!(t, MemoryValueType::U8 | MemoryValueType::U16 | MemoryValueType::U32) && t.size() == v.size as usize));
+                        match ty {
+                            Some(ty) => {
+                                if row.button("Watch").clicked() {
+                                    watch = Some((v.name.clone(), region, offset, ty));
+                                }
+                            }
+                            None => {
+                                row.add_enabled(false, egui::Button::new("Watch")).on_disabled_hover_text("Only numbers can be watched");
+                            }
+                        }
+                        if row.button("Show").clicked() {
+                            show = Some((region, offset, v.size.max(1)));
+                        }
+                    });
+                    ui.end_row();
+                }
+            });
+        });
+        if let Some((name, region, offset, ty)) = watch {
+            self.add_watch(name, region, offset, ty);
+        }
+        if let Some((region, offset, size)) = show {
+            self.go_to(region, offset);
+            self.selection = Some((offset, offset + size - 1));
+        }
+        action
+    }
+}
+
+impl InterfacePage for MemoryInspectorPage {
+    fn make_ui(&mut self, ui: &mut egui::Ui, _frame: &eframe::Frame) -> PageAction {
+        ui.heading("Memory inspector");
+        let mut region = self.region;
+        let mut offset = self.offset;
+        let mut refresh = false;
+        ui.horizontal(|row| {
+            egui::ComboBox::from_id_salt("mem-region").selected_text(region.to_string()).show_ui(row, |ui| {
+                for r in MemoryRegion::ALL {
+                    if ui.selectable_value(&mut region, r, r.to_string()).clicked() {
+                        offset = 0;
+                    }
+                }
+            });
+            if row.button("<").clicked() {
+                offset = offset.saturating_sub(PAGE_SIZE);
+            }
+            row.add(DragValue::new(&mut offset).hexadecimal(6, false, true).range(0..=region.size() - 1).speed(PAGE_SIZE));
+            if row.button(">").clicked() && offset + PAGE_SIZE < region.size() {
+                offset += PAGE_SIZE;
+            }
+            refresh = row.button("Refresh").clicked();
+            row.separator();
+            row.label("Go to address");
+            row.add(DragValue::new(&mut self.goto).hexadecimal(6, false, true));
+            if row.button("Go").clicked() {
+                match MemoryRegion::find(self.goto, 1) {
+                    Some((r, off)) => {
+                        region = r;
+                        offset = off;
+                        self.selection = Some((off, off));
+                    }
+                    None => {
+                        *self.page.write().unwrap() = PageState::Err(format!("0x{:06X} is not in any memory region", self.goto))
+                    }
+                }
+            }
+        });
+        if region != self.region {
+            self.selection = None;
+        }
+        self.go_to(region, offset);
+        if refresh {
+            self.read_page();
+        }
+        ui.separator();
+        let page = self.page.read().unwrap().clone();
+        let mut action = PageAction::None;
+        ScrollArea::vertical().id_salt("mem-scroll").show(ui, |ui| {
+            match &page {
+                PageState::Reading => {
+                    ui.horizontal(|row| {
+                        row.spinner();
+                        row.label("Reading memory");
+                    });
+                }
+                PageState::Err(e) => {
+                    ui.colored_label(Color32::RED, e);
+                }
+                PageState::Ready { region, offset, data } if *region == self.region => {
+                    ui.horizontal_top(|row| {
+                        row.vertical(|ui| self.make_hex_view(ui, *offset, data));
+                        row.separator();
+                        row.vertical(|ui| action = self.make_selection(ui, *offset, data));
+                    });
+                }
+                PageState::Ready { .. } => {}
+            }
+            ui.separator();
+            let elf_action = self.make_variables(ui);
+            if !matches!(elf_action, PageAction::None) {
+                action = elf_action;
+            }
+            ui.separator();
+            self.make_watches(ui);
+        });
+        if matches!(action, PageAction::None) {
+            if let Some((text, kind)) = self.write_result.write().unwrap().take() {
+                self.read_page();
+                action = PageAction::SendNotification { text, kind };
+            }
+        }
+        action
+    }
+
+    fn get_title(&self) -> &'static str {
+        "Memory inspector"
+    }
+
+    fn should_show_statusbar(&self) -> bool {
+        true
+    }
+}
+
+impl Drop for MemoryInspectorPage {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
@@ -1,15 +1,18 @@
 use eframe::egui;
 use eframe::egui::Color32;
 
-use crate::window::InterfacePage;
+use crate::window::{InterfacePage, PageAction};
 
 pub mod configuration;
+pub mod coredump;
 pub mod diagnostics;
 pub mod io_maipulator;
 pub mod kwp_event;
 pub mod launcher;
 pub mod main;
 pub mod map_editor;
+pub mod memory_inspector;
+pub mod nvs_browser;
 pub mod routine_tests;
 pub mod widgets;
 pub mod updater;
@@ -32,3 +35,18 @@ impl egui::Widget for StatusText {
         }
     }
 }
+
+/// Asks the user where to save a file, then saves it. Returns None if the user cancelled
+pub fn save_file(title: &str, name: &str, ext: &str, contents: &[u8]) -> Option<PageAction> {
+    let path = rfd::FileDialog::new().set_title(title).add_filter(ext, &[ext]).set_file_name(name).save_file()?;
+    Some(match std::fs::write(&path, contents) {
+        Ok(_) => PageAction::SendNotification {
+            text: format!("Saved {}", path.display()),
+            kind: egui_notify::ToastLevel::Success,
+        },
+        Err(e) => PageAction::SendNotification {
+            text: format!("Could not save {}: {e}", path.display()),
+            kind: egui_notify::ToastLevel::Error,
+        },
+    })
+}
@@ -0,0 +1,351 @@
+use std::{collections::HashMap, sync::{Arc, RwLock}};
+
+use backend::{
+    diag::{
+        flash::PartitionInfo,
+        nvs::{NvsItem, NvsPage, NvsPageState, NvsPartition, NvsValue, NVS_ENTRIES_PER_PAGE, NVS_PAGE_SIZE, TCU_NVS_NAMESPACE},
+        Nag52Diag,
+    },
+    ecu_diagnostics::kwp2000::KwpSessionTypeByte,
+};
+use eframe::egui::{self, Color32, ProgressBar, RichText, ScrollArea};
+
+use crate::window::{InterfacePage, PageAction};
+
+use super::{
+    map_editor::{map_list::MapType, read_map_eeprom_keys, MapEditor},
+    save_file,
+    settings_ui_gen::{read_module_settings_from_tcu, TcuAdvSettingsUi},
+};
+
+#[derive(Debug, Clone)]
+enum NvsLoadState {
+    Msg(String),
+    Read { addr: u32, done: u32, total: u32 },
+    Ready,
+    Err(String),
+}
+
+/// Editor that understands the contents of an EEPROM key
+#[derive(Debug, Clone)]
+enum NvsLink {
+    Setting { name: String, scn_id: u8 },
+    Map { name: &'static str, map: MapType },
+}
+
+struct NvsData {
+    partition: PartitionInfo,
+    raw: Vec<u8>,
+    nvs: NvsPartition,
+    /// (Namespace, EEPROM key) to editor
+    links: HashMap<(String, String), NvsLink>,
+}
+
+pub struct NvsBrowserPage {
+    nag: Nag52Diag,
+    state: Arc<RwLock<NvsLoadState>>,
+    data: Arc<RwLock<Option<NvsData>>>,
+    filter: String,
+}
+
+impl NvsBrowserPage {
+    pub fn new(nag: Nag52Diag, ctx: egui::Context) -> Self {
+        let ret = Self {
+            nag,
+            state: Arc::new(RwLock::new(NvsLoadState::Msg("Init".into()))),
+            data: Arc::new(RwLock::new(None)),
+            filter: String::new(),
+        };
+        ret.load(ctx);
+        ret
+    }
+
+    fn load(&self, ctx: egui::Context) {
+        let nag = self.nag.clone();
+        let state = self.state.clone();
+        let data = self.data.clone();
+        std::thread::spawn(move || {
+            let set_state = |s: NvsLoadState| {
+                *state.write().unwrap() = s;
+                ctx.request_repaint();
+            };
+            let res = (|| {
+                set_state(NvsLoadState::Msg("Locating NVS partition".into()));
+                let partition = nag.get_nvs_partition_info().map_err(|e| format!("Could not locate the NVS partition: {e}"))?;
+                let raw = nag
+                    .read_flash_region(partition.address, partition.size, |done| {
+                        set_state(NvsLoadState::Read { addr: partition.address + done, done, total: partition.size })
+                    })
+                    .map_err(|e| format!("Could not read the NVS partition: {e}"))?;
+                let nvs = NvsPartition::parse(&raw).map_err(|e| e.to_string())?;
+                set_state(NvsLoadState::Msg("Reading MODULE_SETTINGS and map keys".into()));
+                let links = read_links(&nag);
+                Ok::<_, String>(NvsData { partition, raw, nvs, links })
+            })();
+            match res {
+                Ok(d) => {
+                    *data.write().unwrap() = Some(d);
+                    set_state(NvsLoadState::Ready);
+                }
+                Err(e) => set_state(NvsLoadState::Err(e)),
+            }
+        });
+    }
+}
+
+/// Finds the settings groups and maps stored in EEPROM. Both are optional, as
+/// older firmware may not have MODULE_SETTINGS, so failures just mean there are no links.
+/// Both live in [TCU_NVS_NAMESPACE], so same named keys in other namespaces are not linked
+fn read_links(nag: &Nag52Diag) -> HashMap<(String, String), NvsLink> {
+    let mut ret = HashMap::new();
+    if nag.with_kwp(|x| x.kwp_set_session(KwpSessionTypeByte::Extended(0x93))).is_err() {
+        return ret;
+    }
+    if let Ok(settings) = read_module_settings_from_tcu(nag, |_, _, _| {}) {
+        for s in settings.settings {
+            if let (Some(key), Some(scn_id)) = (s.eeprom_key, s.scn_id) {
+                ret.insert((TCU_NVS_NAMESPACE.to_string(), key), NvsLink::Setting { name: s.name, scn_id });
+            }
+        }
+    }
+    for (map, name, key) in read_map_eeprom_keys(nag) {
+        ret.insert((TCU_NVS_NAMESPACE.to_string(), key), NvsLink::Map { name, map });
+    }
+    ret
+}
+
+fn type_name(v: &NvsValue) -> &'static str {
+    match v {
+        NvsValue::U8(_) => "u8",
+        NvsValue::I8(_) => "i8",
+        NvsValue::U16(_) => "u16",
+        NvsValue::I16(_) => "i16",
+        NvsValue::U32(_) => "u32",
+        NvsValue::I32(_) => "i32",
+        NvsValue::U64(_) => "u64",
+        NvsValue::I64(_) => "i64",
+        NvsValue::Str(_) => "String",
+        NvsValue::Blob(_) => "Blob",
+    }
+}
+
+fn make_value(ui: &mut egui::Ui, v: &NvsValue) {
+    match v {
+        NvsValue::Blob(b) => {
+            let preview: String = b.iter().take(16).map(|x| format!("{x:02X} ")).collect();
+            let more = if b.len() > 16 { "..." } else { "" };
+            ui.monospace(format!("{} bytes: {preview}{more}", b.len()))
+                .on_hover_text(format!("{:02X?}", b));
+        }
+        // Unsigned values are usually flags or IDs, so are shown in hex as well
+        NvsValue::U8(x) => {
+            ui.label(format!("{x} (0x{x:02X})"));
+        }
+        NvsValue::U16(x) => {
+            ui.label(format!("{x} (0x{x:04X})"));
+        }
+        NvsValue::U32(x) => {
+            ui.label(format!("{x} (0x{x:08X})"));
+        }
+        NvsValue::U64(x) => {
+            ui.label(format!("{x} (0x{x:016X})"));
+        }
+        other => {
+            ui.label(other.to_string());
+        }
+    }
+}
+
+fn make_page_table(ui: &mut egui::Ui, pages: &[NvsPage], partition: &PartitionInfo) {
+    egui::Grid::new("nvs-pages").striped(true).num_columns(7).show(ui, |ui| {
+        ui.strong("Page");
+        ui.strong("Address");
+        ui.strong("State");
+        ui.strong("Seq no");
+        ui.strong("Header CRC");
+        ui.strong("Entries (Used / Erased / Free)");
+        ui.strong("Usage");
+        ui.end_row();
+        for (idx, page) in pages.iter().enumerate() {
+            ui.label(idx.to_string());
+            ui.label(format!("0x{:08X}", partition.address as usize + idx * NVS_PAGE_SIZE));
+            match page.state {
+                NvsPageState::Invalid(x) => ui.colored_label(Color32::RED, format!("Invalid (0x{x:08X})")),
+                NvsPageState::Corrupt => ui.colored_label(Color32::RED, "Corrupt"),
+                s => ui.label(format!("{s:?}")),
+            };
+            if page.state == NvsPageState::Uninitialized {
+                ui.label("-");
+                ui.label("-");
+            } else {
+                ui.label(page.seqno.to_string());
+                if page.crc_ok {
+                    ui.label("OK");
+                } else {
+                    ui.colored_label(Color32::RED, "Mismatch");
+                }
+            }
+            ui.label(format!("{} / {} / {}", page.written, page.erased, page.empty));
+            let used = (page.written + page.erased) as f32 / NVS_ENTRIES_PER_PAGE as f32;
+            ui.add(ProgressBar::new(used).desired_width(150.0).show_percentage());
+            ui.end_row();
+        }
+    });
+}
+
+fn make_item_row(ui: &mut egui::Ui, item: &NvsItem, link: Option<&NvsLink>) -> Option<NvsLink> {
+    let mut ret = None;
+    ui.code(&item.key);
+    ui.label(type_name(&item.value));
+    make_value(ui, &item.value);
+    match (item.header_crc_ok, item.data_crc_ok) {
+        (true, true) => ui.label("OK"),
+        (false, _) => ui.colored_label(Color32::RED, "Header CRC mismatch"),
+        (true, false) => ui.colored_label(Color32::RED, "Data CRC mismatch"),
+    };
+    ui.label(item.location.to_string());
+    match link {
+        Some(l @ NvsLink::Setting { name, .. }) => {
+            if ui.button(format!("Open setting {name}")).clicked() {
+                ret = Some(l.clone());
+            }
+        }
+        Some(l @ NvsLink::Map { name, .. }) => {
+            if ui.button(format!("Open map {name}")).clicked() {
+                ret = Some(l.clone());
+            }
+        }
+        None => {
+            ui.label("");
+        }
+    }
+    ret
+}
+
+impl InterfacePage for NvsBrowserPage {
+    fn make_ui(&mut self, ui: &mut egui::Ui, _frame: &eframe::Frame) -> PageAction {
+        let state = self.state.read().unwrap().clone();
+        match state {
+            NvsLoadState::Msg(txt) => {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.label(txt);
+                });
+                return PageAction::None;
+            }
+            NvsLoadState::Read { addr, done, total } => {
+                ui.add(
+                    ProgressBar::new(done as f32 / total as f32)
+                        .show_percentage()
+                        .text(format!("Reading NVS partition. Addr: {addr:08X}")),
+                );
+                return PageAction::None;
+            }
+            NvsLoadState::Err(e) => {
+                ui.strong("Page load failed:");
+                ui.label(e);
+                if ui.button("Retry").clicked() {
+                    self.load(ui.ctx().clone());
+                }
+                return PageAction::None;
+            }
+            NvsLoadState::Ready => {}
+        }
+
+        let guard = self.data.read().unwrap();
+        let Some(data) = guard.as_ref() else {
+            return PageAction::None;
+        };
+        let mut action = None;
+        let mut reload = false;
+        let mut open_link = None;
+        ui.horizontal(|ui| {
+            ui.label(format!(
+                "NVS partition at 0x{:08X} ({} pages, {} items)",
+                data.partition.address,
+                data.nvs.pages.len(),
+                data.nvs.items.len()
+            ));
+            if ui.button("Reload").clicked() {
+                reload = true;
+            }
+            if ui.button("Export JSON").clicked() {
+                let json = serde_json::json!({
+                    "address": data.partition.address,
+                    "size": data.partition.size,
+                    "nvs": data.nvs,
+                });
+                let s = serde_json::to_string_pretty(&json).unwrap_or_default();
+                action = save_file("Export NVS as JSON", "nvs.json", "json", s.as_bytes());
+            }
+            if ui.button("Export .bin").clicked() {
+                action = save_file("Export raw NVS partition", "nvs.bin", "bin", &data.raw);
+            }
+        });
+        if !data.nvs.issues.is_empty() {
+            ui.collapsing(RichText::new(format!("{} issues found", data.nvs.issues.len())).color(Color32::ORANGE), |ui| {
+                for issue in &data.nvs.issues {
+                    ui.label(issue.to_string());
+                }
+            });
+        }
+        ui.collapsing("Pages", |ui| make_page_table(ui, &data.nvs.pages, &data.partition));
+        ui.horizontal(|ui| {
+            ui.label("Filter keys:");
+            ui.text_edit_singleline(&mut self.filter);
+        });
+        ui.separator();
+        let filter = self.filter.to_lowercase();
+        ScrollArea::vertical().show(ui, |ui| {
+            for namespace in data.nvs.namespaces.values() {
+                let items: Vec<&NvsItem> = data
+                    .nvs
+                    .namespace_items(namespace)
+                    .filter(|i| i.key.to_lowercase().contains(&filter))
+                    .collect();
+                egui::CollapsingHeader::new(format!("{namespace} ({} keys)", items.len()))
+                    .id_salt(format!("nvs-ns-{namespace}"))
+                    .default_open(true)
+                    .show(ui, |ui| {
+                        egui::Grid::new(format!("nvs-ns-grid-{namespace}")).striped(true).num_columns(6).show(ui, |ui| {
+                            ui.strong("Key");
+                            ui.strong("Type");
+                            ui.strong("Value");
+                            ui.strong("CRC");
+                            ui.strong("Location");
+                            ui.strong("Editor");
+                            ui.end_row();
+                            for item in items {
+                                if let Some(l) = make_item_row(ui, item, data.links.get(&(item.namespace.clone(), item.key.clone()))) {
+                                    open_link = Some(l);
+                                }
+                                ui.end_row();
+                            }
+                        });
+                    });
+            }
+        });
+        drop(guard);
+
+        if reload {
+            self.load(ui.ctx().clone());
+        }
+        if let Some(link) = open_link {
+            return match link {
+                NvsLink::Setting { scn_id, .. } => {
+                    PageAction::Add(Box::new(TcuAdvSettingsUi::new(self.nag.clone(), ui.ctx().clone()).with_setting(scn_id)))
+                }
+                NvsLink::Map { map, .. } => PageAction::Add(Box::new(MapEditor::new(self.nag.clone()).with_map(map))),
+            };
+        }
+        action.unwrap_or(PageAction::None)
+    }
+
+    fn get_title(&self) -> &'static str {
+        "EEPROM browser"
+    }
+
+    fn should_show_statusbar(&self) -> bool {
+        true
+    }
+}
@@ -13,7 +13,8 @@ pub struct CanLoggerPage {
     nag: Nag52Diag,
     reader_running: Arc<AtomicBool>,
     dialog_open: Arc<AtomicBool>,
-    frames: Arc<RwLock<VecDeque<CanFrame>>>
+    frames: Arc<RwLock<VecDeque<CanFrame>>>,
+    can_err: Arc<RwLock<Option<String>>>
 }
 
 impl CanLoggerPage {
@@ -27,9 +28,6 @@ impl CanLoggerPage {
 
         let nag_c = nag.clone();
 
-        let running = Arc::new(AtomicBool::new(true));
-        let running_c = running.clone();
-
         let dialog_open = Arc::new(AtomicBool::new(false));
         let dialog_open_c = dialog_open.clone();
 
@@ -39,6 +37,9 @@ impl CanLoggerPage {
         let frames = Arc::new(RwLock::new(VecDeque::new()));
         let frames_c = frames.clone();
 
+        let can_err = Arc::new(RwLock::new(None));
+        let can_err_c = can_err.clone();
+
         std::thread::spawn(move|| {
             match nag_c.with_kwp(|k| k.kwp_set_session(KwpSessionType::ExtendedDiagnostics.into())) {
                 Ok(_) => {
@@ -47,6 +48,9 @@ impl CanLoggerPage {
                     if let Ok(mode) = nag_c.read_device_mode() {
                         *dev_mode_c.write() = Some(mode);
                         *state_c.write() = PageLoadState::Ok;
+                        if let Err(e) = nag_c.open_can_sniffer() {
+                            *can_err_c.write() = Some(e.to_string());
+                        }
                         // Now loop querying ECU
                         while running_c.load(Ordering::Relaxed) {
                             if dev_mode_c.read().clone().unwrap_or(TcuDeviceMode::empty()).contains(TcuDeviceMode::CANLOGGER) {
@@ -61,6 +65,7 @@ impl CanLoggerPage {
                             }
                             std::thread::sleep(Duration::from_millis(10));
                         }
+                        nag_c.close_can_sniffer();
                     } else {
                         *state_c.write() = PageLoadState::Err(format!("Query of current session mode failed"));
                     }
@@ -76,9 +81,10 @@ impl CanLoggerPage {
             device_mode: dev_mode,
             state: state,
             nag,
-            reader_running: Arc::new(AtomicBool::new(false)),
+            reader_running: running,
             frames,
-            dialog_open: dialog_open
+            dialog_open: dialog_open,
+            can_err
         }
         
     }
@@ -121,6 +127,9 @@ impl crate::window::InterfacePage for CanLoggerPage {
                 let frames_now = self.frames.read().clone();
                 let mode = self.device_mode.read().clone();
                 ui.label(format!("Current device mode: {mode:?}"));
+                if let Some(e) = self.can_err.read().as_ref() {
+                    ui.label(format!("CAN channel is not available on this adapter: {e}"));
+                }
                 let mut t_mode = None;
                 if let Some(current_mode) = mode {
                     if current_mode.contains(TcuDeviceMode::CANLOGGER) {
@@ -1,5 +1,5 @@
 use std::{fs::File, io::{BufReader, Cursor, Read, Write}, sync::{Arc, RwLock}, time::Instant};
-use backend::{diag::{Nag52Diag, settings::{SettingsData, ModuleSettingsData, EnumMap, SettingsType, SettingsVariable, EnumDesc}}, ecu_diagnostics::{kwp2000::{KwpSessionType, KwpCommand, KwpSessionTypeByte}, DiagServerResult}, serde_yaml};
+use backend::{diag::{Nag52Diag, settings::{SettingsData, ModuleSettingsData, EnumMap, SettingsType, SettingsVariable, EnumDesc, SettingsViolation}, settings_bundle::{BundleImport, SettingsBundle}, settings_migration::{SettingsMigration, TypeChange}, nvs::NvsPartition}, ecu_diagnostics::{kwp2000::{KwpSessionType, KwpSessionTypeByte}, DiagServerResult}, serde_yaml};
 use eframe::{egui::{ProgressBar, DragValue, self, CollapsingHeader, ScrollArea, Label, RichText}, epaint::{Color32, ahash::HashMap}, emath};
 use zip::ZipArchive;
 
@@ -19,6 +19,26 @@ pub enum LoadState {
     Err(String)
 }
 
+/// Reads MODULE_SETTINGS.yml from the embedded container on the TCU. `on_progress` is called
+/// with the address being read, the container size and the bytes read so far
+pub fn read_module_settings_from_tcu(nag: &Nag52Diag, mut on_progress: impl FnMut(u32, u32, u32)) -> Result<ModuleSettingsData, String> {
+    let part_info = nag.get_embed_file_info().map_err(|e| e.to_string())?;
+    let mut read_contents = Vec::new();
+    while read_contents.len() < part_info.size as usize {
+        let to_read = std::cmp::min(250, part_info.size as usize - read_contents.len()) as u8;
+        let addr = part_info.address + read_contents.len() as u32;
+        on_progress(addr, part_info.size, read_contents.len() as u32);
+        let data = nag.read_mem_by_addr_ext(addr, to_read).map_err(|e| e.to_string())?;
+        read_contents.extend_from_slice(&data);
+    }
+    let reader = BufReader::new(Cursor::new(read_contents));
+    let mut zip = ZipArchive::new(reader).map_err(|_| "Data on EGS is corrupt!".to_string())?;
+    let mut mod_settings = zip.by_name("MODULE_SETTINGS.yml").map_err(|_| "Data on EGS does not contain MODULE_SETTINGS".to_string())?;
+    let mut s = String::new();
+    mod_settings.read_to_string(&mut s).map_err(|e| e.to_string())?;
+    serde_yaml::from_str::<ModuleSettingsData>(&s).map_err(|e| e.to_string())
+}
+
 pub struct TcuAdvSettingsUi {
     status: Arc<RwLock<LoadState>>,
     error: Option<String>,
@@ -27,7 +47,43 @@ pub struct TcuAdvSettingsUi {
     yml: Arc<RwLock<Option<ModuleSettingsData>>>,
     current_settings: Arc<RwLock<HashMap<u8, DiagServerResult<Vec<u8>>>>>,
     default_settings: Arc<RwLock<HashMap<u8, DiagServerResult<Vec<u8>>>>>,
-    current_setting: Option<u8>
+    current_setting: Option<u8>,
+    import: Option<PendingImport>
+}
+
+/// Labels of an import. A bundle leaves settings it cannot import unchanged,
+/// whereas a migration drops the old values
+struct ImportWording {
+    title: &'static str,
+    /// Settings with a value that cannot be converted
+    invalid: &'static str,
+    /// Settings that are not on this TCU
+    unused: &'static str,
+}
+
+const BUNDLE_WORDING: ImportWording = ImportWording { title: "Import settings bundle", invalid: "Unchanged", unused: "Skipped" };
+const MIGRATION_WORDING: ImportWording = ImportWording { title: "Migrate settings", invalid: "Dropped", unused: "Dropped" };
+
+/// Settings bundle (Or migration) loaded for import, awaiting confirmation
+struct PendingImport {
+    wording: &'static ImportWording,
+    warnings: Vec<String>,
+    import: BundleImport,
+    converted: Vec<TypeChange>,
+    violations: Vec<SettingsViolation>
+}
+
+impl PendingImport {
+    fn new(wording: &'static ImportWording, warnings: Vec<String>, import: BundleImport, converted: Vec<TypeChange>, yml: &ModuleSettingsData) -> Self {
+        let violations = import.changed_groups()
+            .filter_map(|g| Some((yml.settings.iter().find(|s| s.name == g.name)?, g)))
+            .flat_map(|(s, g)| yml.validate_coding(s, &g.coding).into_iter().map(|mut v| {
+                v.path = format!("{}.{}", g.name, v.path);
+                v
+            }))
+            .collect();
+        Self { wording, warnings, import, converted, violations }
+    }
 }
 
 impl TcuAdvSettingsUi {
@@ -54,26 +110,10 @@ impl TcuAdvSettingsUi {
                 nag.with_kwp(|x| x.kwp_set_session(KwpSessionTypeByte::Extended(0x93))).map_err(|e| e.to_string())?;
                 *status.write().unwrap() = LoadState::Msg(format!("Locating embedded container"));
                 ctx.request_repaint();
-                let part_info = nag.get_embed_file_info().map_err(|e| e.to_string())?;
-                let mut read_contents = Vec::new();
-                while read_contents.len() < part_info.size as usize {
-                    let to_read = std::cmp::min(250, part_info.size as usize - read_contents.len()) as u8;
-                    let addr = part_info.address + read_contents.len() as u32;
-                    *status.write().unwrap() = LoadState::Download {
-                        curr_addr: part_info.address + read_contents.len() as u32,
-                        total: part_info.size,
-                        done: read_contents.len() as u32,
-                    };
+                read_module_settings_from_tcu(&nag, |curr_addr, total, done| {
+                    *status.write().unwrap() = LoadState::Download { curr_addr, total, done };
                     ctx.request_repaint();
-                    let data = nag.read_mem_by_addr_ext(addr, to_read).map_err(|e| e.to_string())?;
-                    read_contents.extend_from_slice(&data);
-                }
-                let reader = BufReader::new(Cursor::new( read_contents));
-                let mut zip = ZipArchive::new(reader).map_err(|e| format!("Data on EGS is corrupt!"))?;
-                let mut mod_settings = zip.by_name("MODULE_SETTINGS.yml").map_err(|e| format!("Data on EGS does not contain MODULE_SETTINGS"))?;
-                let mut s = String::new();
-                let _ = mod_settings.read_to_string(&mut s).unwrap();
-                serde_yaml::from_str::<ModuleSettingsData>(&s).map_err(|e| e.to_string())
+                })
             }   
 
             match load_file(status_c.clone(), nag_c.clone(), ctx.clone()) {
@@ -113,13 +153,185 @@ impl TcuAdvSettingsUi {
             yml,
             current_settings,
             default_settings,
-            current_setting: None
+            current_setting: None,
+            import: None
         }
     } 
+
+    /// Selects the coding string of `scn_id` once the page has loaded
+    pub fn with_setting(mut self, scn_id: u8) -> Self {
+        self.current_setting = Some(scn_id);
+        self
+    }
+
+    fn firmware_version(&self) -> String {
+        self.nag.get_running_fw_info().map(|h| h.get_version()).unwrap_or("Unknown".into())
+    }
+
+    /// Re-reads the current coding strings, after they were written
+    fn refresh_codings(&self, yml: &ModuleSettingsData) {
+        if let Ok(codings) = self.nag.read_settings_codings(yml) {
+            let mut current = self.current_settings.write().unwrap();
+            for (id, coding) in codings {
+                current.insert(id, Ok(coding));
+            }
+        }
+    }
+
+    fn export_bundle(&self, yml: &ModuleSettingsData) -> Result<Option<String>, String> {
+        let Some(path) = rfd::FileDialog::new().set_title("Save settings bundle").add_filter("JSON", &["json"]).save_file() else {
+            return Ok(None);
+        };
+        let codings = self.nag.read_settings_codings(yml).map_err(|e| format!("Could not read settings: {e}"))?;
+        let bundle = SettingsBundle::new(yml, self.firmware_version(), &codings);
+        std::fs::write(&path, bundle.to_json()).map_err(|e| e.to_string())?;
+        Ok(Some(format!("Settings bundle saved to {}", path.display())))
+    }
+
+    fn load_bundle(&mut self, yml: &ModuleSettingsData) -> Result<(), String> {
+        let Some(path) = rfd::FileDialog::new().set_title("Open settings bundle").add_filter("JSON", &["json"]).pick_file() else {
+            return Ok(());
+        };
+        let s = std::fs::read_to_string(&path).map_err(|e| e.to_string())?;
+        let bundle = SettingsBundle::from_json(&s).map_err(|e| format!("Invalid settings bundle: {e}"))?;
+        let codings = self.nag.read_settings_codings(yml).map_err(|e| format!("Could not read settings: {e}"))?;
+        let mut warnings = Vec::new();
+        let firmware_version = self.firmware_version();
+        if bundle.firmware_version != firmware_version {
+            warnings.push(format!("Bundle was saved on firmware {}, but the TCU runs {firmware_version}", bundle.firmware_version));
+        }
+        if bundle.key_magic != yml.key_magic() {
+            warnings.push("Bundle was saved with a different MODULE_SETTINGS layout. Settings are matched by name".into());
+        }
+        self.import = Some(PendingImport::new(&BUNDLE_WORDING, warnings, bundle.import(yml, &codings), Vec::new(), yml));
+        Ok(())
+    }
+
+    /// Migrates the settings of an EEPROM backup, made with an older MODULE_SETTINGS layout
+    fn load_migration(&mut self, yml: &ModuleSettingsData) -> Result<(), String> {
+        let Some(yml_path) = rfd::FileDialog::new().set_title("Choose the old MODULE_SETTINGS.yml").add_filter("YML", &["yml"]).pick_file() else {
+            return Ok(());
+        };
+        let Some(nvs_path) = rfd::FileDialog::new().set_title("Choose the EEPROM (NVS) backup made with it").add_filter("BIN", &["bin"]).pick_file() else {
+            return Ok(());
+        };
+        let s = std::fs::read_to_string(&yml_path).map_err(|e| e.to_string())?;
+        let old = serde_yaml::from_str::<ModuleSettingsData>(&s).map_err(|e| format!("Invalid MODULE_SETTINGS: {e}"))?;
+        let nvs = std::fs::read(&nvs_path).map_err(|e| e.to_string())?;
+        let nvs = NvsPartition::parse(&nvs).map_err(|e| format!("Invalid EEPROM backup: {e}"))?;
+        let old_codings = old.codings_from_nvs(&nvs);
+        if old_codings.is_empty() {
+            return Err("EEPROM backup does not contain any settings of the old MODULE_SETTINGS".into());
+        }
+        let codings = self.nag.read_settings_codings(yml).map_err(|e| format!("Could not read settings: {e}"))?;
+        let migration = SettingsMigration::new(&old, &old_codings, yml, &codings);
+        let warnings = vec![format!("Settings are migrated from {} groups of the EEPROM backup", old_codings.len())];
+        self.import = Some(PendingImport::new(&MIGRATION_WORDING, warnings, migration.import, migration.converted, yml));
+        Ok(())
+    }
+
+    fn make_import_window(&mut self, ctx: &egui::Context, yml: &ModuleSettingsData) -> Option<PageAction> {
+        let pending = self.import.as_ref()?;
+        let mut ret = None;
+        let mut close = false;
+        egui::Window::new(pending.wording.title).collapsible(false).default_width(600.0).show(ctx, |ui| {
+            let changed: Vec<_> = pending.import.changed_groups().collect();
+            for w in &pending.warnings {
+                ui.colored_label(Color32::YELLOW, w);
+            }
+            if !pending.import.missing_groups.is_empty() {
+                ui.colored_label(Color32::YELLOW, format!("Groups not on this TCU (Skipped): {}", pending.import.missing_groups.join(", ")));
+            }
+            ScrollArea::vertical().max_height(400.0).show(ui, |ui| {
+                for g in &pending.import.groups {
+                    let r = &g.report;
+                    let mut title = RichText::new(format!("{}: {} changes", g.name, r.changed.len()));
+                    if !r.errors.is_empty() || !r.unused.is_empty() {
+                        title = title.color(Color32::YELLOW);
+                    }
+                    CollapsingHeader::new(title).id_salt(format!("bundle-group-{}", g.name)).default_open(g.is_changed()).show(ui, |ui| {
+                        if !r.changed.is_empty() {
+                            egui::Grid::new(format!("bundle-diff-{}", g.name)).striped(true).num_columns(3).show(ui, |ui| {
+                                ui.strong("Setting");
+                                ui.strong("TCU");
+                                ui.strong("Bundle");
+                                ui.end_row();
+                                for c in &r.changed {
+                                    ui.code(&c.path);
+                                    ui.label(c.old.to_string());
+                                    ui.label(RichText::new(c.new.to_string()).color(Color32::RED));
+                                    ui.end_row();
+                                }
+                            });
+                        }
+                        for (path, e) in &r.errors {
+                            ui.colored_label(Color32::YELLOW, format!("{path}: {e} ({})", pending.wording.invalid));
+                        }
+                        if !r.unused.is_empty() {
+                            ui.colored_label(Color32::YELLOW, format!("Not on this TCU ({}): {}", pending.wording.unused, r.unused.join(", ")));
+                        }
+                        if !r.missing.is_empty() {
+                            ui.label(format!("No value to import (Unchanged): {}", r.missing.join(", ")));
+                        }
+                        for c in pending.converted.iter().filter(|c| c.group == g.name) {
+                            ui.label(format!("{}: Converted from {} to {}", c.path, c.from, c.to));
+                        }
+                    });
+                }
+            });
+            if !pending.violations.is_empty() {
+                ui.colored_label(Color32::RED, format!("{} settings are invalid:", pending.violations.len()));
+                for v in &pending.violations {
+                    ui.colored_label(Color32::RED, format!("• {v}"));
+                }
+            }
+            ui.separator();
+            ui.horizontal(|row| {
+                let can_write = !changed.is_empty() && pending.violations.is_empty();
+                if row.add_enabled(can_write, egui::Button::new(format!("Write {} changed groups", changed.len()))).clicked() {
+                    let groups: Vec<(&SettingsData, &[u8])> = changed.iter()
+                        .filter_map(|g| Some((yml.settings.iter().find(|s| s.name == g.name)?, g.coding.as_slice())))
+                        .collect();
+                    ret = Some(match self.nag.write_settings_codings(yml, &groups) {
+                        Ok(_) => PageAction::SendNotification {
+                            text: format!("{} groups written", groups.len()),
+                            kind: egui_notify::ToastLevel::Success
+                        },
+                        Err(e) => PageAction::SendNotification {
+                            text: format!("Writing settings failed: {e}"),
+                            kind: egui_notify::ToastLevel::Error
+                        }
+                    });
+                    close = true;
+                }
+                if row.button("Cancel").clicked() {
+                    close = true;
+                }
+            });
+        });
+        if close {
+            if ret.is_some() {
+                self.refresh_codings(yml);
+            }
+            self.import = None;
+        }
+        ret
+    }
 }
 
+/// Drag value limited to the min/max of the setting (Or its type), with entered values rounded to its step.
+/// Values already out of range are only changed once edited, so they are still shown as violations
 fn gen_drag_value<'a, Num: emath::Numeric>(value: &'a mut Num, var: &'a SettingsVariable, decimals: bool) -> DragValue<'a> {
-    let mut dv = DragValue::new(value).speed(0.0);
+    let percent = var.unit.as_deref() == Some("%");
+    // Obvious
+    let min = var.min.or(percent.then_some(0.0)).unwrap_or(Num::MIN.to_f64()).max(Num::MIN.to_f64());
+    let max = var.max.or(percent.then_some(100.0)).unwrap_or(Num::MAX.to_f64()).min(Num::MAX.to_f64());
+    let mut dv = DragValue::from_get_set(move |v| {
+        if let Some(v) = v {
+            *value = Num::from_f64(var.snap_value(v).clamp(min, max));
+        }
+        value.to_f64()
+    }).speed(0.0).range(min..=max).clamp_existing_to_range(false);
 
     if decimals {
         dv = dv.max_decimals(3).fixed_decimals(3);
@@ -129,10 +341,6 @@ fn gen_drag_value<'a, Num: emath::Numeric>(value: &'a mut Num, var: &'a Settings
     
 
     if let Some(mut unit) = var.unit.clone() {
-        if unit == "%" {
-            // Obvious
-            dv = dv.clamp_range(0..=100);
-        }
         if unit == "milliseconds" {
             unit = "ms".into();
         }
@@ -148,40 +356,74 @@ fn gen_drag_value<'a, Num: emath::Numeric>(value: &'a mut Num, var: &'a Settings
     dv
 }
 
-fn gen_row(ui: &mut egui::Ui, var: &SettingsVariable, coding: &mut [u8], enums: &[EnumMap], internal_structs: &[SettingsData]) -> SettingsType {
+/// Adds the name, value and description of a setting to the grid, writing any change back into `coding`.
+/// Settings which cannot be decoded are shown as raw bytes, so a newer MODULE_SETTINGS does not break the page
+fn gen_row(ui: &mut egui::Ui, var: &SettingsVariable, coding: &mut [u8], enums: &[EnumMap], internal_structs: &[SettingsData]) {
     ui.code(&var.name);
-    let v = match var.to_settings_type(&coding, enums, internal_structs) {
+    match var.to_settings_type_or_raw(coding, enums, internal_structs) {
+        Ok((value, err)) => {
+            let v = ui.horizontal(|ui| {
+                let v = gen_value(ui, &var.name, var, value, enums, internal_structs);
+                if let Some(e) = err {
+                    ui.colored_label(Color32::YELLOW, "⚠").on_hover_text(format!("Shown as raw bytes. {e}"));
+                }
+                v
+            }).inner;
+            // Values always have the length they were decoded from
+            let _ = var.insert_back_into_coding_string(v, coding);
+        },
+        Err(e) => {
+            ui.colored_label(Color32::RED, e.to_string());
+        }
+    }
+    ui.add(Label::new(var.description.clone().unwrap_or("-".into())).wrap());
+}
+
+fn gen_value(ui: &mut egui::Ui, id: &str, var: &SettingsVariable, value: SettingsType, enums: &[EnumMap], internal_structs: &[SettingsData]) -> SettingsType {
+    match value {
         SettingsType::Bool(mut b) => {
             ui.checkbox(&mut b, "");
             SettingsType::Bool(b)
         },
         SettingsType::F32(mut f) => {
-            ui.add(gen_drag_value(&mut f, &var, true));
+            ui.add(gen_drag_value(&mut f, var, true));
             SettingsType::F32(f)
         },
+        SettingsType::U32(mut u) => {
+            ui.add(gen_drag_value(&mut u, var, false));
+            SettingsType::U32(u)
+        },
+        SettingsType::I32(mut i) => {
+            ui.add(gen_drag_value(&mut i, var, false));
+            SettingsType::I32(i)
+        },
         SettingsType::I16(mut i) => {
-            ui.add(gen_drag_value(&mut i, &var, false));
+            ui.add(gen_drag_value(&mut i, var, false));
             SettingsType::I16(i)
         }
         SettingsType::U16(mut u) => {
-            ui.add(gen_drag_value(&mut u, &var, false));
+            ui.add(gen_drag_value(&mut u, var, false));
             SettingsType::U16(u)
         },
         SettingsType::U8(mut u) => {
-            ui.add(gen_drag_value(&mut u, &var, false));
+            ui.add(gen_drag_value(&mut u, var, false));
             SettingsType::U8(u)
         },
+        SettingsType::I8(mut i) => {
+            ui.add(gen_drag_value(&mut i, var, false));
+            SettingsType::I8(i)
+        },
         SettingsType::Enum { mut value, mapping } => { 
             let s = mapping.mappings.get(&value).cloned().unwrap_or(EnumDesc {
                 name: "INVALID CODING".to_string(),
                 desc: format!("Value of 0x{:02X?} not known", value),
             });
-            egui::ComboBox::from_id_source(format!("Enum-{}-select", var.name))
+            egui::ComboBox::from_id_salt(format!("Enum-{id}-select"))
                 .width(100.0)
                 .selected_text(&s.name)
                 .show_ui(ui, |x| {
                     for (k, e) in mapping.mappings.clone() {
-                        x.push_id(format!("{}-{}", var.name, e.name), |x| {
+                        x.push_id(format!("{id}-{}", e.name), |x| {
                             x.selectable_value(
                                 &mut value, 
                                 k, 
@@ -193,30 +435,52 @@ fn gen_row(ui: &mut egui::Ui, var: &SettingsVariable, coding: &mut [u8], enums:
             SettingsType::Enum { value, mapping } 
         },
         SettingsType::Struct { mut raw, s } => {
-            
             CollapsingHeader::new("Show internal")
-                .id_source(format!("It-var-editor-{}",var.name))
+                .id_salt(format!("It-var-editor-{id}"))
                 .show(ui, |ui| {
-                    egui::Grid::new(format!("setting-var-editor-{}",var.name)).num_columns(3).striped(true).show(ui, |ui| {
+                    egui::Grid::new(format!("setting-var-editor-{id}")).num_columns(3).striped(true).show(ui, |ui| {
                         ui.strong("Setting");
                         ui.strong("Value");
                         ui.strong("Description");
                         ui.end_row();
                         for param in &s.params {
-                            let s = gen_row(ui, param, &mut raw, enums, internal_structs);
+                            gen_row(ui, param, &mut raw, enums, internal_structs);
                             ui.end_row();
-                            param.insert_back_into_coding_string(s, &mut raw);
                         }            
                     });
                 });
             SettingsType::Struct { raw, s }
         },
-    };
-    ui.add(Label::new(var.description.clone().unwrap_or("-".into())).wrap());
-    v
+        SettingsType::Array(values) => {
+            let mut ret = Vec::with_capacity(values.len());
+            CollapsingHeader::new(format!("{} elements", values.len()))
+                .id_salt(format!("Array-var-editor-{id}"))
+                .show(ui, |ui| {
+                    for (idx, v) in values.iter().enumerate() {
+                        ui.horizontal(|ui| {
+                            ui.label(format!("[{idx}]"));
+                            ret.push(gen_value(ui, &format!("{id}[{idx}]"), var, v.clone(), enums, internal_structs));
+                        });
+                    }
+                });
+            // Collapsed, so nothing was edited
+            if ret.len() != values.len() {
+                ret = values;
+            }
+            SettingsType::Array(ret)
+        },
+        SettingsType::Raw(mut bytes) => {
+            ui.horizontal_wrapped(|ui| {
+                for b in bytes.iter_mut() {
+                    ui.add(DragValue::new(b).speed(0.0).hexadecimal(2, false, true));
+                }
+            });
+            SettingsType::Raw(bytes)
+        },
+    }
 }
 
-fn generate_editor_ui(nag: &Nag52Diag, coding: &mut Vec<u8>, default: &[u8], setting: &SettingsData, enums: &[EnumMap], internal_structs: &[SettingsData], ui: &mut egui::Ui) -> Option<PageAction> {
+fn generate_editor_ui(nag: &Nag52Diag, coding: &mut Vec<u8>, default: &[u8], setting: &SettingsData, yml: &ModuleSettingsData, ui: &mut egui::Ui) -> Option<PageAction> {
     let mut ret = None;
     let width = ui.available_width();
     ScrollArea::new([true, false]).max_width(width).id_source("CODING_VIEW").show(ui, |r| {
@@ -243,16 +507,15 @@ fn generate_editor_ui(nag: &Nag52Diag, coding: &mut Vec<u8>, default: &[u8], set
         });
     });
     ui.add_space(10.0);
+    let violations = yml.validate_coding(setting, coding);
     ui.horizontal(|r| {
         if r.button("Reset coding to default").clicked() {
             coding.copy_from_slice(default);
         }
-        if r.button("Write to TCU").clicked() {
-            ret = match nag.with_kwp(|kwp| {
-                let mut tx = vec![KwpCommand::WriteDataByLocalIdentifier.into(), 0xFC, setting.scn_id.unwrap()];
-                tx.extend_from_slice(coding);
-                kwp.send_byte_array_with_response(&tx)
-            }) {
+        let write = r.add_enabled(violations.is_empty(), egui::Button::new("Write to TCU"))
+            .on_disabled_hover_text("Fix the invalid settings before writing");
+        if write.clicked() {
+            ret = match nag.write_settings_coding(yml, setting, coding) {
                 Ok(_) => {
                     Some(
                         PageAction::SendNotification { 
@@ -264,7 +527,7 @@ fn generate_editor_ui(nag: &Nag52Diag, coding: &mut Vec<u8>, default: &[u8], set
                 Err(e) => {
                     Some(
                         PageAction::SendNotification { 
-                            text: format!("Writing of setting {} failed: {e:?}", setting.name),
+                            text: format!("Writing of setting {} failed: {e}", setting.name),
                             kind: egui_notify::ToastLevel::Error
                         }
                     )
@@ -272,6 +535,13 @@ fn generate_editor_ui(nag: &Nag52Diag, coding: &mut Vec<u8>, default: &[u8], set
             }
         }
     });
+    if !violations.is_empty() {
+        ui.add_space(5.0);
+        ui.colored_label(Color32::RED, format!("{} settings are invalid:", violations.len()));
+        for v in &violations {
+            ui.colored_label(Color32::RED, format!("• {v}"));
+        }
+    }
     ui.add_space(10.0);
     ScrollArea::new([false, true]).max_height(ui.available_height()).show(ui, |ui| {
         egui::Grid::new("setting-var-editor").num_columns(3).striped(true).show(ui, |ui| {
@@ -280,9 +550,8 @@ fn generate_editor_ui(nag: &Nag52Diag, coding: &mut Vec<u8>, default: &[u8], set
             ui.strong("Description");
             ui.end_row();
             for param in &setting.params {
-                let s = gen_row(ui, param, coding, enums, internal_structs);
+                gen_row(ui, param, coding, &yml.enums, &yml.internal_structures);
                 ui.end_row();
-                param.insert_back_into_coding_string(s, coding);
             }            
         });
     });
@@ -299,6 +568,30 @@ impl InterfacePage for TcuAdvSettingsUi {
         match state {
             LoadState::Ready => {
                 let yml = yml.as_ref().unwrap().clone();
+                ui.horizontal(|row| {
+                    if row.button("Export settings bundle").clicked() {
+                        action = match self.export_bundle(&yml) {
+                            Ok(Some(text)) => PageAction::SendNotification { text, kind: egui_notify::ToastLevel::Success },
+                            Ok(None) => PageAction::None,
+                            Err(text) => PageAction::SendNotification { text, kind: egui_notify::ToastLevel::Error },
+                        };
+                    }
+                    if row.add_enabled(self.import.is_none(), egui::Button::new("Import settings bundle")).clicked() {
+                        if let Err(text) = self.load_bundle(&yml) {
+                            action = PageAction::SendNotification { text, kind: egui_notify::ToastLevel::Error };
+                        }
+                    }
+                    let migrate = row.add_enabled(self.import.is_none(), egui::Button::new("Migrate settings from EEPROM backup"))
+                        .on_hover_text("Restores the settings of an EEPROM backup made before a firmware update, which changed the settings layout");
+                    if migrate.clicked() {
+                        if let Err(text) = self.load_migration(&yml) {
+                            action = PageAction::SendNotification { text, kind: egui_notify::ToastLevel::Error };
+                        }
+                    }
+                });
+                if let Some(a) = self.make_import_window(ui.ctx(), &yml) {
+                    action = a;
+                }
                 ui.heading("Select coding string");
                 ui.horizontal(|row| {
                     for (k, v) in &curr_settings {
@@ -315,7 +608,7 @@ impl InterfacePage for TcuAdvSettingsUi {
                         let def = default.unwrap().clone();
                         let mut modify = modifying.unwrap().clone();
                         ui.separator();
-                        if let Some(a) = generate_editor_ui(&self.nag, &mut modify, &def, setting_def, &yml.enums, &yml.internal_structures, ui) {
+                        if let Some(a) = generate_editor_ui(&self.nag, &mut modify, &def, setting_def, &yml, ui) {
                             action = a;
                         }
                         self.current_settings.write().unwrap().insert(current_id, Ok(modify));
@@ -421,3 +714,33 @@ impl Drop for TcuAdvSettingsUi {
         self.nag.with_kwp(|x| x.kwp_set_session(KwpSessionType::Normal.into()));
     }
 }
+
+#[cfg(test)]
+pub mod test_settings_ui_gen {
+    use backend::{diag::settings::{ModuleSettingsData, SettingsViolationKind}, hw::sim_ecu::SIM_MODULE_SETTINGS_YML, serde_yaml};
+    use eframe::egui;
+
+    use super::gen_row;
+
+    #[test]
+    pub fn test_render_keeps_out_of_range_value() {
+        let yml: ModuleSettingsData = serde_yaml::from_str(SIM_MODULE_SETTINGS_YML).unwrap();
+        let tcc = &yml.settings[0];
+        let mut coding = vec![0; 22];
+        coding[2..4].copy_from_slice(&5025u16.to_le_bytes());
+        let before = coding.clone();
+        let ctx = egui::Context::default();
+        for _ in 0..2 {
+            let _ = ctx.run(Default::default(), |ctx| {
+                egui::CentralPanel::default().show(ctx, |ui| {
+                    for var in &tcc.params {
+                        gen_row(ui, var, &mut coding, &yml.enums, &yml.internal_structures);
+                    }
+                });
+            });
+        }
+        assert_eq!(coding, before);
+        let violations = yml.validate_coding(tcc, &coding);
+        assert!(violations.iter().any(|v| v.path == "min_locking_rpm" && matches!(v.kind, SettingsViolationKind::AboveMax { .. })));
+    }
+}
@@ -1,13 +1,15 @@
-use std::{sync::{Arc, RwLock}, time::Instant, fs::File, io::{Write, Read}};
+use std::{sync::{Arc, RwLock}, time::Instant, path::PathBuf};
 
-use backend::{diag::{Nag52Diag, flash::PartitionInfo, DataState}, hw::firmware::{Firmware, load_binary, FirmwareHeader, load_binary_from_path}};
+use backend::{diag::{Nag52Diag, flash::{DownloadError, DownloadOptions, PartitionInfo}, module_settings_flash_store::{ModuleSettingsFlashHeader, ModuleSettingsUpload, ModuleSettingsUploadProgress}, DataState}, hw::firmware::{Firmware, load_binary, FirmwareHeader, load_binary_from_path, FirmwareComparison, FirmwareIssue, FirmwareBranch}};
 use curl::easy::{Easy, List};
-use eframe::egui::{self};
-use octocrab::models::repos::Release;
+use eframe::egui::{self, Color32, RichText};
+use octocrab::models::repos::{Asset, Release};
 use tokio::runtime::Runtime;
 
 use crate::window::{InterfacePage, PageAction};
 
+use super::coredump::CoredumpPage;
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum CurrentFlashState {
     None,
@@ -54,7 +56,12 @@ pub struct UpdatePage {
     old_fw: Option<(FirmwareHeader, PartitionInfo)>,
     releases:  Arc<RwLock<DataState<Vec<Release>>>>,
     checked_unstable: bool,
-    selected_release: Option<Release>
+    selected_release: Option<Release>,
+    /// Partition and file of the last backup, until it completes
+    last_backup: Arc<RwLock<Option<(PartitionInfo, PathBuf)>>>,
+    /// MODULE_SETTINGS.yml to upload, and the header of the MODULE_SETTINGS stored on the TCU when it was loaded
+    module_settings: Option<(PathBuf, Arc<ModuleSettingsUpload>, Option<ModuleSettingsFlashHeader>)>,
+    allow_key_change: bool,
 }
 
 impl UpdatePage {
@@ -97,7 +104,83 @@ impl UpdatePage {
             old_fw: curr_fw_info,
             releases: fw_list,
             checked_unstable: false,
-            selected_release: None
+            selected_release: None,
+            last_backup: Arc::new(RwLock::new(None)),
+            module_settings: None,
+            allow_key_change: false,
+        }
+    }
+}
+
+impl UpdatePage {
+    fn make_module_settings_ui(&mut self, ui: &mut egui::Ui, state: &CurrentFlashState) {
+        ui.strong("MODULE_SETTINGS");
+        ui.label("Uploads the description of the TCU settings (MODULE_SETTINGS.yml) which the TCU gives to the app");
+        if ui.button("Load MODULE_SETTINGS.yml").clicked() {
+            if let Some(path) = rfd::FileDialog::new().add_filter("MODULE_SETTINGS", &["yml", "yaml"]).pick_file() {
+                let res = std::fs::read_to_string(&path).map_err(|e| e.to_string())
+                    .and_then(|s| ModuleSettingsUpload::new(&s).map_err(|e| e.to_string()));
+                match res {
+                    Ok(upload) => {
+                        let stored = self.nag.read_module_settings_header().ok().flatten();
+                        self.module_settings = Some((path, Arc::new(upload), stored));
+                        self.allow_key_change = false;
+                    },
+                    Err(e) => {
+                        self.module_settings = None;
+                        *self.status.write().unwrap() = CurrentFlashState::Failed(format!("MODULE_SETTINGS loading failed: {e}"));
+                    }
+                }
+            }
+        }
+        let Some((path, upload, stored)) = &self.module_settings else {
+            return;
+        };
+        egui::Grid::new("module-settings-upload").striped(true).show(ui, |ui| {
+            ui.label("File");
+            ui.label(path.display().to_string());
+            ui.end_row();
+            ui.label("Settings groups");
+            ui.label(upload.settings.settings.len().to_string());
+            ui.end_row();
+            ui.label("Compressed size");
+            ui.label(format!("{} bytes", upload.header.length_compressed));
+            ui.end_row();
+            ui.label("Key magic");
+            ui.label(format!("0x{:08X}", upload.header.key_magic));
+            ui.end_row();
+            ui.label("Key magic on TCU");
+            ui.label(stored.map(|h| format!("0x{:08X}", h.key_magic)).unwrap_or("None".into()));
+            ui.end_row();
+        });
+        let mismatch = stored.is_some_and(|h| h.key_magic != upload.header.key_magic);
+        if mismatch {
+            ui.label(RichText::new("WARNING. The settings keys of this MODULE_SETTINGS differ from the MODULE_SETTINGS on the TCU. It is likely for a different firmware, and the app will show the TCU settings incorrectly").strong().color(Color32::RED));
+            ui.checkbox(&mut self.allow_key_change, "I have read the warning. Upload anyway");
+        }
+        let enabled = state.is_idle() && (!mismatch || self.allow_key_change);
+        if ui.add_enabled(enabled, egui::Button::new("Upload MODULE_SETTINGS")).clicked() {
+            let ng = self.nag.clone();
+            let upload_c = upload.clone();
+            let allow_key_change = self.allow_key_change;
+            let state_c = self.status.clone();
+            let ctx_c = ui.ctx().clone();
+            let start_addr = ng.get_module_settings_desc_partition().address;
+            std::thread::spawn(move || {
+                *state_c.write().unwrap() = CurrentFlashState::Prepare;
+                let res = ng.upload_module_settings(&upload_c, allow_key_change, |p| {
+                    *state_c.write().unwrap() = match p {
+                        ModuleSettingsUploadProgress::Write { done, total } => CurrentFlashState::Write { ty: "MODULE_SETTINGS", start_addr, current: done, total },
+                        ModuleSettingsUploadProgress::Verify { done, total } => CurrentFlashState::Read { start_addr, current: done, total },
+                    };
+                    ctx_c.request_repaint();
+                });
+                *state_c.write().unwrap() = match res {
+                    Ok(()) => CurrentFlashState::Completed("MODULE_SETTINGS uploaded and verified. Reconnect to the TCU to use it".into()),
+                    Err(e) => CurrentFlashState::Failed(format!("MODULE_SETTINGS upload failed. {e}")),
+                };
+                ctx_c.request_repaint();
+            });
         }
     }
 }
@@ -131,11 +214,80 @@ fn make_fw_info(ui: &mut egui::Ui, id: &str, fw: &FirmwareHeader, part_info: Opt
     });
 }
 
+fn make_fw_comparison(ui: &mut egui::Ui, cmp: &FirmwareComparison) {
+    fn sha_str(sha: [u8; 32]) -> String {
+        sha[..8].iter().map(|b| format!("{b:02x}")).collect::<String>() + "..."
+    }
+    let ts = |fw: &FirmwareHeader| fw.get_build_timestamp().map(|f| f.to_string()).unwrap_or("Unknown".into());
+    let rows: [(&str, String, String); 7] = [
+        ("FW Name", cmp.running.get_fw_name(), cmp.new.get_fw_name()),
+        ("FW Version", cmp.running.get_version(), cmp.new.get_version()),
+        ("Branch", cmp.running.get_branch().to_string(), cmp.new.get_branch().to_string()),
+        ("Secure version", cmp.running.get_secure_version().to_string(), cmp.new.get_secure_version().to_string()),
+        ("ESP IDF Version", cmp.running.get_idf_version(), cmp.new.get_idf_version()),
+        ("Build time", ts(&cmp.running), ts(&cmp.new)),
+        ("ELF SHA-256", sha_str(cmp.running.get_elf_sha()), sha_str(cmp.new.get_elf_sha())),
+    ];
+    egui::Grid::new("fwcmp").striped(true).show(ui, |ui| {
+        ui.label("");
+        ui.strong("Current");
+        ui.strong("New");
+        ui.end_row();
+        for (name, running, new) in rows {
+            ui.label(name);
+            let changed = running != new;
+            ui.label(running);
+            if changed {
+                ui.strong(new);
+            } else {
+                ui.label(new);
+            }
+            ui.end_row();
+        }
+    });
+}
+
+/// Downloads a release asset, reporting progress via the flash state. Returns the HTTP response code on failure
+fn download_asset(asset: &Asset, state: &Arc<RwLock<CurrentFlashState>>) -> Result<Vec<u8>, u32> {
+    let url = format!("https://api.github.com{}", asset.url.path());
+    *state.write().unwrap() = CurrentFlashState::Download(0, 0);
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut easy = Easy::new();
+    let mut list = List::new();
+    list.append("Accept: application/octet-stream").unwrap();
+    easy.progress(true);
+    let state_progress = state.clone();
+    easy.progress_function(move|dltotal,dlnow,_,_| {
+        *state_progress.write().unwrap() = CurrentFlashState::Download(dlnow as usize, dltotal as usize);
+        return true;
+    });
+    easy.http_headers(list).unwrap();
+    easy.useragent("request").unwrap();
+    easy.follow_location(true).unwrap();
+    easy.url(&url).unwrap();
+    {
+        let mut transfer = easy.transfer();
+        let _ = transfer.write_function(|data| {
+            buffer.extend_from_slice(data);
+            Ok(data.len())
+        });
+        let _ = transfer.perform();
+    }
+
+    let code = easy.response_code().unwrap_or(0);
+    if code == 200 || code == 302 {
+        Ok(buffer)
+    } else {
+        Err(code)
+    }
+}
+
 impl InterfacePage for UpdatePage {
     fn make_ui(&mut self, ui: &mut eframe::egui::Ui, frame: &eframe::Frame) -> crate::window::PageAction {
         ui.heading("Updater and dumper (New)");
         let state = self.status.read().unwrap().clone();
         let mut read_partition: Option<PartitionInfo> = None;
+        let mut decode_coredump = false;
         ui.heading("Coredump info");
         if let Some(coredump) = &self.coredump {
             if coredump.size != 0 {
@@ -148,15 +300,23 @@ impl InterfacePage for UpdatePage {
                     ui.label(format!("{:.1}Kb", (coredump.size as f32)/1024.0));
                     ui.end_row();
                 });
-                if ui.button("Read coredump").clicked() {
-                    read_partition = Some(coredump.clone())
-                }
+                ui.horizontal(|ui| {
+                    if ui.button("Read coredump").clicked() {
+                        read_partition = Some(coredump.clone())
+                    }
+                    if ui.button("Decode coredump").clicked() {
+                        decode_coredump = true;
+                    }
+                });
             } else {
                 ui.label("No coredump stored on this TCU!");
             }
         } else {
             ui.label("No coredump found");
         }
+        if decode_coredump {
+            return PageAction::Add(Box::new(CoredumpPage::new(self.nag.clone(), ui.ctx().clone())));
+        }
         ui.separator();
         if let Some((info, part_info)) = &self.old_fw {
             ui.heading("Current Firmware");
@@ -212,45 +372,19 @@ impl InterfacePage for UpdatePage {
                             let state_c = self.status.clone();
                             let fw_c = self.fw.clone();
                             std::thread::spawn(move|| {
-                                let url = format!("https://api.github.com{}",fw.url.path());
-                                *state_c.write().unwrap() = CurrentFlashState::Download(0, 0);
-                                let mut buffer_firmware: Vec<u8> = Vec::new();
-                                let buffer_yml: Vec<u8> = Vec::new();
-                                let mut easy = Easy::new();
-                                let mut list = List::new();
-                                list.append("Accept: application/octet-stream").unwrap();
-                                easy.progress(true);
-                                let state_progress = state_c.clone();
-                                easy.progress_function(move|dltotal,dlnow,_,_| {
-                                    *state_progress.write().unwrap() = CurrentFlashState::Download(dlnow as usize, dltotal as usize);
-                                    return true;
-                                });
-                                easy.http_headers(list).unwrap();
-                                easy.useragent("request").unwrap();
-                                easy.follow_location(true).unwrap();
-                                easy.url(&url).unwrap();
-                                {
-                                    let mut transfer = easy.transfer();
-                                    let _ = transfer.write_function(|data| {
-                                        buffer_firmware.extend_from_slice(data);                         
-                                        Ok(data.len())
-                                    });
-                                    let _ = transfer.perform();
-                                }
-                                
-                                let code = easy.response_code().unwrap_or(0);
-                                if code == 200 || code == 302 {
-                                    match load_binary(buffer_firmware) {
+                                match download_asset(&fw, &state_c) {
+                                    Ok(buffer_firmware) => match load_binary(buffer_firmware) {
                                         Ok(fw) => {
                                             *fw_c.write().unwrap() = Some(fw);
                                             *state_c.write().unwrap() = CurrentFlashState::None;
                                         },
                                         Err(e) => {
-                                            *state_c.write().unwrap() = CurrentFlashState::Failed(format!("Firmware is corrupt!"));
+                                            *state_c.write().unwrap() = CurrentFlashState::Failed(format!("Firmware is corrupt! {e}"));
                                         }
+                                    },
+                                    Err(code) => {
+                                        *state_c.write().unwrap() = CurrentFlashState::Failed(format!("Firmware download response code was {code}"));
                                     }
-                                } else {
-                                    *state_c.write().unwrap() = CurrentFlashState::Failed(format!("Firmware download firmware response code was {code}"));
                                 }
                             });
                         }
@@ -258,7 +392,16 @@ impl InterfacePage for UpdatePage {
 
                     if let Some(elf) = elf_url {
                         if ui.button("Download debug elf file").clicked() {
-                            return PageAction::SendNotification { text: format!("Todo. Debugger UI!"), kind: egui_notify::ToastLevel::Info }
+                            if let Some(path) = rfd::FileDialog::new().add_filter(".elf", &["elf"]).set_file_name(&elf.name).save_file() {
+                                let state_c = self.status.clone();
+                                std::thread::spawn(move|| {
+                                    *state_c.write().unwrap() = match download_asset(&elf, &state_c).map(|data| std::fs::write(&path, data)) {
+                                        Ok(Ok(())) => CurrentFlashState::Completed(format!("Saved debug ELF to {}. Load it in the coredump viewer to decode crashes, or in the memory inspector to watch variables", path.display())),
+                                        Ok(Err(e)) => CurrentFlashState::Failed(format!("Could not save debug ELF. {e}")),
+                                        Err(code) => CurrentFlashState::Failed(format!("Debug ELF download response code was {code}")),
+                                    };
+                                });
+                            }
                         }
                     }
                 }
@@ -286,34 +429,46 @@ impl InterfacePage for UpdatePage {
                         *self.fw.write().unwrap() = Some(fw);
                     },
                     Err(e) => {
-                        *self.status.write().unwrap() = CurrentFlashState::Failed(format!("Firmware.bin loading failed: {e:?}"));
+                        *self.status.write().unwrap() = CurrentFlashState::Failed(format!("Firmware.bin loading failed: {e}"));
                     }
                 }
             }
         }
         let c_fw = self.fw.clone().read().unwrap().clone();
         if let Some(fw) = &c_fw {
-            make_fw_info(ui, "nfw",&fw.header, None);
             let mut flash = false;
             let mut disclaimer = false;
-            if let Some(new_ts) = fw.header.get_build_timestamp() {
-                if let Some(old_ts) = self.old_fw.map(|f| f.0.get_build_timestamp()).flatten() {
-                    if old_ts > new_ts {
-                        ui.strong("WARNING. The new firmware is older than the current firmware! This can cause bootloops!");
-                        ui.hyperlink_to("See reverting to old FW versions", "docs.ultiamte-nag52.net");
+            let mut blocked = false;
+            match &self.old_fw {
+                Some((running, _)) => {
+                    let cmp = FirmwareComparison::new(running, &fw.header);
+                    make_fw_comparison(ui, &cmp);
+                    for issue in &cmp.issues {
+                        let color = if issue.is_blocking() { Color32::RED } else { Color32::from_rgb(255, 165, 0) };
+                        ui.label(RichText::new(format!("WARNING. {issue}")).strong().color(color));
+                        if matches!(issue, FirmwareIssue::OlderBuild | FirmwareIssue::SecureVersionDowngrade { .. }) {
+                            ui.hyperlink_to("See reverting to old FW versions", "docs.ultiamte-nag52.net");
+                        }
+                    }
+                    disclaimer = !cmp.issues.is_empty();
+                    blocked = cmp.is_blocked();
+                },
+                None => {
+                    // Running firmware is unknown, so nothing to compare against
+                    make_fw_info(ui, "nfw",&fw.header, None);
+                    if fw.header.get_branch() != FirmwareBranch::Main || fw.header.is_dirty() {
+                        ui.strong("WARNING. You are about to flash potentially unstable firmware. Proceed with caution!");
                         disclaimer = true;
                     }
                 }
             }
-            if (!fw.header.get_version().contains("main") || fw.header.get_version().contains("dirty")) && self.old_fw.map(|x| x.0.get_version().contains("main")).unwrap_or(true) {
-                ui.strong("WARNING. You are about to flash potentially unstable firmware. Proceed with caution!");
-                disclaimer = true;
-            }
             let text = match disclaimer {
                 true => "I have read the warnings. Proceed with flashing",
                 false => "Flash new FW",
             };
-            if ui.button(text).clicked() {
+            if blocked {
+                ui.add_enabled(false, egui::Button::new("Flashing refused"));
+            } else if ui.button(text).clicked() {
                 flash = true;
             }
             if flash {
@@ -358,58 +513,59 @@ impl InterfacePage for UpdatePage {
                 });
             }
         }
+        ui.separator();
+        self.make_module_settings_ui(ui, &state);
+        ui.separator();
+        // Failed backups can be resumed once the TCU is reconnected
+        let last_backup = self.last_backup.read().unwrap().clone();
+        let mut resume = false;
+        if let (CurrentFlashState::Failed(_), Some((part, path))) = (&state, &last_backup) {
+            resume = ui.button(format!("Resume backup of 0x{:08X} to {}", part.address, path.display())).clicked();
+        }
         // Check for read operation
-        if let Some(read_op) = &read_partition {
+        if read_partition.is_some() || resume {
+            let (read_op, save_path) = match read_partition {
+                Some(read_op) => match rfd::FileDialog::new().add_filter(".bin", &["bin"]).save_file() {
+                    Some(f) => (read_op, f),
+                    None => {
+                        *self.status.write().unwrap() = CurrentFlashState::Failed(format!("user did not specify save path"));
+                        return PageAction::None;
+                    }
+                },
+                None => last_backup.unwrap(),
+            };
+            *self.last_backup.write().unwrap() = Some((read_op, save_path.clone()));
             let ng = self.nag.clone();
             let state_c = self.status.clone();
-            let mut save_path = None;
-            if let Some(f) = rfd::FileDialog::new()
-                .add_filter(".bin", &["bin"])
-                .save_file() {
-                    save_path = Some(f);
-            } else {
-                *state_c.write().unwrap() = CurrentFlashState::Failed(format!("user did not specify save path"));
-                return PageAction::None;
-            }
-            let read_op_c = read_op.clone();
+            let last_backup_c = self.last_backup.clone();
             let ctx_c = ui.ctx().clone();
             std::thread::spawn(move || {
                 *state_c.write().unwrap() = CurrentFlashState::Prepare;
-                let bs = match ng.begin_download(&read_op_c) {
-                    Ok(bs) => bs,
-                    Err(e) => {
-                        *state_c.write().unwrap() = CurrentFlashState::Failed(format!("Failed to prepare for reading. {}", e));
-                        return;
-                    },
-                };
-                let mut read = 0;
-                let mut read_buffer: Vec<u8> = vec![];
-                let mut counter = 0u8;
-                let start = read_op_c.address;
-                while read_buffer.len() < read_op_c.size as usize {
-                    counter = counter.wrapping_add(1);
-                    match ng.read_data(counter) {
-                        Ok(data) => { 
-                            read += data.len();
-                            read_buffer.extend_from_slice(&data);
-                            *state_c.write().unwrap() = CurrentFlashState::Read { start_addr: start, current: read as u32, total: read_op_c.size };
-                        },
-                        Err(e) => {
-                            *state_c.write().unwrap() = CurrentFlashState::Failed(format!("Failed to read address 0x{:08X?}. {}", start as usize + read ,e));
-                            return;
-                        }
-                    }
+                let opts = DownloadOptions { resume, ..Default::default() };
+                let res = ng.download_partition(&read_op, &save_path, &opts, |p| {
+                    *state_c.write().unwrap() = if p.verifying {
+                        CurrentFlashState::Verify
+                    } else {
+                        CurrentFlashState::Read { start_addr: read_op.address, current: p.done, total: p.total }
+                    };
                     ctx_c.request_repaint();
-                }
-                match ng.end_ota(false) {
-                    Ok(_) => *state_c.write().unwrap() = {
-                        File::create(save_path.unwrap()).unwrap().write_all(&read_buffer).unwrap();
-                        CurrentFlashState::Completed("Done!".to_string())
+                });
+                match res {
+                    Ok(res) => {
+                        *last_backup_c.write().unwrap() = None;
+                        let sha: String = res.sha256.iter().map(|b| format!("{b:02x}")).collect();
+                        *state_c.write().unwrap() = CurrentFlashState::Completed(format!("Done! {} retries, SHA-256 {sha}", res.retries));
+                    },
+                    Err(e @ DownloadError::VerifyFailed { .. }) => {
+                        // Resuming would keep the mismatching data
+                        *last_backup_c.write().unwrap() = None;
+                        *state_c.write().unwrap() = CurrentFlashState::Failed(format!("Backup failed. {e}"));
                     },
                     Err(e) => {
-                        *state_c.write().unwrap() = CurrentFlashState::Failed(format!("Error verification: {}", e));
+                        *state_c.write().unwrap() = CurrentFlashState::Failed(format!("Backup failed. {e}. Reconnect the TCU, then resume the backup"));
                     }
                 }
+                ctx_c.request_repaint();
             });
         }
 
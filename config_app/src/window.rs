@@ -1,15 +1,17 @@
 use std::{
     collections::VecDeque,
-    time::{Duration, Instant}, sync::Arc, fs::OpenOptions, io::Write,
+    time::{Duration, Instant}, sync::{Arc, mpsc, RwLock}, fs::OpenOptions, io::Write,
 };
 
-use backend::{diag::Nag52Diag, ecu_diagnostics::{dynamic_diag::ServerEvent}, hw::usb::{EspLogMessage, EspLogLevel}};
+use backend::{diag::{crash_monitor::{RebootDetector, RebootEvent}, supervisor::{ConnectionEvent, ConnectionState}, Nag52Diag}, ecu_diagnostics::{dynamic_diag::ServerEvent}, hw::usb::{EspLogMessage, EspLogLevel, USB_MAX_BYTES_PER_SEC}};
 use eframe::{
     egui::{self, Button, CornerRadius, RichText, ScrollArea, Sense}, emath::Align2, epaint::{Color32, FontId, Vec2}
 };
 use egui_extras::{TableBuilder, Column};
 use egui_notify::{Toast, ToastLevel, Toasts};
 
+use crate::ui::coredump::CoredumpPage;
+
 #[derive(Debug, Clone)]
 pub enum PageLoadState {
     Ok,
@@ -30,11 +32,22 @@ pub struct MainWindow {
     last_data_query_time: Instant,
     last_tx_rate: u32,
     last_rx_rate: u32,
-    toasts: Toasts
+    toasts: Toasts,
+    conn_events: Option<mpsc::Receiver<ConnectionEvent>>,
+    reboot_detector: RebootDetector,
+    /// Reboot that left a coredump, waiting for the user to save or ignore it
+    crash_prompt: Arc<RwLock<Option<RebootEvent>>>,
+    /// Fingerprint of the last coredump the user was prompted for, as the TCU keeps
+    /// the dump across later reboots
+    prompted_coredump: Arc<RwLock<Option<Vec<u8>>>>,
+    /// Notifications from background threads
+    notify_tx: mpsc::Sender<(String, ToastLevel)>,
+    notify_rx: mpsc::Receiver<(String, ToastLevel)>,
 }
 
 impl MainWindow {
     pub fn new() -> Self {
+        let (notify_tx, notify_rx) = mpsc::channel();
         Self {
             pages: VecDeque::new(),
             show_sbar: false,
@@ -52,8 +65,13 @@ impl MainWindow {
             .with_anchor(
                 egui_notify::Anchor::BottomRight
             )
-            .with_margin(Vec2::new(0.0, 5.0))
-
+            .with_margin(Vec2::new(0.0, 5.0)),
+            conn_events: None,
+            reboot_detector: RebootDetector::default(),
+            crash_prompt: Arc::new(RwLock::new(None)),
+            prompted_coredump: Arc::new(RwLock::new(None)),
+            notify_tx,
+            notify_rx,
         }
     }
     pub fn add_new_page(&mut self, p: Box<dyn InterfacePage>) {
@@ -72,9 +90,38 @@ impl MainWindow {
             pg.on_load(self.nag.clone());
         }
     }
+
+    /// Checks if the TCU stored a new coredump when it rebooted. The TCU may still be
+    /// booting or reconnecting, so the check is retried a few times
+    fn check_coredump(
+        nag: Arc<Nag52Diag>,
+        evt: RebootEvent,
+        prompt: Arc<RwLock<Option<RebootEvent>>>,
+        prompted: Arc<RwLock<Option<Vec<u8>>>>,
+        ctx: egui::Context,
+    ) {
+        std::thread::spawn(move || {
+            for _ in 0..5 {
+                match nag.read_coredump_fingerprint() {
+                    Ok(Some(fingerprint)) => {
+                        if let (Ok(mut last), Ok(mut prompt)) = (prompted.write(), prompt.write()) {
+                            if last.as_ref() != Some(&fingerprint) {
+                                *last = Some(fingerprint);
+                                *prompt = Some(evt);
+                                ctx.request_repaint();
+                            }
+                        }
+                        return;
+                    }
+                    Ok(None) => return,
+                    Err(_) => std::thread::sleep(Duration::from_secs(1)),
+                }
+            }
+        });
+    }
 }
 
-pub const MAX_BANDWIDTH: f32 = 155200.0 / 4.0;
+pub const MAX_BANDWIDTH: f32 = USB_MAX_BYTES_PER_SEC as f32;
 
 impl eframe::App for MainWindow {
     fn update(&mut self, ctx: &eframe::egui::Context, frame: &mut eframe::Frame) {
@@ -95,19 +142,41 @@ impl eframe::App for MainWindow {
                         }
                         if let Some(nag) = &self.nag {
 
-                            let _ = nag.with_kwp(|f| {
-                                if f.is_ecu_connected() {
-                                    if let Some(mode) = f.get_current_diag_mode() {
-                                        row.label(format!("Mode: {}(0x{:02X?})", mode.name, mode.id));
-                                    } 
-                                } else {
-                                    row.label(RichText::new("Disconnected").color(Color32::RED));
+                            while let Some(evt) = self.conn_events.as_ref().and_then(|r| r.try_recv().ok()) {
+                                self.reboot_detector.on_connection_event(&evt);
+                                let (text, kind) = match evt {
+                                    ConnectionEvent::Lost { reason } => (format!("Connection lost: {reason}"), ToastLevel::Warning),
+                                    ConnectionEvent::RetryFailed { .. } => continue,
+                                    ConnectionEvent::Restored { session: Some(s) } => (format!("Reconnected, restored {s} session"), ToastLevel::Success),
+                                    ConnectionEvent::Restored { session: None } => ("Reconnected".into(), ToastLevel::Success),
+                                };
+                                let mut t = Toast::custom(text, kind);
+                                t.duration(Some(Duration::from_secs(5)));
+                                self.toasts.add(t);
+                            }
+                            match nag.connection_state() {
+                                ConnectionState::Connected => {
+                                    let _ = nag.with_kwp(|f| {
+                                        if let Some(mode) = f.get_current_diag_mode() {
+                                            row.label(format!("Mode: {}(0x{:02X?})", mode.name, mode.id));
+                                        }
+                                        Ok(())
+                                    });
+                                },
+                                ConnectionState::Reconnecting { attempt } => {
+                                    row.label(RichText::new(format!("Disconnected, reconnecting (Attempt {attempt})")).color(Color32::RED));
+                                    ctx.request_repaint_after(Duration::from_millis(250));
                                 }
-                                Ok(())
-                            });
+                            }
 
                             if nag.has_logger() {
                                 while let Some(msg) = nag.read_log_msg() {
+                                    if let Some(evt) = self.reboot_detector.on_log(msg.clone()) {
+                                        let mut t = Toast::warning(format!("TCU rebooted: {}", evt.reason));
+                                        t.duration(Some(Duration::from_secs(5)));
+                                        self.toasts.add(t);
+                                        Self::check_coredump(nag.clone(), evt, self.crash_prompt.clone(), self.prompted_coredump.clone(), ctx.clone());
+                                    }
                                     self.logs.push_back(msg);
                                     if self.logs.len() > 1000 {
                                         self.logs.pop_front();
@@ -123,6 +192,23 @@ impl eframe::App for MainWindow {
                             if row.button("Show packet trace").clicked() {
                                 self.show_tracer = true;
                             }
+                            if nag.is_recording() {
+                                if row.button(RichText::new("Stop recording").color(Color32::RED)).clicked() {
+                                    if let Some(n) = nag.stop_recording() {
+                                        let mut t = Toast::info(format!("Trace saved ({} records)", n));
+                                        t.duration(Some(Duration::from_secs(5)));
+                                        self.toasts.add(t);
+                                    }
+                                }
+                            } else if row.button("Record trace").clicked() {
+                                if let Some(p) = rfd::FileDialog::new().add_filter("trace", &["jsonl"]).save_file() {
+                                    if let Err(e) = nag.start_recording(p) {
+                                        let mut t = Toast::error(format!("Cannot record trace: {}", e));
+                                        t.duration(Some(Duration::from_secs(5)));
+                                        self.toasts.add(t);
+                                    }
+                                }
+                            }
                             if let Some(evt) = nag.get_server_event() {
 
                                 let fmt_str = match evt {
@@ -167,10 +253,12 @@ impl eframe::App for MainWindow {
                             let r_tx = s_tx_resp.rect;
                             let r_rx = s_rx_resp.rect;
                             
-                            if self.last_data_query_time.elapsed().as_millis() > 250 {
+                            let query_elapsed = self.last_data_query_time.elapsed();
+                            if query_elapsed.as_millis() > 250 {
+                                // Counters are reset on every query, so scale them to bytes/sec
                                 if let Some((tx, rx)) = nag.get_data_rate() {
-                                    self.last_tx_rate = tx;
-                                    self.last_rx_rate = rx;
+                                    self.last_tx_rate = (tx as f32 / query_elapsed.as_secs_f32()) as u32;
+                                    self.last_rx_rate = (rx as f32 / query_elapsed.as_secs_f32()) as u32;
                                 }
                                 self.last_data_query_time = Instant::now();
                             }
@@ -244,12 +332,66 @@ impl eframe::App for MainWindow {
                         self.toasts.add(t);
                     }
                     PageAction::RegisterNag(n) => {
+                        self.reboot_detector = RebootDetector::default();
+                        self.conn_events = Some(n.subscribe_connection_events());
                         self.nag = Some(n)
                     },
                 }
             });
+            while let Ok((text, kind)) = self.notify_rx.try_recv() {
+                let mut t = Toast::custom(text, kind);
+                t.closable(true);
+                t.duration(Some(Duration::from_secs(5)));
+                self.toasts.add(t);
+            }
             self.toasts.show(&ctx);
 
+            // Prompt to archive a crash
+            let crash = self.crash_prompt.read().ok().and_then(|c| c.clone());
+            if let (Some(evt), Some(nag)) = (crash, self.nag.clone()) {
+                let mut close = false;
+                egui::Window::new("TCU crash detected").collapsible(false).resizable(false).show(ctx, |ui| {
+                    ui.label(format!("The TCU rebooted at {}, and stored a coredump of the crash.", evt.time.format("%H:%M:%S")));
+                    ui.label(evt.reason.to_string());
+                    ui.label("Save it along with the log lines around the reboot, to report the crash.");
+                    ui.horizontal(|row| {
+                        if row.button("Save crash report").clicked() {
+                            if let Some(dir) = rfd::FileDialog::new().set_title("Save crash report").pick_folder() {
+                                let logs_after = self.reboot_detector.logs_after(&evt);
+                                let tx = self.notify_tx.clone();
+                                let nag = nag.clone();
+                                let evt = evt.clone();
+                                let ctx = ctx.clone();
+                                std::thread::spawn(move || {
+                                    let res = nag
+                                        .create_crash_report(&evt, logs_after, |_, _| {})
+                                        .map_err(|e| e.to_string())
+                                        .and_then(|report| report.save(dir).map_err(|e| e.to_string()));
+                                    let _ = tx.send(match res {
+                                        Ok(path) => (format!("Crash report saved to {}", path.display()), ToastLevel::Success),
+                                        Err(e) => (format!("Could not save the crash report: {e}"), ToastLevel::Error),
+                                    });
+                                    ctx.request_repaint();
+                                });
+                                close = true;
+                            }
+                        }
+                        if row.button("Open coredump viewer").clicked() {
+                            self.add_new_page(Box::new(CoredumpPage::new(nag.as_ref().clone(), ctx.clone())));
+                            close = true;
+                        }
+                        if row.button("Ignore").clicked() {
+                            close = true;
+                        }
+                    });
+                });
+                if close {
+                    if let Ok(mut c) = self.crash_prompt.write() {
+                        *c = None;
+                    }
+                }
+            }
+
             // Show Log viewer
             if self.show_logger {
                 egui::Window::new("Log view").open(&mut self.show_logger).show(ctx, |ui| {
@@ -317,13 +459,7 @@ impl eframe::App for MainWindow {
                                 let mut f = OpenOptions::new().write(true).append(false).create(true).open(p).unwrap();
                                 let mut s = String::new();
                                 for msg in &self.logs {
-                                    let li = match msg.lvl {
-                                        EspLogLevel::Debug => "DD",
-                                        EspLogLevel::Info => "II",
-                                        EspLogLevel::Warn => "WW",
-                                        EspLogLevel::Error => "EE",
-                                    };
-                                    s.push_str(&format!("{} {} - ({}) {}\n", msg.timestamp, li, msg.tag, msg.msg));
+                                    s.push_str(&format!("{msg}\n"));
                                 }
                                 f.write_all(s.as_bytes()).unwrap();
 
@@ -0,0 +1,117 @@
+use std::path::Path;
+
+use backend::diag::{
+    calibration::CalibrationDatabase,
+    calibration_db::{CalibrationDbIssue, CalibrationSourceError, SourceFormat},
+};
+use clap::{Args, Subcommand};
+
+use crate::CliResult;
+
+#[derive(Args)]
+pub struct CaldbArgs {
+    #[command(subcommand)]
+    action: CaldbAction,
+}
+
+#[derive(Subcommand)]
+enum CaldbAction {
+    /// Build egs_db.bin from a source directory of YAML/JSON files
+    Build {
+        dir: String,
+        /// File to write the database to
+        #[arg(short, long, default_value = "egs_db.bin")]
+        output: String,
+        /// Build even if the sources have errors
+        #[arg(long)]
+        force: bool,
+    },
+    /// Decompile egs_db.bin into a source directory
+    Decompile {
+        file: String,
+        /// Directory to write the sources to
+        #[arg(short, long)]
+        output: String,
+        /// Write JSON rather than YAML files
+        #[arg(long)]
+        json: bool,
+        /// Replace the YAML and JSON files of an existing source directory. Other files are kept
+        #[arg(long)]
+        force: bool,
+    },
+    /// Check the references between chassis configurations and records, of a source directory or egs_db.bin
+    Check {
+        path: String,
+        /// List every warning, rather than only counting them
+        #[arg(long)]
+        warnings: bool,
+    },
+}
+
+/// Loads a source directory, or an egs_db.bin file
+fn load(path: &str) -> CliResult<CalibrationDatabase> {
+    if Path::new(path).is_dir() {
+        CalibrationDatabase::load_sources(path).map_err(|e| e.to_string())
+    } else {
+        let bytes = std::fs::read(path).map_err(|e| format!("Could not read {path}: {e}"))?;
+        CalibrationDatabase::decode(&bytes).map_err(|e| format!("{path}: {e}"))
+    }
+}
+
+/// Prints the issues of a database. Returns the number of errors
+fn print_issues(issues: &[CalibrationDbIssue], list_warnings: bool) -> usize {
+    let (errors, warnings): (Vec<_>, Vec<_>) = issues.iter().partition(|i| i.is_error());
+    for e in &errors {
+        eprintln!("error: {e}");
+    }
+    if list_warnings {
+        for w in &warnings {
+            eprintln!("warning: {w}");
+        }
+    }
+    eprintln!("{} errors, {} warnings", errors.len(), warnings.len());
+    errors.len()
+}
+
+fn print_summary(db: &CalibrationDatabase) {
+    println!("EGS part numbers:          {}", db.egs_list.len());
+    println!("Hydraulic calibrations:    {}", db.hydralic_calibrations.len());
+    println!("Mechanical calibrations:   {}", db.mechanical_calibrations.len());
+    println!("TCC property calibrations: {}", db.torqueconverter_calibrations.len());
+    println!("Shift algo calibrations:   {}", db.shift_algo_map_calibration.len());
+}
+
+pub fn run(args: &CaldbArgs) -> CliResult<()> {
+    match &args.action {
+        CaldbAction::Build { dir, output, force } => {
+            let db = CalibrationDatabase::load_sources(dir).map_err(|e| e.to_string())?;
+            let errors = print_issues(&db.validate(), false);
+            if errors != 0 && !force {
+                return Err(format!("Sources have {errors} errors, not building (Use --force to build anyway)"));
+            }
+            std::fs::write(output, db.encode()).map_err(|e| format!("Could not write {output}: {e}"))?;
+            print_summary(&db);
+            println!("Database written to {output}");
+            Ok(())
+        }
+        CaldbAction::Decompile { file, output, json, force } => {
+            let db = load(file)?;
+            let format = if *json { SourceFormat::Json } else { SourceFormat::Yaml };
+            db.write_sources(output, format, *force).map_err(|e| match e {
+                CalibrationSourceError::NotEmpty { .. } => format!("{e} (Use --force to replace its contents)"),
+                e => e.to_string(),
+            })?;
+            print_summary(&db);
+            println!("Sources written to {output}");
+            Ok(())
+        }
+        CaldbAction::Check { path, warnings } => {
+            let db = load(path)?;
+            print_summary(&db);
+            match print_issues(&db.validate(), *warnings) {
+                0 => Ok(()),
+                errors => Err(format!("{errors} errors found")),
+            }
+        }
+    }
+}
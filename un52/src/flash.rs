@@ -0,0 +1,106 @@
+use std::io::Write;
+
+use backend::{
+    diag::{
+        flash::{DownloadError, DownloadOptions, PartitionInfo},
+        Nag52Diag,
+    },
+    hw::firmware::{load_binary_from_path, FirmwareComparison},
+};
+use clap::ValueEnum;
+
+use crate::CliResult;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Partition {
+    /// Coredump of the last crash
+    Coredump,
+    /// Running firmware
+    Running,
+    /// OTA partition that the next firmware update is written to
+    NextOta,
+    /// Embedded file container (MODULE_SETTINGS)
+    Embed,
+    /// Whole flash
+    Full,
+}
+
+fn partition_info(nag: &Nag52Diag, partition: Partition) -> CliResult<PartitionInfo> {
+    match partition {
+        Partition::Coredump => nag.get_coredump_flash_info(),
+        Partition::Running => nag.get_running_partition_flash_info(),
+        Partition::NextOta => nag.get_next_ota_partition_flash_info(),
+        Partition::Embed => nag.get_embed_file_info(),
+        Partition::Full => Ok(nag.get_total_flash_size()),
+    }
+    .map_err(|e| format!("Could not locate partition: {e}"))
+}
+
+/// Progress goes to stderr, so stdout stays clean when piped
+fn print_progress(action: &str, addr: u32, done: u32, total: u32) {
+    let pct = if total == 0 { 100 } else { done as u64 * 100 / total as u64 };
+    eprint!("\r{action} 0x{addr:08X} {done}/{total} bytes ({pct}%)");
+    let _ = std::io::stderr().flush();
+}
+
+pub fn backup(nag: &Nag52Diag, partition: Partition, output: &str, opts: &DownloadOptions) -> CliResult<()> {
+    let info = partition_info(nag, partition)?;
+    eprintln!("Reading {} bytes from 0x{:08X}", info.size, info.address);
+    let res = nag
+        .download_partition(&info, output, opts, |p| {
+            print_progress(if p.verifying { "Verifying" } else { "Reading" }, p.address, p.done, p.total)
+        })
+        .map_err(|e| match e {
+            DownloadError::VerifyFailed { .. } => format!("\n{e}"),
+            e => format!("\n{e}. Run again with --resume to continue the backup"),
+        })?;
+    eprintln!();
+    if res.resumed_from != 0 {
+        eprintln!("Resumed from {} bytes", res.resumed_from);
+    }
+    let sha: String = res.sha256.iter().map(|b| format!("{b:02x}")).collect();
+    println!("Saved {} bytes to {output} ({} retries). SHA-256 {sha}", info.size, res.retries);
+    Ok(())
+}
+
+/// Flashes a firmware image. Identical builds are skipped, and warnings found when comparing
+/// with the running firmware stop the update, unless `force` is set. Secure version downgrades are always refused
+pub fn flash_firmware(nag: &Nag52Diag, path: &str, reboot: bool, force: bool) -> CliResult<()> {
+    let fw = load_binary_from_path(path.to_string()).map_err(|e| e.to_string())?;
+    match nag.get_running_fw_info() {
+        Ok(running) => {
+            let cmp = FirmwareComparison::new(&running, &fw.header);
+            eprintln!("Current firmware: {} ({} {}, {})", running.get_version(), running.get_date(), running.get_time(), running.get_branch());
+            eprintln!("New firmware:     {} ({} {}, {})", fw.header.get_version(), fw.header.get_date(), fw.header.get_time(), fw.header.get_branch());
+            for issue in &cmp.issues {
+                eprintln!("WARNING. {issue}");
+            }
+            if cmp.is_blocked() {
+                return Err("Refusing to flash this firmware".into());
+            }
+            if cmp.is_identical() && !force {
+                println!("Firmware is already running, skipping update (Use --force to flash anyway)");
+                return Ok(());
+            }
+            if !cmp.issues.is_empty() && !force {
+                return Err("Not flashing due to the warnings above (Use --force to flash anyway)".into());
+            }
+        }
+        Err(e) => eprintln!("Could not read the current firmware ({e}), firmware is not compared"),
+    }
+    let (start_addr, bs) = nag
+        .begin_ota(fw.raw.len() as u32)
+        .map_err(|e| format!("Failed to prepare for firmware update. {e}"))?;
+    let mut written = 0;
+    for (bid, block) in fw.raw.chunks(bs as usize).enumerate() {
+        nag.transfer_data(((bid + 1) & 0xFF) as u8, block).map_err(|e| {
+            format!("\nFailed to write to address 0x{:08X}. {e}", start_addr + written)
+        })?;
+        written += block.len() as u32;
+        print_progress("Writing", start_addr + written, written, fw.raw.len() as u32);
+    }
+    eprintln!();
+    nag.end_ota(reboot).map_err(|e| format!("Firmware verification failed. {e}"))?;
+    println!("Flashed {} bytes to 0x{start_addr:08X}", fw.raw.len());
+    Ok(())
+}
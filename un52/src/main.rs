@@ -0,0 +1,257 @@
+//! Ultimate-NAG52 command line tool
+//!
+//! Headless counterpart of the config app, for scripting bench procedures
+//! (Or running them over SSH on a computer in the car).
+//! Every subcommand prints its result to stdout, and errors to stderr, exiting with status 1.
+
+use std::{process::ExitCode, sync::Arc};
+
+use backend::{
+    diag::{device_modes::TcuDeviceMode, flash::DownloadOptions, AdapterHw, AdapterType, Nag52Diag},
+    ecu_diagnostics::{
+        hardware::{passthru::PassthruScanner, HardwareInfo, HardwareScanner},
+        DiagError,
+    },
+    hw::{sim_scanner::Nag52SimScanner, usb_scanner::Nag52UsbScanner},
+};
+#[cfg(target_os = "linux")]
+use backend::ecu_diagnostics::hardware::socketcan::SocketCanScanner;
+use clap::{Parser, Subcommand, ValueEnum};
+
+mod caldb;
+mod flash;
+mod rli;
+mod scn;
+
+#[derive(Parser)]
+#[command(name = "un52", version, about = "Ultimate-NAG52 command line tool")]
+struct Cli {
+    /// Adapter API to use
+    #[arg(short, long, value_enum, default_value_t = Adapter::Usb, env = "UN52_ADAPTER", global = true)]
+    adapter: Adapter,
+    /// Name of the adapter, as shown by `un52 list`. Defaults to the first adapter found
+    #[arg(short, long, env = "UN52_DEVICE", global = true)]
+    device: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Adapter {
+    Usb,
+    Passthru,
+    #[cfg(target_os = "linux")]
+    Socketcan,
+    Sim,
+}
+
+impl From<Adapter> for AdapterType {
+    fn from(a: Adapter) -> Self {
+        match a {
+            Adapter::Usb => AdapterType::USB,
+            Adapter::Passthru => AdapterType::Passthru,
+            #[cfg(target_os = "linux")]
+            Adapter::Socketcan => AdapterType::SocketCAN,
+            Adapter::Sim => AdapterType::Simulation,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List adapters found by the selected adapter API
+    List,
+    /// Print TCU identification, serial number, firmware and device mode
+    Ident,
+    /// Read or set the device mode
+    Mode {
+        #[command(subcommand)]
+        action: ModeAction,
+    },
+    /// Back up a flash partition to a file
+    Backup {
+        #[arg(value_enum)]
+        partition: flash::Partition,
+        /// File to write the partition to
+        #[arg(short, long)]
+        output: String,
+        /// Continue a failed backup from the end of the output file
+        #[arg(long)]
+        resume: bool,
+        /// Attempts per chunk before the backup fails
+        #[arg(long, default_value_t = DownloadOptions::default().max_retries)]
+        retries: u32,
+        /// Skip reading the partition again to verify the backup. Halves the backup time
+        #[arg(long)]
+        no_verify: bool,
+    },
+    /// Flash a firmware .bin file
+    Flash {
+        file: String,
+        /// Do not reboot the TCU once the firmware is written
+        #[arg(long)]
+        no_reboot: bool,
+        /// Flash even if the TCU already runs this build, or the comparison with the running firmware has warnings
+        #[arg(long)]
+        force: bool,
+    },
+    /// Dump RLIs (Record local identifiers)
+    Rli(rli::RliArgs),
+    /// Read or write SCN coding of MODULE_SETTINGS groups
+    Scn(scn::ScnArgs),
+    /// Build, decompile or check the calibration database (egs_db.bin). Does not need a TCU
+    Caldb(caldb::CaldbArgs),
+}
+
+#[derive(Subcommand)]
+enum ModeAction {
+    /// Print the current device mode
+    Get,
+    /// Set the device mode. Flags are separated by '|', EG: 'SLAVE|CANLOGGER'
+    Set {
+        mode: String,
+        /// Store the mode in EEPROM, so it persists after a reboot
+        #[arg(long)]
+        store: bool,
+    },
+    /// Return mode control back to the TCU
+    Release,
+}
+
+pub type CliResult<T> = Result<T, String>;
+
+/// Parses a byte, either as hex (0x20) or decimal
+pub fn parse_u8(s: &str) -> CliResult<u8> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| format!("Invalid value '{s}': {e}"))
+}
+
+/// Parses hex bytes, optionally separated by spaces (EG: "01 A0 FF" or "01A0FF")
+pub fn parse_hex_bytes(s: &str) -> CliResult<Vec<u8>> {
+    let s: String = s.split_whitespace().collect();
+    if let Some(c) = s.chars().find(|c| !c.is_ascii_hexdigit()) {
+        return Err(format!("Hex string '{s}' contains '{c}', which is not a hex digit"));
+    }
+    if !s.len().is_multiple_of(2) {
+        return Err(format!("Hex string '{s}' has an odd length"));
+    }
+    // Only ASCII hex digits, so every byte is a character
+    Ok(s.as_bytes().chunks(2).map(|c| u8::from_str_radix(std::str::from_utf8(c).unwrap(), 16).unwrap()).collect())
+}
+
+fn list_devices(adapter: Adapter) -> Vec<HardwareInfo> {
+    match adapter {
+        Adapter::Usb => Nag52UsbScanner::new().list_devices(),
+        Adapter::Passthru => PassthruScanner::new().list_devices(),
+        #[cfg(target_os = "linux")]
+        Adapter::Socketcan => SocketCanScanner::new().list_devices(),
+        Adapter::Sim => Nag52SimScanner::new().list_devices(),
+    }
+}
+
+fn open_device(cli: &Cli) -> CliResult<Nag52Diag> {
+    let devices = list_devices(cli.adapter);
+    let info = match &cli.device {
+        Some(name) => devices.iter().find(|d| &d.name == name),
+        None => devices.first(),
+    }
+    .ok_or_else(|| match &cli.device {
+        Some(name) => format!("Adapter '{name}' not found"),
+        None => "No adapters found".to_string(),
+    })?;
+    let hw = AdapterHw::try_connect(info, cli.adapter.into())
+        .map_err(|e| DiagError::from(Arc::new(e)))
+        .map_err(|e| format!("Could not open '{}': {e}", info.name))?;
+    Nag52Diag::new(hw).map_err(|e| format!("Could not connect to the TCU: {e}"))
+}
+
+pub fn fmt_device_mode(mode: TcuDeviceMode) -> String {
+    let names: Vec<&str> = mode.iter_names().map(|(name, _)| name).collect();
+    format!("{} (0x{:04X})", names.join("|"), mode.bits())
+}
+
+fn parse_device_mode(s: &str) -> CliResult<TcuDeviceMode> {
+    let mut mode = TcuDeviceMode::empty();
+    for flag in s.split('|').map(|f| f.trim()).filter(|f| !f.is_empty()) {
+        mode |= TcuDeviceMode::from_name(&flag.to_uppercase())
+            .ok_or_else(|| format!("Unknown device mode '{flag}'"))?;
+    }
+    Ok(mode)
+}
+
+fn print_ident(nag: &Nag52Diag) -> CliResult<()> {
+    let ident = nag.query_ecu_data().map_err(|e| e.to_string())?;
+    println!("EGS mode:         {}", ident.egs_mode);
+    println!("PCB version:      {}", ident.board_ver);
+    println!("Production date:  {:02}/{:02}/20{:02}", ident.manf_day, ident.manf_month, ident.manf_year);
+    println!("HW date:          week {} 20{:02}", ident.hw_week, ident.hw_year);
+    println!("SW date:          week {} 20{:02}", ident.sw_week, ident.sw_year);
+    match nag.get_ecu_sn() {
+        Ok(sn) => println!("Serial number:    {sn}"),
+        Err(e) => println!("Serial number:    Unknown ({e})"),
+    }
+    match nag.get_running_fw_info() {
+        Ok(fw) => println!("Firmware:         {} ({} {})", fw.get_version(), fw.get_date(), fw.get_time()),
+        Err(e) => println!("Firmware:         Unknown ({e})"),
+    }
+    match nag.read_device_mode() {
+        Ok(mode) => println!("Device mode:      {}", fmt_device_mode(mode)),
+        Err(e) => println!("Device mode:      Unknown ({e})"),
+    }
+    Ok(())
+}
+
+fn run(cli: &Cli) -> CliResult<()> {
+    if let Command::Caldb(args) = &cli.command {
+        return caldb::run(args);
+    }
+    if let Command::List = cli.command {
+        for dev in list_devices(cli.adapter) {
+            match dev.vendor {
+                Some(vendor) => println!("{} ({vendor})", dev.name),
+                None => println!("{}", dev.name),
+            }
+        }
+        return Ok(());
+    }
+    let nag = open_device(cli)?;
+    match &cli.command {
+        Command::List | Command::Caldb(_) => unreachable!(),
+        Command::Ident => print_ident(&nag),
+        Command::Mode { action } => match action {
+            ModeAction::Get => {
+                let mode = nag.read_device_mode().map_err(|e| e.to_string())?;
+                println!("{}", fmt_device_mode(mode));
+                Ok(())
+            }
+            ModeAction::Set { mode, store } => {
+                let mode = parse_device_mode(mode)?;
+                nag.set_device_mode(mode, *store).map_err(|e| e.to_string())?;
+                println!("Device mode set to {}", fmt_device_mode(mode));
+                Ok(())
+            }
+            ModeAction::Release => nag.return_mode_control_to_ecu().map_err(|e| e.to_string()),
+        },
+        Command::Backup { partition, output, resume, retries, no_verify } => {
+            let opts = DownloadOptions { resume: *resume, max_retries: *retries, verify: !*no_verify, ..Default::default() };
+            flash::backup(&nag, *partition, output, &opts)
+        }
+        Command::Flash { file, no_reboot, force } => flash::flash_firmware(&nag, file, !no_reboot, *force),
+        Command::Rli(args) => rli::run(&nag, args),
+        Command::Scn(args) => scn::run(&nag, args),
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(&cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
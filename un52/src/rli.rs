@@ -0,0 +1,76 @@
+use std::time::{Duration, Instant};
+
+use backend::diag::Nag52Diag;
+use clap::Args;
+
+use crate::{parse_u8, CliResult};
+
+/// RLIs the TCU exposes, see diag_data.h in the TCM source code
+const RLIS: &[(u8, &str)] = &[
+    (0x20, "Gearbox sensors"),
+    (0x21, "Solenoids"),
+    (0x22, "CAN Data"),
+    (0x23, "System usage"),
+    (0x24, "TCC status"),
+    (0x25, "Gearbox pressures"),
+    (0x27, "Shift info"),
+    (0x30, "Clutch speeds"),
+    (0x31, "Shift algorithm"),
+];
+
+#[derive(Args)]
+pub struct RliArgs {
+    /// RLIs to read (EG: 0x20). Defaults to all RLIs
+    #[arg(value_parser = parse_u8)]
+    ids: Vec<u8>,
+    /// Keep reading until interrupted (Or until --count samples are read)
+    #[arg(short, long)]
+    watch: bool,
+    /// Interval between reads when watching
+    #[arg(long, default_value_t = 100)]
+    interval_ms: u64,
+    /// Number of samples to read when watching
+    #[arg(long)]
+    count: Option<u64>,
+}
+
+fn rli_name(id: u8) -> &'static str {
+    RLIS.iter().find(|(x, _)| *x == id).map(|(_, name)| *name).unwrap_or("Unknown")
+}
+
+pub fn run(nag: &Nag52Diag, args: &RliArgs) -> CliResult<()> {
+    let ids: Vec<u8> = if args.ids.is_empty() { RLIS.iter().map(|(id, _)| *id).collect() } else { args.ids.clone() };
+    let start = Instant::now();
+    let mut sample = 0;
+    loop {
+        let sample_start = Instant::now();
+        let mut failed = 0;
+        for id in &ids {
+            let prefix = match args.watch {
+                true => format!("{} ", start.elapsed().as_millis()),
+                false => String::new(),
+            };
+            match nag.with_kwp(|k| k.kwp_read_custom_local_identifier(*id)) {
+                Ok(data) => println!("{prefix}0x{id:02X} {}: {:02X?}", rli_name(*id), data),
+                Err(e) => {
+                    failed += 1;
+                    eprintln!("{prefix}0x{id:02X} {}: {e}", rli_name(*id))
+                }
+            }
+        }
+        sample += 1;
+        if !args.watch {
+            return match failed == ids.len() {
+                true => Err("Could not read any RLI".into()),
+                false => Ok(()),
+            };
+        }
+        if args.count.is_some_and(|c| sample >= c) {
+            return Ok(());
+        }
+        let interval = Duration::from_millis(args.interval_ms);
+        if let Some(wait) = interval.checked_sub(sample_start.elapsed()) {
+            std::thread::sleep(wait);
+        }
+    }
+}
@@ -0,0 +1,469 @@
+use std::io::{Cursor, Read};
+
+use backend::{
+    diag::{
+        settings::{ModuleSettingsData, SettingsData, SettingsType, SettingsVariable},
+        nvs::NvsPartition,
+        settings_bundle::{BundleImport, SettingsBundle},
+        settings_migration::SettingsMigration,
+        module_settings_flash_store::{ModuleSettingsUpload, ModuleSettingsUploadProgress},
+        Nag52Diag,
+    },
+    ecu_diagnostics::kwp2000::{KwpCommand, KwpSessionTypeByte},
+    serde_yaml,
+};
+use clap::{Args, Subcommand};
+use zip::ZipArchive;
+
+use crate::{parse_hex_bytes, parse_u8, CliResult};
+
+#[derive(Args)]
+pub struct ScnArgs {
+    /// MODULE_SETTINGS.yml to use. If not specified, it is read from the TCU
+    #[arg(long)]
+    yml: Option<String>,
+    #[command(subcommand)]
+    action: ScnAction,
+}
+
+#[derive(Subcommand)]
+enum ScnAction {
+    /// List the settings groups in MODULE_SETTINGS
+    List,
+    /// Read the coding of a settings group
+    Read {
+        /// Name or SCN ID of the settings group
+        group: String,
+        /// Read the default coding, rather than the current coding
+        #[arg(long)]
+        default: bool,
+        /// Only print the raw coding string
+        #[arg(long)]
+        raw: bool,
+    },
+    /// Write a raw coding string to a settings group. It is checked against MODULE_SETTINGS first
+    Write {
+        /// Name or SCN ID of the settings group
+        group: String,
+        /// Coding string, as hex bytes
+        coding: String,
+        /// Only check the length of the coding string, not its values. With an SCN ID,
+        /// MODULE_SETTINGS is not needed
+        #[arg(long)]
+        unchecked: bool,
+    },
+    /// Change settings within a group, EG: `un52 scn set TCC enabled=false min_locking_rpm=1200`.
+    /// Settings within structures are addressed as `struct.setting`
+    Set {
+        /// Name or SCN ID of the settings group
+        group: String,
+        #[arg(required = true)]
+        values: Vec<String>,
+    },
+    /// Save the settings of every group to a settings bundle (JSON)
+    Export { file: String },
+    /// Show the differences between a settings bundle and the TCU, then write every changed group
+    Import {
+        file: String,
+        /// Only show the differences
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Migrate the settings of an EEPROM (NVS) backup made with an older MODULE_SETTINGS layout,
+    /// E.g. after a firmware update. Settings are matched by name
+    Migrate {
+        /// MODULE_SETTINGS.yml of the firmware the backup was made with
+        old_yml: String,
+        /// EEPROM (NVS partition) backup
+        nvs: String,
+        /// Only show the differences
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Upload a MODULE_SETTINGS.yml to the TCU, then read it back to verify it
+    Upload {
+        file: String,
+        /// Upload even if the settings keys differ from the MODULE_SETTINGS on the TCU
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+/// Reads MODULE_SETTINGS.yml from the embedded container on the TCU
+fn read_module_settings_from_tcu(nag: &Nag52Diag) -> CliResult<String> {
+    let part_info = nag.get_embed_file_info().map_err(|e| e.to_string())?;
+    eprintln!("Reading MODULE_SETTINGS from the TCU");
+    let mut read_contents = Vec::new();
+    while read_contents.len() < part_info.size as usize {
+        let to_read = std::cmp::min(250, part_info.size as usize - read_contents.len()) as u8;
+        let addr = part_info.address + read_contents.len() as u32;
+        let data = nag.read_mem_by_addr_ext(addr, to_read).map_err(|e| e.to_string())?;
+        read_contents.extend_from_slice(&data);
+    }
+    let mut zip = ZipArchive::new(Cursor::new(read_contents)).map_err(|_| "Data on EGS is corrupt!".to_string())?;
+    let mut mod_settings = zip
+        .by_name("MODULE_SETTINGS.yml")
+        .map_err(|_| "Data on EGS does not contain MODULE_SETTINGS".to_string())?;
+    let mut s = String::new();
+    mod_settings.read_to_string(&mut s).map_err(|e| e.to_string())?;
+    Ok(s)
+}
+
+fn load_module_settings(nag: &Nag52Diag, yml: Option<&str>) -> CliResult<ModuleSettingsData> {
+    let s = match yml {
+        Some(path) => std::fs::read_to_string(path).map_err(|e| format!("Could not read {path}: {e}"))?,
+        None => read_module_settings_from_tcu(nag)?,
+    };
+    serde_yaml::from_str(&s).map_err(|e| format!("Invalid MODULE_SETTINGS: {e}"))
+}
+
+fn find_group<'a>(settings: &'a ModuleSettingsData, group: &str) -> CliResult<&'a SettingsData> {
+    let id = parse_u8(group).ok();
+    settings
+        .settings
+        .iter()
+        .find(|s| s.name.eq_ignore_ascii_case(group) || (id.is_some() && s.scn_id == id))
+        .ok_or_else(|| format!("No settings group '{group}'"))
+}
+
+fn read_coding(nag: &Nag52Diag, scn_id: u8, default: bool) -> CliResult<Vec<u8>> {
+    let id = if default { scn_id | 0b10000000 } else { scn_id };
+    nag.with_kwp(|k| k.send_byte_array_with_response(&[0x21, 0xFC, id]))
+        .map_err(|e| format!("Could not read coding of SCN 0x{scn_id:02X}: {e}"))
+        .and_then(|x| x.get(3..).map(|c| c.to_vec()).ok_or(format!("Response to reading SCN 0x{scn_id:02X} is too short")))
+}
+
+fn write_coding(nag: &Nag52Diag, scn_id: u8, coding: &[u8]) -> CliResult<()> {
+    nag.with_kwp(|kwp| {
+        let mut tx = vec![KwpCommand::WriteDataByLocalIdentifier.into(), 0xFC, scn_id];
+        tx.extend_from_slice(coding);
+        kwp.send_byte_array_with_response(&tx)
+    })
+    .map(|_| ())
+    .map_err(|e| format!("Could not write coding of SCN 0x{scn_id:02X}: {e}"))
+}
+
+/// Checks that a new coding string is as long as the current one, as the TCU
+/// would otherwise misinterpret it
+fn check_coding_len(nag: &Nag52Diag, scn_id: u8, name: &str, coding: &[u8]) -> CliResult<()> {
+    let current = read_coding(nag, scn_id, false)?;
+    if coding.len() != current.len() {
+        return Err(format!(
+            "Coding of {name} is {} bytes long, but {} bytes were given",
+            current.len(),
+            coding.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Writes a coding string after checking it against the constraints in MODULE_SETTINGS
+fn write_checked(nag: &Nag52Diag, settings: &ModuleSettingsData, group: &SettingsData, coding: &[u8]) -> CliResult<()> {
+    nag.write_settings_coding(settings, group, coding)
+        .map_err(|e| format!("Could not write coding of {}: {e}", group.name))
+}
+
+fn firmware_version(nag: &Nag52Diag) -> String {
+    nag.get_running_fw_info().map(|h| h.get_version()).unwrap_or("Unknown".into())
+}
+
+fn import_bundle(nag: &Nag52Diag, settings: &ModuleSettingsData, file: &str, dry_run: bool) -> CliResult<()> {
+    let s = std::fs::read_to_string(file).map_err(|e| format!("Could not read {file}: {e}"))?;
+    let bundle = SettingsBundle::from_json(&s).map_err(|e| format!("Invalid settings bundle {file}: {e}"))?;
+    let version = firmware_version(nag);
+    if bundle.firmware_version != version {
+        eprintln!("warning: Bundle was saved on firmware {}, the TCU runs {version}", bundle.firmware_version);
+    }
+    if bundle.key_magic != settings.key_magic() {
+        eprintln!("warning: Bundle was saved with a different MODULE_SETTINGS layout. Settings are matched by name");
+    }
+    let codings = nag.read_settings_codings(settings).map_err(|e| format!("Could not read settings: {e}"))?;
+    write_import(nag, settings, &bundle.import(settings, &codings), ("unchanged", "skipped"), dry_run)
+}
+
+fn migrate(nag: &Nag52Diag, settings: &ModuleSettingsData, old_yml: &str, nvs: &str, dry_run: bool) -> CliResult<()> {
+    let old = load_module_settings(nag, Some(old_yml))?;
+    let nvs = std::fs::read(nvs).map_err(|e| format!("Could not read {nvs}: {e}"))?;
+    let nvs = NvsPartition::parse(&nvs).map_err(|e| format!("Invalid EEPROM backup: {e}"))?;
+    let old_codings = old.codings_from_nvs(&nvs);
+    if old_codings.is_empty() {
+        return Err("EEPROM backup does not contain any settings of the old MODULE_SETTINGS".into());
+    }
+    let codings = nag.read_settings_codings(settings).map_err(|e| format!("Could not read settings: {e}"))?;
+    let migration = SettingsMigration::new(&old, &old_codings, settings, &codings);
+    for c in &migration.converted {
+        println!("Converted {c}");
+    }
+    for g in &migration.import.groups {
+        for path in &g.report.missing {
+            eprintln!("warning: {}.{path} is new, kept at its current value", g.name);
+        }
+    }
+    write_import(nag, settings, &migration.import, ("dropped", "dropped"), dry_run)
+}
+
+fn upload(nag: &Nag52Diag, file: &str, force: bool) -> CliResult<()> {
+    let s = std::fs::read_to_string(file).map_err(|e| format!("Could not read {file}: {e}"))?;
+    let upload = ModuleSettingsUpload::new(&s).map_err(|e| e.to_string())?;
+    let mut last = None;
+    nag.upload_module_settings(&upload, force, |p| {
+        let stage = match p {
+            ModuleSettingsUploadProgress::Write { .. } => "Writing",
+            ModuleSettingsUploadProgress::Verify { .. } => "Verifying",
+        };
+        if last != Some(stage) {
+            eprintln!("{stage} MODULE_SETTINGS");
+            last = Some(stage);
+        }
+    })
+    .map_err(|e| format!("Could not upload MODULE_SETTINGS: {e}"))?;
+    println!("Uploaded {file} ({} settings groups, key magic 0x{:08X})", upload.settings.settings.len(), upload.header.key_magic);
+    Ok(())
+}
+
+/// Prints the changes of an import, then writes every changed group. `(invalid, unused)` say what
+/// happens to settings with a value that cannot be converted, and to settings that are not on this TCU
+fn write_import(
+    nag: &Nag52Diag,
+    settings: &ModuleSettingsData,
+    import: &BundleImport,
+    (invalid, unused): (&str, &str),
+    dry_run: bool,
+) -> CliResult<()> {
+    for g in &import.missing_groups {
+        eprintln!("warning: {g} is not on this TCU, skipped");
+    }
+    for g in &import.groups {
+        for c in &g.report.changed {
+            println!("{}.{}: {} -> {}", g.name, c.path, c.old, c.new);
+        }
+        for (path, e) in &g.report.errors {
+            eprintln!("warning: {}.{path}: {e}, {invalid}", g.name);
+        }
+        for path in &g.report.unused {
+            eprintln!("warning: {}.{path} is not on this TCU, {unused}", g.name);
+        }
+    }
+    let groups: Vec<(&SettingsData, &[u8])> = import
+        .changed_groups()
+        .filter_map(|g| Some((settings.settings.iter().find(|s| s.name == g.name)?, g.coding.as_slice())))
+        .collect();
+    if groups.is_empty() {
+        println!("TCU already has these settings");
+        return Ok(());
+    }
+    if dry_run {
+        println!("{} groups would be written", groups.len());
+        return Ok(());
+    }
+    nag.write_settings_codings(settings, &groups)
+        .map_err(|e| format!("Could not write settings: {e}"))?;
+    println!("{} groups written", groups.len());
+    Ok(())
+}
+
+fn fmt_value(value: &SettingsType) -> String {
+    match value {
+        SettingsType::Bool(b) => b.to_string(),
+        SettingsType::F32(v) => v.to_string(),
+        SettingsType::U32(v) => v.to_string(),
+        SettingsType::I32(v) => v.to_string(),
+        SettingsType::U16(v) => v.to_string(),
+        SettingsType::I16(v) => v.to_string(),
+        SettingsType::U8(v) => v.to_string(),
+        SettingsType::I8(v) => v.to_string(),
+        SettingsType::Enum { value, mapping } => match mapping.mappings.get(value) {
+            Some(desc) => format!("{} ({value})", desc.name),
+            None => format!("Unknown ({value})"),
+        },
+        SettingsType::Struct { raw, .. } | SettingsType::Raw(raw) => format!("{:02X?}", raw),
+        SettingsType::Array(values) => values.iter().map(fmt_value).collect::<Vec<_>>().join(", "),
+    }
+}
+
+fn print_value(name: &str, var: &SettingsVariable, value: &SettingsType, settings: &ModuleSettingsData) {
+    match value {
+        SettingsType::Struct { raw, s } => print_params(&s.params, raw, settings, &format!("{name}.")),
+        SettingsType::Array(values) if values.iter().any(|v| matches!(v, SettingsType::Struct { .. })) => {
+            for (idx, v) in values.iter().enumerate() {
+                print_value(&format!("{name}[{idx}]"), var, v, settings);
+            }
+        }
+        _ => {
+            let unit = var.unit.as_deref().map(|u| format!(" {u}")).unwrap_or_default();
+            println!("{name} = {}{unit}", fmt_value(value));
+        }
+    }
+}
+
+fn print_params(params: &[SettingsVariable], raw: &[u8], settings: &ModuleSettingsData, prefix: &str) {
+    for var in params {
+        let name = format!("{prefix}{}", var.name);
+        match var.to_settings_type_or_raw(raw, &settings.enums, &settings.internal_structures) {
+            Ok((value, None)) => print_value(&name, var, &value, settings),
+            Ok((value, Some(e))) => println!("{name} = {} ({e})", fmt_value(&value)),
+            Err(e) => println!("{name} = ? ({e})"),
+        }
+    }
+}
+
+fn parse_value(value: SettingsType, s: &str) -> CliResult<SettingsType> {
+    let invalid = |e: String| format!("Invalid value '{s}': {e}");
+    Ok(match value {
+        SettingsType::Bool(_) => SettingsType::Bool(match s.to_lowercase().as_str() {
+            "true" | "1" => true,
+            "false" | "0" => false,
+            _ => return Err(invalid("Expected true or false".into())),
+        }),
+        SettingsType::F32(_) => SettingsType::F32(s.parse().map_err(|e| invalid(format!("{e}")))?),
+        SettingsType::U32(_) => SettingsType::U32(s.parse().map_err(|e| invalid(format!("{e}")))?),
+        SettingsType::I32(_) => SettingsType::I32(s.parse().map_err(|e| invalid(format!("{e}")))?),
+        SettingsType::U16(_) => SettingsType::U16(s.parse().map_err(|e| invalid(format!("{e}")))?),
+        SettingsType::I16(_) => SettingsType::I16(s.parse().map_err(|e| invalid(format!("{e}")))?),
+        SettingsType::U8(_) => SettingsType::U8(s.parse().map_err(|e| invalid(format!("{e}")))?),
+        SettingsType::I8(_) => SettingsType::I8(s.parse().map_err(|e| invalid(format!("{e}")))?),
+        SettingsType::Enum { mapping, .. } => {
+            let value = mapping
+                .mappings
+                .iter()
+                .find(|(_, desc)| desc.name.eq_ignore_ascii_case(s))
+                .map(|(v, _)| *v)
+                .or_else(|| s.parse().ok().filter(|v| mapping.mappings.contains_key(v)))
+                .ok_or_else(|| {
+                    let mut names: Vec<&str> = mapping.mappings.values().map(|d| d.name.as_str()).collect();
+                    names.sort();
+                    invalid(format!("Expected one of {}", names.join(", ")))
+                })?;
+            SettingsType::Enum { value, mapping }
+        }
+        SettingsType::Struct { s: ty, .. } => {
+            return Err(invalid(format!("{} is a structure, set its members instead", ty.name)))
+        }
+        SettingsType::Array(values) => {
+            let parts: Vec<&str> = s.split(',').map(|p| p.trim()).collect();
+            if parts.len() != values.len() {
+                return Err(invalid(format!("Expected {} comma separated values", values.len())));
+            }
+            SettingsType::Array(
+                values.into_iter().zip(parts).map(|(v, p)| parse_value(v, p)).collect::<CliResult<Vec<_>>>()?,
+            )
+        }
+        SettingsType::Raw(raw) => {
+            let bytes = parse_hex_bytes(s)?;
+            if bytes.len() != raw.len() {
+                return Err(invalid(format!("Expected {} hex bytes", raw.len())));
+            }
+            SettingsType::Raw(bytes)
+        }
+    })
+}
+
+/// Sets a setting in a coding string. `path` is the setting name, with
+/// settings inside of structures addressed as `struct.setting`
+fn set_param(
+    params: &[SettingsVariable],
+    coding: &mut [u8],
+    settings: &ModuleSettingsData,
+    path: &str,
+    s: &str,
+) -> CliResult<()> {
+    let (name, rest) = match path.split_once('.') {
+        Some((name, rest)) => (name, Some(rest)),
+        None => (path, None),
+    };
+    let var = params
+        .iter()
+        .find(|v| v.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("No setting '{name}'"))?;
+    let (value, _) = var
+        .to_settings_type_or_raw(coding, &settings.enums, &settings.internal_structures)
+        .map_err(|e| format!("{name}: {e}"))?;
+    let new_value = match (value, rest) {
+        (SettingsType::Struct { mut raw, s: ty }, Some(rest)) => {
+            set_param(&ty.params, &mut raw, settings, rest, s)?;
+            SettingsType::Struct { raw, s: ty }
+        }
+        (_, Some(_)) => return Err(format!("{name} is not a structure")),
+        (value, None) => parse_value(value, s)?,
+    };
+    var.insert_back_into_coding_string(new_value, coding).map_err(|e| format!("{name}: {e}"))
+}
+
+pub fn run(nag: &Nag52Diag, args: &ScnArgs) -> CliResult<()> {
+    nag.with_kwp(|x| x.kwp_set_session(KwpSessionTypeByte::Extended(0x93)))
+        .map_err(|e| format!("Could not enter diagnostic mode: {e}"))?;
+    // Raw access by SCN ID does not need MODULE_SETTINGS
+    match &args.action {
+        ScnAction::Read { group, default, raw: true } if args.yml.is_none() => {
+            if let Ok(id) = parse_u8(group) {
+                println!("{:02X?}", read_coding(nag, id, *default)?);
+                return Ok(());
+            }
+        }
+        ScnAction::Write { group, coding, unchecked: true } if args.yml.is_none() => {
+            if let Ok(id) = parse_u8(group) {
+                let coding = parse_hex_bytes(coding)?;
+                check_coding_len(nag, id, &format!("SCN 0x{id:02X}"), &coding)?;
+                return write_coding(nag, id, &coding);
+            }
+        }
+        ScnAction::Upload { file, force } => return upload(nag, file, *force),
+        _ => {}
+    }
+
+    let settings = load_module_settings(nag, args.yml.as_deref())?;
+    match &args.action {
+        ScnAction::List => {
+            for s in &settings.settings {
+                let len = s.params.iter().map(|p| p.offset_bytes + p.size_bytes).max().unwrap_or_default();
+                let id = s.scn_id.map(|id| format!("0x{id:02X}")).unwrap_or("----".into());
+                println!("{id} {} ({len} bytes) {}", s.name, s.description.as_deref().unwrap_or_default());
+            }
+            Ok(())
+        }
+        ScnAction::Read { group, default, raw } => {
+            let group = find_group(&settings, group)?;
+            let id = group.scn_id.ok_or_else(|| format!("{} has no SCN ID", group.name))?;
+            let coding = read_coding(nag, id, *default)?;
+            if *raw {
+                println!("{:02X?}", coding);
+            } else {
+                print_params(&group.params, &coding, &settings, "");
+            }
+            Ok(())
+        }
+        ScnAction::Write { group, coding, unchecked } => {
+            let group = find_group(&settings, group)?;
+            let id = group.scn_id.ok_or_else(|| format!("{} has no SCN ID", group.name))?;
+            let coding = parse_hex_bytes(coding)?;
+            check_coding_len(nag, id, &group.name, &coding)?;
+            if *unchecked {
+                write_coding(nag, id, &coding)
+            } else {
+                write_checked(nag, &settings, group, &coding)
+            }
+        }
+        ScnAction::Set { group, values } => {
+            let group = find_group(&settings, group)?;
+            let id = group.scn_id.ok_or_else(|| format!("{} has no SCN ID", group.name))?;
+            let mut coding = read_coding(nag, id, false)?;
+            for v in values {
+                let (path, value) = v.split_once('=').ok_or_else(|| format!("Expected setting=value, got '{v}'"))?;
+                set_param(&group.params, &mut coding, &settings, path.trim(), value.trim())?;
+            }
+            write_checked(nag, &settings, group, &coding)?;
+            print_params(&group.params, &read_coding(nag, id, false)?, &settings, "");
+            Ok(())
+        }
+        ScnAction::Export { file } => {
+            let codings = nag.read_settings_codings(&settings).map_err(|e| format!("Could not read settings: {e}"))?;
+            let bundle = SettingsBundle::new(&settings, firmware_version(nag), &codings);
+            std::fs::write(file, bundle.to_json()).map_err(|e| format!("Could not write {file}: {e}"))?;
+            println!("Settings of {} groups saved to {file}", bundle.groups.len());
+            Ok(())
+        }
+        ScnAction::Import { file, dry_run } => import_bundle(nag, &settings, file, *dry_run),
+        ScnAction::Migrate { old_yml, nvs, dry_run } => migrate(nag, &settings, old_yml, nvs, *dry_run),
+        ScnAction::Upload { .. } => unreachable!("Upload does not need MODULE_SETTINGS"),
+    }
+}